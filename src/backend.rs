@@ -0,0 +1,532 @@
+//! Storage the sync writes to. The core sync logic only goes through [`Backend`], so
+//! destinations other than the local file system can be plugged in with
+//! [`crate::Synchronize::backend`].
+
+use crate::{
+    copy::{self, OnCopied},
+    CopyEngine, CopyMethod,
+};
+use filetime::FileTime;
+use std::{
+    ffi::OsString,
+    fs::{self, File, Metadata},
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Operations performed on the destination, paths are the destination paths being synced
+pub trait Backend: Send + Sync {
+    /// Metadata of `path` without following symlinks, `None` when it doesn't exist
+    fn metadata(&self, path: &Path) -> io::Result<Option<Meta>>;
+
+    /// Names of the entries in the directory at `path`
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>>;
+
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Copy the local file `src` to `dest`, reusing `src_file` when it is already open
+    fn copy(
+        &self,
+        src: &Path,
+        meta: &Metadata,
+        src_file: Option<File>,
+        dest: &Path,
+    ) -> io::Result<CopyMethod>;
+
+    /// [`Backend::copy`], calling `on_copied` with the number of bytes written as the data is
+    /// copied. Backends that can't report progress copy the file in one go.
+    fn copy_with_progress(
+        &self,
+        src: &Path,
+        meta: &Metadata,
+        src_file: Option<File>,
+        dest: &Path,
+        on_copied: OnCopied,
+    ) -> io::Result<CopyMethod> {
+        let _ = on_copied;
+        self.copy(src, meta, src_file, dest)
+    }
+
+    /// Apply unix permission bits, only the readonly bit is used on Windows
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()>;
+
+    fn set_times(&self, path: &Path, atime: FileTime, mtime: FileTime) -> io::Result<()>;
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()>;
+
+    /// Create a symlink to a directory, which Windows tells apart from a symlink to a file
+    fn symlink_dir(&self, target: &Path, path: &Path) -> io::Result<()> {
+        self.symlink(target, path)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Remove a file or symlink
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove an empty directory
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Move a file to `to`, replacing what is there
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let _ = (from, to);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the destination doesn't support renaming files",
+        ))
+    }
+
+    /// Smallest difference between modified times the backend can store
+    fn mtime_resolution(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+
+    /// Whether destination paths can also be used directly with `std::fs`, which content
+    /// checks, delta transfer, merkle trees and file attributes rely on
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+/// The parts of a destination entry's metadata the sync compares and preserves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Meta {
+    pub kind: Kind,
+    pub len: u64,
+    pub mtime: FileTime,
+    pub mode: u32,
+}
+
+impl Meta {
+    pub fn from_local(meta: &Metadata) -> Self {
+        let file_type = meta.file_type();
+        let kind = match () {
+            _ if file_type.is_symlink() => Kind::Symlink,
+            _ if file_type.is_dir() => Kind::Dir,
+            _ if file_type.is_file() => Kind::File,
+            _ => Kind::Other,
+        };
+        Self {
+            kind,
+            len: meta.len(),
+            mtime: FileTime::from_last_modification_time(meta),
+            mode: mode(&meta.permissions()),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn mode(permissions: &fs::Permissions) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    permissions.mode()
+}
+
+#[cfg(not(unix))]
+fn mode(permissions: &fs::Permissions) -> u32 {
+    match permissions.readonly() {
+        true => 0o444,
+        false => 0o666,
+    }
+}
+
+/// The local file system, copying with the configured fallback chain of methods
+#[derive(Debug, Clone)]
+pub(crate) struct Local {
+    pub(crate) copy_strategy: Vec<CopyMethod>,
+    pub(crate) sendfile: bool,
+    pub(crate) keep_partial: bool,
+    pub(crate) engine: CopyEngine,
+    /// Files at least this large are copied in chunks in parallel
+    pub(crate) chunk_threshold: Option<u64>,
+    /// Sources are read without updating their access time
+    pub(crate) noatime: bool,
+}
+
+impl Default for Local {
+    fn default() -> Self {
+        Self {
+            copy_strategy: vec![CopyMethod::Copy],
+            sendfile: false,
+            keep_partial: false,
+            engine: CopyEngine::Std,
+            chunk_threshold: None,
+            noatime: false,
+        }
+    }
+}
+
+impl Local {
+    fn open_source(&self, path: &Path) -> io::Result<File> {
+        match self.noatime {
+            true => copy::open_noatime(path),
+            false => File::open(path),
+        }
+    }
+
+    fn copy_data(
+        &self,
+        meta: &Metadata,
+        src_file: Option<File>,
+        original: &Path,
+        link: &Path,
+        on_copied: OnCopied,
+    ) -> io::Result<()> {
+        // Every path reads from one descriptor, so the source is only opened once
+        #[cfg(target_os = "linux")]
+        let reused = src_file.is_some();
+        let file = match src_file {
+            Some(file) => file,
+            None => self.open_source(original)?,
+        };
+        let copied = match () {
+            #[cfg(target_os = "linux")]
+            _ if reused && self.sendfile && copy::same_file_system(meta, link) => {
+                copy::sendfile(&file, meta.len(), link, on_copied)
+            }
+            #[cfg(target_os = "linux")]
+            _ if copy::is_sparse(meta) => copy::sparse(&file, link, on_copied),
+            #[cfg(unix)]
+            _ if self.chunk_threshold.is_some_and(|x| meta.len() >= x) => {
+                copy::chunked(&file, link, on_copied).map(|_| ())
+            }
+            #[cfg(target_os = "linux")]
+            _ => match self.engine {
+                CopyEngine::Std => copy::copy_range(&file, link, on_copied).map(|_| ()),
+                CopyEngine::IoUring => crate::uring::copy(&file, link, on_copied).map(|_| ()),
+            },
+            #[cfg(not(target_os = "linux"))]
+            _ => {
+                // A reused descriptor may have been read comparing contents
+                use std::io::Seek;
+                let mut file = &file;
+                file.seek(io::SeekFrom::Start(0))?;
+                let mut out = File::create(link)?;
+                copy::copy_stream(&mut file, &mut out, on_copied).map(|_| ())
+            }
+        };
+        copied?;
+        // Like fs::copy, the copy gets the permissions of the source whichever way it's copied
+        fs::set_permissions(link, meta.permissions())
+    }
+}
+
+impl Backend for Local {
+    fn metadata(&self, path: &Path) -> io::Result<Option<Meta>> {
+        match fs::symlink_metadata(path) {
+            Ok(meta) => Ok(Some(Meta::from_local(&meta))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            // A path below a file can't exist either
+            Err(e) if e.kind() == io::ErrorKind::NotADirectory => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        fs::read_dir(path)?
+            .map(|x| x.map(|y| y.file_name()))
+            .collect()
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir(path)
+    }
+
+    fn copy(
+        &self,
+        src: &Path,
+        meta: &Metadata,
+        src_file: Option<File>,
+        dest: &Path,
+    ) -> io::Result<CopyMethod> {
+        self.copy_with_progress(src, meta, src_file, dest, &|_| {})
+    }
+
+    fn copy_with_progress(
+        &self,
+        src: &Path,
+        meta: &Metadata,
+        mut src_file: Option<File>,
+        dest: &Path,
+        on_copied: OnCopied,
+    ) -> io::Result<CopyMethod> {
+        let before = dest.symlink_metadata().ok();
+        let mut result = Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No copy method configured",
+        ));
+        for method in self.copy_strategy.iter() {
+            result = match method {
+                // Clones can't cross file systems, so don't truncate `dest` trying
+                #[cfg(target_os = "linux")]
+                CopyMethod::Reflink if !copy::same_file_system(meta, dest) => {
+                    Err(io::Error::from_raw_os_error(libc::EXDEV))
+                }
+                #[cfg(target_os = "linux")]
+                CopyMethod::Reflink => match &src_file {
+                    Some(file) => copy::reflink(file, dest),
+                    None => self
+                        .open_source(src)
+                        .and_then(|file| copy::reflink(&file, dest)),
+                },
+                #[cfg(not(target_os = "linux"))]
+                CopyMethod::Reflink => copy::reflink(src, dest),
+                CopyMethod::Hardlink => copy::hardlink(src, dest),
+                CopyMethod::Copy => self.copy_data(meta, src_file.take(), src, dest, on_copied),
+            }
+            .map(|_| *method);
+            if result.is_ok() {
+                break;
+            }
+        }
+        if result.is_err() && !self.keep_partial {
+            remove_partial(before, dest);
+        }
+        result
+    }
+
+    #[cfg(unix)]
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+
+    #[cfg(not(unix))]
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let mut permissions = fs::metadata(path)?.permissions();
+        permissions.set_readonly(mode & 0o222 == 0);
+        fs::set_permissions(path, permissions)
+    }
+
+    fn set_times(&self, path: &Path, atime: FileTime, mtime: FileTime) -> io::Result<()> {
+        filetime::set_file_times(path, atime, mtime)
+    }
+
+    #[cfg(unix)]
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        std::os::unix::fs::symlink(target, path)
+    }
+
+    /// A symlink to a directory when `target` already is one, seen from where the link goes
+    #[cfg(windows)]
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        let resolved = path.parent().unwrap_or(Path::new("")).join(target);
+        match resolved.is_dir() {
+            true => std::os::windows::fs::symlink_dir(target, path),
+            false => std::os::windows::fs::symlink_file(target, path),
+        }
+    }
+
+    #[cfg(windows)]
+    fn symlink_dir(&self, target: &Path, path: &Path) -> io::Result<()> {
+        std::os::windows::fs::symlink_dir(target, path)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    #[cfg(unix)]
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    /// Symlinks to directories are removed as directories on Windows
+    #[cfg(windows)]
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        use std::os::windows::fs::FileTypeExt;
+
+        match fs::symlink_metadata(path)?.file_type().is_symlink_dir() {
+            true => fs::remove_dir(path),
+            false => fs::remove_file(path),
+        }
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+/// Remove whatever a failed copy left at `dest`, unless it is untouched since `before`
+fn remove_partial(before: Option<Metadata>, dest: &Path) {
+    let Ok(after) = dest.symlink_metadata() else {
+        return;
+    };
+    let untouched = before
+        .map(|before| {
+            before.len() == after.len() && before.modified().ok() == after.modified().ok()
+        })
+        .unwrap_or(false);
+    if !untouched {
+        let _ = fs::remove_file(dest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backend, Local};
+    use crate::temp_fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_copy_reuses_open_source() {
+        use std::io::Read;
+
+        let temp = temp_fs!(input / bar: 100_000);
+        let src = temp.path().join("input/bar.text");
+        let dest = temp.path().join("bar.text");
+        // The descriptor was read to the end comparing contents
+        let mut file = std::fs::File::open(&src).unwrap();
+        std::io::copy(&mut file.by_ref(), &mut std::io::sink()).unwrap();
+
+        let meta = src.metadata().unwrap();
+        Local::default()
+            .copy(&src, &meta, Some(file), &dest)
+            .unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), [b'a'; 100_000]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_large_files_are_copied_in_chunks() {
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (temp.path().join("src"), temp.path().join("dest"));
+        let data = (0..2 * super::copy::CHUNK as usize + 12345)
+            .map(|x| (x % 251) as u8)
+            .collect::<Vec<_>>();
+        std::fs::write(&src, &data).unwrap();
+
+        let local = Local {
+            chunk_threshold: Some(1024),
+            ..Local::default()
+        };
+        let meta = src.metadata().unwrap();
+        local.copy(&src, &meta, None, &dest).unwrap();
+        assert!(std::fs::read(&dest).unwrap() == data);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copies_get_source_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let src = temp.path().join("src");
+        let file = std::fs::File::create(&src).unwrap();
+        // Large enough to copy in chunks, and sparse as nothing is written
+        file.set_len(1 << 20).unwrap();
+        drop(file);
+        std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o751)).unwrap();
+
+        let meta = src.metadata().unwrap();
+        let chunked = Local {
+            chunk_threshold: Some(1024),
+            ..Local::default()
+        };
+        for (i, local) in [Local::default(), chunked].iter().enumerate() {
+            let dest = temp.path().join(i.to_string());
+            local.copy(&src, &meta, None, &dest).unwrap();
+            let mode = dest.metadata().unwrap().permissions().mode();
+            assert_eq!(mode & 0o7777, 0o751);
+        }
+        // Not sparse, so copied with copy_file_range on Linux
+        std::fs::write(&src, b"data").unwrap();
+        let dest = temp.path().join("small");
+        let meta = src.metadata().unwrap();
+        Local::default().copy(&src, &meta, None, &dest).unwrap();
+        assert_eq!(
+            dest.metadata().unwrap().permissions().mode() & 0o7777,
+            0o751
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_sparse_files_keep_holes() {
+        use std::{
+            io::{Seek, SeekFrom, Write},
+            os::unix::fs::MetadataExt,
+        };
+
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (temp.path().join("src"), temp.path().join("dest"));
+        let mut file = std::fs::File::create(&src).unwrap();
+        file.set_len(8 << 20).unwrap();
+        file.seek(SeekFrom::Start(4 << 20)).unwrap();
+        file.write_all(b"data").unwrap();
+        drop(file);
+
+        let meta = src.metadata().unwrap();
+        Local::default().copy(&src, &meta, None, &dest).unwrap();
+        assert_eq!(std::fs::read(&src).unwrap(), std::fs::read(&dest).unwrap());
+        // Only checkable on file systems that support holes
+        if meta.blocks() * 512 < meta.len() {
+            assert!(dest.metadata().unwrap().blocks() * 512 < 1 << 20);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_failed_copy_removes_partial() {
+        // Reading /proc/self/mem fails with EIO after the destination has been created
+        let src = Path::new("/proc/self/mem");
+        let meta = src.metadata().unwrap();
+        let temp = temp_fs!(output / bar: 32);
+        let dest = temp.path().join("output/bar.text");
+
+        let local = Local {
+            keep_partial: true,
+            ..Local::default()
+        };
+        assert!(local.copy(src, &meta, None, &dest).is_err());
+        assert!(dest.exists());
+
+        let local = Local::default();
+        assert!(local.copy(src, &meta, None, &dest).is_err());
+        assert!(!dest.exists());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_symlinks_to_directories() {
+        use std::os::windows::fs::FileTypeExt;
+
+        let temp = temp_fs!(input / sub / a: 4);
+        let input = temp.path().join("input");
+        let local = Local::default();
+        let file_type = |x: &str| input.join(x).symlink_metadata().unwrap().file_type();
+        local.symlink(Path::new("sub"), &input.join("dir")).unwrap();
+        local
+            .symlink(Path::new("sub\\a.text"), &input.join("file"))
+            .unwrap();
+        // The target doesn't exist yet, as when links are synced before what they point to
+        local
+            .symlink_dir(Path::new("later"), &input.join("hinted"))
+            .unwrap();
+        assert!(file_type("dir").is_symlink_dir());
+        assert!(file_type("file").is_symlink_file());
+        assert!(file_type("hinted").is_symlink_dir());
+        assert!(input.join("dir\\a.text").is_file());
+
+        for link in ["dir", "file", "hinted"] {
+            local.remove(&input.join(link)).unwrap();
+        }
+        assert!(input.join("sub\\a.text").is_file());
+    }
+}
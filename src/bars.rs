@@ -0,0 +1,57 @@
+//! Progress drawn in a terminal as a line of totals above a bar for each large file being
+//! copied
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::path::Path;
+
+#[derive(Debug)]
+pub(crate) struct Bars {
+    multi: MultiProgress,
+    overall: ProgressBar,
+}
+
+impl Bars {
+    pub(crate) fn new() -> Self {
+        let multi = MultiProgress::with_draw_target(ProgressDrawTarget::stderr());
+        let overall = multi.add(ProgressBar::new_spinner());
+        overall.set_style(ProgressStyle::with_template("{spinner} {msg}").unwrap());
+        Self { multi, overall }
+    }
+
+    /// Show the totals
+    pub(crate) fn set_totals(&self, line: String) {
+        self.overall.set_message(line);
+        self.overall.tick();
+    }
+
+    /// Print `line` above the bars
+    pub(crate) fn println(&self, line: &str) {
+        let _ = self.multi.println(line);
+    }
+
+    /// A bar for copying `len` bytes of `path`, removed once dropped
+    pub(crate) fn file(&self, path: &Path, len: u64) -> ProgressBar {
+        let style = ProgressStyle::with_template(
+            "  {wide_msg} {bytes}/{total_bytes} [{bar:30}] {bytes_per_sec}",
+        )
+        .unwrap()
+        .progress_chars("=> ");
+        let bar = ProgressBar::new(len)
+            .with_style(style)
+            .with_message(
+                path.file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+            .with_finish(indicatif::ProgressFinish::AndClear);
+        self.multi.add(bar)
+    }
+}
+
+// Leave the last totals drawn, they may not have been since the last redraw
+impl Drop for Bars {
+    fn drop(&mut self) {
+        self.overall.finish();
+    }
+}
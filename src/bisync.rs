@@ -0,0 +1,659 @@
+//! Two way sync between local directories, using a snapshot of both sides from the previous
+//! run to tell a path created on one side from a path deleted on the other
+
+use crate::{
+    backend::{Kind, Meta},
+    delete::DeletePlan,
+    encoding::{invalid_data, os_bytes, unescape, write_escaped},
+    truncate, ChangeKind, CopyMethod, Differences, Error, ProgressReporter, Result, SyncReport,
+    Synchronize,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io::{self, BufRead, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+const HEADER: &str = "fsync-bisync 1";
+
+/// Name of the snapshot kept in the destination when no state file is configured
+pub(crate) const STATE_FILE: &str = ".fsync-bisync";
+
+type Tree = BTreeMap<PathBuf, Entry>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Entry {
+    File { len: u64, mtime: i128 },
+    Dir,
+    Symlink(blake3::Hash),
+}
+
+impl Entry {
+    fn change_kind(&self) -> ChangeKind {
+        match self {
+            Entry::File { .. } => ChangeKind::Copy,
+            Entry::Dir => ChangeKind::Mkdir,
+            Entry::Symlink(_) => ChangeKind::Symlink,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Src,
+    Dest,
+}
+
+impl Side {
+    fn other(self) -> Self {
+        match self {
+            Side::Src => Side::Dest,
+            Side::Dest => Side::Src,
+        }
+    }
+}
+
+/// How a path changed on both sides since the previous bidirectional sync is resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Keep the version with the latest modified time
+    NewestWins,
+    /// Keep the source's version
+    SourceWins,
+    /// Keep both, renaming the destination's version with a `.conflict` suffix
+    #[default]
+    KeepBoth,
+    /// Stop before changing anything
+    Fail,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "newest" => Ok(ConflictPolicy::NewestWins),
+            "source" => Ok(ConflictPolicy::SourceWins),
+            "keep-both" => Ok(ConflictPolicy::KeepBoth),
+            "fail" => Ok(ConflictPolicy::Fail),
+            _ => Err(Error::Unsupported(format!(
+                "Unknown conflict policy {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+enum Action {
+    Copy(Side),
+    Conflict,
+}
+
+impl Synchronize {
+    /// Propagate the changes made on either side since the last run to the other side
+    pub(crate) fn bisync(&self) -> Result<SyncReport> {
+        let state_path = self.state_path();
+        let state = load(&state_path)?;
+        let src = self.scan(&self.src)?;
+        let dest = self.scan(&self.dest)?;
+
+        let paths = src.keys().chain(dest.keys()).collect::<BTreeSet<_>>();
+        self.progress.on_scanned(paths.len());
+        let mut actions = Vec::new();
+        for rel in paths {
+            let (a, b, s) = (src.get(rel), dest.get(rel), state.get(rel));
+            let action = match (a == b, b == s, a == s) {
+                (true, ..) => {
+                    self.progress.on_skipped(1);
+                    continue;
+                }
+                (_, true, _) => Action::Copy(Side::Src),
+                (_, _, true) => Action::Copy(Side::Dest),
+                // A path deleted on one side but changed on the other is restored
+                _ if a.is_none() => Action::Copy(Side::Dest),
+                _ if b.is_none() => Action::Copy(Side::Src),
+                _ => Action::Conflict,
+            };
+            actions.push((rel, action));
+        }
+        let conflicts = actions
+            .iter()
+            .filter(|(_, x)| matches!(x, Action::Conflict))
+            .map(|(rel, _)| rel.to_path_buf())
+            .collect::<Vec<_>>();
+        if self.conflict_policy == ConflictPolicy::Fail && !conflicts.is_empty() {
+            return Err(Error::Conflict(conflicts));
+        }
+        self.check_removals(&actions, &src, &dest)?;
+
+        let mut removed_dirs = Vec::new();
+        for (rel, action) in actions {
+            if self.cancel.is_cancelled() {
+                break;
+            }
+            let from = match action {
+                Action::Copy(side) => side,
+                Action::Conflict => {
+                    self.progress.on_conflict(1);
+                    match self.resolve_conflict(rel, &src, &dest) {
+                        Ok(Some(side)) => side,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            self.progress.on_error(rel, &e);
+                            continue;
+                        }
+                    }
+                }
+            };
+            let (entry, to_tree) = match from {
+                Side::Src => (src.get(rel), &dest),
+                Side::Dest => (dest.get(rel), &src),
+            };
+            let result = match entry {
+                Some(entry) => self.apply(from, rel, entry, to_tree, &state),
+                None => self.remove(from, rel, &mut removed_dirs),
+            };
+            if let Err(e) = result {
+                self.progress.on_error(rel, &e);
+            }
+        }
+
+        // Directories go last, deepest first, and stay when something inside them was kept
+        let removed_dirs = match self.cancel.is_cancelled() {
+            true => Vec::new(),
+            false => removed_dirs,
+        };
+        for path in removed_dirs.into_iter().rev() {
+            match fs::remove_dir(&path) {
+                Ok(_) => {
+                    self.progress.on_deleted(1);
+                    self.progress
+                        .changed(ChangeKind::Delete, &path, 0, Differences::default());
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) if e.kind() == io::ErrorKind::DirectoryNotEmpty => {}
+                Err(e) => self.progress.on_error(&path, &e),
+            }
+        }
+
+        if !self.dry_run {
+            // Only paths that now match on both sides count as synced next time
+            let src = self.scan(&self.src)?;
+            let dest = self.scan(&self.dest)?;
+            let synced = src
+                .into_iter()
+                .filter(|(rel, entry)| dest.get(rel) == Some(entry))
+                .collect();
+            if let Some(parent) = state_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            save(&state_path, &synced)?;
+        }
+        self.progress.print();
+        Ok(self.report())
+    }
+
+    /// The side whose version of `rel` should be copied to the other, if any
+    fn resolve_conflict(&self, rel: &Path, src: &Tree, dest: &Tree) -> Result<Option<Side>> {
+        let side = match self.conflict_policy {
+            ConflictPolicy::SourceWins | ConflictPolicy::Fail => Side::Src,
+            ConflictPolicy::NewestWins => {
+                let a = Meta::from_local(&self.src.join(rel).symlink_metadata()?);
+                let b = Meta::from_local(&self.dest.join(rel).symlink_metadata()?);
+                match a.mtime >= b.mtime {
+                    true => Side::Src,
+                    false => Side::Dest,
+                }
+            }
+            ConflictPolicy::KeepBoth => {
+                // Move a file rather than a directory aside, as its children are synced later
+                let side = match dest.get(rel) {
+                    Some(Entry::Dir) => Side::Src,
+                    _ => Side::Dest,
+                };
+                let tree = match side {
+                    Side::Src => src,
+                    Side::Dest => dest,
+                };
+                let path = self.root(side).join(rel);
+                let renamed = conflict_path(&path);
+                self.progress.println(format!(
+                    "Conflict: {:?} changed on both sides, keeping {:?}",
+                    rel, renamed
+                ));
+                if self.dry_run {
+                    return Ok(None);
+                }
+                fs::rename(&path, &renamed)?;
+                let renamed_rel = rel.with_file_name(renamed.file_name().unwrap_or_default());
+                let empty = Tree::new();
+                let entry = &tree[rel];
+                self.apply(side, &renamed_rel, entry, &empty, &empty)?;
+                return Ok(Some(side.other()));
+            }
+        };
+        self.progress.println(format!(
+            "Conflict: {:?} changed on both sides, keeping the {} version",
+            rel,
+            match side {
+                Side::Src => "source",
+                Side::Dest => "destination",
+            }
+        ));
+        Ok(Some(side))
+    }
+
+    pub(crate) fn state_path(&self) -> PathBuf {
+        match &self.state_file {
+            Some(path) => path.clone(),
+            None => self.dest.join(STATE_FILE),
+        }
+    }
+
+    fn root(&self, side: Side) -> &Path {
+        match side {
+            Side::Src => &self.src,
+            Side::Dest => &self.dest,
+        }
+    }
+
+    /// Copy `entry` at `rel` from one side to the other
+    fn apply(
+        &self,
+        from: Side,
+        rel: &Path,
+        entry: &Entry,
+        to_tree: &Tree,
+        state: &Tree,
+    ) -> Result<()> {
+        let to = from.other();
+        let src = self.root(from).join(rel);
+        let dest = self.root(to).join(rel);
+        let backend = self.dest_backend();
+        let meta = src.symlink_metadata()?;
+        let existing = backend.metadata(&dest)?;
+        let differences = self.differences(&Meta::from_local(&meta), existing.as_ref());
+        if self.dry_run {
+            self.report_dry_run("copy", &src);
+            self.progress.on_copied(1);
+            match existing {
+                Some(_) => self.progress.on_updated(1),
+                None => self.progress.on_created(1),
+            }
+            self.progress
+                .changed(entry.change_kind(), &dest, 0, differences);
+            return Ok(());
+        }
+        match &existing {
+            Some(meta) if meta.kind == Kind::Dir && *entry == Entry::Dir => {}
+            Some(meta) if meta.kind == Kind::Dir => {
+                // Replacing a directory would lose whatever changed inside it
+                let changed = to_tree
+                    .range(rel.to_path_buf()..)
+                    .take_while(|(x, _)| x.starts_with(rel))
+                    .any(|(x, entry)| state.get(x) != Some(entry));
+                if changed {
+                    return Err(Error::Conflict(vec![rel.to_path_buf()]));
+                }
+                fs::remove_dir_all(&dest)?;
+            }
+            Some(_) => backend.remove(&dest)?,
+            None => {}
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let (mut bytes, mut method) = (0, None);
+        match entry {
+            Entry::Dir => {
+                if !existing.as_ref().is_some_and(|x| x.kind == Kind::Dir) {
+                    backend.create_dir(&dest)?;
+                }
+            }
+            Entry::Symlink(_) => {
+                let target = fs::read_link(&src)?;
+                match src.metadata().is_ok_and(|x| x.is_dir()) {
+                    true => backend.symlink_dir(&target, &dest)?,
+                    false => backend.symlink(&target, &dest)?,
+                }
+            }
+            Entry::File { .. } => {
+                // A hardlink is the other side's file, which already has its attributes
+                let copied = self.copy_file(&meta, None, &src, &dest)?;
+                if copied != CopyMethod::Hardlink {
+                    let src_meta = Meta::from_local(&meta);
+                    if !self.skip_permissions {
+                        backend.set_permissions(&dest, src_meta.mode)?;
+                    }
+                    let atime = filetime::FileTime::from_last_access_time(&meta);
+                    backend.set_times(&dest, atime, src_meta.mtime)?;
+                }
+                self.progress.on_bytes_copied(meta.len());
+                (bytes, method) = (meta.len(), Some(copied));
+            }
+        }
+        self.progress.on_copied(1);
+        match method {
+            Some(method) => self.progress.copied(method, &dest, bytes, differences),
+            None => self
+                .progress
+                .changed(entry.change_kind(), &dest, bytes, differences),
+        }
+        match existing {
+            Some(_) => self.progress.on_updated(1),
+            None => self.progress.on_created(1),
+        }
+        Ok(())
+    }
+
+    /// Fail before changing anything when the paths deleted on one side would remove more files
+    /// from the other than [`Synchronize::max_delete`] allows or aren't confirmed
+    fn check_removals(
+        &self,
+        actions: &[(&PathBuf, Action)],
+        src: &Tree,
+        dest: &Tree,
+    ) -> Result<()> {
+        if self.max_delete.is_none() && self.confirm_deletes.is_none() {
+            return Ok(());
+        }
+        let tree = |side| match side {
+            Side::Src => src,
+            Side::Dest => dest,
+        };
+        let is_file = |entry: &Entry| *entry != Entry::Dir;
+        // Files on either side, the share of which a percentage limit is taken from
+        let files = src.iter().chain(dest.iter()).filter(|(_, x)| is_file(x));
+        let mut plan = DeletePlan {
+            total: files.map(|(rel, _)| rel).collect::<BTreeSet<_>>().len(),
+            ..DeletePlan::default()
+        };
+        for (rel, action) in actions {
+            let Action::Copy(from) = action else {
+                continue;
+            };
+            let to = from.other();
+            match (tree(*from).get(*rel), tree(to).get(*rel)) {
+                (None, Some(entry)) if !self.filter.is_protected(rel) => {
+                    plan.files += usize::from(is_file(entry));
+                    plan.paths.push(self.root(to).join(rel));
+                }
+                _ => {}
+            }
+        }
+        self.approve_deletes(plan).map(|_| ())
+    }
+
+    /// Delete `rel` from the side opposite to the one it was deleted on, unless it's protected
+    fn remove(&self, from: Side, rel: &Path, removed_dirs: &mut Vec<PathBuf>) -> Result<()> {
+        let to = from.other();
+        let path = self.root(to).join(rel);
+        if self.filter.is_protected(rel) {
+            self.progress.on_skipped(1);
+            return Ok(());
+        }
+        if self.dry_run {
+            self.report_dry_run("delete", &path);
+            self.progress.on_deleted(1);
+            self.progress
+                .changed(ChangeKind::Delete, &path, 0, Differences::default());
+            return Ok(());
+        }
+        match self.dest_backend().metadata(&path)? {
+            Some(meta) if meta.kind == Kind::Dir => removed_dirs.push(path),
+            Some(_) => {
+                self.dest_backend().remove(&path)?;
+                self.progress.on_deleted(1);
+                self.progress
+                    .changed(ChangeKind::Delete, &path, 0, Differences::default());
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Every path below `root` that isn't excluded, relative to it
+    fn scan(&self, root: &Path) -> io::Result<Tree> {
+        let mut tree = Tree::new();
+        let mut stack = vec![PathBuf::new()];
+        let state = self.state_path();
+        while let Some(dir) = stack.pop() {
+            let entries = match fs::read_dir(root.join(&dir)) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound && dir.as_os_str().is_empty() => {
+                    return Ok(tree);
+                }
+                Err(e) => return Err(e),
+            };
+            for entry in entries {
+                let entry = entry?;
+                let path = entry.path();
+                let rel = dir.join(entry.file_name());
+                let meta = entry.path().symlink_metadata()?;
+                let hidden = entry.file_name().to_string_lossy().starts_with('.');
+                if path == state
+                    || self.is_internal(&path)
+                    || (self.skip_hidden && hidden)
+                    || self.is_excluded(&self.src.join(&rel), meta.is_dir())
+                {
+                    continue;
+                }
+                let entry = if meta.file_type().is_symlink() {
+                    let target = fs::read_link(&path)?;
+                    Entry::Symlink(blake3::hash(&os_bytes(target.as_os_str())))
+                } else if meta.is_dir() {
+                    stack.push(rel.clone());
+                    Entry::Dir
+                } else {
+                    let meta = Meta::from_local(&meta);
+                    Entry::File {
+                        len: meta.len,
+                        mtime: truncate(meta.mtime, Duration::from_nanos(1)),
+                    }
+                };
+                tree.insert(rel, entry);
+            }
+        }
+        Ok(tree)
+    }
+}
+
+/// `name.conflict.ext` next to `path`, numbered when that already exists
+fn conflict_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = match path.extension() {
+        Some(ext) => format!(".{}", ext.to_string_lossy()),
+        None => String::new(),
+    };
+    (0..)
+        .map(|i| match i {
+            0 => path.with_file_name(format!("{}.conflict{}", stem, ext)),
+            i => path.with_file_name(format!("{}.conflict-{}{}", stem, i, ext)),
+        })
+        .find(|x| x.symlink_metadata().is_err())
+        .unwrap()
+}
+
+fn load(path: &Path) -> io::Result<Tree> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Tree::new()),
+        Err(e) => return Err(e),
+    };
+    let mut lines = io::BufReader::new(file).split(b'\n');
+    match lines.next().transpose()? {
+        Some(header) if header == HEADER.as_bytes() => {}
+        _ => return Err(invalid_data("missing bisync state header")),
+    }
+    lines.map(|line| parse_line(&line?)).collect()
+}
+
+fn save(path: &Path, tree: &Tree) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "{}", HEADER)?;
+    for (rel, entry) in tree.iter() {
+        match entry {
+            Entry::File { len, mtime } => write!(out, "f {} {} ", len, mtime)?,
+            Entry::Dir => write!(out, "d ")?,
+            Entry::Symlink(hash) => write!(out, "l {} ", hash.to_hex())?,
+        }
+        write_escaped(&mut out, rel.as_os_str())?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()
+}
+
+fn parse_line(line: &[u8]) -> io::Result<(PathBuf, Entry)> {
+    let fields = match line.first() {
+        Some(b'f') => 4,
+        Some(b'l') => 3,
+        _ => 2,
+    };
+    let parts = line.splitn(fields, |b| *b == b' ').collect::<Vec<_>>();
+    if parts.len() != fields {
+        return Err(invalid_data("truncated bisync entry"));
+    }
+    let number = |bytes: &[u8]| {
+        std::str::from_utf8(bytes)
+            .ok()
+            .and_then(|x| x.parse::<i128>().ok())
+            .ok_or_else(|| invalid_data("invalid bisync entry"))
+    };
+    let entry = match parts[0] {
+        b"f" => Entry::File {
+            len: number(parts[1])? as u64,
+            mtime: number(parts[2])?,
+        },
+        b"d" => Entry::Dir,
+        b"l" => Entry::Symlink(
+            blake3::Hash::from_hex(parts[1]).map_err(|_| invalid_data("invalid bisync hash"))?,
+        ),
+        _ => return Err(invalid_data("invalid bisync entry kind")),
+    };
+    let rel = PathBuf::from(unescape(parts[fields - 1]));
+    Ok((rel, entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, save, ConflictPolicy, Tree};
+    use crate::{temp_fs, DeleteLimit, Error, SyncReport, Synchronize};
+    use std::{fs, path::Path};
+
+    fn bisync(temp: &tempfile::TempDir) {
+        bisync_with(temp, ConflictPolicy::default()).unwrap();
+    }
+
+    fn bisync_with(temp: &tempfile::TempDir, policy: ConflictPolicy) -> crate::Result<SyncReport> {
+        Synchronize::new(temp.path().join("a"), temp.path().join("b"))
+            .bidirectional(true)
+            .conflict_policy(policy)
+            .sync()
+    }
+
+    #[test]
+    fn test_bisync_propagates_both_ways() {
+        let temp = temp_fs!(a / one: 4, a / dir / two: 4, b / three: 4);
+        bisync(&temp);
+        for side in ["a", "b"] {
+            let root = temp.path().join(side);
+            assert!(root.join("one.text").exists());
+            assert!(root.join("dir/two.text").exists());
+            assert!(root.join("three.text").exists());
+        }
+
+        // Deleting on one side now deletes on the other instead of restoring the file
+        let (a, b) = (temp.path().join("a"), temp.path().join("b"));
+        fs::remove_file(b.join("one.text")).unwrap();
+        fs::remove_dir_all(a.join("dir")).unwrap();
+        fs::write(b.join("three.text"), b"changed").unwrap();
+        bisync(&temp);
+        assert!(!a.join("one.text").exists());
+        assert!(!b.join("dir").exists());
+        assert_eq!(fs::read(a.join("three.text")).unwrap(), b"changed");
+
+        // Changes on both sides keep both versions on both sides
+        fs::write(a.join("three.text"), b"from a").unwrap();
+        fs::write(b.join("three.text"), b"from b!").unwrap();
+        bisync(&temp);
+        for root in [&a, &b] {
+            assert_eq!(fs::read(root.join("three.text")).unwrap(), b"from a");
+            assert_eq!(
+                fs::read(root.join("three.conflict.text")).unwrap(),
+                b"from b!"
+            );
+        }
+    }
+
+    #[test]
+    fn test_conflict_policies() {
+        let temp = temp_fs!(a / one: 4);
+        bisync(&temp);
+        let (a, b) = (temp.path().join("a"), temp.path().join("b"));
+        let conflict = |a_text: &[u8], b_text: &[u8]| {
+            fs::write(a.join("one.text"), a_text).unwrap();
+            fs::write(b.join("one.text"), b_text).unwrap();
+            let old = filetime::FileTime::from_unix_time(1_000_000, 0);
+            filetime::set_file_mtime(a.join("one.text"), old).unwrap();
+        };
+
+        conflict(b"old a", b"new b");
+        let result = bisync_with(&temp, ConflictPolicy::Fail);
+        assert!(matches!(result, Err(Error::Conflict(paths)) if paths == [Path::new("one.text")]));
+        assert_eq!(fs::read(b.join("one.text")).unwrap(), b"new b");
+
+        let report = bisync_with(&temp, ConflictPolicy::NewestWins).unwrap();
+        assert_eq!(report.conflicts, 1);
+        assert_eq!(fs::read(a.join("one.text")).unwrap(), b"new b");
+
+        conflict(b"old a", b"newer b");
+        bisync_with(&temp, ConflictPolicy::SourceWins).unwrap();
+        assert_eq!(fs::read(b.join("one.text")).unwrap(), b"old a");
+        assert!(!b.join("one.conflict.text").exists());
+    }
+
+    #[test]
+    fn test_deletes_are_limited_and_protected() {
+        let temp = temp_fs!(a / one: 4, a / two: 4, a / keep: 4);
+        bisync(&temp);
+        let (a, b) = (temp.path().join("a"), temp.path().join("b"));
+        for name in ["one.text", "two.text", "keep.text"] {
+            fs::remove_file(a.join(name)).unwrap();
+        }
+        let sync = || {
+            Synchronize::new(&a, &b)
+                .bidirectional(true)
+                .protect("keep.text")
+        };
+
+        // An emptied side doesn't wipe the other past the limit
+        let result = sync().max_delete(Some(DeleteLimit::Count(1))).sync();
+        assert!(matches!(
+            result,
+            Err(Error::TooManyDeletes { count: 2, .. })
+        ));
+        let result = sync().confirm_deletes(|_| false).sync();
+        assert!(matches!(result, Err(Error::Declined)));
+        assert!(b.join("one.text").exists());
+
+        sync().sync().unwrap();
+        assert!(!b.join("one.text").exists());
+        assert!(b.join("keep.text").exists());
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let temp = temp_fs!(a / one: 4, a / sub / two: 4);
+        let sync = Synchronize::new(temp.path().join("a"), temp.path().join("b"));
+        let mut tree = sync.scan(&temp.path().join("a")).unwrap();
+        tree.insert("with space\nnewline".into(), super::Entry::Dir);
+        let path = temp.path().join("state");
+        save(&path, &tree).unwrap();
+        assert_eq!(load(&path).unwrap(), tree);
+        assert_eq!(load(&temp.path().join("missing")).unwrap(), Tree::new());
+    }
+}
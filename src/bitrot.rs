@@ -0,0 +1,104 @@
+//! Checksums of copied files kept in an extended attribute of the destination, so corruption
+//! that leaves a file's size and modified time alone can still be found
+
+use crate::{
+    encoding::to_hex, xattr, Comparison, ContentCheck, Error, Mismatch, ProgressReporter, Reason,
+    Result, Synchronize,
+};
+use rayon::prelude::*;
+use std::{fs, path::Path, sync::Arc};
+
+/// Extended attribute holding the sha256 of a file's contents in hex
+pub(crate) const CHECKSUM_XATTR: &str = "user.fsync.sha256";
+
+impl Synchronize {
+    /// Store the sha256 of the file just written at `dest` in its extended attribute
+    pub(crate) fn store_checksum(&self, dest: &Path) -> Result<()> {
+        let mut file = fs::File::open(dest).map_err(|e| Error::with_path(e, dest))?;
+        let digest = ContentCheck::Sha256
+            .digest(&mut file)
+            .map_err(|e| Error::with_path(e, dest))?;
+        xattr::set(dest, CHECKSUM_XATTR, to_hex(&digest).as_bytes())
+            .map_err(|e| Error::with_path(e, dest))
+    }
+
+    /// Hash every file below the source with a checksum stored by
+    /// [`Synchronize::store_checksums`] again, returning the ones whose contents no longer
+    /// match it as [`Reason::Content`] differences. Run on the destination of earlier syncs,
+    /// this finds files that were corrupted without their size or modified time changing.
+    pub fn scrub(mut self) -> Result<Vec<Comparison>> {
+        self.prepare_manifest()?;
+        let pool = Arc::new(self.get_thread_pool()?);
+        let sync = Arc::new(self);
+        let files = sync.manifest_files(&pool, None)?;
+        let corrupt = pool.install(|| {
+            files
+                .par_iter()
+                .map(|path| sync.is_corrupt(path).map(|x| x.then_some(path)))
+                .collect::<Result<Vec<_>>>()
+        })?;
+        Ok(corrupt
+            .into_iter()
+            .flatten()
+            .map(|path| Comparison {
+                path: path
+                    .strip_prefix(&sync.src)
+                    .expect("walked below the source")
+                    .to_path_buf(),
+                mismatch: Mismatch::Differs(vec![Reason::Content]),
+            })
+            .collect())
+    }
+
+    /// Whether the contents of `path` differ from its stored checksum, files without one
+    /// never do
+    fn is_corrupt(&self, path: &Path) -> Result<bool> {
+        let Some(stored) =
+            xattr::get(path, CHECKSUM_XATTR).map_err(|e| Error::with_path(e, path))?
+        else {
+            return Ok(false);
+        };
+        let mut file = fs::File::open(path).map_err(|e| Error::with_path(e, path))?;
+        let digest = ContentCheck::Sha256
+            .digest(&mut file)
+            .map_err(|e| Error::with_path(e, path))?;
+        self.progress.on_scanned(1);
+        Ok(!to_hex(&digest).as_bytes().eq_ignore_ascii_case(&stored))
+    }
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "macos")))]
+mod tests {
+    use super::CHECKSUM_XATTR;
+    use crate::{temp_fs, xattr, Comparison, Mismatch, Reason, Synchronize};
+    use std::{fs, path::PathBuf};
+
+    #[test]
+    fn test_scrub_finds_corrupt_files() {
+        let temp = temp_fs!(input / a: 4, input / sub / b: 6);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        Synchronize::new(&input, &output)
+            .store_checksums(true)
+            .sync()
+            .unwrap();
+        let stored = xattr::get(&output.join("a.text"), CHECKSUM_XATTR).unwrap();
+        assert_eq!(stored.unwrap().len(), 64);
+        assert_eq!(Synchronize::new(&output, &output).scrub().unwrap(), []);
+
+        // Flipped bits leave the size and modified time as they were
+        let corrupted = output.join("sub/b.text");
+        let mtime =
+            filetime::FileTime::from_last_modification_time(&fs::metadata(&corrupted).unwrap());
+        let mut bytes = fs::read(&corrupted).unwrap();
+        bytes[0] ^= 1;
+        fs::write(&corrupted, bytes).unwrap();
+        filetime::set_file_mtime(&corrupted, mtime).unwrap();
+        assert_eq!(
+            Synchronize::new(&output, &output).scrub().unwrap(),
+            [Comparison {
+                path: PathBuf::from("sub/b.text"),
+                mismatch: Mismatch::Differs(vec![Reason::Content]),
+            }]
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use crate::{
+    encoding::{from_hex, invalid_data, to_hex, unescape, write_escaped},
+    ContentCheck,
+};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufRead, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+const HEADER: &str = "fsync-checksums 1";
+
+/// Digests from previous runs, reused while a file's size and modified time are unchanged
+#[derive(Debug)]
+pub(crate) struct ChecksumCache {
+    path: PathBuf,
+    entries: Mutex<HashMap<PathBuf, Entry>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    check: ContentCheck,
+    size: u64,
+    mtime: u128,
+    digest: Vec<u8>,
+}
+
+impl ChecksumCache {
+    /// Load the cache stored at `path`, starting empty if it doesn't exist yet
+    pub(crate) fn load(path: &Path) -> io::Result<Self> {
+        let mut entries = HashMap::new();
+        match File::open(path) {
+            Ok(file) => {
+                let mut lines = io::BufReader::new(file).split(b'\n');
+                match lines.next().transpose()? {
+                    Some(header) if header == HEADER.as_bytes() => {}
+                    _ => return Err(invalid_data("missing checksum cache header")),
+                }
+                for line in lines {
+                    let (path, entry) = parse_line(&line?)?;
+                    entries.insert(path, entry);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Digest of the open `file` at `path`, only hashing it when the cached entry is stale
+    pub(crate) fn digest(
+        &self,
+        check: ContentCheck,
+        path: &Path,
+        file: &mut File,
+    ) -> io::Result<Vec<u8>> {
+        let meta = file.metadata()?;
+        let size = meta.len();
+        let mtime = meta
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_nanos())
+            .unwrap_or(0);
+        if let Some(entry) = self.entries.lock().unwrap().get(path) {
+            if entry.check == check && entry.size == size && entry.mtime == mtime {
+                return Ok(entry.digest.clone());
+            }
+        }
+        let digest = check.digest(file)?;
+        let entry = Entry {
+            check,
+            size,
+            mtime,
+            digest: digest.clone(),
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), entry);
+        Ok(digest)
+    }
+
+    pub(crate) fn save(&self) -> io::Result<()> {
+        // Write next to the cache and rename so an interrupted save keeps the old cache
+        let tmp = self.path.with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        writeln!(out, "{}", HEADER)?;
+        for (path, entry) in self.entries.lock().unwrap().iter() {
+            write!(
+                out,
+                "{} {} {} {} ",
+                entry.check.name(),
+                entry.size,
+                entry.mtime,
+                to_hex(&entry.digest)
+            )?;
+            write_escaped(&mut out, path.as_os_str())?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()?;
+        drop(out);
+        fs::rename(tmp, &self.path)
+    }
+}
+
+fn parse_line(line: &[u8]) -> io::Result<(PathBuf, Entry)> {
+    let mut parts = line.splitn(5, |b| *b == b' ');
+    let mut next = || {
+        parts
+            .next()
+            .ok_or_else(|| invalid_data("truncated checksum cache entry"))
+    };
+    let check = std::str::from_utf8(next()?)
+        .ok()
+        .and_then(|x| x.parse::<ContentCheck>().ok())
+        .ok_or_else(|| invalid_data("invalid checksum cache algorithm"))?;
+    let size = parse_number(next()?, "size")? as u64;
+    let mtime = parse_number(next()?, "mtime")?;
+    let digest = from_hex(next()?).ok_or_else(|| invalid_data("invalid checksum cache digest"))?;
+    let path = PathBuf::from(unescape(next()?));
+    Ok((
+        path,
+        Entry {
+            check,
+            size,
+            mtime,
+            digest,
+        },
+    ))
+}
+
+fn parse_number(bytes: &[u8], field: &str) -> io::Result<u128> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|x| x.parse::<u128>().ok())
+        .ok_or_else(|| invalid_data(&format!("invalid checksum cache {}", field)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChecksumCache;
+    use crate::{temp_fs, ContentCheck};
+    use std::fs::File;
+
+    #[test]
+    fn test_cache_round_trip() {
+        let temp = temp_fs!(bar: 16);
+        let file = temp.path().join("bar.text");
+        let cache_path = temp.path().join("cache");
+
+        let cache = ChecksumCache::load(&cache_path).unwrap();
+        let digest = cache
+            .digest(ContentCheck::Sha256, &file, &mut File::open(&file).unwrap())
+            .unwrap();
+        cache.save().unwrap();
+
+        let cache = ChecksumCache::load(&cache_path).unwrap();
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.get(&file).unwrap().digest, digest);
+    }
+}
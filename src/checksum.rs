@@ -0,0 +1,132 @@
+use sha2::Digest;
+use std::{
+    fs::File,
+    io::{self, Read},
+    str::FromStr,
+};
+
+/// How file contents are compared when checking content
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentCheck {
+    /// Read both files and compare them byte by byte
+    Bytes,
+    Xxh3,
+    Blake3,
+    Sha256,
+}
+
+impl FromStr for ContentCheck {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bytes" => Ok(ContentCheck::Bytes),
+            "xxh3" => Ok(ContentCheck::Xxh3),
+            "blake3" => Ok(ContentCheck::Blake3),
+            "sha256" => Ok(ContentCheck::Sha256),
+            _ => Err(crate::Error::Unsupported(format!(
+                "Unknown checksum {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+impl ContentCheck {
+    pub fn name(self) -> &'static str {
+        match self {
+            ContentCheck::Bytes => "bytes",
+            ContentCheck::Xxh3 => "xxh3",
+            ContentCheck::Blake3 => "blake3",
+            ContentCheck::Sha256 => "sha256",
+        }
+    }
+
+    /// Digest of everything read from `reader`, byte comparison falls back to blake3
+    pub fn digest(self, reader: &mut impl Read) -> io::Result<Vec<u8>> {
+        let mut buffer = vec![0; 64 * 1024];
+        match self {
+            ContentCheck::Xxh3 => {
+                let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+                read_chunks(reader, &mut buffer, |x| hasher.update(x))?;
+                Ok(hasher.digest128().to_be_bytes().to_vec())
+            }
+            ContentCheck::Bytes | ContentCheck::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                read_chunks(reader, &mut buffer, |x| {
+                    hasher.update(x);
+                })?;
+                Ok(hasher.finalize().as_bytes().to_vec())
+            }
+            ContentCheck::Sha256 => {
+                let mut hasher = sha2::Sha256::new();
+                read_chunks(reader, &mut buffer, |x| hasher.update(x))?;
+                Ok(hasher.finalize().to_vec())
+            }
+        }
+    }
+
+    pub(crate) fn equal(self, src: &mut File, dest: &mut File) -> io::Result<bool> {
+        if src.metadata()?.len() != dest.metadata()?.len() {
+            return Ok(false);
+        }
+        match self {
+            ContentCheck::Bytes => bytes_equal(src, dest),
+            _ => Ok(self.digest(src)? == self.digest(dest)?),
+        }
+    }
+}
+
+fn read_chunks(
+    reader: &mut impl Read,
+    buffer: &mut [u8],
+    mut f: impl FnMut(&[u8]),
+) -> io::Result<()> {
+    loop {
+        match reader.read(buffer) {
+            Ok(0) => return Ok(()),
+            Ok(n) => f(&buffer[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn bytes_equal(file1: &mut File, file2: &mut File) -> io::Result<bool> {
+    let mut buffer1 = [0; 1024]; // Using a buffer of 1024 bytes
+    let mut buffer2 = [0; 1024];
+
+    loop {
+        let count1 = file1.read(&mut buffer1)?;
+        let count2 = file2.read(&mut buffer2)?;
+
+        if count1 != count2 || buffer1[..count1] != buffer2[..count2] {
+            return Ok(false);
+        }
+
+        if count1 == 0 || count2 == 0 {
+            break;
+        }
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ContentCheck;
+
+    #[test]
+    fn test_digests_differ_by_content() {
+        for check in [
+            ContentCheck::Xxh3,
+            ContentCheck::Blake3,
+            ContentCheck::Sha256,
+        ] {
+            let a = check.digest(&mut &b"hello"[..]).unwrap();
+            let b = check.digest(&mut &b"hellp"[..]).unwrap();
+            assert_eq!(a, check.digest(&mut &b"hello"[..]).unwrap());
+            assert_ne!(a, b);
+        }
+    }
+}
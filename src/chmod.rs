@@ -0,0 +1,191 @@
+//! Permission rules applied to what a sync writes, in the syntax of rsync's `--chmod`, so the
+//! destination can have a permission policy of its own
+
+use crate::Error;
+use std::str::FromStr;
+
+/// Comma separated rules rewriting the permissions copied from the source, such as
+/// `D755,F644` or `Fgo-w`. A rule starting with `D` only applies to directories and one
+/// starting with `F` only to files, and is either an octal mode or a symbolic change like
+/// those of `chmod`. Rules apply in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Chmod {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    /// Whether the rule is only for directories or only for files
+    dirs: Option<bool>,
+    change: Change,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Change {
+    Octal(u32),
+    /// The permission bits of the classes it's for, and each operator with the permissions it
+    /// adds, removes or sets
+    Symbolic {
+        who: u32,
+        ops: Vec<(u8, Perms)>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Perms {
+    bits: u32,
+    /// `X`, execute for directories and files executable by anyone
+    search: bool,
+}
+
+const PERMISSIONS: u32 = 0o7777;
+
+impl FromStr for Chmod {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rules = s
+            .split(',')
+            .map(|rule| {
+                parse_rule(rule)
+                    .ok_or_else(|| Error::Unsupported(format!("Invalid chmod rule {:?}", rule)))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+}
+
+fn parse_rule(rule: &str) -> Option<Rule> {
+    let (dirs, change) = match rule.as_bytes().first()? {
+        b'D' => (Some(true), &rule[1..]),
+        b'F' => (Some(false), &rule[1..]),
+        _ => (None, rule),
+    };
+    if !change.is_empty() && change.len() <= 4 && change.bytes().all(|x| (b'0'..=b'7').contains(&x))
+    {
+        let mode = u32::from_str_radix(change, 8).ok()?;
+        return Some(Rule {
+            dirs,
+            change: Change::Octal(mode),
+        });
+    }
+    let split = change.find(['+', '-', '='])?;
+    let mut who = 0;
+    for class in change[..split].bytes() {
+        who |= match class {
+            b'u' => 0o4700,
+            b'g' => 0o2070,
+            b'o' => 0o1007,
+            b'a' => PERMISSIONS,
+            _ => return None,
+        };
+    }
+    // Without a class the change is for everyone, as umask doesn't apply to copied files
+    if who == 0 {
+        who = PERMISSIONS;
+    }
+    let mut ops: Vec<(u8, Perms)> = Vec::new();
+    for byte in change[split..].bytes() {
+        let perms = match byte {
+            b'+' | b'-' | b'=' => {
+                ops.push((byte, Perms::default()));
+                continue;
+            }
+            _ => &mut ops.last_mut()?.1,
+        };
+        match byte {
+            b'r' => perms.bits |= 0o444,
+            b'w' => perms.bits |= 0o222,
+            b'x' => perms.bits |= 0o111,
+            b'X' => perms.search = true,
+            b's' => perms.bits |= 0o6000,
+            b't' => perms.bits |= 0o1000,
+            _ => return None,
+        }
+    }
+    Some(Rule {
+        dirs,
+        change: Change::Symbolic { who, ops },
+    })
+}
+
+impl Chmod {
+    /// These rules followed by `other`'s
+    pub(crate) fn extend(&mut self, other: Chmod) {
+        self.rules.extend(other.rules);
+    }
+
+    /// The `mode` of a directory or file after the rules that apply to it
+    pub fn apply(&self, mut mode: u32, is_dir: bool) -> u32 {
+        for rule in self
+            .rules
+            .iter()
+            .filter(|x| x.dirs.is_none_or(|x| x == is_dir))
+        {
+            match &rule.change {
+                Change::Octal(value) => mode = (mode & !PERMISSIONS) | value,
+                Change::Symbolic { who, ops } => {
+                    for (op, perms) in ops {
+                        let mut bits = perms.bits;
+                        if perms.search && (is_dir || mode & 0o111 != 0) {
+                            bits |= 0o111;
+                        }
+                        bits &= who;
+                        match op {
+                            b'+' => mode |= bits,
+                            b'-' => mode &= !bits,
+                            _ => mode = (mode & !who) | bits,
+                        }
+                    }
+                }
+            }
+        }
+        mode
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Chmod;
+
+    #[test]
+    fn test_chmod_rules() {
+        let rules = "D755,F644,Fgo-w".parse::<Chmod>().unwrap();
+        assert_eq!(rules.apply(0o700, true), 0o755);
+        assert_eq!(rules.apply(0o777, false), 0o644);
+        let symbolic = "go=rX,o-r".parse::<Chmod>().unwrap();
+        assert_eq!(symbolic.apply(0o600, false), 0o640);
+        assert_eq!(symbolic.apply(0o700, true), 0o751);
+        assert_eq!(
+            "u+x,go=rX".parse::<Chmod>().unwrap().apply(0o600, false),
+            0o755
+        );
+        // File type bits are kept
+        assert_eq!(
+            "a=r".parse::<Chmod>().unwrap().apply(0o100755, false),
+            0o100444
+        );
+        assert_eq!("+t".parse::<Chmod>().unwrap().apply(0o755, true), 0o1755);
+        for invalid in ["", "D", "F8", "q+r", "u+z", "u"] {
+            assert!(invalid.parse::<Chmod>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_chmod_applies_to_the_destination() {
+        use crate::{temp_fs, Synchronize};
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = temp_fs!(input / sub / a: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        Synchronize::new(&input, &output)
+            .chmod("D750".parse().unwrap())
+            .chmod("F600,Fg+r".parse().unwrap())
+            .sync()
+            .unwrap();
+        let mode = |path: &str| output.join(path).metadata().unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode("sub"), 0o750);
+        assert_eq!(mode("sub/a.text"), 0o640);
+    }
+}
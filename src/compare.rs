@@ -0,0 +1,231 @@
+//! Comparing the source with the destination without changing either, to audit a mirror or see
+//! what a sync would do
+
+use crate::{
+    backend::{Kind, Meta},
+    journal, Error, Result, Synchronize,
+};
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Why a path in both the source and the destination differs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum Reason {
+    /// One is a file and the other a directory or symlink
+    Type,
+    Size,
+    /// Modified at different times, ignored when comparing contents
+    Mtime,
+    /// Files of the same size with different contents
+    Content,
+    /// Symlinks pointing somewhere else
+    Target,
+}
+
+impl Reason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Reason::Type => "type",
+            Reason::Size => "size",
+            Reason::Mtime => "mtime",
+            Reason::Content => "content",
+            Reason::Target => "target",
+        }
+    }
+}
+
+/// How a path differs between the source and the destination
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    /// Only in the source, with everything below it for a directory
+    Missing,
+    /// Only in the destination, where a sync with delete would remove it
+    Extra,
+    Differs(Vec<Reason>),
+}
+
+/// A path that differs, relative to the source and destination
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comparison {
+    pub path: PathBuf,
+    pub mismatch: Mismatch,
+}
+
+impl Synchronize {
+    /// Compare the source with the destination without changing either, returning the paths
+    /// that differ in the order of a walk of both trees. Paths the sync's filters exclude are
+    /// left out, and with a content check files are compared by contents instead of modified
+    /// time. A sync brings every path but the [`Mismatch::Extra`] ones up to date, and those
+    /// too with [`Synchronize::delete`].
+    pub fn compare(mut self) -> Result<Vec<Comparison>> {
+        if self.prepare()?.is_some() {
+            return Err(Error::Unsupported(
+                "sftp sources can't be compared".to_string(),
+            ));
+        }
+        if self.check_content.is_some() && !self.dest_backend().is_local() {
+            return Err(Error::Unsupported(
+                "contents can only be compared with a local destination".to_string(),
+            ));
+        }
+        let mut out = Vec::new();
+        match self.dest_backend().metadata(&self.dest)? {
+            Some(_) => self.compare_dir(Path::new(""), &mut out)?,
+            None => out.push(Comparison {
+                path: PathBuf::new(),
+                mismatch: Mismatch::Missing,
+            }),
+        }
+        Ok(out)
+    }
+
+    fn compare_dir(&self, rel: &Path, out: &mut Vec<Comparison>) -> Result<()> {
+        let (src_dir, dest_dir) = (self.src.join(rel), self.dest.join(rel));
+        let mut names = BTreeSet::new();
+        for entry in fs::read_dir(&src_dir).map_err(|e| Error::with_path(e, &src_dir))? {
+            names.insert(entry?.file_name());
+        }
+        names.extend(self.dest_backend().read_dir(&dest_dir)?);
+        let journal = self.dest.join(journal::JOURNAL_FILE);
+        for name in names {
+            let path = rel.join(&name);
+            let (src, dest) = (self.src.join(&path), self.dest.join(&path));
+            // Hidden source entries aren't synced, so they count as missing from the source
+            let hidden = self.skip_hidden && name.to_string_lossy().starts_with('.');
+            let src_meta = match src.symlink_metadata() {
+                Ok(meta) => Some(meta).filter(|_| !hidden),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(Error::with_path(e, &src)),
+            };
+            let dest_meta = self.dest_backend().metadata(&dest)?;
+            let is_dir = match (&src_meta, &dest_meta) {
+                (Some(meta), _) => meta.is_dir(),
+                (None, Some(meta)) => meta.kind == Kind::Dir,
+                (None, None) => continue,
+            };
+            if self.is_excluded(&src, is_dir)
+                || self.is_internal(&dest)
+                || self.is_protected(&dest)
+                || dest == journal
+            {
+                continue;
+            }
+            let mismatch = match (src_meta, dest_meta) {
+                (Some(_), None) => Mismatch::Missing,
+                (None, Some(_)) => Mismatch::Extra,
+                (Some(src_meta), Some(dest_meta)) => {
+                    let reasons = self.compare_entry(&src, &src_meta, &dest, &dest_meta)?;
+                    if reasons.is_empty() {
+                        if src_meta.is_dir() {
+                            self.compare_dir(&path, out)?;
+                        }
+                        continue;
+                    }
+                    Mismatch::Differs(reasons)
+                }
+                (None, None) => continue,
+            };
+            out.push(Comparison { path, mismatch });
+        }
+        Ok(())
+    }
+
+    /// Why the source `src` and the destination `dest` differ, if they do
+    fn compare_entry(
+        &self,
+        src: &Path,
+        src_meta: &fs::Metadata,
+        dest: &Path,
+        dest_meta: &Meta,
+    ) -> Result<Vec<Reason>> {
+        let meta = Meta::from_local(src_meta);
+        if meta.kind != dest_meta.kind {
+            return Ok(vec![Reason::Type]);
+        }
+        let mut reasons = Vec::new();
+        match meta.kind {
+            Kind::File if meta.len != dest_meta.len => reasons.push(Reason::Size),
+            Kind::File if self.check_content.is_some() => {
+                let mut file = fs::File::open(src).map_err(|e| Error::with_path(e, src))?;
+                if !self.check_content_equal(src, &mut file, dest)? {
+                    reasons.push(Reason::Content);
+                }
+            }
+            Kind::File if !self.is_equal(&meta, dest_meta) => reasons.push(Reason::Mtime),
+            Kind::Symlink => {
+                let target = fs::read_link(src).map_err(|e| Error::with_path(e, src))?;
+                if self.dest_backend().read_link(dest)? != target {
+                    reasons.push(Reason::Target);
+                }
+            }
+            _ => {}
+        }
+        Ok(reasons)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Comparison, Mismatch, Reason};
+    use crate::{temp_fs, Synchronize};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_compare_lists_what_differs() {
+        let temp = temp_fs!(
+            input / same: 4,
+            input / new: 2,
+            input / sub / longer: 8,
+            input / sub / rewritten: 4,
+            output / same: 4,
+            output / old: 2,
+            output / sub / longer: 4,
+            output / sub / rewritten: 4,
+        );
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let copy = |name: &str| std::fs::copy(input.join(name), output.join(name)).unwrap();
+        copy("same.text");
+        std::fs::write(output.join("sub/rewritten.text"), b"diff").unwrap();
+        let time = filetime::FileTime::from_unix_time(1_700_000_000, 0);
+        for root in [&input, &output] {
+            for name in ["same.text", "sub/rewritten.text"] {
+                filetime::set_file_mtime(root.join(name), time).unwrap();
+            }
+        }
+
+        let comparison = |path: &str, mismatch| Comparison {
+            path: PathBuf::from(path),
+            mismatch,
+        };
+        let by_metadata = Synchronize::new(&input, &output).compare().unwrap();
+        assert_eq!(
+            by_metadata,
+            [
+                comparison("new.text", Mismatch::Missing),
+                comparison("old.text", Mismatch::Extra),
+                comparison("sub/longer.text", Mismatch::Differs(vec![Reason::Size])),
+            ]
+        );
+        // The same size and time but different bytes only show up comparing contents
+        let by_content = Synchronize::new(&input, &output)
+            .check_content(true)
+            .exclude("new.text")
+            .compare()
+            .unwrap();
+        assert_eq!(
+            by_content[1..],
+            [
+                comparison("sub/longer.text", Mismatch::Differs(vec![Reason::Size])),
+                comparison(
+                    "sub/rewritten.text",
+                    Mismatch::Differs(vec![Reason::Content])
+                ),
+            ]
+        );
+        assert_eq!(by_content.len(), 3);
+    }
+}
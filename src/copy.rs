@@ -0,0 +1,485 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    str::FromStr,
+};
+
+/// Bytes at the end of a partial copy compared with the source before appending to it
+const PARTIAL_CHECK: u64 = 64 * 1024;
+
+/// Bytes of a large file each thread copies at a time, see [`chunked`]
+pub(crate) const CHUNK: u64 = 8 * 1024 * 1024;
+
+/// Buffer each thread copies a chunk through
+const CHUNK_BUFFER: usize = 1024 * 1024;
+
+/// Called with the number of bytes a copy just wrote, to report progress on large files
+pub(crate) type OnCopied<'a> = &'a (dyn Fn(u64) + Sync);
+
+/// Copy the rest of `src` into `dest` through a buffer, returning the number of bytes copied
+pub(crate) fn copy_stream(
+    src: &mut impl Read,
+    dest: &mut impl Write,
+    on_copied: OnCopied,
+) -> io::Result<u64> {
+    let mut buffer = vec![0; CHUNK_BUFFER];
+    let mut copied = 0;
+    loop {
+        let read = match src.read(&mut buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        dest.write_all(&buffer[..read])?;
+        copied += read as u64;
+        on_copied(read as u64);
+    }
+}
+
+/// A way of materializing a source file at the destination, tried in the order given to
+/// [`crate::Synchronize::copy_strategy`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyMethod {
+    /// Copy-on-write clone sharing the source's data blocks
+    Reflink,
+    /// Hardlink to the source, only safe when the source won't be modified afterwards
+    Hardlink,
+    /// Full copy of the file data
+    Copy,
+}
+
+impl CopyMethod {
+    /// Name of the method in `--copy-strategy` and the NDJSON output
+    pub fn name(&self) -> &'static str {
+        match self {
+            CopyMethod::Reflink => "reflink",
+            CopyMethod::Hardlink => "hardlink",
+            CopyMethod::Copy => "copy",
+        }
+    }
+}
+
+impl FromStr for CopyMethod {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reflink" => Ok(CopyMethod::Reflink),
+            "hardlink" => Ok(CopyMethod::Hardlink),
+            "copy" => Ok(CopyMethod::Copy),
+            _ => Err(crate::Error::Unsupported(format!(
+                "Unknown copy method {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// How the data of a full copy is read and written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CopyEngine {
+    /// `copy_file_range` on Linux, otherwise the standard library's copy
+    #[default]
+    Std,
+    /// Batches of reads and writes submitted through io_uring, Linux only
+    IoUring,
+}
+
+impl FromStr for CopyEngine {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "std" => Ok(CopyEngine::Std),
+            "io-uring" => Ok(CopyEngine::IoUring),
+            _ => Err(crate::Error::Unsupported(format!(
+                "Unknown copy engine {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Which times of the source a copy is given
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PreserveTimes {
+    /// Modified and access times
+    #[default]
+    All,
+    /// Only the modified time, the access time is that of the copy
+    Mtime,
+    /// Neither, so unless files are compared by size or contents every sync copies them again
+    None,
+}
+
+impl FromStr for PreserveTimes {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(PreserveTimes::All),
+            "mtime" => Ok(PreserveTimes::Mtime),
+            "none" => Ok(PreserveTimes::None),
+            _ => Err(crate::Error::Unsupported(format!(
+                "Unknown times to preserve {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Open the source file `path` without updating its access time where the platform allows, which
+/// on Linux is only for files the user owns
+pub(crate) fn open_noatime(path: &Path) -> io::Result<File> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOATIME)
+            .open(path);
+        match file {
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {}
+            file => return file,
+        }
+    }
+    File::open(path)
+}
+
+/// Clone the already open `src` into `dest` without copying data, on file systems that
+/// support it
+#[cfg(target_os = "linux")]
+pub(crate) fn reflink(src: &File, dest: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let out = File::create(dest)?;
+    if unsafe { libc::ioctl(out.as_raw_fd(), libc::FICLONE as _, src.as_raw_fd()) } == -1 {
+        let e = io::Error::last_os_error();
+        drop(out);
+        let _ = fs::remove_file(dest);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Clone `src` into `dest` without copying data, on file systems that support it
+#[cfg(target_os = "macos")]
+pub(crate) fn reflink(src: &Path, dest: &Path) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let to_cstring = |path: &Path| {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let (src, dest_c) = (to_cstring(src)?, to_cstring(dest)?);
+    remove_existing(dest)?;
+    match unsafe { libc::clonefile(src.as_ptr(), dest_c.as_ptr(), 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn reflink(_src: &Path, _dest: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks are not supported on this platform",
+    ))
+}
+
+/// Replace `dest` with a hardlink to `src`
+pub(crate) fn hardlink(src: &Path, dest: &Path) -> io::Result<()> {
+    remove_existing(dest)?;
+    fs::hard_link(src, dest)
+}
+
+/// Apply the hidden, system, archive and readonly attributes of `meta` to `dest`
+#[cfg(windows)]
+pub(crate) fn set_attributes(meta: &fs::Metadata, dest: &Path) -> io::Result<()> {
+    use std::os::windows::{ffi::OsStrExt, fs::MetadataExt};
+    use windows_sys::Win32::Storage::FileSystem::{
+        SetFileAttributesW, FILE_ATTRIBUTE_ARCHIVE, FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_NORMAL,
+        FILE_ATTRIBUTE_READONLY, FILE_ATTRIBUTE_SYSTEM,
+    };
+
+    const MASK: u32 = FILE_ATTRIBUTE_ARCHIVE
+        | FILE_ATTRIBUTE_HIDDEN
+        | FILE_ATTRIBUTE_READONLY
+        | FILE_ATTRIBUTE_SYSTEM;
+    let current = dest.symlink_metadata()?.file_attributes();
+    let attributes = match (current & !MASK) | (meta.file_attributes() & MASK) {
+        0 => FILE_ATTRIBUTE_NORMAL,
+        x => x,
+    };
+    let wide = dest
+        .as_os_str()
+        .encode_wide()
+        .chain(Some(0))
+        .collect::<Vec<u16>>();
+    match unsafe { SetFileAttributesW(wide.as_ptr(), attributes) } {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(windows))]
+pub(crate) fn set_attributes(_meta: &fs::Metadata, _dest: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Give `dest` the creation time of `meta` where the platform can set it, which Linux can't
+#[cfg(any(windows, target_os = "macos"))]
+pub(crate) fn set_created(meta: &fs::Metadata, dest: &Path) -> io::Result<()> {
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::FileTimesExt;
+    #[cfg(windows)]
+    use std::os::windows::fs::{FileTimesExt, OpenOptionsExt};
+
+    // File systems without creation times have nothing to copy
+    let Ok(created) = meta.created() else {
+        return Ok(());
+    };
+    let mut options = fs::OpenOptions::new();
+    // Only the attributes are written, so readonly files and directories can be opened too
+    #[cfg(windows)]
+    options.access_mode(windows_sys::Win32::Storage::FileSystem::FILE_WRITE_ATTRIBUTES);
+    #[cfg(target_os = "macos")]
+    options.read(true);
+    options
+        .open(dest)?
+        .set_times(fs::FileTimes::new().set_created(created))
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+pub(crate) fn set_created(_meta: &fs::Metadata, _dest: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Continue the partial copy of the already open `src` at `dest` where it stopped, returning
+/// the number of bytes written. A partial copy whose end doesn't match the source is started
+/// over.
+pub(crate) fn resume(src: &mut File, dest: &Path) -> io::Result<u64> {
+    let mut dest = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dest)?;
+    let mut offset = dest.metadata()?.len();
+    if offset > src.metadata()?.len() || !same_range(src, &mut dest, offset)? {
+        offset = 0;
+    }
+    dest.set_len(offset)?;
+    src.seek(SeekFrom::Start(offset))?;
+    dest.seek(SeekFrom::Start(offset))?;
+    io::copy(src, &mut dest)
+}
+
+/// Whether the bytes before `end` are the same in both files
+fn same_range(a: &mut File, b: &mut File, end: u64) -> io::Result<bool> {
+    let start = end.saturating_sub(PARTIAL_CHECK);
+    let mut buffers = [Vec::new(), Vec::new()];
+    for (file, buffer) in [a, b].into_iter().zip(buffers.iter_mut()) {
+        file.seek(SeekFrom::Start(start))?;
+        file.take(end - start).read_to_end(buffer)?;
+    }
+    Ok(buffers[0] == buffers[1])
+}
+
+fn remove_existing(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Copy `len` bytes from an already open file into `dest` using `sendfile(2)`
+#[cfg(target_os = "linux")]
+pub(crate) fn sendfile(src: &File, len: u64, dest: &Path, on_copied: OnCopied) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let out = File::create(dest)?;
+    let mut offset: libc::off_t = 0;
+    while (offset as u64) < len {
+        let remaining = (len - offset as u64).min(CHUNK) as usize;
+        let written =
+            unsafe { libc::sendfile(out.as_raw_fd(), src.as_raw_fd(), &mut offset, remaining) };
+        match written {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the source was truncated while copying",
+                ))
+            }
+            n => on_copied(n as u64),
+        }
+    }
+    Ok(())
+}
+
+/// Copy an already open file into `dest` in the kernel with `copy_file_range(2)`, which can
+/// also copy on the server over NFS 4.2, returning the number of bytes copied. Falls back to
+/// reading and writing between file systems that don't support it.
+#[cfg(target_os = "linux")]
+pub(crate) fn copy_range(src: &File, dest: &Path, on_copied: OnCopied) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut out = File::create(dest)?;
+    let mut offset: libc::loff_t = 0;
+    loop {
+        let copied = unsafe {
+            libc::copy_file_range(
+                src.as_raw_fd(),
+                &mut offset,
+                out.as_raw_fd(),
+                std::ptr::null_mut(),
+                CHUNK as usize,
+                0,
+            )
+        };
+        match copied {
+            -1 => {
+                let e = io::Error::last_os_error();
+                let unsupported = [libc::EXDEV, libc::ENOSYS, libc::EOPNOTSUPP, libc::EINVAL];
+                match e.raw_os_error() {
+                    Some(code) if unsupported.contains(&code) => break,
+                    _ => return Err(e),
+                }
+            }
+            0 => break,
+            n => on_copied(n as u64),
+        }
+    }
+    // Copy whatever is left by hand, including the contents of files that report no length
+    // such as those in /proc
+    let mut src = src;
+    src.seek(SeekFrom::Start(offset as u64))?;
+    Ok(offset as u64 + copy_stream(&mut src, &mut out, on_copied)?)
+}
+
+/// Copy the already open `src` to `dest` in chunks written at their own offsets in parallel on
+/// the current thread pool, returning the number of bytes copied. The whole length is
+/// allocated first.
+#[cfg(unix)]
+pub(crate) fn chunked(src: &File, dest: &Path, on_copied: OnCopied) -> io::Result<u64> {
+    use rayon::prelude::*;
+    use std::os::unix::fs::FileExt;
+
+    let len = src.metadata()?.len();
+    let out = File::create(dest)?;
+    allocate(&out, len)?;
+    (0..len.div_ceil(CHUNK))
+        .into_par_iter()
+        .try_for_each(|i| -> io::Result<()> {
+            let mut buffer = vec![0; CHUNK_BUFFER];
+            let (mut at, end) = (i * CHUNK, ((i + 1) * CHUNK).min(len));
+            while at < end {
+                let n = (end - at).min(CHUNK_BUFFER as u64) as usize;
+                let read = src.read_at(&mut buffer[..n], at)?;
+                if read == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the source was truncated while copying",
+                    ));
+                }
+                out.write_all_at(&buffer[..read], at)?;
+                at += read as u64;
+                on_copied(read as u64);
+            }
+            Ok(())
+        })?;
+    Ok(len)
+}
+
+/// Reserve `len` bytes for `file` up front where the file system supports it
+#[cfg(target_os = "linux")]
+fn allocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len as libc::off_t) } {
+        0 => Ok(()),
+        libc::EOPNOTSUPP | libc::EINVAL => file.set_len(len),
+        e => Err(io::Error::from_raw_os_error(e)),
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn allocate(file: &File, len: u64) -> io::Result<()> {
+    file.set_len(len)
+}
+
+/// Whether a file with `meta` has fewer blocks allocated than its length needs, so it has holes
+#[cfg(target_os = "linux")]
+pub(crate) fn is_sparse(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    meta.blocks().saturating_mul(512) < meta.len()
+}
+
+/// Copy the already open `src` to `dest` leaving holes where `src` has them rather than writing
+/// out zeros
+#[cfg(target_os = "linux")]
+pub(crate) fn sparse(src: &File, dest: &Path, on_copied: OnCopied) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut src = src;
+    let mut out = File::create(dest)?;
+    let len = src.metadata()?.len();
+    // Everything not written below is left as a hole
+    out.set_len(len)?;
+    let seek = |file: &File, offset: u64, whence| match unsafe {
+        libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence)
+    } {
+        -1 => Err(io::Error::last_os_error()),
+        x => Ok(x as u64),
+    };
+    let mut offset = 0;
+    while offset < len {
+        let start = match seek(src, offset, libc::SEEK_DATA) {
+            Ok(start) => start,
+            // Only a hole is left
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(e) => return Err(e),
+        };
+        let end = seek(src, start, libc::SEEK_HOLE)?.min(len);
+        src.seek(SeekFrom::Start(start))?;
+        out.seek(SeekFrom::Start(start))?;
+        copy_stream(&mut (&mut src).take(end - start), &mut out, on_copied)?;
+        offset = end;
+    }
+    Ok(())
+}
+
+/// Whether `dest` would be created on the same file system as a file with `meta`
+#[cfg(target_os = "linux")]
+pub(crate) fn same_file_system(meta: &std::fs::Metadata, dest: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    dest.parent()
+        .and_then(|parent| parent.metadata().ok())
+        .map(|parent| parent.dev() == meta.dev())
+        .unwrap_or(false)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use crate::temp_fs;
+    use std::{fs::File, io};
+
+    #[test]
+    fn test_sendfile() {
+        let temp = temp_fs!(src: 64);
+        let (src, dest) = (temp.path().join("src.text"), temp.path().join("dest.text"));
+        let file = File::open(&src).unwrap();
+        super::sendfile(&file, 64, &dest, &|_| {}).unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), [b'a'; 64]);
+
+        // A source shorter than expected fails rather than leaving a short copy
+        let e = super::sendfile(&file, 128, &dest, &|_| {}).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
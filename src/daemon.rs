@@ -0,0 +1,351 @@
+//! Long running mode that keeps several source and destination pairs in sync
+
+use crate::{
+    http, metrics, ConflictPolicy, ContentCheck, Error, Metrics, Progress, Result, Synchronize,
+};
+use serde::Deserialize;
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// How long a watch job waits before watching again after it fails
+const WATCH_RETRY: Duration = Duration::from_secs(60);
+
+/// Jobs read from a toml file with a `[[job]]` table per source and destination pair
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Serve the metrics of every job at `/metrics` on this address, e.g. "127.0.0.1:9100"
+    #[serde(default)]
+    pub metrics: Option<String>,
+    #[serde(rename = "job", default)]
+    pub jobs: Vec<Job>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Job {
+    pub name: String,
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    /// Sync again this long after the previous run, e.g. "30s", "15m", "1h", "1d" or "1w"
+    pub interval: Option<String>,
+    /// Sync the paths that change in the source as they change
+    pub watch: bool,
+    /// Append the job's progress and errors to this file instead of stderr
+    pub log: Option<PathBuf>,
+    /// Post the outcome of each run as JSON to this URL
+    pub webhook: Option<String>,
+    /// Shell commands run before and after each run, see [`Synchronize::pre_hook`]
+    pub pre_hook: Option<String>,
+    pub post_hook: Option<String>,
+    pub delete: bool,
+    pub ignore_errors: bool,
+    pub retries: u32,
+    pub bidirectional: bool,
+    pub conflict: Option<String>,
+    pub dry_run: bool,
+    pub exclude: Vec<String>,
+    pub include: Vec<String>,
+    pub protect: Vec<String>,
+    pub gitignore: bool,
+    pub skip_hidden: bool,
+    pub skip_permissions: bool,
+    pub checksum: Option<String>,
+    pub checksum_cache: Option<PathBuf>,
+    pub delta: bool,
+    pub threads: Option<u8>,
+    pub rsh: Option<String>,
+    pub remote_fsync: Option<String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let config = Self::parse(&text)?;
+        if config.jobs.is_empty() {
+            return Err(Error::Config(format!("{:?} doesn't define any jobs", path)));
+        }
+        Ok(config)
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let config: Self = toml::from_str(text)?;
+        for job in config.jobs.iter() {
+            if job.name.is_empty() {
+                return Err(Error::Config("Every job needs a name".to_string()));
+            }
+            job.interval()?;
+            job.synchronize()?;
+        }
+        Ok(config)
+    }
+
+    /// Run every job on its own thread until all of them are done. Jobs with an interval or
+    /// watching the source never finish.
+    pub fn run(self) -> Result<()> {
+        let jobs = self
+            .jobs
+            .into_iter()
+            .map(|job| (job, Metrics::new()))
+            .collect::<Vec<_>>();
+        if let Some(addr) = &self.metrics {
+            let metrics = jobs.iter().map(|(job, x)| (job.name.clone(), x.clone()));
+            metrics::serve(addr, metrics.collect())?;
+        }
+        let handles = jobs
+            .into_iter()
+            .map(|(job, metrics)| {
+                std::thread::Builder::new()
+                    .name(job.name.clone())
+                    .spawn(move || job.run(metrics))
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        for handle in handles {
+            if let Err(panic) = handle.join() {
+                std::panic::resume_unwind(panic);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Job {
+    fn run(&self, metrics: Arc<Metrics>) {
+        let interval = self.interval().unwrap_or_default();
+        loop {
+            let result = self.synchronize().and_then(|sync| {
+                let sync = sync.metrics(metrics.clone());
+                self.log(&format!(
+                    "Syncing {:?} to {:?}",
+                    self.source, self.destination
+                ));
+                match self.watch {
+                    true => sync.watch().map(|_| 0),
+                    false => sync.sync().map(|report| report.errors.len()),
+                }
+            });
+            match result {
+                Ok(0) => self.log("Finished"),
+                Ok(errors) => self.log(&format!("Finished with {} errors", errors)),
+                Err(e) => self.log(&format!("Failed: {}", e)),
+            }
+            let wait = match (interval, self.watch) {
+                (Some(interval), _) => interval,
+                (None, true) => WATCH_RETRY,
+                (None, false) => return,
+            };
+            std::thread::sleep(wait);
+        }
+    }
+
+    fn interval(&self) -> Result<Option<Duration>> {
+        self.interval.as_deref().map(parse_interval).transpose()
+    }
+
+    /// Configure a sync from the job's options
+    fn synchronize(&self) -> Result<Synchronize> {
+        let mut sync = Synchronize::new(&self.source, &self.destination)
+            .delete(self.delete)
+            .ignore_errors(self.ignore_errors)
+            .retries(self.retries)
+            .bidirectional(self.bidirectional)
+            .dry_run(self.dry_run)
+            .respect_gitignore(self.gitignore)
+            .skip_hidden(self.skip_hidden)
+            .skip_permissions(self.skip_permissions)
+            .checksum_cache(self.checksum_cache.as_ref())
+            .delta(self.delta)
+            .num_threads(self.threads)
+            .webhook(self.webhook.as_ref())
+            .pre_hook(self.pre_hook.as_ref())
+            .post_hook(self.post_hook.as_ref());
+        if let Some(checksum) = &self.checksum {
+            sync = sync.content_check(checksum.parse::<ContentCheck>()?);
+        }
+        if let Some(conflict) = &self.conflict {
+            sync = sync.conflict_policy(conflict.parse::<ConflictPolicy>()?);
+        }
+        if let Some(rsh) = &self.rsh {
+            sync = sync.remote_shell(rsh);
+        }
+        if let Some(command) = &self.remote_fsync {
+            sync = sync.remote_command(command);
+        }
+        for pattern in self.exclude.iter() {
+            sync = sync.exclude(pattern);
+        }
+        for pattern in self.include.iter() {
+            sync = sync.include(pattern);
+        }
+        for pattern in self.protect.iter() {
+            sync = sync.protect(pattern);
+        }
+        sync.progress = Progress::to_log(self.open_log());
+        Ok(sync)
+    }
+
+    fn open_log(&self) -> Option<fs::File> {
+        let path = self.log.as_ref()?;
+        match fs::OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                eprintln!("[{}] Failed to open log {:?}: {:?}", self.name, path, e);
+                None
+            }
+        }
+    }
+
+    /// Record a timestamped line in the job's log, or stderr when it doesn't have one
+    fn log(&self, message: &str) {
+        let line = format!(
+            "{} [{}] {}",
+            timestamp(SystemTime::now()),
+            self.name,
+            message
+        );
+        match self.open_log() {
+            Some(mut file) => {
+                let _ = writeln!(file, "{}", line);
+            }
+            None => eprintln!("{}", line),
+        }
+    }
+}
+
+/// Parse a number of seconds with an optional s, m, h or d unit
+fn parse_interval(value: &str) -> Result<Duration> {
+    match parse_duration(value) {
+        Ok(interval) if interval.is_zero() => Err(Error::Config(format!(
+            "Interval must be positive {:?}",
+            value
+        ))),
+        Ok(interval) => Ok(interval),
+        Err(e) => Err(Error::Config(format!(
+            "Invalid interval {:?}: {}",
+            value, e
+        ))),
+    }
+}
+
+/// Parse a duration of seconds with an optional s, m, h, d or w suffix, such as `90` or `2d`
+pub fn parse_duration(value: &str) -> std::result::Result<Duration, String> {
+    let value = value.trim();
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let scale = match &value[digits.len()..] {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        unit => return Err(format!("unknown time unit {:?}", unit)),
+    };
+    let number = digits.trim().parse::<u64>().map_err(|e| e.to_string())?;
+    number
+        .checked_mul(scale)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("{} is too long", value))
+}
+
+/// Format `time` as an RFC 3339 UTC timestamp
+pub(crate) fn timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (year, month, day) = http::civil_from_days(secs.div_euclid(86400));
+    let secs = secs.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_interval, timestamp, Config};
+    use crate::temp_fs;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            r#"
+            [[job]]
+            name = "docs"
+            source = "/home/me/docs"
+            destination = "backup:/srv/docs"
+            interval = "15m"
+            delete = true
+            exclude = ["*.tmp"]
+
+            [[job]]
+            name = "photos"
+            source = "/home/me/photos"
+            destination = "s3://bucket/photos"
+            watch = true
+            checksum = "xxh3"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.jobs.len(), 2);
+        assert_eq!(
+            config.jobs[0].interval().unwrap(),
+            Some(Duration::from_secs(900))
+        );
+        assert_eq!(config.jobs[0].exclude, vec!["*.tmp"]);
+        assert!(config.jobs[1].watch);
+
+        assert!(Config::parse("[[job]]\nname = \"a\"\nchecksum = \"md5\"").is_err());
+        assert!(Config::parse("[[job]]\nname = \"a\"\nintervall = \"1h\"").is_err());
+        assert_eq!(parse_interval("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_interval("2d").unwrap(), Duration::from_secs(172800));
+        assert_eq!(parse_interval("1 w").unwrap(), Duration::from_secs(604800));
+        assert!(parse_interval("0s").is_err());
+        assert!(parse_interval("1y").is_err());
+        assert!(parse_interval("999999999999999999d").is_err());
+    }
+
+    #[test]
+    fn test_run_logs_each_job() {
+        let temp = temp_fs!(a / bar: 4, b / baz: 4);
+        let root = temp.path().display();
+        let config = Config::parse(&format!(
+            r#"
+            [[job]]
+            name = "a"
+            source = "{root}/a"
+            destination = "{root}/out-a"
+            log = "{root}/a.log"
+
+            [[job]]
+            name = "b"
+            source = "{root}/b"
+            destination = "{root}/out-b"
+            log = "{root}/b.log"
+            "#
+        ))
+        .unwrap();
+        config.run().unwrap();
+
+        assert!(temp.path().join("out-a/bar.text").exists());
+        assert!(temp.path().join("out-b/baz.text").exists());
+        let log = std::fs::read_to_string(temp.path().join("a.log")).unwrap();
+        assert!(log.contains("[a] Syncing"));
+        assert!(log.contains("Files: "));
+        assert!(log.contains("[a] Finished"));
+        assert_eq!(
+            timestamp(SystemTime::UNIX_EPOCH + Duration::from_secs(951782400 + 3723)),
+            "2000-02-29T01:02:03Z"
+        );
+    }
+}
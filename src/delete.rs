@@ -0,0 +1,387 @@
+//! Removing destination paths missing from the source, and working out which ones a sync
+//! with [`Synchronize::delete`] removes before removing any of them
+
+use crate::{backend::Kind, ChangeKind, Differences, Error, ProgressReporter, Result, Synchronize};
+use rayon::{prelude::*, ThreadPool};
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+/// When a sync removes destination paths missing from the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeleteTiming {
+    /// Before copying anything, freeing space first
+    Before,
+    /// Directory by directory as the source is walked
+    #[default]
+    During,
+    /// Once everything has been copied
+    After,
+}
+
+/// Most destination files a single sync may delete, see [`Synchronize::max_delete`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeleteLimit {
+    Count(usize),
+    /// Share of the files in the destination
+    Percent(f64),
+}
+
+impl DeleteLimit {
+    fn allows(&self, deletes: usize, total: usize) -> bool {
+        match *self {
+            DeleteLimit::Count(max) => deletes <= max,
+            DeleteLimit::Percent(max) => total == 0 || deletes as f64 * 100.0 <= max * total as f64,
+        }
+    }
+}
+
+impl FromStr for DeleteLimit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || Error::Unsupported(format!("Invalid delete limit {:?}", s));
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.parse::<f64>() {
+                Ok(x) if (0.0..=100.0).contains(&x) => Ok(DeleteLimit::Percent(x)),
+                _ => Err(invalid()),
+            },
+            None => s.parse().map(DeleteLimit::Count).map_err(|_| invalid()),
+        }
+    }
+}
+
+impl fmt::Display for DeleteLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeleteLimit::Count(x) => write!(f, "{}", x),
+            DeleteLimit::Percent(x) => write!(f, "{}%", x),
+        }
+    }
+}
+
+/// Destination paths missing from the source, along with how many files they hold
+#[derive(Debug, Default)]
+pub(crate) struct DeletePlan {
+    pub(crate) paths: Vec<PathBuf>,
+    /// Files that would be deleted
+    pub(crate) files: usize,
+    /// Files in the destination, deleted or not
+    pub(crate) total: usize,
+}
+
+impl Synchronize {
+    /// Sync the whole source, deleting what `plan` lists before or after when configured to
+    pub(crate) fn sync_all(
+        self: &Arc<Self>,
+        thread_pool: &Arc<ThreadPool>,
+        plan: Option<DeletePlan>,
+    ) -> Result<()> {
+        let (before, after) = match self.delete_timing {
+            DeleteTiming::Before => (plan, None),
+            _ if !self.deletes_at_once() => (None, None),
+            _ => (None, plan),
+        };
+        if let Some(plan) = before {
+            self.apply_deletes(thread_pool, plan)?;
+        }
+        let dirs = self.sync_tree(thread_pool, &self.src)?;
+        // A cancelled sync may not have copied everything it would delete in favour of
+        if let Some(plan) = after.filter(|_| !self.cancel.is_cancelled()) {
+            self.apply_deletes(thread_pool, plan)?;
+        }
+        self.finish_dirs(dirs)
+    }
+
+    /// Whether deletes are planned up front rather than found directory by directory. Renamed
+    /// files are only detected against a plan, applied after copying so they are still there.
+    pub(crate) fn deletes_at_once(&self) -> bool {
+        self.delete_timing != DeleteTiming::During || self.detect_renames
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(paths = plan.paths.len()))
+    )]
+    fn apply_deletes(&self, thread_pool: &ThreadPool, plan: DeletePlan) -> Result<()> {
+        thread_pool.install(|| plan.paths.par_iter().try_for_each(|x| self.remove_all(x)))?;
+        Ok(())
+    }
+
+    /// Remove the destination path `path` along with everything below it, in parallel on the
+    /// current thread pool. Protected paths and the directories holding them are kept.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(path = ?path))
+    )]
+    pub(crate) fn remove_all(&self, path: &Path) -> io::Result<()> {
+        if self.dry_run {
+            self.report_dry_run("delete", path);
+        }
+        self.remove_tree(path).map(|_| ())
+    }
+
+    /// Returns whether `path` was removed, counting each removed path
+    fn remove_tree(&self, path: &Path) -> io::Result<bool> {
+        if self.is_protected(path) {
+            return Ok(false);
+        }
+        // Files renamed into place are gone already
+        let Some(meta) = self.dest_backend().metadata(path)? else {
+            return Ok(true);
+        };
+        if meta.kind != Kind::Dir {
+            if !self.dry_run {
+                self.remove_file(path)?;
+            }
+            trace!(trace, path = ?path, "deleted file");
+            self.progress.on_deleted(1);
+            self.progress
+                .changed(ChangeKind::Delete, path, 0, Differences::default());
+            // A backup renamed in place keeps its directory around
+            return Ok(!self.backup || self.backup_dir.is_some());
+        }
+        let removed = self
+            .dest_backend()
+            .read_dir(path)?
+            .par_iter()
+            .map(|x| match path.join(x) {
+                // Backups of files deleted before are kept like protected paths
+                child if self.is_backup(&child) => Ok(false),
+                child => self.remove_tree(&child),
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        if removed.contains(&false) {
+            return Ok(false);
+        }
+        if !self.dry_run {
+            self.dest_backend().remove_dir(path)?;
+        }
+        trace!(trace, path = ?path, "deleted directory");
+        self.progress.on_deleted(1);
+        self.progress
+            .changed(ChangeKind::Delete, path, 0, Differences::default());
+        Ok(true)
+    }
+
+    /// Delete a destination file, or move it to its backup or the trash
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        match (self.backup, self.trash) {
+            (true, _) => self.backup_file(path),
+            (false, true) => trash::delete(path).map_err(io::Error::other),
+            (false, false) => self.dest_backend().remove(path),
+        }
+    }
+
+    /// Work out the deletes up front when they are applied at once, checked against
+    /// `max_delete` or confirmed. Fails without deleting anything when there are too many or
+    /// they aren't confirmed.
+    pub(crate) fn check_deletes(&self) -> Result<Option<DeletePlan>> {
+        let at_once = self.deletes_at_once();
+        if !self.delete || !at_once && self.max_delete.is_none() && self.confirm_deletes.is_none() {
+            return Ok(None);
+        }
+        self.approve_deletes(self.plan_deletes()?).map(Some)
+    }
+
+    /// Fail when `plan` deletes more than [`Synchronize::max_delete`] allows or
+    /// [`Synchronize::confirm_deletes`] declines it
+    pub(crate) fn approve_deletes(&self, plan: DeletePlan) -> Result<DeletePlan> {
+        if let Some(limit) = self.max_delete {
+            if !limit.allows(plan.files, plan.total) {
+                return Err(Error::TooManyDeletes {
+                    count: plan.files,
+                    total: plan.total,
+                    limit,
+                });
+            }
+        }
+        match &self.confirm_deletes {
+            Some(confirm) if !plan.paths.is_empty() && !confirm(&plan.paths) => {
+                Err(Error::Declined)
+            }
+            _ => Ok(plan),
+        }
+    }
+
+    pub(crate) fn plan_deletes(&self) -> Result<DeletePlan> {
+        let mut plan = DeletePlan::default();
+        if self.is_dir(&self.dest)? {
+            self.plan_dir(&self.src, &self.dest, &mut plan)?;
+        }
+        Ok(plan)
+    }
+
+    fn plan_dir(&self, src: &Path, dest: &Path, plan: &mut DeletePlan) -> Result<()> {
+        let sources = self.source_names(src);
+        for name in self.dest_backend().read_dir(dest)? {
+            let (src, dest) = (
+                src.join(sources.get(&name).unwrap_or(&name)),
+                dest.join(&name),
+            );
+            let is_dir = self.is_dir(&dest)?;
+            if self.is_excluded(&src, is_dir) || self.is_internal(&dest) || self.is_protected(&dest)
+            {
+                continue;
+            }
+            // Hidden source entries aren't walked, so their destination is deleted like rsync
+            let hidden = self.skip_hidden && name.to_string_lossy().starts_with('.');
+            let src_meta = self.source_metadata(&src).ok().filter(|_| !hidden);
+            match src_meta {
+                None => {
+                    let files = self.count_files(&dest, is_dir)?;
+                    plan.paths.push(dest);
+                    plan.files += files;
+                    plan.total += files;
+                }
+                Some(meta) if is_dir && meta.is_dir() && !self.skips_contents(&src) => {
+                    self.plan_dir(&src, &dest, plan)?
+                }
+                Some(_) => plan.total += self.count_files(&dest, is_dir)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Files at or below the destination path `path`
+    fn count_files(&self, path: &Path, is_dir: bool) -> Result<usize> {
+        if !is_dir {
+            return Ok(1);
+        }
+        let mut count = 0;
+        for name in self.dest_backend().read_dir(path)? {
+            let child = path.join(name);
+            if !self.is_protected(&child) {
+                count += self.count_files(&child, self.is_dir(&child)?)?;
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeleteLimit, DeleteTiming};
+    use crate::{temp_fs, Error, Synchronize};
+
+    #[test]
+    fn test_max_delete_aborts_before_deleting() {
+        assert_eq!("10".parse::<DeleteLimit>().unwrap(), DeleteLimit::Count(10));
+        assert_eq!(
+            "2.5%".parse::<DeleteLimit>().unwrap(),
+            DeleteLimit::Percent(2.5)
+        );
+        assert!("150%".parse::<DeleteLimit>().is_err());
+
+        let temp = temp_fs!(
+            input / keep: 1,
+            output / keep: 1,
+            output / a: 1,
+            output / old / b: 1,
+            output / old / c: 1,
+        );
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let sync = |limit| {
+            Synchronize::new(&input, &output)
+                .delete(true)
+                .max_delete(Some(limit))
+                .sync()
+        };
+        let result = sync(DeleteLimit::Count(2));
+        assert!(matches!(
+            result,
+            Err(Error::TooManyDeletes {
+                count: 3,
+                total: 4,
+                ..
+            })
+        ));
+        assert!(output.join("a.text").exists());
+        assert!(sync(DeleteLimit::Percent(50.0)).is_err());
+
+        let report = sync(DeleteLimit::Percent(75.0)).unwrap();
+        assert_eq!(report.deleted, 4);
+        assert!(!output.join("old").exists());
+    }
+
+    #[test]
+    fn test_delete_timing() {
+        for timing in [DeleteTiming::Before, DeleteTiming::After] {
+            let temp = temp_fs!(input / new: 1, input / sub / b: 1, output / old / a: 1);
+            let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+            let report = Synchronize::new(&input, &output)
+                .delete(true)
+                .delete_timing(timing)
+                .sync()
+                .unwrap();
+            assert_eq!((report.created, report.deleted), (2, 2));
+            assert!(!output.join("old").exists());
+            assert!(output.join("sub/b.text").exists());
+        }
+    }
+
+    #[test]
+    fn test_protected_paths_are_kept() {
+        let temp = temp_fs!(
+            input / keep: 1,
+            output / logs / a: 1,
+            output / old / b: 1,
+            output / old / c: 1,
+        );
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let report = Synchronize::new(&input, &output)
+            .delete(true)
+            .protect("logs/**")
+            .protect("c.text")
+            .sync()
+            .unwrap();
+        assert_eq!(report.deleted, 1);
+        assert!(output.join("logs/a.text").exists());
+        assert!(output.join("old/c.text").exists());
+        assert!(!output.join("old/b.text").exists());
+    }
+
+    #[test]
+    fn test_remove_all_removes_directories() {
+        let temp = temp_fs!(input / keep: 1, output / a / b / c: 1, output / a / d: 1);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let sync = |dry_run| {
+            Synchronize::new(&input, &output)
+                .delete(true)
+                .dry_run(dry_run)
+                .sync()
+                .unwrap()
+        };
+        // A dry run counts what would go without removing it
+        assert_eq!(sync(true).deleted, 4);
+        assert!(output.join("a/b/c.text").exists());
+        assert_eq!(sync(false).deleted, 4);
+        assert!(!output.join("a").exists());
+    }
+
+    #[test]
+    fn test_confirm_deletes() {
+        let temp = temp_fs!(input / keep: 1, output / a: 1, output / old / b: 1);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let sync = |answer| {
+            let expected = vec![output.join("a.text"), output.join("old")];
+            Synchronize::new(&input, &output)
+                .delete(true)
+                .confirm_deletes(move |paths| {
+                    let mut paths = paths.to_vec();
+                    paths.sort();
+                    assert_eq!(paths, expected);
+                    answer
+                })
+                .sync()
+        };
+        assert!(matches!(sync(false), Err(Error::Declined)));
+        assert!(output.join("a.text").exists());
+        assert!(!output.join("keep.text").exists());
+
+        assert_eq!(sync(true).unwrap().deleted, 3);
+    }
+}
@@ -0,0 +1,379 @@
+//! Rsync style delta transfer. A signature of weak rolling and strong checksums is computed
+//! for each block of the existing destination file, then the source is scanned with a rolling
+//! window to find blocks the destination already has so only the remaining data is written.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Checksums of every block of a basis file
+#[derive(Debug)]
+pub(crate) struct Signature {
+    block_size: usize,
+    blocks: Vec<Block>,
+    lookup: HashMap<u32, Vec<usize>>,
+}
+
+#[derive(Debug)]
+struct Block {
+    len: usize,
+    strong: [u8; 16],
+}
+
+/// How to reconstruct the source in terms of the basis file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Op {
+    /// Basis block `block` is the source data at `offset`
+    Copy { block: usize, offset: u64 },
+    /// Source data at `offset` that the basis doesn't have
+    Data { offset: u64, len: u64 },
+}
+
+impl Signature {
+    pub(crate) fn new(basis: &mut impl Read, block_size: usize) -> io::Result<Self> {
+        let mut blocks = Vec::new();
+        let mut lookup = HashMap::<u32, Vec<usize>>::new();
+        let mut buffer = vec![0; block_size];
+        loop {
+            let len = read_full(basis, &mut buffer)?;
+            if len == 0 {
+                break;
+            }
+            let data = &buffer[..len];
+            lookup
+                .entry(Rolling::new(data).digest())
+                .or_default()
+                .push(blocks.len());
+            blocks.push(Block {
+                len,
+                strong: strong(data),
+            });
+            if len < block_size {
+                break;
+            }
+        }
+        Ok(Self {
+            block_size,
+            blocks,
+            lookup,
+        })
+    }
+
+    fn find(&self, weak: u32, data: &[u8]) -> Option<usize> {
+        let candidates = self.lookup.get(&weak)?;
+        let hash = strong(data);
+        candidates
+            .iter()
+            .copied()
+            .find(|i| self.blocks[*i].len == data.len() && self.blocks[*i].strong == hash)
+    }
+}
+
+/// Block size for a file of `len` bytes, roughly its square root like rsync
+pub(crate) fn block_size(len: u64) -> usize {
+    let size = (len as f64).sqrt() as usize;
+    size.clamp(1024, 128 * 1024) / 8 * 8
+}
+
+/// Scan `source` for blocks present in the basis described by `sig`
+pub(crate) fn delta(sig: &Signature, source: &mut impl Read) -> io::Result<Vec<Op>> {
+    let bs = sig.block_size;
+    let mut window = Window::new(source);
+    let mut ops = Vec::new();
+    let mut literal = 0u64;
+    let mut offset = 0u64;
+    let mut rolling: Option<Rolling> = None;
+
+    loop {
+        // One byte past the block so the window can be rolled forward
+        window.fill(offset + bs as u64 + 1)?;
+        let data = window.slice(offset, offset + bs as u64);
+        if data.is_empty() {
+            break;
+        }
+        let len = data.len();
+        let weak = match &rolling {
+            Some(rolling) if len == bs => rolling.digest(),
+            _ => Rolling::new(data).digest(),
+        };
+        if let Some(block) = sig.find(weak, data) {
+            if literal < offset {
+                ops.push(Op::Data {
+                    offset: literal,
+                    len: offset - literal,
+                });
+            }
+            ops.push(Op::Copy { block, offset });
+            offset += len as u64;
+            literal = offset;
+            rolling = None;
+            window.discard(offset);
+            continue;
+        }
+        // The final short window can only match the basis' short last block
+        if len < bs {
+            break;
+        }
+        let next = window.slice(offset + bs as u64, offset + bs as u64 + 1);
+        let mut state = rolling.take().unwrap_or_else(|| Rolling::new(data));
+        match next.first() {
+            Some(byte) => state.roll(data[0], *byte),
+            None => {
+                offset += 1;
+                continue;
+            }
+        }
+        rolling = Some(state);
+        offset += 1;
+        window.discard(offset);
+    }
+
+    // A short last block in the basis can still match the end of the source
+    let end = window.end();
+    let last = sig.blocks.len().wrapping_sub(1);
+    if let Some(block) = sig.blocks.get(last).filter(|x| x.len < bs) {
+        let start = end.saturating_sub(block.len as u64);
+        let data = window.slice(start, end);
+        if start >= literal && data.len() == block.len && strong(data) == block.strong {
+            if literal < start {
+                ops.push(Op::Data {
+                    offset: literal,
+                    len: start - literal,
+                });
+            }
+            ops.push(Op::Copy {
+                block: last,
+                offset: start,
+            });
+            literal = end;
+        }
+    }
+    if literal < end {
+        ops.push(Op::Data {
+            offset: literal,
+            len: end - literal,
+        });
+    }
+    Ok(ops)
+}
+
+/// Rewrite `dest` to match the already open `src`, writing only data the destination doesn't
+/// already have. Returns the number of bytes written.
+pub(crate) fn sync_file(src: &mut File, dest: &Path) -> io::Result<u64> {
+    let len = src.metadata()?.len();
+    let block_size = block_size(len);
+    let sig = Signature::new(&mut io::BufReader::new(File::open(dest)?), block_size)?;
+    src.seek(SeekFrom::Start(0))?;
+    let ops = delta(&sig, &mut io::BufReader::new(&mut *src))?;
+    let mut source = src;
+
+    // Blocks that are already in place need no writes, so the file can be patched directly
+    let in_place = ops.iter().all(|op| match op {
+        Op::Copy { block, offset } => (*block * block_size) as u64 == *offset,
+        Op::Data { .. } => true,
+    });
+    if in_place {
+        let mut out = OpenOptions::new().write(true).open(dest)?;
+        let mut written = 0;
+        for op in ops.iter() {
+            if let Op::Data { offset, len } = op {
+                source.seek(SeekFrom::Start(*offset))?;
+                out.seek(SeekFrom::Start(*offset))?;
+                written += io::copy(&mut (&mut source).take(*len), &mut out)?;
+            }
+        }
+        out.set_len(len)?;
+        return Ok(written);
+    }
+
+    // Otherwise moved blocks are read from the basis while building a new file beside it
+    let tmp = dest.with_file_name(format!(
+        ".{}.fsync-delta",
+        dest.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    let result = (|| {
+        let mut basis = File::open(dest)?;
+        let mut out = io::BufWriter::new(File::create(&tmp)?);
+        let mut written = 0;
+        for op in ops.iter() {
+            match op {
+                Op::Copy { block, .. } => {
+                    basis.seek(SeekFrom::Start((*block * block_size) as u64))?;
+                    let len = sig.blocks[*block].len as u64;
+                    io::copy(&mut (&mut basis).take(len), &mut out)?;
+                }
+                Op::Data { offset, len } => {
+                    source.seek(SeekFrom::Start(*offset))?;
+                    written += io::copy(&mut (&mut source).take(*len), &mut out)?;
+                }
+            }
+        }
+        out.flush()?;
+        Ok(written)
+    })();
+    match result {
+        Ok(written) => {
+            fs::rename(&tmp, dest)?;
+            Ok(written)
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+/// Adler style checksum that can be rolled forward one byte at a time
+#[derive(Debug, Clone)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(data: &[u8]) -> Self {
+        let len = data.len() as u32;
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, byte) in data.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(*byte as u32));
+        }
+        Self { a, b, len }
+    }
+
+    fn roll(&mut self, out: u8, add: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(add as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong(data: &[u8]) -> [u8; 16] {
+    let mut out = [0; 16];
+    out.copy_from_slice(&blake3::hash(data).as_bytes()[..16]);
+    out
+}
+
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match reader.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Buffered view of a reader addressed by absolute offsets
+struct Window<R> {
+    reader: R,
+    buf: Vec<u8>,
+    start: u64,
+    eof: bool,
+}
+
+impl<R: Read> Window<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+            start: 0,
+            eof: false,
+        }
+    }
+
+    fn end(&self) -> u64 {
+        self.start + self.buf.len() as u64
+    }
+
+    fn fill(&mut self, end: u64) -> io::Result<()> {
+        let mut chunk = [0; 64 * 1024];
+        while !self.eof && self.end() < end {
+            match self.reader.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn slice(&self, from: u64, to: u64) -> &[u8] {
+        let from = (from.max(self.start) - self.start) as usize;
+        let to = ((to.min(self.end()) - self.start) as usize).max(from);
+        &self.buf[from.min(self.buf.len())..to]
+    }
+
+    /// Drop buffered data before `offset` once enough has accumulated
+    fn discard(&mut self, offset: u64) {
+        let count = (offset.saturating_sub(self.start) as usize).min(self.buf.len());
+        if count >= 1024 * 1024 {
+            self.buf.drain(..count);
+            self.start += count as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{delta, sync_file, Op, Signature};
+
+    fn data(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_delta_finds_shifted_blocks() {
+        let basis = data(8 * 1024, 1);
+        let mut source = b"inserted".to_vec();
+        source.extend_from_slice(&basis);
+        let sig = Signature::new(&mut &basis[..], 1024).unwrap();
+        let ops = delta(&sig, &mut &source[..]).unwrap();
+        assert_eq!(ops[0], Op::Data { offset: 0, len: 8 });
+        assert_eq!(ops.len(), 9);
+    }
+
+    #[test]
+    fn test_sync_file_rewrites_changed_blocks() {
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (temp.path().join("src"), temp.path().join("dest"));
+        let open = || std::fs::File::open(&src).unwrap();
+        let basis = data(64 * 1024, 2);
+
+        // A change in place only writes the block containing it
+        let mut source = basis.clone();
+        source[10_000] ^= 0xff;
+        std::fs::write(&src, &source).unwrap();
+        std::fs::write(&dest, &basis).unwrap();
+        assert_eq!(sync_file(&mut open(), &dest).unwrap(), 1024);
+        assert_eq!(std::fs::read(&dest).unwrap(), source);
+
+        // Inserted data shifts the remaining blocks, which are rebuilt from the basis
+        let mut source = data(100, 3);
+        source.extend_from_slice(&basis[..50_000]);
+        std::fs::write(&src, &source).unwrap();
+        std::fs::write(&dest, &basis).unwrap();
+        assert!(sync_file(&mut open(), &dest).unwrap() < 2048);
+        assert_eq!(std::fs::read(&dest).unwrap(), source);
+    }
+}
@@ -0,0 +1,73 @@
+//! Helpers for storing paths in the line based files written by fsync
+
+use std::{
+    ffi::{OsStr, OsString},
+    io::{self, Write},
+};
+
+#[cfg(unix)]
+pub(crate) fn os_bytes(name: &OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    name.as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn os_bytes(name: &OsStr) -> Vec<u8> {
+    name.to_string_lossy().as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+pub(crate) fn os_from_bytes(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn os_from_bytes(bytes: Vec<u8>) -> OsString {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+/// Write `name` escaping backslashes and newlines so it fits on a single line
+pub(crate) fn write_escaped(out: &mut impl Write, name: &OsStr) -> io::Result<()> {
+    for byte in os_bytes(name) {
+        match byte {
+            b'\\' => out.write_all(b"\\\\")?,
+            b'\n' => out.write_all(b"\\n")?,
+            b => out.write_all(&[b])?,
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn unescape(bytes: &[u8]) -> OsString {
+    let mut name = Vec::new();
+    let mut escaped = false;
+    for &byte in bytes {
+        if escaped {
+            name.push(if byte == b'n' { b'\n' } else { byte });
+            escaped = false;
+        } else if byte == b'\\' {
+            escaped = true;
+        } else {
+            name.push(byte);
+        }
+    }
+    os_from_bytes(name)
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn from_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+pub(crate) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,86 @@
+use std::{io, path::PathBuf};
+
+/// Everything that can make a sync fail
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Failed to read the source: {0}")]
+    Walk(#[from] jwalk::Error),
+    #[error("Permission denied: {0:?}")]
+    PermissionDenied(PathBuf),
+    #[error("Failed to write to the destination {path:?}: {source}")]
+    DestinationUnwritable { path: PathBuf, source: io::Error },
+    #[error("Failed to copy file {src:?} -> {dest:?}: {source}")]
+    CopyFailed {
+        src: PathBuf,
+        dest: PathBuf,
+        source: io::Error,
+    },
+    #[error("Failed to create symlink {src:?} -> {dest:?}: {source}")]
+    SymlinkFailed {
+        src: PathBuf,
+        dest: PathBuf,
+        source: io::Error,
+    },
+    /// A source symlink points nowhere under [`crate::DanglingLinks::Fail`]
+    #[error("Symlink {path:?} points to {target:?}, which doesn't exist")]
+    DanglingSymlink { path: PathBuf, target: PathBuf },
+    /// A source path fsync can't sync the type of under
+    /// [`crate::Synchronize::fail_on_unsupported`]
+    #[error("Can't sync {path:?}, its file type isn't supported")]
+    UnsupportedType { path: PathBuf },
+    /// A source file wasn't removed by [`crate::Synchronize::move_files`] as the destination
+    /// didn't match it after copying
+    #[error("Not removing {0:?} as its copy doesn't match it")]
+    Unverified(PathBuf),
+    #[error("Invalid pattern: {0}")]
+    Pattern(#[from] globset::Error),
+    /// Paths changed on both sides of a bidirectional sync under [`crate::ConflictPolicy::Fail`]
+    #[error("Paths changed on both sides: {0:?}")]
+    Conflict(Vec<PathBuf>),
+    #[error("Failed to watch the source: {0}")]
+    Watch(#[from] notify::Error),
+    #[error("Invalid config: {0}")]
+    Config(String),
+    /// Options that can't be used together, or with this source or destination
+    #[error("{0}")]
+    Unsupported(String),
+    #[error("Refusing to delete {count} of {total} destination files, the limit is {limit}")]
+    TooManyDeletes {
+        count: usize,
+        total: usize,
+        limit: crate::DeleteLimit,
+    },
+    /// The deletes were rejected by [`crate::Synchronize::confirm_deletes`]
+    #[error("Deletes were not confirmed")]
+    Declined,
+    /// A pre or post hook command exited unsuccessfully
+    #[error("The {hook} hook {command:?} failed: {status}")]
+    Hook {
+        hook: &'static str,
+        command: String,
+        status: String,
+    },
+    #[error("Failed to start threads: {0}")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Name the path that couldn't be accessed when permission was the reason
+    pub(crate) fn with_path(e: io::Error, path: impl Into<PathBuf>) -> Self {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => Error::PermissionDenied(path.into()),
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Self {
+        Error::Config(e.to_string())
+    }
+}
@@ -0,0 +1,223 @@
+//! Syncing to FAT and exFAT file systems, as found on USB sticks and SD cards, which have no
+//! permissions or symlinks, store modified times in 2 second steps and reject some characters
+//! in names
+
+use crate::{ProgressReporter, Synchronize};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fs,
+    path::Path,
+    time::Duration,
+};
+
+/// Smallest modify window that covers the 2 second steps of FAT modified times
+pub(crate) const MODIFY_WINDOW: Duration = Duration::from_secs(2);
+
+/// Characters FAT doesn't allow in names, besides control characters
+const INVALID: &[u8] = b"<>:\"\\|?*";
+
+/// The name `name` is stored under on FAT, with invalid characters and the trailing dots and
+/// spaces FAT drops replaced by underscores
+pub(crate) fn sanitize(name: &OsStr) -> Cow<'_, OsStr> {
+    let bytes = name.as_encoded_bytes();
+    let kept = bytes
+        .iter()
+        .rposition(|x| !matches!(x, b'.' | b' '))
+        .map_or(0, |x| x + 1);
+    let invalid = |x: &u8| *x < 0x20 || INVALID.contains(x);
+    if kept == bytes.len() && !bytes.iter().any(invalid) {
+        return Cow::Borrowed(name);
+    }
+    let mut out = bytes
+        .iter()
+        .map(|x| if invalid(x) { b'_' } else { *x })
+        .collect::<Vec<_>>();
+    out[kept..].fill(b'_');
+    // Only ASCII bytes were replaced, so the bytes are still a valid encoding
+    Cow::Owned(unsafe { OsString::from_encoded_bytes_unchecked(out) })
+}
+
+/// The names among the entries `names` of a directory that FAT would store under the same name
+/// as another of them. Names FAT stores unchanged win over those it doesn't, then the first in
+/// order, so the same one is kept on every run.
+pub(crate) fn collisions<'a>(names: impl IntoIterator<Item = &'a OsStr>) -> HashSet<OsString> {
+    let mut names = names.into_iter().collect::<Vec<_>>();
+    names.sort_by_key(|x| (matches!(sanitize(x), Cow::Owned(_)), *x));
+    let mut stored = HashSet::new();
+    names
+        .into_iter()
+        .filter(|x| !stored.insert(sanitize(x)))
+        .map(OsStr::to_os_string)
+        .collect()
+}
+
+impl Synchronize {
+    /// The name the source entry `name` has in the destination
+    pub(crate) fn dest_name<'a>(&self, name: &'a OsStr) -> Cow<'a, OsStr> {
+        match self.fat {
+            true => sanitize(name),
+            false => Cow::Borrowed(name),
+        }
+    }
+
+    /// The names of the entries of the source directory `dir` by the name each has in a FAT
+    /// destination, so destination entries can be matched to their source
+    pub(crate) fn source_names(&self, dir: &Path) -> HashMap<OsString, OsString> {
+        let mut names = HashMap::new();
+        if !self.fat {
+            return names;
+        }
+        let entries = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|x| x.file_name())
+            .collect::<Vec<_>>();
+        let collisions = collisions(entries.iter().map(OsString::as_os_str));
+        for name in entries.into_iter().filter(|x| !collisions.contains(x)) {
+            if let Cow::Owned(sanitized) = sanitize(&name) {
+                names.insert(sanitized, name);
+            }
+        }
+        names
+    }
+
+    /// Drop the `entries` of the source directory `dir` that a FAT destination would store
+    /// under the name of another, warning about each, rather than overwrite one with the other
+    pub(crate) fn skip_collisions<T>(
+        &self,
+        dir: &Path,
+        entries: &mut Vec<T>,
+        name: impl Fn(&T) -> Option<&OsStr>,
+    ) {
+        if !self.fat {
+            return;
+        }
+        let collisions = collisions(entries.iter().filter_map(&name));
+        entries.retain(|entry| match name(entry) {
+            Some(name) if collisions.contains(name) => {
+                self.progress.println(format!(
+                    "Skipping {:?}: its name on FAT is the same as another's",
+                    dir.join(name)
+                ));
+                self.progress.on_skipped(1);
+                false
+            }
+            _ => true,
+        });
+    }
+
+    /// Whether the symlink `src` is skipped as a FAT destination can't store it, with a
+    /// warning when it is
+    pub(crate) fn skips_symlink(&self, src: &Path) -> bool {
+        if self.fat {
+            self.progress.println(format!(
+                "Skipping symlink {:?}: FAT file systems can't store symlinks",
+                src
+            ));
+            self.progress.on_skipped(1);
+        }
+        self.fat
+    }
+}
+
+/// Whether `path`, or the closest of its parents that exists, is on a FAT or exFAT file system
+#[cfg(target_os = "linux")]
+pub(crate) fn is_fat(path: &Path) -> bool {
+    const MSDOS_SUPER_MAGIC: u32 = 0x4d44;
+    const EXFAT_SUPER_MAGIC: u32 = 0x2011_bab0;
+    let Some(stat) = statfs(path) else {
+        return false;
+    };
+    [MSDOS_SUPER_MAGIC, EXFAT_SUPER_MAGIC].contains(&(stat.f_type as u32))
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn is_fat(path: &Path) -> bool {
+    let Some(stat) = statfs(path) else {
+        return false;
+    };
+    let name = unsafe { std::ffi::CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    matches!(name.to_bytes(), b"msdos" | b"exfat")
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn is_fat(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn statfs(path: &Path) -> Option<libc::statfs> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let existing = path
+        .ancestors()
+        .map(|x| match x.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => x,
+        })
+        .find(|x| x.exists())?;
+    let path = std::ffi::CString::new(existing.as_os_str().as_bytes()).ok()?;
+    let mut stat = unsafe { std::mem::zeroed::<libc::statfs>() };
+    match unsafe { libc::statfs(path.as_ptr(), &mut stat) } {
+        0 => Some(stat),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{collisions, sanitize};
+    use crate::{temp_fs, Synchronize};
+    use std::ffi::OsStr;
+
+    #[test]
+    fn test_sanitize() {
+        let name = |x: &str| sanitize(OsStr::new(x)).into_owned();
+        assert_eq!(name("photo.jpg"), "photo.jpg");
+        assert_eq!(name("a:b?c*.txt"), "a_b_c_.txt");
+        assert_eq!(name("dots.. "), "dots___");
+        assert_eq!(name("tab\there"), "tab_here");
+        assert_eq!(name(".hidden"), ".hidden");
+
+        let names = ["a*", "a_", "a?", "b?", "b*"].map(OsStr::new);
+        let mut collided = collisions(names).into_iter().collect::<Vec<_>>();
+        collided.sort();
+        assert_eq!(collided, ["a*", "a?", "b?"]);
+    }
+
+    // Windows doesn't allow these names in the source either
+    #[cfg(unix)]
+    #[test]
+    fn test_fat_destinations() {
+        let temp = temp_fs!(input / keep: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        std::fs::write(input.join("what?.text"), b"data").unwrap();
+        std::os::unix::fs::symlink("keep.text", input.join("link")).unwrap();
+        let sync = || {
+            Synchronize::new(&input, &output)
+                .fat(true)
+                .delete(true)
+                .delete_timing(crate::DeleteTiming::After)
+                .sync()
+                .unwrap()
+        };
+        sync();
+        assert_eq!(std::fs::read(output.join("what_.text")).unwrap(), b"data");
+        assert!(output.join("link").symlink_metadata().is_err());
+
+        // The renamed file isn't deleted as extra by the next sync
+        sync();
+        assert!(output.join("what_.text").exists());
+
+        // Of two names stored the same, only the first is synced and it isn't deleted later
+        std::fs::write(input.join("what*.text"), b"other").unwrap();
+        let report = sync();
+        assert_eq!((report.copied, report.deleted), (1, 0));
+        let report = sync();
+        assert_eq!((report.copied, report.deleted), (0, 0));
+        assert_eq!(std::fs::read(output.join("what_.text")).unwrap(), b"other");
+    }
+}
@@ -0,0 +1,308 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use regex::Regex;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Include and exclude rules applied to paths relative to the source root
+#[derive(Debug, Default)]
+pub(crate) struct Filter {
+    include: Rules,
+    exclude: Rules,
+    regexes: Vec<Regex>,
+    ignore_files: Option<IgnoreFiles>,
+    protect: Rules,
+}
+
+impl Filter {
+    pub(crate) fn new(
+        include: &[String],
+        exclude: &[String],
+        regexes: &[Regex],
+    ) -> crate::Result<Self> {
+        Ok(Self {
+            include: Rules::new(include)?,
+            exclude: Rules::new(exclude)?,
+            regexes: regexes.to_vec(),
+            ignore_files: None,
+            protect: Rules::default(),
+        })
+    }
+
+    /// Never delete destination paths matching these patterns, or anything below them. A
+    /// trailing `/` is allowed for directories.
+    pub(crate) fn protect(mut self, patterns: &[String]) -> crate::Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|x| x.strip_suffix('/').unwrap_or(x).to_string())
+            .collect::<Vec<_>>();
+        self.protect = Rules::new(&patterns)?;
+        Ok(self)
+    }
+
+    /// Whether `rel` inside the destination, or a directory it is in, is protected
+    pub(crate) fn is_protected(&self, rel: &Path) -> bool {
+        !self.protect.is_empty()
+            && rel
+                .ancestors()
+                .take_while(|x| !x.as_os_str().is_empty())
+                .any(|x| self.protect.is_match(x))
+    }
+
+    /// Also skip paths ignored by `.fsyncignore` files in the source. With `gitignore` set,
+    /// `.gitignore` files, `.git/info/exclude` and the global git excludes file apply too.
+    pub(crate) fn ignore_files(mut self, root: &Path, gitignore: bool) -> Self {
+        let mut names = vec![".fsyncignore"];
+        let mut global = Vec::new();
+        if gitignore {
+            names.push(".gitignore");
+            global.push(GitignoreBuilder::new(root).build_global().0);
+            let mut exclude = GitignoreBuilder::new(root);
+            if exclude.add(root.join(".git/info/exclude")).is_none() {
+                global.extend(exclude.build().ok());
+            }
+        }
+        self.ignore_files = Some(IgnoreFiles {
+            root: root.to_path_buf(),
+            names,
+            global,
+            dirs: Mutex::default(),
+        });
+        self
+    }
+
+    /// Whether `path` inside the source is ignored by an ignore file
+    pub(crate) fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        match &self.ignore_files {
+            Some(ignore_files) => ignore_files.is_ignored(path, is_dir),
+            None => false,
+        }
+    }
+
+    /// Paths matching an exclude rule or regex are skipped. When include rules are given, files
+    /// matching none of them are skipped too, while directories are still descended into.
+    pub(crate) fn is_excluded(&self, rel: &Path, is_dir: bool) -> bool {
+        if self.exclude.is_match(rel) {
+            return true;
+        }
+        if !self.regexes.is_empty() {
+            let rel = rel.to_string_lossy();
+            if self.regexes.iter().any(|regex| regex.is_match(&rel)) {
+                return true;
+            }
+        }
+        !is_dir && !self.include.is_empty() && !self.include.is_match(rel)
+    }
+}
+
+/// Ignore files loaded lazily for each source directory, where rules in deeper directories
+/// take precedence over their parents
+#[derive(Debug)]
+struct IgnoreFiles {
+    root: PathBuf,
+    names: Vec<&'static str>,
+    global: Vec<Gitignore>,
+    dirs: Mutex<HashMap<PathBuf, Option<Arc<Gitignore>>>>,
+}
+
+impl IgnoreFiles {
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let dirs = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.root))
+            .filter_map(|dir| self.load(dir))
+            .collect::<Vec<_>>();
+        let matchers = dirs.iter().map(|x| x.as_ref()).chain(self.global.iter());
+        for gitignore in matchers {
+            match gitignore.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+
+    fn load(&self, dir: &Path) -> Option<Arc<Gitignore>> {
+        if let Some(gitignore) = self.dirs.lock().unwrap().get(dir) {
+            return gitignore.clone();
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        let mut found = false;
+        for name in self.names.iter() {
+            let path = dir.join(name);
+            if path.is_file() {
+                // Invalid lines are skipped like git does
+                builder.add(path);
+                found = true;
+            }
+        }
+        let gitignore = match found {
+            true => builder.build().ok().map(Arc::new),
+            false => None,
+        };
+        self.dirs
+            .lock()
+            .unwrap()
+            .insert(dir.to_path_buf(), gitignore.clone());
+        gitignore
+    }
+}
+
+/// Patterns containing a `/` match the whole relative path, others match the file name at any
+/// depth. A trailing `/**` also matches the directory itself so it is never descended into.
+#[derive(Debug)]
+struct Rules {
+    name: GlobSet,
+    path: GlobSet,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            name: GlobSet::empty(),
+            path: GlobSet::empty(),
+        }
+    }
+}
+
+impl Rules {
+    fn new(patterns: &[String]) -> crate::Result<Self> {
+        let mut name = GlobSetBuilder::new();
+        let mut path = GlobSetBuilder::new();
+        for pattern in patterns {
+            let anchored = pattern.trim_start_matches('/');
+            if !anchored.contains('/') && anchored.len() == pattern.len() {
+                name.add(Glob::new(pattern)?);
+                continue;
+            }
+            path.add(Glob::new(anchored)?);
+            if let Some(dir) = anchored.strip_suffix("/**") {
+                path.add(Glob::new(dir)?);
+            }
+        }
+        Ok(Self {
+            name: name.build()?,
+            path: path.build()?,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.name.is_empty() && self.path.is_empty()
+    }
+
+    fn is_match(&self, rel: &Path) -> bool {
+        self.path.is_match(rel)
+            || rel
+                .file_name()
+                .map(|name| self.name.is_match(name))
+                .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Filter;
+    use regex::Regex;
+    use std::path::Path;
+
+    #[test]
+    fn test_glob_rules() {
+        let filter = Filter::new(
+            &[],
+            &[
+                "*.o".to_string(),
+                "target/**".to_string(),
+                "/root".to_string(),
+            ],
+            &[],
+        )
+        .unwrap();
+        assert!(filter.is_excluded(Path::new("a/b.o"), false));
+        assert!(filter.is_excluded(Path::new("target"), true));
+        assert!(!filter.is_excluded(Path::new("a/target"), true));
+        assert!(filter.is_excluded(Path::new("root"), true));
+        assert!(!filter.is_excluded(Path::new("a/root"), true));
+        assert!(!filter.is_excluded(Path::new("a/b.rs"), false));
+
+        let filter = Filter::new(&["*.rs".to_string()], &["build.rs".to_string()], &[]).unwrap();
+        assert!(!filter.is_excluded(Path::new("src"), true));
+        assert!(!filter.is_excluded(Path::new("src/lib.rs"), false));
+        assert!(filter.is_excluded(Path::new("README.md"), false));
+        assert!(filter.is_excluded(Path::new("build.rs"), false));
+    }
+
+    #[test]
+    fn test_protect_rules() {
+        let filter = Filter::new(&[], &[], &[])
+            .unwrap()
+            .protect(&["logs/**".to_string(), ".snapshots/".to_string()])
+            .unwrap();
+        assert!(filter.is_protected(Path::new("logs")));
+        assert!(filter.is_protected(Path::new("logs/a/b.log")));
+        assert!(filter.is_protected(Path::new("a/.snapshots/1")));
+        assert!(!filter.is_protected(Path::new("a/logs")));
+        assert!(!filter.is_protected(Path::new("")));
+    }
+
+    #[test]
+    fn test_regex_rules() {
+        let regex = Regex::new(r"(^|/)\.cache(/|$)|\.tmp$").unwrap();
+        let filter = Filter::new(&[], &[], &[regex]).unwrap();
+        assert!(filter.is_excluded(Path::new(".cache"), true));
+        assert!(filter.is_excluded(Path::new("a/.cache"), true));
+        assert!(filter.is_excluded(Path::new("a/b.tmp"), false));
+        assert!(!filter.is_excluded(Path::new("a/b.tmp.rs"), false));
+    }
+
+    #[test]
+    fn test_gitignore_rules() {
+        let temp = crate::temp_fs!(
+            keep: 0,
+            build / out: 0,
+            sub / keep: 0,
+            sub / skip: 0,
+        );
+        std::fs::write(
+            temp.path().join(".gitignore"),
+            "build/\n*.text\n!keep.text\n",
+        )
+        .unwrap();
+        std::fs::write(temp.path().join("sub/.gitignore"), "keep.text\n").unwrap();
+        let filter = Filter::new(&[], &[], &[])
+            .unwrap()
+            .ignore_files(temp.path(), true);
+        let ignored = |path: &str, is_dir| filter.is_ignored(&temp.path().join(path), is_dir);
+        assert!(ignored("build", true));
+        assert!(!ignored("keep.text", false));
+        assert!(ignored("sub/skip.text", false));
+        assert!(ignored("sub/keep.text", false));
+        assert!(!ignored("sub", true));
+    }
+
+    #[test]
+    fn test_fsyncignore_rules() {
+        let temp = crate::temp_fs!(
+            keep: 0,
+            sub / skip: 0,
+            sub / deeper / keep: 0,
+        );
+        std::fs::write(temp.path().join(".fsyncignore"), "*.text\n!keep.text\n").unwrap();
+        std::fs::write(temp.path().join("sub/deeper/.fsyncignore"), "keep.text\n").unwrap();
+        std::fs::write(temp.path().join(".gitignore"), "keep.text\n").unwrap();
+        let filter = Filter::new(&[], &[], &[])
+            .unwrap()
+            .ignore_files(temp.path(), false);
+        let ignored = |path: &str, is_dir| filter.is_ignored(&temp.path().join(path), is_dir);
+        assert!(!ignored("keep.text", false));
+        assert!(ignored("sub/skip.text", false));
+        assert!(ignored("sub/deeper/keep.text", false));
+    }
+}
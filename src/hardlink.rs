@@ -0,0 +1,202 @@
+//! Recreating hardlinks between source files at the destination instead of copying the data of
+//! each link
+
+use crate::{
+    backend::Meta, copy, ChangeKind, CopyMethod, Error, ProgressReporter, Result, Synchronize,
+};
+use std::{
+    collections::HashMap,
+    fs::{self, File, Metadata},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Device and inode of a source file
+type Key = (u64, u64);
+
+/// Destination of the first link to a source file that was synced
+type Slot = Arc<Mutex<Option<PathBuf>>>;
+
+/// Source files with more than one link seen so far, see [`Synchronize::hard_links`]
+#[derive(Debug, Default)]
+pub(crate) struct HardLinks(Mutex<HashMap<Key, Slot>>);
+
+impl HardLinks {
+    fn slot(&self, meta: &Metadata) -> Option<Slot> {
+        let key = linked_id(meta)?;
+        Some(self.0.lock().unwrap().entry(key).or_default().clone())
+    }
+}
+
+impl Synchronize {
+    /// Sync the source file `src` as a hardlink to where another link to it was synced, or
+    /// with `sync` when it's the first one
+    pub(crate) fn sync_hard_link(
+        &self,
+        src: &Path,
+        meta: &Metadata,
+        dest: &Path,
+        sync: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        let Some(slot) = self.hard_links.as_ref().and_then(|x| x.slot(meta)) else {
+            return sync();
+        };
+        // Held while the first link is synced, so the others wait until it's in place
+        let mut first = slot.lock().unwrap();
+        // A watch may have deleted the first link since
+        match first.as_ref().filter(|x| self.dry_run || x.exists()) {
+            Some(target) => self.link_file(src, target, dest),
+            None => {
+                sync()?;
+                *first = Some(dest.to_path_buf());
+                Ok(())
+            }
+        }
+    }
+
+    /// File in one of the [`Synchronize::link_dest`] directories matching the source file
+    /// `src`, which `dest` can be linked to instead of copying it
+    pub(crate) fn find_link_dest(
+        &self,
+        src: &Path,
+        meta: &Metadata,
+        dest: &Path,
+    ) -> Result<Option<PathBuf>> {
+        let rel = dest.strip_prefix(&self.dest).unwrap_or(dest);
+        for dir in self.link_dest.iter() {
+            let candidate = self.dest.join(dir).join(rel);
+            let Ok(other) = candidate.symlink_metadata() else {
+                continue;
+            };
+            // A link shares the permissions too, so they have to match as well
+            if !other.is_file()
+                || !self.is_equal(&Meta::from_local(meta), &Meta::from_local(&other))
+                || !self.skip_permissions && other.permissions() != meta.permissions()
+            {
+                continue;
+            }
+            if let Some(check) = self.check_content {
+                if !check.equal(&mut self.open_source(src)?, &mut File::open(&candidate)?)? {
+                    continue;
+                }
+            }
+            return Ok(Some(candidate));
+        }
+        Ok(None)
+    }
+
+    /// Make `dest` a hardlink to `target`, a destination file already matching the source
+    /// file `src`
+    pub(crate) fn link_file(&self, src: &Path, target: &Path, dest: &Path) -> Result<()> {
+        let dest_meta = dest.symlink_metadata().ok();
+        let target_meta = fs::metadata(target).map_err(|e| Error::with_path(e, target))?;
+        let linked = match &dest_meta {
+            Some(a) => linked_id(a).is_some() && linked_id(a) == linked_id(&target_meta),
+            None => false,
+        };
+        let differences = self.differences(
+            &Meta::from_local(&target_meta),
+            dest_meta.as_ref().map(Meta::from_local).as_ref(),
+        );
+        if linked {
+            self.progress.on_skipped(1);
+            return self.remove_source(src, None, dest, |x| fs::remove_file(x));
+        }
+        if self.dry_run {
+            self.report_dry_run("hardlink", dest);
+            self.progress.on_copied(1);
+            self.progress
+                .changed(ChangeKind::Hardlink, dest, 0, differences);
+            return self.remove_source(src, None, dest, |x| fs::remove_file(x));
+        }
+        if dest_meta.is_some() && self.backup {
+            self.backup_file(dest)?;
+        }
+        copy::hardlink(target, dest).map_err(|source| Error::CopyFailed {
+            src: src.to_path_buf(),
+            dest: dest.to_path_buf(),
+            source,
+        })?;
+        self.progress.on_copied(1);
+        self.progress.on_method(CopyMethod::Hardlink);
+        self.progress
+            .changed(ChangeKind::Hardlink, dest, 0, differences);
+        match dest_meta {
+            Some(_) => self.progress.on_updated(1),
+            None => self.progress.on_created(1),
+        }
+        self.remove_source(src, None, dest, |x| fs::remove_file(x))
+    }
+}
+
+/// Device and inode of a file with more than one link
+#[cfg(unix)]
+fn linked_id(meta: &Metadata) -> Option<Key> {
+    use std::os::unix::fs::MetadataExt;
+    (meta.nlink() > 1).then(|| (meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn linked_id(_meta: &Metadata) -> Option<Key> {
+    None
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::{temp_fs, Synchronize};
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn test_hard_links_are_preserved() {
+        let temp = temp_fs!(input / a / one: 4, input / two: 8);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        std::fs::hard_link(input.join("a/one.text"), input.join("link.text")).unwrap();
+        std::fs::hard_link(input.join("a/one.text"), input.join("a/link.text")).unwrap();
+        let sync = || {
+            Synchronize::new(&input, &output)
+                .hard_links(true)
+                .sync()
+                .unwrap()
+        };
+        let report = sync();
+        assert_eq!(report.created, 4);
+        assert_eq!(report.bytes_copied, 12);
+
+        let inode = |path: &str| std::fs::metadata(output.join(path)).unwrap().ino();
+        assert_eq!(inode("a/one.text"), inode("link.text"));
+        assert_eq!(inode("a/one.text"), inode("a/link.text"));
+        assert_ne!(inode("a/one.text"), inode("two.text"));
+        assert_eq!(
+            std::fs::metadata(output.join("link.text")).unwrap().nlink(),
+            3
+        );
+
+        // Links already in place are left alone
+        let report = sync();
+        assert_eq!((report.copied, report.skipped), (0, 6));
+    }
+
+    #[test]
+    fn test_link_dest_links_unchanged_files() {
+        let temp = temp_fs!(input / same: 4, input / changed: 8);
+        let input = temp.path().join("input");
+        let sync = |dest: &str| {
+            Synchronize::new(&input, temp.path().join(dest))
+                .link_dest("../first")
+                .sync()
+                .unwrap()
+        };
+        sync("first");
+        std::fs::write(input.join("changed.text"), b"changed").unwrap();
+        let report = sync("second");
+        assert_eq!((report.created, report.bytes_copied), (2, 7));
+
+        let inode = |path: &str| std::fs::metadata(temp.path().join(path)).unwrap().ino();
+        assert_eq!(inode("first/same.text"), inode("second/same.text"));
+        assert_ne!(inode("first/changed.text"), inode("second/changed.text"));
+        assert_eq!(
+            std::fs::read(temp.path().join("first/changed.text")).unwrap(),
+            [b'a'; 8]
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! Commands run before and after a sync, such as mounting the destination or invalidating a
+//! cache, told about the sync through environment variables
+
+use crate::{webhook, Error, Result, SyncReport, Synchronize};
+use std::{
+    io,
+    process::{Command, Stdio},
+};
+
+impl Synchronize {
+    /// Run `run` between the pre and post hooks. A failing pre hook stops the sync before it
+    /// starts, while the post hook runs however the sync ended and fails it when the sync
+    /// didn't fail already.
+    pub(crate) fn with_hooks<T>(
+        mut self,
+        run: impl FnOnce(Self) -> Result<T>,
+        report: impl Fn(&T) -> Option<&SyncReport>,
+    ) -> Result<T> {
+        let (pre, post) = (self.pre_hook.take(), self.post_hook.take());
+        let env = vec![
+            ("FSYNC_SOURCE", self.src.to_string_lossy().into_owned()),
+            (
+                "FSYNC_DESTINATION",
+                self.dest.to_string_lossy().into_owned(),
+            ),
+            ("FSYNC_DRY_RUN", (self.dry_run as u8).to_string()),
+        ];
+        if let Some(command) = &pre {
+            run_hook("pre", command, &env)?;
+        }
+        let result = run(self);
+        let Some(command) = &post else {
+            return result;
+        };
+        let mut env = env;
+        match result.as_ref().map(&report) {
+            Ok(Some(report)) => {
+                env.push(("FSYNC_STATUS", webhook::status(Ok(report)).to_string()));
+                env.push(("FSYNC_COPIED", report.copied.to_string()));
+                env.push(("FSYNC_DELETED", report.deleted.to_string()));
+                env.push(("FSYNC_BYTES_COPIED", report.bytes_copied.to_string()));
+                env.push(("FSYNC_ERRORS", report.errors.len().to_string()));
+                env.push(("FSYNC_REPORT", report.to_json()));
+            }
+            Ok(None) => env.push(("FSYNC_STATUS", "finished".to_string())),
+            Err(e) => {
+                env.push(("FSYNC_STATUS", webhook::status(Err(e)).to_string()));
+                env.push(("FSYNC_ERROR", e.to_string()));
+            }
+        }
+        let hook = run_hook("post", command, &env);
+        result.and_then(|x| hook.map(|_| x))
+    }
+}
+
+/// Run `command` through the shell with `env` set, and `FSYNC_HOOK` set to `hook`. Its output
+/// goes to stderr, leaving stdout to what the sync prints.
+fn run_hook(hook: &'static str, command: &str, env: &[(&str, String)]) -> Result<()> {
+    #[cfg(windows)]
+    let mut shell = {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    };
+    #[cfg(not(windows))]
+    let mut shell = {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let status = shell
+        .arg(command)
+        .env("FSYNC_HOOK", hook)
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .stdin(Stdio::null())
+        .stdout(io::stderr())
+        .status()?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(Error::Hook {
+            hook,
+            command: command.to_string(),
+            status: status.to_string(),
+        }),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::{temp_fs, Error, Synchronize};
+
+    #[test]
+    fn test_hooks_run_around_the_sync() {
+        let temp = temp_fs!(input / a: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let log = temp.path().join("hooks.log");
+        let hook = |vars: &str| format!("echo \"$FSYNC_HOOK {}\" >> {:?}", vars, log);
+        Synchronize::new(&input, &output)
+            .pre_hook(Some(hook("$FSYNC_DRY_RUN")))
+            .post_hook(Some(hook("$FSYNC_STATUS $FSYNC_COPIED $FSYNC_ERRORS")))
+            .sync()
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "pre 0\npost finished 1 0\n"
+        );
+
+        // Nothing is synced after a failing pre hook, and a failing post hook fails the sync
+        let other = temp.path().join("other");
+        let result = Synchronize::new(&input, &other)
+            .pre_hook(Some("exit 3"))
+            .sync();
+        assert!(matches!(result, Err(Error::Hook { hook: "pre", .. })));
+        assert!(!other.exists());
+        let result = Synchronize::new(&input, &other)
+            .post_hook(Some("false"))
+            .sync();
+        assert!(matches!(result, Err(Error::Hook { hook: "post", .. })));
+        assert!(other.join("a.text").exists());
+    }
+}
@@ -0,0 +1,182 @@
+//! Helpers shared by the HTTP based backends
+
+use std::io;
+
+/// Percent encode everything but unreserved characters, and `/` unless `slash` is set
+pub(crate) fn percent_encode(value: &str, slash: bool) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(byte as char)
+            }
+            b'/' if !slash => out.push('/'),
+            _ => out += &format!("%{:02X}", byte),
+        }
+    }
+    out
+}
+
+pub(crate) fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+pub(crate) fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+/// Year, month and day for a count of days since the unix epoch, see Howard Hinnant's
+/// `civil_from_days`
+pub(crate) fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + (month <= 2) as i64, month, day)
+}
+
+/// Inverse of [`civil_from_days`]
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = year - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Seconds since the epoch for an RFC 1123 date such as `Sun, 06 Nov 1994 08:49:37 GMT`
+pub(crate) fn parse_http_date(value: &str) -> Option<i64> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let mut parts = value.split_whitespace().skip(1);
+    let day = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|x| *x == month)? as i64 + 1;
+    let year = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(|x| x.parse::<i64>().ok());
+    let (h, m, s) = (time.next()??, time.next()??, time.next()??);
+    Some(days_from_civil(year, month, day) * 86400 + h * 3600 + m * 60 + s)
+}
+
+/// Map HTTP failures to io errors, keeping 404 as `NotFound`. Throttling, server errors and
+/// transport failures get kinds that `--retries` retries. `message` pulls a readable message
+/// out of an error response body.
+pub(crate) fn call(
+    service: &str,
+    result: Result<ureq::Response, ureq::Error>,
+    message: impl FnOnce(&str) -> Option<String>,
+) -> io::Result<ureq::Response> {
+    match result {
+        Ok(response) => Ok(response),
+        Err(ureq::Error::Status(404, _)) => Err(io::ErrorKind::NotFound.into()),
+        Err(ureq::Error::Status(status, response)) => {
+            let kind = match status {
+                429 | 500 | 502 | 503 | 504 => io::ErrorKind::ResourceBusy,
+                _ => io::ErrorKind::Other,
+            };
+            let body = response.into_string().unwrap_or_default();
+            let message = message(&body).unwrap_or(body);
+            Err(io::Error::new(
+                kind,
+                format!("{} error {}: {}", service, status, message),
+            ))
+        }
+        Err(ureq::Error::Transport(transport)) => {
+            // Keep the kind of the underlying failure, such as a timeout or a reset connection
+            let kind = std::error::Error::source(&transport)
+                .and_then(|x| x.downcast_ref::<io::Error>())
+                .map(io::Error::kind)
+                .unwrap_or(match transport.kind() {
+                    ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed => {
+                        io::ErrorKind::NotConnected
+                    }
+                    ureq::ErrorKind::Io => io::ErrorKind::ConnectionAborted,
+                    _ => io::ErrorKind::Other,
+                });
+            Err(io::Error::new(kind, transport))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        base64, civil_from_days, days_from_civil, parse_http_date, percent_decode, percent_encode,
+    };
+
+    #[test]
+    fn test_encodings() {
+        assert_eq!(percent_encode("/a b/c+d", false), "/a%20b/c%2Bd");
+        assert_eq!(percent_decode("/a%20b/c%2Bd%"), "/a b/c+d%");
+        assert_eq!(base64(b"user:pass"), "dXNlcjpwYXNz");
+        assert_eq!(base64(b"ab"), "YWI=");
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let status = |code| {
+            let response = ureq::Response::new(code, "", "SlowDown").unwrap();
+            let result = super::call("S3", Err(ureq::Error::Status(code, response)), |_| None);
+            result.unwrap_err().kind()
+        };
+        assert_eq!(status(404), std::io::ErrorKind::NotFound);
+        assert_eq!(status(403), std::io::ErrorKind::Other);
+        assert_eq!(status(503), std::io::ErrorKind::ResourceBusy);
+        assert_eq!(status(429), std::io::ErrorKind::ResourceBusy);
+
+        let refused = super::call("S3", ureq::get("http://127.0.0.1:1/").call(), |_| None);
+        assert_eq!(
+            refused.unwrap_err().kind(),
+            std::io::ErrorKind::ConnectionRefused
+        );
+    }
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(civil_from_days(15849), (2013, 5, 24));
+        assert_eq!(days_from_civil(2013, 5, 24), 15849);
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784111777)
+        );
+    }
+}
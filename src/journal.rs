@@ -0,0 +1,118 @@
+//! Record of the directories a sync has finished, so an interrupted sync can resume without
+//! comparing their files again
+
+use crate::encoding::{os_bytes, unescape, write_escaped};
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+const HEADER: &str = "fsync-journal 1";
+
+/// Name of the journal kept in the destination when no path is configured
+pub(crate) const JOURNAL_FILE: &str = ".fsync-journal";
+
+#[derive(Debug)]
+pub(crate) struct Journal {
+    path: PathBuf,
+    done: HashSet<PathBuf>,
+    out: Mutex<File>,
+}
+
+impl Journal {
+    /// Start a journal at `path`, or with `resume` continue the one there when it was written
+    /// for a sync of `src`
+    pub(crate) fn open(path: &Path, src: &Path, resume: bool) -> io::Result<Self> {
+        let source = os_bytes(src.as_os_str());
+        let mut done = HashSet::new();
+        let mut resumed = false;
+        if let Some(file) = File::open(path).ok().filter(|_| resume) {
+            let mut lines = io::BufReader::new(file).split(b'\n');
+            let header = lines.next().transpose()?;
+            let journal_src = lines.next().transpose()?;
+            if header.as_deref() == Some(HEADER.as_bytes()) && journal_src == Some(source.clone()) {
+                resumed = true;
+                for line in lines {
+                    done.insert(PathBuf::from(unescape(&line?)));
+                }
+            }
+        }
+        let out = match resumed {
+            true => OpenOptions::new().append(true).open(path)?,
+            false => {
+                let mut out = File::create(path)?;
+                writeln!(out, "{}", HEADER)?;
+                out.write_all(&source)?;
+                out.write_all(b"\n")?;
+                out
+            }
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            done,
+            out: Mutex::new(out),
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the directory `rel` was finished by a previous, interrupted sync
+    pub(crate) fn is_done(&self, rel: &Path) -> bool {
+        self.done.contains(rel)
+    }
+
+    /// Mark the directory `rel` as finished, writing it out straight away so it survives the
+    /// process being killed
+    pub(crate) fn record(&self, rel: &Path) -> io::Result<()> {
+        let mut line = Vec::new();
+        write_escaped(&mut line, rel.as_os_str())?;
+        line.push(b'\n');
+        let mut out = self.out.lock().unwrap();
+        out.write_all(&line)?;
+        out.flush()
+    }
+
+    /// Delete the journal once the sync has finished
+    pub(crate) fn remove(&self) -> io::Result<()> {
+        fs::remove_file(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Journal;
+    use std::path::Path;
+
+    #[test]
+    fn test_journal_resumes_for_same_source() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("journal");
+        let journal = Journal::open(&path, Path::new("/src"), true).unwrap();
+        journal.record(Path::new("")).unwrap();
+        journal.record(Path::new("a/new\nline")).unwrap();
+        assert!(!journal.is_done(Path::new("")));
+        drop(journal);
+
+        let journal = Journal::open(&path, Path::new("/src"), true).unwrap();
+        assert!(journal.is_done(Path::new("")));
+        assert!(journal.is_done(Path::new("a/new\nline")));
+        assert!(!journal.is_done(Path::new("a")));
+        drop(journal);
+
+        let journal = Journal::open(&path, Path::new("/other"), true).unwrap();
+        assert!(!journal.is_done(Path::new("")));
+        journal.record(Path::new("")).unwrap();
+        drop(journal);
+
+        // Without resuming the previous journal is discarded
+        let journal = Journal::open(&path, Path::new("/other"), false).unwrap();
+        assert!(!journal.is_done(Path::new("")));
+        journal.remove().unwrap();
+        assert!(!path.exists());
+    }
+}
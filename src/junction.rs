@@ -0,0 +1,222 @@
+//! NTFS junctions, the directory links Windows had before symlinks, which are reparse points
+//! of their own that std only sees as symlinks
+
+use crate::{Error, ProgressReporter, Synchronize};
+use std::{io, path::Path, str::FromStr};
+
+/// What happens to junctions in the source, which only exist on Windows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Junctions {
+    /// Create a junction to the same directory in a local destination, and a directory
+    /// symlink elsewhere
+    #[default]
+    Recreate,
+    /// Sync the directory it points to in its place
+    Follow,
+    /// Leave them out with a warning
+    Skip,
+}
+
+impl FromStr for Junctions {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "recreate" => Ok(Junctions::Recreate),
+            "follow" => Ok(Junctions::Follow),
+            "skip" => Ok(Junctions::Skip),
+            _ => Err(Error::Unsupported(format!(
+                "Unknown junction handling {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+impl Synchronize {
+    /// Whether the source symlink `path` is walked as what it points to, with
+    /// [`Synchronize::copy_links`] or as a junction followed by [`Synchronize::junctions`]
+    pub(crate) fn follows(&self, path: &Path) -> bool {
+        self.copy_links || (self.junctions == Junctions::Follow && is_junction(path))
+    }
+
+    /// Whether the source junction `src` is skipped by [`Synchronize::junctions`], with a
+    /// warning when it is
+    pub(crate) fn skips_junction(&self, src: &Path) -> bool {
+        let skipped = self.junctions == Junctions::Skip;
+        if skipped {
+            self.progress
+                .println(format!("Skipping junction {:?}", src));
+            self.progress.on_skipped(1);
+        }
+        skipped
+    }
+}
+
+/// Reparse tag of junctions, which Windows calls mount points
+#[cfg(windows)]
+const IO_REPARSE_TAG_MOUNT_POINT: u32 = 0xa000_0003;
+
+#[cfg(windows)]
+fn open_reparse_point(path: &Path, write: bool) -> io::Result<std::fs::File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    use windows_sys::Win32::Storage::FileSystem::{
+        FILE_FLAG_BACKUP_SEMANTICS, FILE_FLAG_OPEN_REPARSE_POINT,
+    };
+
+    let mut options = std::fs::OpenOptions::new();
+    match write {
+        true => options.write(true),
+        false => options.access_mode(0),
+    };
+    // Directories need backup semantics to be opened at all
+    options
+        .custom_flags(FILE_FLAG_OPEN_REPARSE_POINT | FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)
+}
+
+/// Whether `path` is a junction, rather than a symlink or anything else
+#[cfg(windows)]
+pub(crate) fn is_junction(path: &Path) -> bool {
+    use std::os::windows::{fs::MetadataExt, io::AsRawHandle};
+    use windows_sys::Win32::Storage::FileSystem::{
+        FileAttributeTagInfo, GetFileInformationByHandleEx, FILE_ATTRIBUTE_REPARSE_POINT,
+        FILE_ATTRIBUTE_TAG_INFO,
+    };
+
+    // Most files aren't reparse points, which their attributes already tell
+    let is_reparse_point = path
+        .symlink_metadata()
+        .is_ok_and(|x| x.file_attributes() & FILE_ATTRIBUTE_REPARSE_POINT != 0);
+    if !is_reparse_point {
+        return false;
+    }
+    let Ok(file) = open_reparse_point(path, false) else {
+        return false;
+    };
+    let mut info = unsafe { std::mem::zeroed::<FILE_ATTRIBUTE_TAG_INFO>() };
+    let result = unsafe {
+        GetFileInformationByHandleEx(
+            file.as_raw_handle() as _,
+            FileAttributeTagInfo,
+            (&mut info as *mut FILE_ATTRIBUTE_TAG_INFO).cast(),
+            std::mem::size_of::<FILE_ATTRIBUTE_TAG_INFO>() as u32,
+        )
+    };
+    result != 0 && info.ReparseTag == IO_REPARSE_TAG_MOUNT_POINT
+}
+
+#[cfg(not(windows))]
+pub(crate) fn is_junction(_path: &Path) -> bool {
+    false
+}
+
+/// Create a junction at `path` to the directory `target`, which is taken from where the
+/// junction goes when relative, as junctions only hold absolute paths
+#[cfg(windows)]
+pub(crate) fn create(target: &Path, path: &Path) -> io::Result<()> {
+    use std::os::windows::{ffi::OsStrExt, io::AsRawHandle};
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    const FSCTL_SET_REPARSE_POINT: u32 = 0x0009_00a4;
+
+    let target = std::path::absolute(path.parent().unwrap_or(Path::new("")).join(target))?;
+    let print = target.as_os_str().encode_wide().collect::<Vec<u16>>();
+    let substitute = std::ffi::OsStr::new(r"\??\")
+        .encode_wide()
+        .chain(print.iter().copied())
+        .collect::<Vec<u16>>();
+    // Both names end in a nul their lengths leave out
+    let names = [&substitute[..], &[0], &print[..], &[0]].concat();
+    let bytes = |len: usize| (len * 2) as u16;
+
+    // The mount point form of REPARSE_DATA_BUFFER
+    let mut buffer = IO_REPARSE_TAG_MOUNT_POINT.to_le_bytes().to_vec();
+    let header = [
+        8 + bytes(names.len()),
+        0,
+        0,
+        bytes(substitute.len()),
+        bytes(substitute.len() + 1),
+        bytes(print.len()),
+    ];
+    buffer.extend(header.iter().flat_map(|x| x.to_le_bytes()));
+    buffer.extend(names.iter().flat_map(|x| x.to_le_bytes()));
+
+    std::fs::create_dir(path)?;
+    let result = open_reparse_point(path, true).and_then(|file| {
+        let mut returned = 0;
+        let result = unsafe {
+            DeviceIoControl(
+                file.as_raw_handle() as _,
+                FSCTL_SET_REPARSE_POINT,
+                buffer.as_ptr().cast(),
+                buffer.len() as u32,
+                std::ptr::null_mut(),
+                0,
+                &mut returned,
+                std::ptr::null_mut(),
+            )
+        };
+        match result {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_dir(path);
+    }
+    result
+}
+
+#[cfg(not(windows))]
+pub(crate) fn create(_target: &Path, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "junctions are only available on Windows",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Junctions;
+
+    #[test]
+    fn test_parse_junctions() {
+        assert_eq!("follow".parse::<Junctions>().unwrap(), Junctions::Follow);
+        assert!("mount".parse::<Junctions>().is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_junctions() {
+        use super::{create, is_junction};
+        use crate::{temp_fs, Synchronize};
+
+        let temp = temp_fs!(input / a: 4, target / b: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        create(&temp.path().join("target"), &input.join("junction")).unwrap();
+        assert!(is_junction(&input.join("junction")));
+        assert!(!is_junction(&input.join("a.text")));
+
+        let sync = |junctions| {
+            Synchronize::new(&input, &output)
+                .junctions(junctions)
+                .sync()
+                .unwrap()
+        };
+        sync(Junctions::Recreate);
+        assert!(is_junction(&output.join("junction")));
+        assert!(output.join("junction\\b.text").is_file());
+
+        std::fs::remove_dir(output.join("junction")).unwrap();
+        sync(Junctions::Follow);
+        assert!(!is_junction(&output.join("junction")));
+        assert!(output.join("junction\\b.text").is_file());
+
+        std::fs::remove_dir_all(&output).unwrap();
+        sync(Junctions::Skip);
+        assert!(output.join("a.text").is_file());
+        assert!(output.join("junction").symlink_metadata().is_err());
+    }
+}
@@ -1,25 +1,101 @@
+/// Emit a `tracing` event when built with the tracing feature, as in `trace!(debug, "message")`
+macro_rules! trace {
+    ($level:ident, $($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)*);
+    };
+}
+
+pub mod backend;
+#[cfg(feature = "progress-bars")]
+mod bars;
+mod bisync;
+mod bitrot;
+mod cache;
+mod cancel;
+mod checksum;
+mod chmod;
+mod compare;
+mod copy;
+pub mod daemon;
+mod delete;
+mod delta;
+mod encoding;
+mod error;
+mod fat;
+mod filter;
+mod hardlink;
+mod hooks;
+mod http;
+mod journal;
+mod junction;
+mod links;
+mod manifest;
+pub mod merkle;
+pub mod metrics;
+mod owner;
+mod pipeline;
+pub mod profile;
+mod progress;
+pub mod remote;
+mod rename;
+mod retry;
+mod s3;
+mod sftp;
+mod snapshot;
+mod special;
+#[cfg(target_os = "linux")]
+mod uring;
+mod watch;
+mod webdav;
+mod webhook;
+mod xattr;
+mod xml;
+
+pub use bisync::ConflictPolicy;
+pub use cancel::CancelToken;
+pub use checksum::ContentCheck;
+pub use chmod::Chmod;
+pub use compare::{Comparison, Mismatch, Reason};
+pub use copy::{CopyEngine, CopyMethod, PreserveTimes};
+pub use delete::{DeleteLimit, DeleteTiming};
+pub use error::{Error, Result};
+pub use junction::Junctions;
+pub use links::{AbsoluteLinks, DanglingLinks};
+pub use metrics::Metrics;
+pub use owner::IdMap;
+pub use pipeline::SchedulingPolicy;
+pub use progress::{Change, ChangeKind, Differences, OutputFormat, ProgressReporter};
+pub use snapshot::Retention;
+
+use backend::{Backend, Kind, Local, Meta};
+use cache::ChecksumCache;
+use filetime::FileTime;
+use filter::Filter;
+use hardlink::HardLinks;
+use journal::Journal;
 use jwalk::DirEntry;
+use pipeline::{Copiers, DirWork, FileJob};
 use rayon::ThreadPool;
+use regex::Regex;
+use remote::Remote;
+use sftp::Sftp;
 use std::{
     borrow::Borrow,
+    cmp::Reverse,
     collections::HashSet,
+    ffi::OsStr,
     fs::{self, Metadata},
-    io::{self, Read},
+    io::{self, IsTerminal, Write},
     ops::Sub,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc, Mutex,
     },
     time::Duration,
 };
 
-#[cfg(unix)]
-use std::os::unix::fs::symlink;
-
-#[cfg(windows)]
-use std::os::windows::fs::symlink_file as symlink;
-
 pub struct Synchronize {
     src: PathBuf,
     dest: PathBuf,
@@ -28,21 +104,105 @@ pub struct Synchronize {
     num_threads: Option<u8>,
     skip_hidden: bool,
     display_progress: bool,
-    check_content: bool,
+    check_content: Option<ContentCheck>,
     skip_permissions: bool,
+    modify_window: Duration,
+    preserve_times: PreserveTimes,
+    noatime: bool,
+    size_only: bool,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    min_age: Option<Duration>,
+    max_age: Option<Duration>,
+    max_depth: Option<usize>,
+    copy_links: bool,
+    absolute_links: AbsoluteLinks,
+    dangling_links: DanglingLinks,
+    junctions: Junctions,
+    specials: bool,
+    devices: bool,
+    fail_on_unsupported: bool,
+    one_file_system: bool,
+    /// Device of the source root, for one_file_system
+    src_dev: Option<u64>,
+    update: bool,
+    ignore_existing: bool,
+    existing: bool,
+    fat: bool,
+    require_symlink_target: bool,
+    sendfile: bool,
+    merkle_tree: Option<PathBuf>,
+    keep_partial: bool,
+    copy_strategy: Vec<CopyMethod>,
+    copy_engine: CopyEngine,
+    chunk_threshold: Option<u64>,
+    scheduling: SchedulingPolicy,
+    preserve_attributes: bool,
+    store_checksums: bool,
+    xattrs: bool,
+    acls: bool,
+    owner: bool,
+    group: bool,
+    usermap: Option<IdMap>,
+    groupmap: Option<IdMap>,
+    chmod: Chmod,
+    dry_run: bool,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    filter_regex: Vec<Regex>,
+    respect_gitignore: bool,
+    filter: Filter,
+    checksum_cache_path: Option<PathBuf>,
+    checksum_cache: Option<ChecksumCache>,
+    delta: bool,
+    remote_shell: String,
+    remote_command: String,
+    backend: Option<Box<dyn Backend>>,
+    ignore_errors: bool,
+    retries: u32,
+    retry_delay: Duration,
+    bidirectional: bool,
+    state_file: Option<PathBuf>,
+    conflict_policy: ConflictPolicy,
+    cancel: CancelToken,
+    resume: bool,
+    journal: Option<Journal>,
+    atomic_writes: bool,
+    partial_dir: Option<PathBuf>,
+    backup: bool,
+    backup_dir: Option<PathBuf>,
+    backup_suffix: Option<String>,
+    trash: bool,
+    max_delete: Option<DeleteLimit>,
+    confirm_deletes: Option<ConfirmDeletes>,
+    delete_timing: DeleteTiming,
+    protect: Vec<String>,
+    move_files: bool,
+    detect_renames: bool,
+    renames: Option<rename::Renames>,
+    hard_links: Option<HardLinks>,
+    link_dest: Vec<PathBuf>,
+    /// Snapshots deleted once the sync finishes without errors, see [`Synchronize::snapshot`]
+    pruned_snapshots: Vec<PathBuf>,
 
     // Reporting
     progress: Progress,
+    log_file: Option<PathBuf>,
+    webhook: Option<String>,
+    pre_hook: Option<String>,
+    post_hook: Option<String>,
 }
 
 #[derive(Debug, Default, Clone)]
 struct DirState {
     is_error: bool,
-    error: Arc<Mutex<Option<io::Error>>>,
+    error: Arc<Mutex<Option<Error>>>,
 }
 
 type ClientState = (DirState, ());
 
+type ConfirmDeletes = Box<dyn Fn(&[PathBuf]) -> bool + Send + Sync>;
+
 impl Synchronize {
     pub fn new<A: Into<PathBuf>, B: Into<PathBuf>>(src: A, dest: B) -> Self {
         Self {
@@ -51,10 +211,90 @@ impl Synchronize {
             delete: false,
             num_threads: None,
             skip_hidden: false,
-            check_content: false,
+            check_content: None,
             display_progress: false,
             skip_permissions: false,
+            modify_window: Duration::ZERO,
+            preserve_times: PreserveTimes::default(),
+            noatime: false,
+            size_only: false,
+            min_size: None,
+            max_size: None,
+            min_age: None,
+            max_age: None,
+            max_depth: None,
+            copy_links: false,
+            absolute_links: AbsoluteLinks::default(),
+            dangling_links: DanglingLinks::default(),
+            junctions: Junctions::default(),
+            specials: false,
+            devices: false,
+            fail_on_unsupported: false,
+            one_file_system: false,
+            src_dev: None,
+            update: false,
+            ignore_existing: false,
+            existing: false,
+            fat: false,
+            require_symlink_target: false,
+            sendfile: false,
+            merkle_tree: None,
+            keep_partial: false,
+            copy_strategy: vec![CopyMethod::Copy],
+            copy_engine: CopyEngine::Std,
+            chunk_threshold: None,
+            scheduling: SchedulingPolicy::Walk,
+            preserve_attributes: false,
+            store_checksums: false,
+            xattrs: false,
+            acls: false,
+            owner: false,
+            group: false,
+            usermap: None,
+            groupmap: None,
+            chmod: Chmod::default(),
+            dry_run: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            filter_regex: Vec::new(),
+            respect_gitignore: false,
+            filter: Filter::default(),
+            checksum_cache_path: None,
+            checksum_cache: None,
+            delta: false,
+            remote_shell: "ssh".to_string(),
+            remote_command: "fsync".to_string(),
+            backend: None,
+            ignore_errors: false,
+            retries: 0,
+            retry_delay: Duration::from_millis(100),
+            bidirectional: false,
+            state_file: None,
+            conflict_policy: ConflictPolicy::default(),
+            cancel: CancelToken::default(),
+            resume: false,
+            journal: None,
+            atomic_writes: true,
+            partial_dir: None,
+            backup: false,
+            backup_dir: None,
+            backup_suffix: None,
+            trash: false,
+            max_delete: None,
+            confirm_deletes: None,
+            delete_timing: DeleteTiming::default(),
+            protect: Vec::new(),
+            move_files: false,
+            detect_renames: false,
+            renames: None,
+            hard_links: None,
+            link_dest: Vec::new(),
+            pruned_snapshots: Vec::new(),
             progress: Progress::default(),
+            log_file: None,
+            webhook: None,
+            pre_hook: None,
+            post_hook: None,
         }
     }
 
@@ -78,376 +318,2592 @@ impl Synchronize {
         self
     }
 
-    pub fn check_content(mut self, value: bool) -> Self {
-        self.check_content = value;
+    /// Append a timestamped record of each change, message and error to the file at `path`
+    pub fn log_file<P: Into<PathBuf>>(mut self, path: Option<P>) -> Self {
+        self.log_file = path.map(|x| x.into());
         self
     }
 
-    pub fn skip_permissions(mut self, value: bool) -> Self {
-        self.skip_permissions = value;
+    /// Post the outcome of the sync as JSON to `url` once it finishes or fails
+    pub fn webhook<S: Into<String>>(mut self, url: Option<S>) -> Self {
+        self.webhook = url.map(|x| x.into());
         self
     }
 
-    pub fn sync(self) -> anyhow::Result<()> {
-        let sync = Arc::new(self);
+    /// Run `command` through the shell before syncing, stopping the sync when it fails. It's
+    /// given `FSYNC_SOURCE`, `FSYNC_DESTINATION` and `FSYNC_DRY_RUN` in its environment.
+    pub fn pre_hook<S: Into<String>>(mut self, command: Option<S>) -> Self {
+        self.pre_hook = command.map(|x| x.into());
+        self
+    }
 
-        // Threadpool used by jwalk
-        let thread_pool = Arc::new(sync.get_thread_pool()?);
-        let parallelism = jwalk::Parallelism::RayonExistingPool {
-            pool: thread_pool.clone(),
-            busy_timeout: None,
-        };
+    /// Run `command` through the shell once the sync is done, whether or not it failed. On top
+    /// of the pre hook's environment it's given `FSYNC_STATUS` ("finished", "cancelled" or
+    /// "failed", also when only some paths failed) along with `FSYNC_ERROR`, or the totals in
+    /// `FSYNC_COPIED`, `FSYNC_DELETED`, `FSYNC_BYTES_COPIED` and `FSYNC_ERRORS` and the whole
+    /// report in `FSYNC_REPORT`.
+    pub fn post_hook<S: Into<String>>(mut self, command: Option<S>) -> Self {
+        self.post_hook = command.map(|x| x.into());
+        self
+    }
 
-        // Read all source files and create the destination folder structure
-        let sync_clone = sync.clone();
-        let src_files = jwalk::WalkDirGeneric::<ClientState>::new(&sync_clone.src)
-            .skip_hidden(sync_clone.skip_hidden)
-            .parallelism(parallelism)
-            .process_read_dir(move |depth, path, state, c| {
-                if depth.is_none() {
-                    return;
-                }
-                if state.is_error {
-                    return;
-                }
-                match sync_clone.sync_dir(path, c) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        state.is_error = true;
-                        state.error.lock().unwrap().replace(e);
-                    }
-                }
-            });
+    /// Also write each change and error to stdout in `format`
+    pub fn output(mut self, format: OutputFormat) -> Self {
+        self.progress.output = format;
+        self
+    }
 
-        // Write symlinks
-        src_files
-            .into_iter()
-            .map(|x| match x {
-                Ok(x) => {
-                    if x.path_is_symlink() {
-                        return sync.sync_symlink(&x.path());
-                    }
-                    Ok(())
-                }
-                Err(e) => Err(anyhow::Error::msg(e.to_string())),
-            })
-            .collect::<anyhow::Result<Vec<_>>>()?;
+    /// Also print each source path skipped as its type isn't supported
+    pub fn verbose(mut self, value: bool) -> Self {
+        self.progress.verbose = value;
+        self
+    }
 
-        sync.progress.print();
+    /// Add what the sync does to `metrics`, which can be shared by several syncs and served
+    /// with [`metrics::serve`]
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.progress.metrics = Some(metrics);
+        self
+    }
 
-        Ok(())
+    /// Report progress to `reporter` instead of drawing it on stderr
+    pub fn progress_reporter(mut self, reporter: impl ProgressReporter + 'static) -> Self {
+        self.progress.reporter = Some(Arc::new(reporter));
+        #[cfg(feature = "progress-bars")]
+        {
+            self.progress.bars = None;
+        }
+        self
     }
 
-    fn sync_dir(
-        &self,
-        dir: &Path,
-        children: &mut [jwalk::Result<DirEntry<ClientState>>],
-    ) -> io::Result<()> {
-        // Update progress
-        self.progress.add_source(children.len());
+    pub fn check_content(mut self, value: bool) -> Self {
+        self.check_content = value.then_some(ContentCheck::Bytes);
+        self
+    }
 
-        // Create destination directory if it doesn't already exist
-        let dest = self.get_destination_path(dir);
-        if !dest.exists() {
-            match std::fs::create_dir(&dest) {
-                Ok(_) => {}
-                Err(e) => panic!("Failed to create directory {:?}: Error {:?}", &dest, e),
-            }
-            self.progress.add_copied(1);
-        } else {
-            self.progress.add_skipped(1);
-        }
+    /// Compare file contents using this algorithm instead of modified time
+    pub fn content_check(mut self, value: ContentCheck) -> Self {
+        self.check_content = Some(value);
+        self
+    }
 
-        let mut deletes = HashSet::new();
-        if self.delete {
-            deletes = fs::read_dir(dest)?
-                .map(|x| x.map(|y| y.path()))
-                .collect::<io::Result<HashSet<_>>>()?;
-        }
+    pub fn skip_permissions(mut self, value: bool) -> Self {
+        self.skip_permissions = value;
+        self
+    }
 
-        // Syncronize files
-        for entry in children.iter_mut().flatten() {
-            let pth = entry.path();
-            let dest = self.get_destination_path(&pth);
-            deletes.remove(&dest);
-            if pth.is_file() && !pth.is_symlink() {
-                match self.sync_file(&entry.path(), &dest) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        self.progress.println(format!(
-                            "Error syncing {:?}: {:?}",
-                            &entry.path(),
-                            e
-                        ));
-                        entry.read_children_path = None;
-                    }
-                }
-            }
-        }
+    /// Treat modified times this far apart as the same, for destinations such as FAT and some
+    /// network file systems that round the times they store
+    pub fn modify_window(mut self, value: Duration) -> Self {
+        self.modify_window = value;
+        self
+    }
 
-        for delete in deletes.into_iter() {
-            self.remove_all(&delete)?;
-        }
+    /// Which times of the source copies are given, both the modified and access times unless
+    /// set
+    pub fn preserve_times(mut self, value: PreserveTimes) -> Self {
+        self.preserve_times = value;
+        self
+    }
 
-        Ok(())
+    /// Read source files without updating their access times, where the platform allows
+    pub fn noatime(mut self, value: bool) -> Self {
+        self.noatime = value;
+        self
     }
 
-    fn sync_file(&self, src: &Path, dest: &Path) -> anyhow::Result<()> {
-        let meta = src.symlink_metadata()?;
-        let exists = dest.exists();
+    /// Treat files of the same size as unchanged whatever their modified times, for
+    /// destinations that don't keep times, such as some object storage gateways
+    pub fn size_only(mut self, value: bool) -> Self {
+        self.size_only = value;
+        self
+    }
 
-        if exists
-            && (self.check_content && self.check_content_equal(src, dest).unwrap_or(false)
-                || self.is_equal(&meta, dest).unwrap_or(false))
-        {
-            self.progress.add_skipped(1);
-            return Ok(());
-        }
+    /// Skip source files smaller than this many bytes
+    pub fn min_size(mut self, value: Option<u64>) -> Self {
+        self.min_size = value;
+        self
+    }
 
-        // Copy file data
-        self.copy_file(&meta, src, dest)?;
+    /// Skip source files larger than this many bytes
+    pub fn max_size(mut self, value: Option<u64>) -> Self {
+        self.max_size = value;
+        self
+    }
 
-        self.progress.add_copied(1);
-        self.progress.add_bytes_copied(meta.len() as usize);
+    /// Skip source files modified less than this long ago, which may still be being written
+    pub fn min_age(mut self, value: Option<Duration>) -> Self {
+        self.min_age = value;
+        self
+    }
 
-        // Preserve permissions
-        if !self.skip_permissions {
-            let perm = meta.permissions();
-            std::fs::set_permissions(dest, perm)?;
-        }
+    /// Skip source files modified more than this long ago
+    pub fn max_age(mut self, value: Option<Duration>) -> Self {
+        self.max_age = value;
+        self
+    }
 
-        // Preserve modified time
-        let mtime = meta.modified()?;
-        let atime = meta.accessed()?;
-        filetime::set_file_times(dest, atime.into(), mtime.into())?;
+    /// Only sync this many levels below the source, so directories at the last level are
+    /// created without their contents. Nothing below that level is deleted either.
+    pub fn max_depth(mut self, value: Option<usize>) -> Self {
+        self.max_depth = value;
+        self
+    }
 
-        Ok(())
+    /// Copy what source symlinks point to instead of the symlinks, skipping those whose target
+    /// doesn't exist and links to a directory above them, which would be followed forever
+    pub fn copy_links(mut self, value: bool) -> Self {
+        self.copy_links = value;
+        self
     }
 
-    fn sync_symlink(&self, src: &Path) -> anyhow::Result<()> {
-        let dest: PathBuf = self.get_destination_path(src);
-        let link_path = std::fs::read_link(src)?;
-        if dest.exists() {
-            let meta = src.symlink_metadata()?;
-            if !self.is_equal(&meta, &dest)? {
-                return Ok(());
-            }
-            std::fs::remove_file(&dest)?;
-        }
-        match symlink(&link_path, &dest) {
-            Err(e) => Err(anyhow::Error::msg(format!(
-                "Failed to create symlink {:?} -> {:?} Error {:?}",
-                src, dest, e
-            ))),
-            _ => Ok(()),
-        }?;
-        self.progress.add_copied(1);
-        Ok(())
+    /// What copied symlinks with absolute targets inside the source point to, the same path
+    /// back in the source unless set
+    pub fn absolute_links(mut self, value: AbsoluteLinks) -> Self {
+        self.absolute_links = value;
+        self
     }
 
-    fn remove_all(&self, path: &Path) -> io::Result<()> {
-        let filetype = fs::symlink_metadata(path)?.file_type();
-        if filetype.is_symlink() || filetype.is_file() {
-            fs::remove_file(path)?;
-            self.progress.add_deleted(1);
-            Ok(())
-        } else {
-            for child in fs::read_dir(path)? {
-                let child = child?;
-                if child.file_type()?.is_dir() {
-                    self.remove_all(&child.path())?;
-                } else {
-                    fs::remove_file(child.path())?;
-                    self.progress.add_deleted(1);
-                }
-            }
-            Ok(())
-        }
+    /// What happens to source symlinks whose target doesn't exist, copied as they are unless
+    /// set
+    pub fn dangling_links(mut self, value: DanglingLinks) -> Self {
+        self.dangling_links = value;
+        self
     }
 
-    fn get_thread_pool(&self) -> anyhow::Result<ThreadPool> {
-        let mut pool = rayon::ThreadPoolBuilder::new();
-        if let Some(threads) = self.num_threads {
-            pool = pool.num_threads(threads as usize)
-        }
-        let pool = pool.build()?;
-        Ok(pool)
+    /// What happens to NTFS junctions in the source on Windows, recreated as junctions unless
+    /// set
+    pub fn junctions(mut self, value: Junctions) -> Self {
+        self.junctions = value;
+        self
     }
 
-    fn is_equal(&self, src_meta: &Metadata, dest_path: impl AsRef<Path>) -> anyhow::Result<bool> {
-        let dest_meta = dest_path.as_ref().metadata()?;
-        let same_l = dest_meta.len() == src_meta.len();
-        let same_m = dest_meta.modified()? == src_meta.modified()?;
-        Ok(same_l && same_m)
+    /// Recreate named pipes in the destination, which are left out unless set
+    pub fn specials(mut self, value: bool) -> Self {
+        self.specials = value;
+        self
     }
 
-    fn check_content_equal(
-        &self,
-        src: impl AsRef<Path>,
-        dest: impl AsRef<Path>,
-    ) -> anyhow::Result<bool> {
-        let mut file1 = fs::File::open(src.as_ref())?;
-        let mut file2 = fs::File::open(dest.as_ref())?;
+    /// Recreate block and character devices in the destination, which needs root and leaves
+    /// them out unless set
+    pub fn devices(mut self, value: bool) -> Self {
+        self.devices = value;
+        self
+    }
 
-        let mut buffer1 = [0; 1024]; // Using a buffer of 1024 bytes
-        let mut buffer2 = [0; 1024];
+    /// Fail on source paths of types fsync can't sync, such as sockets, instead of skipping
+    /// them
+    pub fn fail_on_unsupported(mut self, value: bool) -> Self {
+        self.fail_on_unsupported = value;
+        self
+    }
 
-        loop {
-            let count1 = file1.read(&mut buffer1)?;
-            let count2 = file2.read(&mut buffer2)?;
+    /// Don't descend into directories on other file systems than the source, such as `/proc`
+    /// or network mounts below `/`. Their mount points are still created.
+    pub fn one_file_system(mut self, value: bool) -> Self {
+        self.one_file_system = value;
+        self
+    }
 
-            if count1 != count2 || buffer1[..count1] != buffer2[..count2] {
-                return Ok(false);
-            }
+    /// Skip files modified later in the destination than in the source, so changes made
+    /// directly to the mirror aren't overwritten
+    pub fn update(mut self, value: bool) -> Self {
+        self.update = value;
+        self
+    }
 
-            if count1 == 0 || count2 == 0 {
-                break;
-            }
-        }
+    /// Only create what's missing from the destination, leaving everything already there as
+    /// it is
+    pub fn ignore_existing(mut self, value: bool) -> Self {
+        self.ignore_existing = value;
+        self
+    }
 
-        Ok(true)
-        //sz
+    /// Only update what's already in the destination, without creating files or directories
+    pub fn existing(mut self, value: bool) -> Self {
+        self.existing = value;
+        self
     }
-    fn get_destination_path(&self, src_path: &Path) -> PathBuf {
-        let mut dest = self.dest.clone();
-        dest.push(src_path.strip_prefix(&self.src).unwrap());
-        dest
+
+    /// Sync to a FAT or exFAT file system, which is also detected on Linux and macOS: skip
+    /// permissions and symlinks, compare modified times with a 2 second window and replace
+    /// characters FAT doesn't allow in names with underscores
+    pub fn fat(mut self, value: bool) -> Self {
+        self.fat = value;
+        self
     }
 
-    // File system utilities
-    fn copy_file(&self, _meta: &Metadata, original: &Path, link: &Path) -> anyhow::Result<()> {
-        match std::fs::copy(original, link) {
-            Err(e) => Err(anyhow::Error::msg(format!(
-                "Failed to copy file {:?} -> {:?} Error {:?}",
-                link, original, e
-            ))),
-            _ => Ok(()),
-        }
+    /// Only recreate symlinks whose target exists, or will exist, inside the destination tree
+    pub fn require_symlink_target(mut self, value: bool) -> Self {
+        self.require_symlink_target = value;
+        self
     }
-}
 
-#[derive(Debug)]
-struct Progress {
-    last_tick: Mutex<std::time::Instant>,
-    start: std::time::Instant,
-    paths: AtomicUsize,
-    paths_deleted: AtomicUsize,
-    paths_copied: AtomicUsize,
-    paths_skipped: AtomicUsize,
-    bytes_copied: AtomicUsize,
-}
+    /// Copy with `sendfile` on Linux, reusing the source descriptor opened by `check_content`
+    pub fn sendfile(mut self, value: bool) -> Self {
+        self.sendfile = value;
+        self
+    }
 
-impl Default for Progress {
-    fn default() -> Self {
-        Self {
-            last_tick: Mutex::new(std::time::Instant::now().sub(Duration::from_millis(120))),
-            start: std::time::Instant::now(),
-            paths: AtomicUsize::default(),
-            paths_deleted: AtomicUsize::default(),
-            paths_copied: AtomicUsize::default(),
-            paths_skipped: AtomicUsize::default(),
-            bytes_copied: AtomicUsize::default(),
-        }
+    /// Store a merkle tree of the destination at this path after syncing, see [`merkle::verify`]
+    pub fn merkle_tree<P: Into<PathBuf>>(mut self, value: Option<P>) -> Self {
+        self.merkle_tree = value.map(Into::into);
+        self
     }
-}
 
-impl Progress {
-    fn add_source(&self, bytes: usize) {
-        self.paths.fetch_add(bytes, Ordering::Relaxed);
-        self.tick();
+    /// Keep partially written destination files when a copy fails instead of removing them
+    pub fn keep_partial(mut self, value: bool) -> Self {
+        self.keep_partial = value;
+        self
     }
 
-    fn add_copied(&self, bytes: usize) {
-        self.paths_copied.fetch_add(bytes, Ordering::Relaxed);
-        self.tick();
+    /// Ordered fallback chain of copy methods, each tried until one succeeds
+    pub fn copy_strategy(mut self, value: Vec<CopyMethod>) -> Self {
+        self.copy_strategy = value;
+        self
     }
 
-    fn add_skipped(&self, bytes: usize) {
-        self.paths_skipped.fetch_add(bytes, Ordering::Relaxed);
-        self.tick();
+    /// Copy files of at least this many bytes in chunks on several threads at once, on unix
+    pub fn chunk_threshold(mut self, value: Option<u64>) -> Self {
+        self.chunk_threshold = value;
+        self
     }
 
-    fn add_deleted(&self, bytes: usize) {
-        self.paths_deleted.fetch_add(bytes, Ordering::Relaxed);
-        self.tick();
+    /// Order files are handed to the threads copying them in
+    pub fn scheduling(mut self, value: SchedulingPolicy) -> Self {
+        self.scheduling = value;
+        self
     }
 
-    fn add_bytes_copied(&self, bytes: usize) {
-        self.bytes_copied.fetch_add(bytes, Ordering::Relaxed);
-        self.tick();
+    /// How full copies read and write file data, [`CopyEngine::IoUring`] needs Linux
+    pub fn copy_engine(mut self, value: CopyEngine) -> Self {
+        self.copy_engine = value;
+        self
     }
 
-    fn println<S: Borrow<str>>(&self, s: S) {
-        eprintln!("\r{}", s.borrow());
-        self.print();
+    /// Copy hidden, system, archive and readonly attributes on Windows, no-op elsewhere
+    pub fn preserve_attributes(mut self, value: bool) -> Self {
+        self.preserve_attributes = value;
+        self
     }
 
-    fn tick(&self) {
-        let mut last_tick = self.last_tick.lock().unwrap();
+    /// Copy the extended attributes of files and directories, those in the user and trusted
+    /// namespaces on Linux and all of them on macOS. Needs a local destination.
+    pub fn xattrs(mut self, value: bool) -> Self {
+        self.xattrs = value;
+        self
+    }
 
-        if last_tick.elapsed() > Duration::from_millis(120) {
-            *last_tick = std::time::Instant::now();
-            self.print();
-        }
+    /// Copy the POSIX ACLs of files and the default ACLs of directories, on Linux with a
+    /// local destination
+    pub fn acls(mut self, value: bool) -> Self {
+        self.acls = value;
+        self
     }
 
-    fn print(&self) {
-        let paths = self.paths.load(Ordering::Relaxed);
-        let paths_copied = self.paths_copied.load(Ordering::Relaxed);
-        let paths_skipped = self.paths_skipped.load(Ordering::Relaxed);
-        let paths_deleted = self.paths_deleted.load(Ordering::Relaxed);
-        let bytes_copied = self.bytes_copied.load(Ordering::Relaxed);
-        let elapsed = self.start.elapsed();
+    /// Give destination paths the owner of the source's, which usually needs root. Paths
+    /// whose owner can't be changed are warned about without failing the sync.
+    pub fn owner(mut self, value: bool) -> Self {
+        self.owner = value;
+        self
+    }
 
-        let del = match paths_deleted > 0 {
-            true => format!("Deleted {:?} ", paths_deleted),
-            false => "".to_string(),
-        };
+    /// Give destination paths the group of the source's, as far as the user may
+    pub fn group(mut self, value: bool) -> Self {
+        self.group = value;
+        self
+    }
 
-        eprint!(
-            "\rFiles: {}, Copied: {}, Skipped: {}, Transfered {}, {}Elapsed: {:.2?} ",
-            paths,
-            paths_copied,
-            paths_skipped,
-            human_bytes::human_bytes(bytes_copied as f64),
-            del,
-            elapsed,
-        );
+    /// Translate the owners of source paths with these rules, even without
+    /// [`Synchronize::owner`]
+    pub fn usermap(mut self, value: IdMap) -> Self {
+        self.usermap = Some(value);
+        self
     }
-}
 
-#[macro_export]
-macro_rules! temp_fs {
-    ($($($dir:ident)/+: $file:expr),+ $(,)?) => {{
-        use std::io::Write;
-        let temp = tempfile::tempdir().unwrap();
-        $(
-            {
-                let path = concat!($(stringify!($dir), "/",)+);
-                let path = temp.path().join(format!("{}.text", &path[0..path.len() - 1]));
-                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
-                let mut file = std::fs::File::create(&path).unwrap();
-                file.write_all(&[b'a'; $file]).unwrap();
-            }
-        )+
-        temp
-    }};
-}
+    /// Translate the groups of source paths with these rules, even without
+    /// [`Synchronize::group`]
+    pub fn groupmap(mut self, value: IdMap) -> Self {
+        self.groupmap = Some(value);
+        self
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::path::Path;
+    /// Rewrite the permissions copied from the source with these rules once files are
+    /// written and directories synced, after the rules of earlier calls
+    pub fn chmod(mut self, value: Chmod) -> Self {
+        self.chmod.extend(value);
+        self
+    }
 
-    use crate::temp_fs;
+    /// Store the sha256 of each copied file in its `user.fsync.sha256` extended attribute,
+    /// for [`Synchronize::scrub`] to find corruption later. Needs a local destination on Linux
+    /// or macOS.
+    pub fn store_checksums(mut self, value: bool) -> Self {
+        self.store_checksums = value;
+        self
+    }
 
-    use super::Synchronize;
-    use jwalk::WalkDir;
+    /// Report every change that would be made without touching the destination
+    pub fn dry_run(mut self, value: bool) -> Self {
+        self.dry_run = value;
+        self
+    }
 
-    pub fn paths<P: AsRef<Path>>(walk: WalkDir, rel: P) -> Vec<String> {
-        walk.into_iter()
-            .map(|x| {
-                x.unwrap()
-                    .path()
+    /// Skip paths relative to the source matching this glob, e.g. `*.o` or `target/**`
+    pub fn exclude<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.exclude.push(pattern.into());
+        self
+    }
+
+    /// Exclude each line as a glob pattern, skipping blank lines and `#` or `;` comments like
+    /// rsync's `--exclude-from`
+    pub fn exclude_from<I, S>(mut self, lines: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for line in lines {
+            let line = line.as_ref().trim_end_matches(['\r', '\n']);
+            if line.trim().is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            self.exclude.push(line.to_string());
+        }
+        self
+    }
+
+    /// Only sync files matching this glob, directories are still descended into
+    pub fn include<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.include.push(pattern.into());
+        self
+    }
+
+    /// Skip paths relative to the source matching this regular expression
+    pub fn filter_regex(mut self, regex: Regex) -> Self {
+        self.filter_regex.push(regex);
+        self
+    }
+
+    /// Skip paths ignored by `.gitignore` files, `.git/info/exclude` and global git excludes
+    pub fn respect_gitignore(mut self, value: bool) -> Self {
+        self.respect_gitignore = value;
+        self
+    }
+
+    /// Store content digests here and only rehash files whose size or modified time changed.
+    /// Has no effect when comparing with [`ContentCheck::Bytes`].
+    pub fn checksum_cache<P: Into<PathBuf>>(mut self, value: Option<P>) -> Self {
+        self.checksum_cache_path = value.map(Into::into);
+        self
+    }
+
+    /// Only write the blocks of changed files that differ from the existing destination file
+    pub fn delta(mut self, value: bool) -> Self {
+        self.delta = value;
+        self
+    }
+
+    /// Program used to reach `user@host:/path` destinations, e.g. `ssh -p 2222`
+    pub fn remote_shell<S: Into<String>>(mut self, value: S) -> Self {
+        self.remote_shell = value.into();
+        self
+    }
+
+    /// Path of the fsync executable on the remote host
+    pub fn remote_command<S: Into<String>>(mut self, value: S) -> Self {
+        self.remote_command = value.into();
+        self
+    }
+
+    /// Write to this backend instead of the local file system or a remote shell
+    pub fn backend<B: Backend + 'static>(mut self, backend: B) -> Self {
+        self.backend = Some(Box::new(backend));
+        self
+    }
+
+    /// Record failures to read or write a directory or symlink in the report and carry on,
+    /// rather than stopping at the first one
+    pub fn ignore_errors(mut self, value: bool) -> Self {
+        self.ignore_errors = value;
+        self
+    }
+
+    /// Retry destination operations failing with errors that may pass, such as a busy file or
+    /// a dropped network connection, up to this many times
+    pub fn retries(mut self, value: u32) -> Self {
+        self.retries = value;
+        self
+    }
+
+    /// Delay before the first retry, doubling for each one after it
+    pub fn retry_delay(mut self, value: Duration) -> Self {
+        self.retry_delay = value;
+        self
+    }
+
+    /// Propagate changes from both sides, deletions included, instead of mirroring the source
+    pub fn bidirectional(mut self, value: bool) -> Self {
+        self.bidirectional = value;
+        self
+    }
+
+    /// Where a bidirectional sync keeps the state of the previous run, defaults to a file
+    /// in the destination
+    pub fn state_file<P: Into<PathBuf>>(mut self, value: Option<P>) -> Self {
+        self.state_file = value.map(|x| x.into());
+        self
+    }
+
+    /// How a bidirectional sync resolves paths changed on both sides
+    pub fn conflict_policy(mut self, value: ConflictPolicy) -> Self {
+        self.conflict_policy = value;
+        self
+    }
+
+    /// Stop the sync early once `token` is cancelled
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    /// Skip directories an interrupted sync into the same destination already finished
+    pub fn resume(mut self, value: bool) -> Self {
+        self.resume = value;
+        self
+    }
+
+    /// Copy into a temporary file next to the destination and rename it over the destination
+    /// once complete, so readers never see a partly written file. On by default, local
+    /// destinations only.
+    pub fn atomic_writes(mut self, value: bool) -> Self {
+        self.atomic_writes = value;
+        self
+    }
+
+    /// Copy files through this directory, relative to the destination unless absolute, keeping
+    /// interrupted copies there to continue on the next run. Must be on the destination's file
+    /// system, local destinations only.
+    pub fn partial_dir<P: Into<PathBuf>>(mut self, value: Option<P>) -> Self {
+        self.partial_dir = value.map(Into::into);
+        self
+    }
+
+    /// Move destination files aside before overwriting or deleting them
+    pub fn backup(mut self, value: bool) -> Self {
+        self.backup = value;
+        self
+    }
+
+    /// Move backups into this directory, relative to the destination unless absolute, under
+    /// their path in the destination. Implies [`Synchronize::backup`].
+    pub fn backup_dir<P: Into<PathBuf>>(mut self, value: Option<P>) -> Self {
+        self.backup_dir = value.map(Into::into);
+        self.backup |= self.backup_dir.is_some();
+        self
+    }
+
+    /// Appended to the names of backups, defaults to `~` or nothing with a backup directory
+    pub fn backup_suffix<S: Into<String>>(mut self, value: Option<S>) -> Self {
+        self.backup_suffix = value.map(Into::into);
+        self
+    }
+
+    /// Move deleted files to the system trash instead of removing them, local destinations only
+    pub fn trash(mut self, value: bool) -> Self {
+        self.trash = value;
+        self
+    }
+
+    /// Fail before deleting anything when [`Synchronize::delete`] would remove more destination
+    /// files than this
+    pub fn max_delete(mut self, value: Option<DeleteLimit>) -> Self {
+        self.max_delete = value;
+        self
+    }
+
+    /// Ask `confirm` whether to go ahead with deleting these destination paths before changing
+    /// anything, failing with [`Error::Declined`] when it returns false
+    pub fn confirm_deletes<F>(mut self, confirm: F) -> Self
+    where
+        F: Fn(&[PathBuf]) -> bool + Send + Sync + 'static,
+    {
+        self.confirm_deletes = Some(Box::new(confirm));
+        self
+    }
+
+    /// Whether [`Synchronize::delete`] removes paths before, during or after copying
+    pub fn delete_timing(mut self, value: DeleteTiming) -> Self {
+        self.delete_timing = value;
+        self
+    }
+
+    /// Never delete destination paths matching this glob, or anything below them, even when
+    /// they are missing from the source
+    pub fn protect<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.protect.push(pattern.into());
+        self
+    }
+
+    /// Remove each source file once the destination matches it, whether it was copied or was
+    /// already up to date. Directories are left in place.
+    pub fn move_files(mut self, value: bool) -> Self {
+        self.move_files = value;
+        self
+    }
+
+    /// Rename a destination file that [`Synchronize::delete`] would remove into place when a
+    /// new source file has the same size and modified time, and contents when they are
+    /// compared, instead of copying it again. Has no effect with [`DeleteTiming::Before`].
+    pub fn detect_renames(mut self, value: bool) -> Self {
+        self.detect_renames = value;
+        self
+    }
+
+    /// Recreate source files with several hardlinks as hardlinks at the destination, copying
+    /// their data once. Needs a local destination.
+    pub fn hard_links(mut self, value: bool) -> Self {
+        self.hard_links = value.then(HardLinks::default);
+        self
+    }
+
+    /// Hardlink files that are missing or changed in the destination to the same file in
+    /// `dir` when it matches the source, instead of copying them. Relative to the destination
+    /// and tried in the order given, so each sync can be a full snapshot sharing unchanged
+    /// files with the previous one. Needs a local destination.
+    pub fn link_dest<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.link_dest.push(dir.into());
+        self
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "sync", skip_all, fields(src = ?self.src, dest = ?self.dest))
+    )]
+    pub fn sync(mut self) -> Result<SyncReport> {
+        let Some(url) = self.webhook.take() else {
+            return self.with_hooks(Self::sync_once, |x| Some(x));
+        };
+        let (src, dest) = (self.src.clone(), self.dest.clone());
+        let result = self.with_hooks(Self::sync_once, |x| Some(x));
+        webhook::notify(&url, &src, &dest, result.as_ref());
+        result
+    }
+
+    fn sync_once(mut self) -> Result<SyncReport> {
+        let source = self.prepare()?;
+        if self.bidirectional {
+            if source.is_some() || !self.dest_backend().is_local() {
+                return Err(Error::Unsupported(
+                    "bidirectional sync needs a local source and destination".to_string(),
+                ));
+            }
+            if self.move_files {
+                return Err(Error::Unsupported(
+                    "files can't be moved by a bidirectional sync".to_string(),
+                ));
+            }
+            if self.hard_links.is_some() || !self.link_dest.is_empty() {
+                return Err(Error::Unsupported(
+                    "a bidirectional sync can't make hardlinks".to_string(),
+                ));
+            }
+            return self.bisync();
+        }
+        if let Some(source) = source {
+            let at_once = self.delete_timing != DeleteTiming::During;
+            if self.max_delete.is_some() || self.confirm_deletes.is_some() || at_once {
+                return Err(Error::Unsupported(
+                    "deletes from sftp sources can't be limited, confirmed or delayed".to_string(),
+                ));
+            }
+            if !self.dest_backend().is_local() {
+                return Err(Error::Unsupported(
+                    "sftp sources can only be synced to a local destination".to_string(),
+                ));
+            }
+            if self.hard_links.is_some() || !self.link_dest.is_empty() {
+                return Err(Error::Unsupported(
+                    "syncs from sftp sources can't make hardlinks".to_string(),
+                ));
+            }
+            self.pull_dir(&source, &self.src, &self.dest)?;
+            self.progress.print();
+            let report = self.report();
+            if !report.cancelled && report.errors.is_empty() {
+                self.prune_snapshots()?;
+            }
+            return Ok(report);
+        }
+        let deletes = self.check_deletes()?;
+        if let Some(plan) = deletes.as_ref().filter(|_| self.detect_renames) {
+            if self.delete_timing != DeleteTiming::Before {
+                self.renames = Some(self.index_renames(plan)?);
+            }
+        }
+        if self.dest_backend().is_local() && !self.dry_run {
+            self.create_dest_dir(&self.dest)?;
+            let path = self.dest.join(journal::JOURNAL_FILE);
+            self.journal = Some(Journal::open(&path, &self.src, self.resume)?);
+        } else if self.resume {
+            return Err(Error::Unsupported(
+                "only syncs to a local destination can be resumed".to_string(),
+            ));
+        }
+        let sync = Arc::new(self);
+
+        // Threadpool used by jwalk
+        let thread_pool = Arc::new(sync.get_thread_pool()?);
+        sync.sync_all(&thread_pool, deletes)?;
+        sync.finish(&thread_pool)?;
+        let report = sync.report();
+        // Keep the journal for the next run unless everything was synced
+        if !report.cancelled && report.errors.is_empty() {
+            if let Some(journal) = sync.journal.as_ref() {
+                journal.remove()?;
+            }
+            sync.prune_snapshots()?;
+        }
+        Ok(report)
+    }
+
+    /// Build the filters, connect to the destination and load the checksum cache. Returns the
+    /// connection to an sftp source, which is pulled from instead of walked.
+    fn prepare(&mut self) -> Result<Option<Sftp>> {
+        let source = match sftp::parse_url(&self.src) {
+            Some(url) => {
+                self.src = url.path.clone();
+                Some(Sftp::connect(&url)?)
+            }
+            None => None,
+        };
+        self.build_filter()?;
+        if self.backend.is_none() {
+            self.backend = Some(self.connect()?);
+        }
+        let local = self.backend.as_ref().is_some_and(|x| x.is_local());
+        self.progress.roots = vec![self.dest.clone(), self.src.clone()];
+        if !self.fat && local && fat::is_fat(&self.dest) {
+            self.progress.println(format!(
+                "{:?} is on a FAT file system, syncing as with --fat",
+                self.dest
+            ));
+            self.fat = true;
+        }
+        if self.one_file_system && source.is_none() {
+            if !cfg!(unix) {
+                return Err(Error::Unsupported(
+                    "staying on one file system is only available on Unix".to_string(),
+                ));
+            }
+            let meta = self
+                .src
+                .metadata()
+                .map_err(|e| Error::with_path(e, &self.src))?;
+            self.src_dev = Some(device(&meta));
+        }
+        if self.fat {
+            self.skip_permissions = true;
+            self.modify_window = self.modify_window.max(fat::MODIFY_WINDOW);
+        }
+        if let Some(path) = &self.log_file {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| Error::with_path(e, path))?;
+            self.progress.records = Some(Mutex::new(file));
+            self.progress
+                .record(format_args!("Syncing {:?} to {:?}", self.src, self.dest));
+        }
+        if self.copy_engine == CopyEngine::IoUring && !cfg!(target_os = "linux") {
+            return Err(Error::Unsupported(
+                "io_uring is only available on Linux".to_string(),
+            ));
+        }
+        if self.trash && !local {
+            return Err(Error::Unsupported(
+                "only files in a local destination can be moved to the trash".to_string(),
+            ));
+        }
+        if self.store_checksums && !local {
+            return Err(Error::Unsupported(
+                "checksums can only be stored in a local destination".to_string(),
+            ));
+        }
+        if self.xattrs && !local {
+            return Err(Error::Unsupported(
+                "extended attributes can only be copied to a local destination".to_string(),
+            ));
+        }
+        if self.copies_owner() && !(local && cfg!(unix)) {
+            return Err(Error::Unsupported(
+                "owners can only be copied to a local destination on Unix".to_string(),
+            ));
+        }
+        if self.acls && !(local && cfg!(target_os = "linux")) {
+            return Err(Error::Unsupported(
+                "ACLs can only be copied to a local destination on Linux".to_string(),
+            ));
+        }
+        if (self.hard_links.is_some() || !self.link_dest.is_empty()) && !local {
+            return Err(Error::Unsupported(
+                "hardlinks can only be made in a local destination".to_string(),
+            ));
+        }
+        if self.retries > 0 {
+            let inner = self.backend.take().expect("backend was just set");
+            self.backend = Some(Box::new(retry::Retry {
+                inner,
+                retries: self.retries,
+                delay: self.retry_delay,
+                noatime: self.noatime,
+            }));
+        }
+        if source.is_none() {
+            if let Some(path) = &self.checksum_cache_path {
+                self.checksum_cache = Some(ChecksumCache::load(path)?);
+            }
+        }
+        Ok(source)
+    }
+
+    fn build_filter(&mut self) -> Result<()> {
+        self.filter = Filter::new(&self.include, &self.exclude, &self.filter_regex)?
+            .protect(&self.protect)?
+            .ignore_files(&self.src, self.respect_gitignore);
+        Ok(())
+    }
+
+    /// Sync the source directory `root` and everything below it, returning the directories
+    /// synced for [`Synchronize::finish_dirs`]
+    fn sync_tree(
+        self: &Arc<Self>,
+        thread_pool: &Arc<ThreadPool>,
+        root: &Path,
+    ) -> Result<Vec<PathBuf>> {
+        let parallelism = jwalk::Parallelism::RayonExistingPool {
+            pool: thread_pool.clone(),
+            busy_timeout: None,
+        };
+
+        // Read all source files and create the destination folder structure, queueing the files
+        // for the copiers
+        let copiers = Arc::new(Copiers::start(self)?);
+        let sync_clone = self.clone();
+        let dir_copiers = copiers.clone();
+        let state = DirState::default();
+        let error = state.error.clone();
+        let synced_dirs = Arc::new(Mutex::new(Vec::new()));
+        let dirs = synced_dirs.clone();
+        // Directories are synced on the walk's threads, under the span of the whole sync
+        #[cfg(feature = "tracing")]
+        let span = tracing::Span::current();
+        let src_files = jwalk::WalkDirGeneric::<ClientState>::new(root)
+            .skip_hidden(sync_clone.skip_hidden)
+            .parallelism(parallelism)
+            .root_read_dir_state(state)
+            .process_read_dir(move |depth, path, state, c| {
+                #[cfg(feature = "tracing")]
+                let _span = span.enter();
+                if depth.is_none() {
+                    return;
+                }
+                if state.is_error {
+                    return;
+                }
+                if sync_clone.cancel.is_cancelled() {
+                    for entry in c.iter_mut().flatten() {
+                        entry.read_children_path = None;
+                    }
+                    return;
+                }
+                // Drop excluded entries so their subtrees are never read
+                c.retain(|entry| match entry {
+                    Ok(entry) => !sync_clone.is_excluded(&entry.path(), entry.file_type().is_dir()),
+                    Err(_) => true,
+                });
+                sync_clone.skip_collisions(path, c, |x| x.as_ref().ok().map(|x| x.file_name()));
+                if sync_clone.skips_contents(path) {
+                    c.clear();
+                }
+                if sync_clone.skips_missing_dir(path) {
+                    c.clear();
+                    return;
+                }
+                sync_clone.follow_links(path, c);
+                if sync_clone.is_journaled(path) {
+                    let files = c.iter().flatten().filter(|x| !x.file_type().is_dir());
+                    sync_clone.progress.on_scanned(c.len());
+                    sync_clone.progress.on_skipped(files.count() + 1);
+                    return;
+                }
+                match sync_clone.sync_dir(path, c, &dir_copiers) {
+                    Ok(_) => dirs.lock().unwrap().push(path.to_path_buf()),
+                    Err(e) if sync_clone.ignore_errors => {
+                        state.is_error = true;
+                        sync_clone.progress.on_error(path, &e);
+                    }
+                    Err(e) => {
+                        state.is_error = true;
+                        state.error.lock().unwrap().replace(e);
+                    }
+                }
+            });
+
+        // Write symlinks, which are compared again even in journaled directories as they are
+        // only synced once the walk is over
+        for entry in src_files {
+            if self.cancel.is_cancelled() {
+                break;
+            }
+            let result = match entry {
+                Ok(x) if x.path_is_symlink() && !self.follows(&x.path()) => {
+                    self.sync_symlink(&x.path()).map_err(|e| (x.path(), e))
+                }
+                Ok(_) => Ok(()),
+                Err(e) => Err((e.path().unwrap_or(root).to_path_buf(), e.into())),
+            };
+            match result {
+                Ok(_) => {}
+                Err((path, e)) if self.ignore_errors => self.progress.on_error(&path, &e),
+                Err((_, e)) => return Err(e),
+            }
+        }
+
+        copiers.finish();
+
+        // Without ignore_errors the first directory that failed stops the sync
+        if let Some(e) = error.lock().unwrap().take() {
+            return Err(e);
+        }
+        let dirs = std::mem::take(&mut *synced_dirs.lock().unwrap());
+        Ok(dirs)
+    }
+
+    /// Give the destination directories of the source ones `dirs` their permissions and
+    /// modified times once everything in them is synced, as writing their contents changes
+    /// the times. The deepest go first, so a readonly directory doesn't stop its contents
+    /// being written.
+    pub(crate) fn finish_dirs(&self, mut dirs: Vec<PathBuf>) -> Result<()> {
+        if self.dry_run || self.cancel.is_cancelled() {
+            return Ok(());
+        }
+        dirs.sort_by_key(|x| Reverse(x.components().count()));
+        for dir in dirs {
+            let result = (|| {
+                let meta = self.source_metadata(&dir)?;
+                let src_meta = Meta::from_local(&meta);
+                let dest = self.get_destination_path(&dir);
+                if !self.skip_permissions {
+                    let mode = self.chmod.apply(src_meta.mode, true);
+                    self.dest_backend().set_permissions(&dest, mode)?;
+                }
+                let atime = FileTime::from_last_access_time(&meta);
+                self.set_times(&dest, atime, src_meta.mtime)?;
+                if self.dest_backend().is_local() {
+                    copy::set_created(&meta, &dest).map_err(|e| Error::with_path(e, &dest))?;
+                }
+                Ok(())
+            })();
+            match result {
+                Ok(()) => {}
+                Err(e) if self.ignore_errors => self.progress.on_error(&dir, &e),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the merkle tree and checksum cache, then print the summary
+    fn finish(&self, thread_pool: &ThreadPool) -> Result<()> {
+        let local = self.dest_backend().is_local();
+        if let Some(path) = self.merkle_tree.as_ref().filter(|_| !self.dry_run && local) {
+            let tree = thread_pool
+                .install(|| merkle::MerkleTree::build(&self.dest, std::slice::from_ref(path)))?;
+            tree.save(path)?;
+        }
+
+        if let Some(cache) = &self.checksum_cache {
+            cache.save()?;
+        }
+
+        if let Some(dir) = self.partial_path().filter(|_| !self.dry_run && local) {
+            remove_empty_dirs(&dir)?;
+        }
+
+        self.progress.print();
+
+        Ok(())
+    }
+
+    /// Backend for the destination, taking the path within it from a remote or s3 url
+    fn connect(&mut self) -> Result<Box<dyn Backend>> {
+        if let Some((bucket, prefix)) = s3::parse_destination(&self.dest) {
+            self.dest = prefix;
+            return Ok(Box::new(s3::S3::from_env(&bucket)?));
+        }
+        if let Some(url) = webdav::parse_url(&self.dest) {
+            self.dest = url.path.clone();
+            return Ok(Box::new(webdav::Webdav::new(&url)));
+        }
+        if let Some(url) = sftp::parse_url(&self.dest) {
+            self.dest = url.path.clone();
+            return Ok(Box::new(Sftp::connect(&url)?));
+        }
+        if let Some((host, root)) = remote::parse_destination(&self.dest) {
+            self.dest = root;
+            let remote = Remote::connect(&self.remote_shell, &self.remote_command, &host)?;
+            return Ok(Box::new(remote));
+        }
+        Ok(Box::new(Local {
+            copy_strategy: self.copy_strategy.clone(),
+            sendfile: self.sendfile,
+            keep_partial: self.keep_partial,
+            engine: self.copy_engine,
+            chunk_threshold: self.chunk_threshold,
+            noatime: self.noatime,
+        }))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(dir = ?dir))
+    )]
+    fn sync_dir(
+        &self,
+        dir: &Path,
+        children: &mut [jwalk::Result<DirEntry<ClientState>>],
+        copiers: &Copiers,
+    ) -> Result<()> {
+        // Update progress
+        self.progress.on_scanned(children.len());
+
+        // Create destination directory if it doesn't already exist
+        let dest = self.get_destination_path(dir);
+        let dest_meta = self.dest_backend().metadata(&dest)?;
+        let dest_exists = dest_meta.is_some();
+        // A readonly directory from an earlier sync is made writable until its permissions
+        // are set again by finish_dirs
+        if let Some(meta) = dest_meta.filter(|x| x.mode & 0o200 == 0 && x.kind == Kind::Dir) {
+            if !self.dry_run && !self.skip_permissions {
+                self.dest_backend()
+                    .set_permissions(&dest, meta.mode | 0o200)?;
+            }
+        }
+        if !dest_exists && self.dry_run {
+            self.report_dry_run("create directory", &dest);
+            self.progress.on_copied(1);
+            self.progress
+                .changed(ChangeKind::Mkdir, &dest, 0, Differences::CREATED);
+        } else if !dest_exists {
+            self.create_dest_dir(&dest)?;
+            trace!(debug, dest = ?dest, "created directory");
+            self.progress.on_copied(1);
+            self.progress
+                .changed(ChangeKind::Mkdir, &dest, 0, Differences::CREATED);
+        } else {
+            self.progress.on_skipped(1);
+        }
+        if !self.dry_run && self.copies_owner() {
+            let meta = self
+                .source_metadata(dir)
+                .map_err(|e| Error::with_path(e, dir))?;
+            self.copy_owner(&meta, &dest);
+        }
+        if !self.dry_run {
+            self.copy_xattrs(dir, &dest)?;
+        }
+
+        let mut deletes = HashSet::new();
+        if self.delete && dest_exists && !self.deletes_at_once() && !self.skips_contents(dir) {
+            // Excluded paths and files written by fsync itself are left alone
+            for path in self
+                .dest_backend()
+                .read_dir(&dest)?
+                .into_iter()
+                .map(|x| dest.join(x))
+            {
+                let is_dir = self.is_dir(&path)?;
+                let excluded = match path.strip_prefix(&self.dest) {
+                    Ok(rel) => self.is_excluded(&self.src.join(rel), is_dir),
+                    Err(_) => false,
+                };
+                if !excluded && !self.is_internal(&path) {
+                    deletes.insert(path);
+                }
+            }
+        }
+
+        // Queue files for the copiers
+        let work = DirWork::new(dir.strip_prefix(&self.src).unwrap_or(dir).to_path_buf());
+        for entry in children.iter_mut().flatten() {
+            let pth = entry.path();
+            let dest = self.get_destination_path(&pth);
+            deletes.remove(&dest);
+            if self.cancel.is_cancelled() {
+                entry.read_children_path = None;
+                continue;
+            }
+            if pth.is_file() && (!pth.is_symlink() || self.follows(&pth)) {
+                let len = match copiers.by_size() {
+                    true => entry.metadata().map_or(0, |x| x.len()),
+                    false => 0,
+                };
+                let job = FileJob {
+                    src: pth,
+                    dest,
+                    dir: work.clone(),
+                };
+                copiers.send(job, len)?;
+            } else if special::is_special(entry.file_type()) {
+                let result = match self.syncs_special(entry.file_type()) {
+                    true => self.sync_special(&pth, &dest),
+                    false => self.skip_unsupported(&pth),
+                };
+                match result {
+                    Err(e) if self.ignore_errors => self.progress.on_error(&pth, &e),
+                    result => result?,
+                }
+            }
+        }
+
+        // A cancelled sync only deletes from directories it finished
+        if self.cancel.is_cancelled() {
+            return Ok(());
+        }
+        for delete in deletes.into_iter() {
+            self.remove_all(&delete)?;
+        }
+        self.finish_work(&work)
+    }
+
+    /// Whether the source directory `dir` is left out with everything below it by
+    /// [`Synchronize::existing`], as it isn't in the destination
+    fn skips_missing_dir(&self, dir: &Path) -> bool {
+        let dest = self.get_destination_path(dir);
+        // Errors are left for sync_dir to report
+        let skipped = self.existing && matches!(self.dest_backend().metadata(&dest), Ok(None));
+        if skipped {
+            self.progress.on_skipped(1);
+        }
+        skipped
+    }
+
+    /// Whether nothing in the source directory `dir` is synced, as it's as deep as
+    /// [`Synchronize::max_depth`] goes or on another file system with
+    /// [`Synchronize::one_file_system`]
+    fn skips_contents(&self, dir: &Path) -> bool {
+        self.max_depth.is_some_and(|max| self.depth(dir) >= max)
+            || self
+                .src_dev
+                .is_some_and(|dev| self.source_metadata(dir).is_ok_and(|x| device(&x) != dev))
+    }
+
+    /// The metadata of the source path `path`, or of what it points to with
+    /// [`Synchronize::copy_links`] and followed junctions
+    fn source_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        match self.follows(path) {
+            true => path.metadata(),
+            false => path.symlink_metadata(),
+        }
+    }
+
+    /// Have the walk read the symlinks among the `children` of the source directory `dir`
+    /// that point to directories, with [`Synchronize::copy_links`]
+    fn follow_links(&self, dir: &Path, children: &mut [jwalk::Result<DirEntry<ClientState>>]) {
+        if !self.copy_links && self.junctions != Junctions::Follow {
+            return;
+        }
+        for entry in children.iter_mut().flatten() {
+            let path = entry.path();
+            if !entry.path_is_symlink() || !self.follows(&path) {
+                continue;
+            }
+            let skipped = match path.metadata() {
+                Ok(meta) if meta.is_dir() => {
+                    // Real paths resolve every link between the source and the directory
+                    match (fs::canonicalize(&path), fs::canonicalize(dir)) {
+                        (Ok(target), Ok(dir)) if dir.starts_with(&target) => {
+                            Some("it points to a directory above it")
+                        }
+                        (Ok(_), Ok(_)) => {
+                            entry.read_children_path = Some(Arc::from(path.as_path()));
+                            None
+                        }
+                        _ => Some("its target can't be resolved"),
+                    }
+                }
+                Ok(_) => None,
+                Err(_) => Some("its target doesn't exist"),
+            };
+            if let Some(reason) = skipped {
+                self.progress
+                    .println(format!("Skipping symlink {:?}: {}", path, reason));
+                self.progress.on_skipped(1);
+            }
+        }
+    }
+
+    /// Levels the source path `path` is below the source
+    fn depth(&self, path: &Path) -> usize {
+        path.strip_prefix(&self.src)
+            .map_or(0, |rel| rel.components().count())
+    }
+
+    /// Whether an interrupted sync already finished the source directory `dir`
+    fn is_journaled(&self, dir: &Path) -> bool {
+        let Some(journal) = &self.journal else {
+            return false;
+        };
+        dir.strip_prefix(&self.src)
+            .is_ok_and(|rel| journal.is_done(rel))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(src = ?src))
+    )]
+    fn sync_file(&self, src: &Path, dest: &Path) -> Result<()> {
+        let meta = self
+            .source_metadata(src)
+            .map_err(|e| Error::with_path(e, src))?;
+        self.sync_hard_link(src, &meta, dest, || self.update_file(src, &meta, dest))
+    }
+
+    /// Bring `dest` up to date with the source file `src`
+    fn update_file(&self, src: &Path, meta: &Metadata, dest: &Path) -> Result<()> {
+        if self.skips_file(&Meta::from_local(meta)) {
+            trace!(trace, "outside the size or age limits");
+            self.progress.on_skipped(1);
+            return Ok(());
+        }
+        let dest_meta = self.dest_backend().metadata(dest)?;
+        let exists = dest_meta.is_some();
+        let local = self.dest_backend().is_local();
+
+        if self.keeps_destination(&Meta::from_local(meta), dest_meta.as_ref()) {
+            trace!(trace, "kept");
+            self.progress.on_skipped(1);
+            return Ok(());
+        }
+
+        let compare_content = exists && local && self.check_content.is_some();
+        // Keep the source open after comparing so the copy can reuse the descriptor
+        let mut src_file = match compare_content || self.noatime {
+            true => Some(self.open_source(src)?),
+            false => None,
+        };
+
+        if exists
+            && (src_file
+                .as_mut()
+                .filter(|_| compare_content)
+                .map(|file| self.check_content_equal(src, file, dest).unwrap_or(false))
+                .unwrap_or(false)
+                || dest_meta
+                    .as_ref()
+                    .is_some_and(|x| self.is_equal(&Meta::from_local(meta), x)))
+        {
+            trace!(trace, "unchanged");
+            self.progress.on_skipped(1);
+            return self.remove_source(src, None, dest, |x| fs::remove_file(x));
+        }
+
+        if !exists {
+            if let Some(from) = self.take_renamed(src, &Meta::from_local(meta))? {
+                match self.rename_file(src, meta, &from, dest) {
+                    // Fall back to copying to destinations that can't rename
+                    Err(Error::Io(e)) if e.kind() == io::ErrorKind::Unsupported => {}
+                    result => return result,
+                }
+            }
+        }
+
+        if let Some(target) = self.find_link_dest(src, meta, dest)? {
+            return self.link_file(src, &target, dest);
+        }
+
+        if self.dry_run {
+            self.report_dry_run("copy", src);
+            self.progress.on_copied(1);
+            match exists {
+                true => self.progress.on_updated(1),
+                false => self.progress.on_created(1),
+            }
+            self.progress.on_bytes_copied(meta.len());
+            let differences = self.differences(&Meta::from_local(meta), dest_meta.as_ref());
+            self.progress
+                .changed(ChangeKind::Copy, dest, meta.len(), differences);
+            return self.remove_source(src, None, dest, |x| fs::remove_file(x));
+        }
+
+        // Update with delta transfer in place, otherwise copy into the partial directory or a
+        // temporary file when writing atomically. A partial copy that should be kept without a
+        // partial directory has to stay at the destination, and a backup takes away the file a
+        // delta transfer would update.
+        let backup = exists && self.backup;
+        let delta = exists && self.delta && local && dest.is_file() && !backup;
+        let partial = self.partial_path().filter(|_| local && !delta);
+        let atomic =
+            partial.is_some() || self.atomic_writes && local && !delta && !self.keep_partial;
+        let (target, mode) = match (partial, atomic) {
+            (Some(dir), _) => {
+                let target = dir.join(dest.strip_prefix(&self.dest).unwrap_or(dest));
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                (target, WriteMode::Resume)
+            }
+            (None, true) => (temp_path(dest), WriteMode::Copy),
+            (None, false) if delta => (dest.to_path_buf(), WriteMode::Delta),
+            (None, false) => (dest.to_path_buf(), WriteMode::Copy),
+        };
+        if backup && !atomic {
+            self.backup_file(dest)?;
+        }
+        let written = self
+            .write_file(meta, src_file, src, &target, mode)
+            .and_then(|written| match atomic {
+                true => {
+                    // Moving the old file away last keeps the destination missing briefly
+                    if backup {
+                        self.backup_file(dest)?;
+                    }
+                    fs::rename(&target, dest)
+                        .map(|_| written)
+                        .map_err(|source| Error::CopyFailed {
+                            src: src.to_path_buf(),
+                            dest: dest.to_path_buf(),
+                            source,
+                        })
+                }
+                false => Ok(written),
+            });
+        if written.is_err() && atomic && mode != WriteMode::Resume {
+            let _ = fs::remove_file(&target);
+        }
+        let (method, written) = written?;
+        trace!(debug, ?method, bytes = written, updated = exists, "copied");
+
+        self.progress.on_copied(1);
+        self.progress.on_method(method);
+        match exists {
+            true => self.progress.on_updated(1),
+            false => self.progress.on_created(1),
+        }
+        self.progress.on_bytes_copied(written);
+        let meta = Meta::from_local(meta);
+        let differences = self.differences(&meta, dest_meta.as_ref());
+        self.progress.copied(method, dest, written, differences);
+        self.remove_source(src, Some(&meta), dest, |x| fs::remove_file(x))
+    }
+
+    /// Move the destination file `from`, which matches `src`, to `dest`
+    fn rename_file(&self, src: &Path, meta: &Metadata, from: &Path, dest: &Path) -> Result<()> {
+        if self.dry_run {
+            self.progress
+                .println(format!("Would rename {:?} to {:?}", from, dest));
+        } else {
+            self.dest_backend().rename(from, dest)?;
+            if !self.skip_permissions {
+                let mode = self.chmod.apply(Meta::from_local(meta).mode, false);
+                self.dest_backend().set_permissions(dest, mode)?;
+            }
+        }
+        trace!(debug, from = ?from, "renamed");
+        self.progress.on_renamed(1);
+        self.progress
+            .changed(ChangeKind::Rename, dest, 0, Differences::CREATED);
+        self.remove_source(src, None, dest, |x| fs::remove_file(x))
+    }
+
+    /// With `move_files`, remove the source file `src` using `remove`, once `dest` is checked to
+    /// match its metadata `meta`. Without `meta` the two are already known to match.
+    fn remove_source(
+        &self,
+        src: &Path,
+        meta: Option<&Meta>,
+        dest: &Path,
+        remove: impl FnOnce(&Path) -> io::Result<()>,
+    ) -> Result<()> {
+        if !self.move_files {
+            return Ok(());
+        }
+        if self.dry_run {
+            self.report_dry_run("remove source file", src);
+            return Ok(());
+        }
+        if let Some(meta) = meta {
+            let copied = self.dest_backend().metadata(dest)?;
+            if !copied.is_some_and(|x| self.is_equal(meta, &x)) {
+                return Err(Error::Unverified(src.to_path_buf()));
+            }
+        }
+        remove(src).map_err(|e| Error::with_path(e, src))
+    }
+
+    /// Write the contents, permissions, times and attributes of `src` to `dest`, returning the
+    /// number of bytes written
+    fn write_file(
+        &self,
+        meta: &Metadata,
+        src_file: Option<fs::File>,
+        src: &Path,
+        dest: &Path,
+        mode: WriteMode,
+    ) -> Result<(CopyMethod, u64)> {
+        let (method, written) = match mode {
+            WriteMode::Copy => (self.copy_file(meta, src_file, src, dest)?, meta.len()),
+            WriteMode::Delta => {
+                let mut file = src_file.map_or_else(|| self.open_source(src), Ok)?;
+                (CopyMethod::Copy, delta::sync_file(&mut file, dest)?)
+            }
+            WriteMode::Resume => {
+                let mut file = src_file.map_or_else(|| self.open_source(src), Ok)?;
+                let written =
+                    copy::resume(&mut file, dest).map_err(|source| Error::CopyFailed {
+                        src: src.to_path_buf(),
+                        dest: dest.to_path_buf(),
+                        source,
+                    })?;
+                (CopyMethod::Copy, written)
+            }
+        };
+        // A hardlink is the source itself, which the fixups below would change
+        if method == CopyMethod::Hardlink {
+            return Ok((method, written));
+        }
+
+        // Changing the owner clears setuid bits, so it comes before the permissions
+        self.copy_owner(meta, dest);
+
+        // Preserve permissions
+        let src_meta = Meta::from_local(meta);
+        if !self.skip_permissions {
+            let mode = self.chmod.apply(src_meta.mode, false);
+            self.dest_backend().set_permissions(dest, mode)?;
+        }
+
+        // Preserve modified and creation times
+        let atime = FileTime::from_last_access_time(meta);
+        self.set_times(dest, atime, src_meta.mtime)?;
+        if self.dest_backend().is_local() {
+            copy::set_created(meta, dest).map_err(|e| Error::with_path(e, dest))?;
+        }
+
+        // After the permissions, which would otherwise change the ACL's mask
+        self.copy_xattrs(src, dest)?;
+        if self.store_checksums {
+            self.store_checksum(dest)?;
+        }
+
+        // Preserve file attributes last as a readonly destination can't have its times set
+        if self.preserve_attributes && self.dest_backend().is_local() {
+            copy::set_attributes(meta, dest)?;
+        }
+
+        Ok((method, written))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(src = ?src))
+    )]
+    fn sync_symlink(&self, src: &Path) -> Result<()> {
+        if self.skips_symlink(src) {
+            return Ok(());
+        }
+        let is_junction = junction::is_junction(src);
+        if is_junction && self.skips_junction(src) {
+            return Ok(());
+        }
+        let dest: PathBuf = self.get_destination_path(src);
+        let Some(target) = self.read_link(src)? else {
+            return Ok(());
+        };
+        let link_path = self.link_target(src, target);
+        if self.require_symlink_target && !self.symlink_target_exists(&dest, &link_path) {
+            self.progress.println(format!(
+                "Skipping symlink {:?} -> {:?}: target is not in the destination",
+                src, link_path
+            ));
+            self.progress.on_skipped(1);
+            return Ok(());
+        }
+        let meta = Meta::from_local(&src.symlink_metadata()?);
+        let dest_meta = self.dest_backend().metadata(&dest)?;
+        if self.keeps_destination(&meta, dest_meta.as_ref()) {
+            self.progress.on_skipped(1);
+            return Ok(());
+        }
+        if let Some(dest_meta) = &dest_meta {
+            // Only the target matters for links, their own times and permissions aren't kept
+            if dest_meta.kind == Kind::Symlink && self.dest_backend().read_link(&dest)? == link_path
+            {
+                self.progress.on_skipped(1);
+                return Ok(());
+            }
+            if !self.dry_run {
+                self.dest_backend().remove(&dest)?;
+            }
+        }
+        let differences = self.differences(&meta, dest_meta.as_ref());
+        if self.dry_run {
+            self.report_dry_run("link", &dest);
+            self.progress.on_copied(1);
+            self.progress
+                .changed(ChangeKind::Symlink, &dest, 0, differences);
+            return Ok(());
+        }
+        // Links to directories in the source are copied as such, even when the directory isn't
+        // in the destination yet
+        let to_dir = src.metadata().is_ok_and(|x| x.is_dir());
+        match (is_junction && self.dest_backend().is_local(), to_dir) {
+            (true, _) => junction::create(&link_path, &dest),
+            (false, true) => self.dest_backend().symlink_dir(&link_path, &dest),
+            (false, false) => self.dest_backend().symlink(&link_path, &dest),
+        }
+        .map_err(|source| Error::SymlinkFailed {
+            src: src.to_path_buf(),
+            dest: dest.clone(),
+            source,
+        })?;
+        if self.dest_backend().is_local() {
+            self.copy_owner(&src.symlink_metadata()?, &dest);
+        }
+        self.progress.on_copied(1);
+        self.progress
+            .changed(ChangeKind::Symlink, &dest, 0, differences);
+        Ok(())
+    }
+
+    /// Download a directory from an sftp source, one entry at a time
+    fn pull_dir(&self, source: &Sftp, src: &Path, dest: &Path) -> Result<()> {
+        let dest_exists = self.dest_backend().metadata(dest)?.is_some();
+        if !dest_exists && self.existing {
+            self.progress.on_skipped(1);
+            return Ok(());
+        }
+        if dest_exists {
+            self.progress.on_skipped(1);
+        } else {
+            match self.dry_run {
+                true => self.report_dry_run("create directory", dest),
+                false => self.create_dest_dir(dest)?,
+            }
+            self.progress.on_copied(1);
+            self.progress
+                .changed(ChangeKind::Mkdir, dest, 0, Differences::CREATED);
+        }
+
+        let mut entries = source.entries(src)?;
+        entries.retain(|(name, meta)| {
+            let hidden = name.to_string_lossy().starts_with('.');
+            let is_dir = meta.kind == Kind::Dir;
+            !(self.skip_hidden && hidden || self.is_excluded(&src.join(name), is_dir))
+        });
+        self.skip_collisions(src, &mut entries, |x| Some(x.0.as_os_str()));
+        self.progress.on_scanned(entries.len());
+
+        if self.delete && dest_exists {
+            let names = entries
+                .iter()
+                .map(|x| self.dest_name(&x.0))
+                .collect::<HashSet<_>>();
+            for name in self.dest_backend().read_dir(dest)? {
+                let path = dest.join(&name);
+                let excluded = self.is_excluded(&src.join(&name), self.is_dir(&path)?);
+                if !names.contains(name.as_os_str()) && !excluded && !self.is_internal(&path) {
+                    self.remove_all(&path)?;
+                }
+            }
+        }
+
+        for (name, meta) in entries {
+            if self.cancel.is_cancelled() {
+                break;
+            }
+            let (src, dest) = (src.join(&name), dest.join(self.dest_name(&name)));
+            let result = match meta.kind {
+                Kind::Dir => {
+                    self.pull_dir(source, &src, &dest)?;
+                    continue;
+                }
+                Kind::Symlink => self.pull_symlink(source, &meta, &src, &dest),
+                Kind::File => self.pull_file(source, &meta, &src, &dest),
+                Kind::Other => continue,
+            };
+            if let Err(e) = result {
+                self.progress.on_error(&src, &e);
+            }
+        }
+        Ok(())
+    }
+
+    fn pull_file(&self, source: &Sftp, meta: &Meta, src: &Path, dest: &Path) -> Result<()> {
+        if self.skips_file(meta) {
+            self.progress.on_skipped(1);
+            return Ok(());
+        }
+        let dest_meta = self.dest_backend().metadata(dest)?;
+        let remove = |x: &Path| source.remove(x);
+        if self.keeps_destination(meta, dest_meta.as_ref()) {
+            self.progress.on_skipped(1);
+            return Ok(());
+        }
+        if dest_meta.as_ref().is_some_and(|x| self.is_equal(meta, x)) {
+            self.progress.on_skipped(1);
+            return self.remove_source(src, None, dest, remove);
+        }
+        if self.dry_run {
+            self.report_dry_run("copy", src);
+            self.progress.on_copied(1);
+            match dest_meta {
+                Some(_) => self.progress.on_updated(1),
+                None => self.progress.on_created(1),
+            }
+            self.progress.on_bytes_copied(meta.len);
+            let differences = self.differences(meta, dest_meta.as_ref());
+            self.progress
+                .changed(ChangeKind::Copy, dest, meta.len, differences);
+            return self.remove_source(src, None, dest, remove);
+        }
+
+        let written = source.download(src, dest)?;
+        self.progress.on_copied(1);
+        match dest_meta {
+            Some(_) => self.progress.on_updated(1),
+            None => self.progress.on_created(1),
+        }
+        self.progress.on_bytes_copied(written);
+        let differences = self.differences(meta, dest_meta.as_ref());
+        self.progress
+            .copied(CopyMethod::Copy, dest, written, differences);
+
+        if !self.skip_permissions {
+            let mode = self.chmod.apply(meta.mode, false);
+            self.dest_backend().set_permissions(dest, mode)?;
+        }
+        // Access times aren't listed by sftp, so the modified time stands in for both
+        self.set_times(dest, meta.mtime, meta.mtime)?;
+        self.remove_source(src, Some(meta), dest, remove)
+    }
+
+    fn pull_symlink(&self, source: &Sftp, meta: &Meta, src: &Path, dest: &Path) -> Result<()> {
+        if self.skips_symlink(src) {
+            return Ok(());
+        }
+        let target = self.link_target(src, source.read_link(src)?);
+        let existing = self.dest_backend().metadata(dest)?;
+        if self.keeps_destination(meta, existing.as_ref()) {
+            self.progress.on_skipped(1);
+            return Ok(());
+        }
+        if let Some(existing) = &existing {
+            if existing.kind == Kind::Symlink && self.dest_backend().read_link(dest)? == target {
+                self.progress.on_skipped(1);
+                return Ok(());
+            }
+            if !self.dry_run {
+                self.dest_backend().remove(dest)?;
+            }
+        }
+        match self.dry_run {
+            true => self.report_dry_run("link", dest),
+            false => self.dest_backend().symlink(&target, dest)?,
+        }
+        let differences = match existing {
+            Some(_) => Differences::default(),
+            None => Differences::CREATED,
+        };
+        self.progress.on_copied(1);
+        self.progress
+            .changed(ChangeKind::Symlink, dest, 0, differences);
+        Ok(())
+    }
+
+    fn symlink_target_exists(&self, dest: &Path, target: &Path) -> bool {
+        let resolved = match dest.parent() {
+            Some(parent) => normalize_path(&parent.join(target)),
+            None => normalize_path(target),
+        };
+        let Ok(rel) = resolved.strip_prefix(normalize_path(&self.dest)) else {
+            return false;
+        };
+        // The target may not have been synced yet, so also look for it in the source, where
+        // it only counts when the filters let it through
+        let in_dest = self
+            .dest_backend()
+            .metadata(&resolved)
+            .ok()
+            .flatten()
+            .is_some();
+        let in_src = self.src.join(rel);
+        in_dest || (in_src.exists() && !self.is_excluded_below(&in_src))
+    }
+
+    /// Move the destination file `path` to its backup, replacing an older backup
+    fn backup_file(&self, path: &Path) -> io::Result<()> {
+        let rel = path.strip_prefix(&self.dest).unwrap_or(path);
+        let mut backup = match &self.backup_dir {
+            Some(dir) => self.dest.join(dir).join(rel).into_os_string(),
+            None => path.as_os_str().to_os_string(),
+        };
+        let default_suffix = match self.backup_dir {
+            Some(_) => "",
+            None => "~",
+        };
+        backup.push(self.backup_suffix.as_deref().unwrap_or(default_suffix));
+        let backup = PathBuf::from(backup);
+        if let Some(parent) = backup.parent() {
+            if self.dest_backend().metadata(parent)?.is_none() {
+                self.create_dest_dir(parent).map_err(io::Error::other)?;
+            }
+        }
+        self.dest_backend().rename(path, &backup)
+    }
+
+    /// Give `dest` the extended attributes and ACLs of `src` the sync copies
+    fn copy_xattrs(&self, src: &Path, dest: &Path) -> Result<()> {
+        if !self.xattrs && !self.acls {
+            return Ok(());
+        }
+        let copied =
+            |name: &OsStr| self.xattrs && xattr::is_user(name) || self.acls && xattr::is_acl(name);
+        xattr::copy(src, dest, copied).map_err(|e| Error::with_path(e, dest))
+    }
+
+    /// Create `dest` along with any missing parents. A directory another thread created in the
+    /// meantime counts as success.
+    fn create_dest_dir(&self, dest: &Path) -> Result<()> {
+        let backend = self.dest_backend();
+        let mut result = backend.create_dir(dest);
+        if result.is_err() {
+            if let Some(parent) = dest.parent().filter(|x| !x.as_os_str().is_empty()) {
+                if backend.metadata(parent)?.is_none() {
+                    self.create_dest_dir(parent)?;
+                    result = backend.create_dir(dest);
+                }
+            }
+        }
+        match result {
+            Ok(_) => Ok(()),
+            Err(_) if self.is_dir(dest)? => Ok(()),
+            Err(source) => Err(Error::DestinationUnwritable {
+                path: dest.to_path_buf(),
+                source,
+            }),
+        }
+    }
+
+    fn report(&self) -> SyncReport {
+        let report = SyncReport {
+            cancelled: self.cancel.is_cancelled(),
+            ..self.progress.report()
+        };
+        self.progress.on_finish(&report);
+        report
+    }
+
+    fn dest_backend(&self) -> &dyn Backend {
+        self.backend
+            .as_deref()
+            .expect("backend is set when the sync starts")
+    }
+
+    fn is_dir(&self, path: &Path) -> io::Result<bool> {
+        let meta = self.dest_backend().metadata(path)?;
+        Ok(meta.is_some_and(|x| x.kind == Kind::Dir))
+    }
+
+    fn is_internal(&self, path: &Path) -> bool {
+        let cache = self.checksum_cache.as_ref().map(|x| x.path());
+        let state = self.bidirectional && self.state_path() == path;
+        let journal = self.journal.as_ref().is_some_and(|x| x.path() == path);
+        let partial = self.partial_path().is_some_and(|x| x == path);
+        let backup = self
+            .backup_dir
+            .as_ref()
+            .is_some_and(|x| self.dest.join(x) == path);
+        let link_dest = self.link_dest.iter().any(|x| self.dest.join(x) == path);
+        self.merkle_tree.as_deref() == Some(path)
+            || self.is_backup(path)
+            || cache == Some(path)
+            || state
+            || journal
+            || partial
+            || backup
+            || link_dest
+    }
+
+    fn partial_path(&self) -> Option<PathBuf> {
+        self.partial_dir.as_ref().map(|x| self.dest.join(x))
+    }
+
+    /// Whether `path` is a backup kept next to the file it replaced, which deletes leave alone
+    /// so it isn't backed up again on the next run
+    pub(crate) fn is_backup(&self, path: &Path) -> bool {
+        let suffix = self.backup_suffix.as_deref().unwrap_or("~");
+        self.backup
+            && self.backup_dir.is_none()
+            && !suffix.is_empty()
+            && path
+                .file_name()
+                .is_some_and(|x| x.to_string_lossy().ends_with(suffix))
+    }
+
+    fn is_protected(&self, dest: &Path) -> bool {
+        dest.strip_prefix(&self.dest)
+            .is_ok_and(|rel| self.filter.is_protected(rel))
+    }
+
+    fn is_excluded(&self, src: &Path, is_dir: bool) -> bool {
+        match src.strip_prefix(&self.src) {
+            Ok(rel) => self.filter.is_excluded(rel, is_dir) || self.filter.is_ignored(src, is_dir),
+            Err(_) => false,
+        }
+    }
+
+    /// Whether `path` or a directory between it and the source root is excluded
+    pub(crate) fn is_excluded_below(&self, path: &Path) -> bool {
+        let mut is_dir = path.is_dir();
+        for ancestor in path.ancestors().take_while(|x| *x != self.src) {
+            if self.skip_hidden
+                && ancestor
+                    .file_name()
+                    .is_some_and(|x| x.to_string_lossy().starts_with('.'))
+            {
+                return true;
+            }
+            if self.is_excluded(ancestor, is_dir) {
+                return true;
+            }
+            is_dir = true;
+        }
+        false
+    }
+
+    fn report_dry_run(&self, action: &str, path: &Path) {
+        self.progress
+            .println(format!("Would {} {:?}", action, path));
+    }
+
+    fn get_thread_pool(&self) -> Result<ThreadPool> {
+        let mut pool = rayon::ThreadPoolBuilder::new();
+        if let Some(threads) = self.num_threads {
+            pool = pool.num_threads(threads as usize)
+        }
+        let pool = pool.build()?;
+        Ok(pool)
+    }
+
+    fn is_equal(&self, src_meta: &Meta, dest_meta: &Meta) -> bool {
+        let resolution = self.dest_backend().mtime_resolution();
+        let same_l = dest_meta.len == src_meta.len;
+        if self.size_only {
+            return same_l;
+        }
+        let same_m = truncate(src_meta.mtime, resolution) == truncate(dest_meta.mtime, resolution)
+            || nanos(src_meta.mtime).abs_diff(nanos(dest_meta.mtime))
+                <= self.modify_window.as_nanos();
+        same_l && same_m
+    }
+
+    /// Whether the source file `meta` is left out by the size limits of
+    /// [`Synchronize::min_size`] and [`Synchronize::max_size`] or the age limits of
+    /// [`Synchronize::min_age`] and [`Synchronize::max_age`]. Like rsync, its destination isn't
+    /// deleted either.
+    fn skips_file(&self, meta: &Meta) -> bool {
+        let len = meta.len;
+        if self.min_size.is_some_and(|x| len < x) || self.max_size.is_some_and(|x| len > x) {
+            return true;
+        }
+        // Files modified in the future are as young as can be
+        let age = nanos(FileTime::now())
+            .saturating_sub(nanos(meta.mtime))
+            .max(0) as u128;
+        self.min_age.is_some_and(|x| age < x.as_nanos())
+            || self.max_age.is_some_and(|x| age > x.as_nanos())
+    }
+
+    /// Whether the destination `dest_meta` of a source entry is left as it is by
+    /// [`Synchronize::update`], [`Synchronize::ignore_existing`] or [`Synchronize::existing`]
+    fn keeps_destination(&self, src_meta: &Meta, dest_meta: Option<&Meta>) -> bool {
+        match dest_meta {
+            Some(dest_meta) => self.ignore_existing || self.is_newer(dest_meta, src_meta),
+            None => self.existing,
+        }
+    }
+
+    /// Whether the destination file `dest_meta` was modified after the source one `src_meta`
+    /// and is kept by [`Synchronize::update`]
+    fn is_newer(&self, dest_meta: &Meta, src_meta: &Meta) -> bool {
+        let resolution = self.dest_backend().mtime_resolution();
+        self.update
+            && dest_meta.kind == Kind::File
+            && truncate(dest_meta.mtime, resolution) > truncate(src_meta.mtime, resolution)
+            && nanos(dest_meta.mtime) - nanos(src_meta.mtime)
+                > self.modify_window.as_nanos() as i128
+    }
+
+    /// Open the source file `src`, without updating its access time with
+    /// [`Synchronize::noatime`]
+    fn open_source(&self, src: &Path) -> Result<fs::File> {
+        let file = match self.noatime {
+            true => copy::open_noatime(src),
+            false => fs::File::open(src),
+        };
+        file.map_err(|e| Error::with_path(e, src))
+    }
+
+    /// Give `dest` the times of its source that [`Synchronize::preserve_times`] selects
+    fn set_times(&self, dest: &Path, atime: FileTime, mtime: FileTime) -> io::Result<()> {
+        match self.preserve_times {
+            PreserveTimes::All => self.dest_backend().set_times(dest, atime, mtime),
+            PreserveTimes::Mtime => self.dest_backend().set_times(dest, FileTime::now(), mtime),
+            PreserveTimes::None => Ok(()),
+        }
+    }
+
+    /// What a copy of the source `src` changes about the destination `dest`
+    fn differences(&self, src: &Meta, dest: Option<&Meta>) -> Differences {
+        let mut differences = Differences::between(src, dest);
+        differences.permissions &= !self.skip_permissions;
+        differences
+    }
+
+    fn check_content_equal(
+        &self,
+        src_path: &Path,
+        src: &mut fs::File,
+        dest: &Path,
+    ) -> Result<bool> {
+        let check = self.check_content.unwrap_or(ContentCheck::Bytes);
+        let mut dest_file = fs::File::open(dest)?;
+        match &self.checksum_cache {
+            Some(cache) if check != ContentCheck::Bytes => {
+                if src.metadata()?.len() != dest_file.metadata()?.len() {
+                    return Ok(false);
+                }
+                let a = cache.digest(check, src_path, src)?;
+                let b = cache.digest(check, dest, &mut dest_file)?;
+                Ok(a == b)
+            }
+            _ => Ok(check.equal(src, &mut dest_file)?),
+        }
+    }
+
+    fn get_destination_path(&self, src_path: &Path) -> PathBuf {
+        let rel = src_path.strip_prefix(&self.src).unwrap();
+        if self.fat {
+            return rel.components().fold(self.dest.clone(), |dest, x| {
+                dest.join(self.dest_name(x.as_os_str()))
+            });
+        }
+        let mut dest = self.dest.clone();
+        dest.push(rel);
+        dest
+    }
+
+    // File system utilities
+    fn copy_file(
+        &self,
+        meta: &Metadata,
+        src_file: Option<fs::File>,
+        original: &Path,
+        link: &Path,
+    ) -> Result<CopyMethod> {
+        let result = match meta.len() >= LARGE_FILE {
+            true => {
+                let file = self.progress.start_file(original, meta.len());
+                let result = self.dest_backend().copy_with_progress(
+                    original,
+                    meta,
+                    src_file,
+                    link,
+                    &|bytes| self.progress.file_copied(&file, bytes),
+                );
+                self.progress.finish_file(&file);
+                result
+            }
+            false => self.dest_backend().copy(original, meta, src_file, link),
+        };
+        result.map_err(|source| Error::CopyFailed {
+            src: original.to_path_buf(),
+            dest: link.to_path_buf(),
+            source,
+        })
+    }
+}
+
+/// How [`Synchronize::write_file`] writes the contents of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WriteMode {
+    /// With the configured copy strategy
+    Copy,
+    /// Only the blocks that differ from the file already there
+    Delta,
+    /// Appending to what an earlier, interrupted copy left behind
+    Resume,
+}
+
+/// Remove the directories below `path` and `path` itself when they contain no files
+fn remove_empty_dirs(path: &Path) -> io::Result<()> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_empty_dirs(&entry.path())?;
+        }
+    }
+    match fs::remove_dir(path) {
+        Err(e) if e.kind() == io::ErrorKind::DirectoryNotEmpty => Ok(()),
+        result => result,
+    }
+}
+
+/// Unused name next to `dest` for writing it atomically
+fn temp_path(dest: &Path) -> PathBuf {
+    use std::hash::{BuildHasher, Hasher};
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    dest.with_file_name(format!(".fsync-tmp-{:08x}", random as u32))
+}
+
+/// Count of whole `resolution` steps since the epoch, so times can be compared at the precision
+/// a backend stores
+fn truncate(time: FileTime, resolution: Duration) -> i128 {
+    nanos(time).div_euclid(resolution.as_nanos().max(1) as i128)
+}
+
+/// The device of the file system holding `meta`'s file
+#[cfg(unix)]
+fn device(meta: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.dev()
+}
+
+#[cfg(not(unix))]
+fn device(_meta: &Metadata) -> u64 {
+    0
+}
+
+/// Nanoseconds since the epoch
+fn nanos(time: FileTime) -> i128 {
+    time.unix_seconds() as i128 * 1_000_000_000 + time.nanoseconds() as i128
+}
+
+/// Lexically resolve `.` and `..` components without touching the file system
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !out.pop() {
+                    out.push(component);
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// What a finished sync did, counted in paths unless noted otherwise
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    pub files_scanned: usize,
+    pub copied: usize,
+    pub created: usize,
+    pub updated: usize,
+    pub skipped: usize,
+    /// Destination files moved into place instead of copied, see [`Synchronize::detect_renames`]
+    pub renamed: usize,
+    pub deleted: usize,
+    pub conflicts: usize,
+    /// Copied files cloned copy-on-write instead of copied
+    pub reflinked: usize,
+    /// Copied files hardlinked instead of copied
+    pub hardlinked: usize,
+    pub bytes_copied: u64,
+    /// Whether the sync stopped early because its [`CancelToken`] was cancelled
+    pub cancelled: bool,
+    /// Paths that failed to sync without stopping the rest of the sync
+    pub errors: Vec<FileError>,
+    /// Source paths of types fsync can't sync, such as sockets, which are also counted as
+    /// skipped
+    pub unsupported: Vec<PathBuf>,
+    pub duration: Duration,
+}
+
+impl SyncReport {
+    /// The report as a JSON document, for monitoring systems
+    pub fn to_json(&self) -> String {
+        self.json().to_string()
+    }
+
+    fn json(&self) -> serde_json::Value {
+        let errors = self.errors.iter().map(|x| {
+            serde_json::json!({
+                "path": x.path.to_string_lossy(),
+                "cause": x.cause,
+            })
+        });
+        serde_json::json!({
+            "files_scanned": self.files_scanned,
+            "copied": self.copied,
+            "bytes_copied": self.bytes_copied,
+            "duration_secs": self.duration.as_secs_f64(),
+            "cancelled": self.cancelled,
+            "breakdown": {
+                "created": self.created,
+                "updated": self.updated,
+                "skipped": self.skipped,
+                "renamed": self.renamed,
+                "deleted": self.deleted,
+                "conflicts": self.conflicts,
+                "reflinked": self.reflinked,
+                "hardlinked": self.hardlinked,
+                "unsupported": self.unsupported.len(),
+            },
+            "errors": errors.collect::<Vec<_>>(),
+            "unsupported": self
+                .unsupported
+                .iter()
+                .map(|x| x.to_string_lossy())
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// A path that couldn't be synced and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileError {
+    pub path: PathBuf,
+    pub cause: String,
+}
+
+/// Files at least this large show how far along their copy is in the progress line
+const LARGE_FILE: u64 = 16 * 1024 * 1024;
+
+/// A large file being copied
+#[derive(Debug)]
+struct FileProgress {
+    path: PathBuf,
+    len: u64,
+    copied: AtomicU64,
+    #[cfg(feature = "progress-bars")]
+    bar: Option<indicatif::ProgressBar>,
+}
+
+impl FileProgress {
+    /// Bytes copied so far, a retried copy starting over isn't counted past the length
+    fn copied(&self) -> u64 {
+        self.copied.load(Ordering::Relaxed).min(self.len)
+    }
+}
+
+struct Progress {
+    last_tick: Mutex<std::time::Instant>,
+    start: std::time::Instant,
+    paths: AtomicUsize,
+    paths_deleted: AtomicUsize,
+    paths_copied: AtomicUsize,
+    paths_created: AtomicUsize,
+    paths_updated: AtomicUsize,
+    paths_skipped: AtomicUsize,
+    paths_reflinked: AtomicUsize,
+    paths_hardlinked: AtomicUsize,
+    paths_renamed: AtomicUsize,
+    paths_conflicted: AtomicUsize,
+    bytes_copied: AtomicU64,
+    // Large files being copied, their bytes so far aren't in `bytes_copied` yet
+    active: Mutex<Vec<Arc<FileProgress>>>,
+    errors: Mutex<Vec<FileError>>,
+    unsupported: Mutex<Vec<PathBuf>>,
+    // Whether skipped unsupported paths are printed
+    verbose: bool,
+    // Whether stderr is a terminal that can redraw the progress line in place
+    interactive: bool,
+    // Written to instead of stderr, e.g. a daemon job's log
+    log: Option<Mutex<fs::File>>,
+    // Receives the progress instead of stderr or the log
+    reporter: Option<Arc<dyn ProgressReporter>>,
+    // Changes and errors are also written to stdout in this format
+    output: OutputFormat,
+    // Destination and source, which itemized paths are shown relative to
+    roots: Vec<PathBuf>,
+    // Every change, message and error is appended to this file as well
+    records: Option<Mutex<fs::File>>,
+    // Drawn instead of the progress line in a terminal
+    #[cfg(feature = "progress-bars")]
+    bars: Option<bars::Bars>,
+    // Totals served to a monitoring system, kept across syncs
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self {
+            last_tick: Mutex::new(std::time::Instant::now().sub(Duration::from_millis(120))),
+            start: std::time::Instant::now(),
+            paths: AtomicUsize::default(),
+            paths_deleted: AtomicUsize::default(),
+            paths_copied: AtomicUsize::default(),
+            paths_created: AtomicUsize::default(),
+            paths_updated: AtomicUsize::default(),
+            paths_skipped: AtomicUsize::default(),
+            paths_reflinked: AtomicUsize::default(),
+            paths_hardlinked: AtomicUsize::default(),
+            paths_renamed: AtomicUsize::default(),
+            paths_conflicted: AtomicUsize::default(),
+            bytes_copied: AtomicU64::default(),
+            active: Mutex::default(),
+            errors: Mutex::default(),
+            unsupported: Mutex::default(),
+            verbose: false,
+            interactive: io::stderr().is_terminal(),
+            log: None,
+            reporter: None,
+            output: OutputFormat::Text,
+            roots: Vec::new(),
+            records: None,
+            #[cfg(feature = "progress-bars")]
+            bars: io::stderr().is_terminal().then(bars::Bars::new),
+            metrics: None,
+        }
+    }
+}
+
+impl ProgressReporter for Progress {
+    fn on_scanned(&self, paths: usize) {
+        self.paths.fetch_add(paths, Ordering::Relaxed);
+        self.forward(|x| x.on_scanned(paths));
+    }
+
+    fn on_copied(&self, paths: usize) {
+        self.paths_copied.fetch_add(paths, Ordering::Relaxed);
+        self.count(|x| &x.files_copied, paths as u64);
+        self.forward(|x| x.on_copied(paths));
+    }
+
+    fn on_created(&self, paths: usize) {
+        self.paths_created.fetch_add(paths, Ordering::Relaxed);
+        self.forward(|x| x.on_created(paths));
+    }
+
+    fn on_updated(&self, paths: usize) {
+        self.paths_updated.fetch_add(paths, Ordering::Relaxed);
+        self.forward(|x| x.on_updated(paths));
+    }
+
+    fn on_method(&self, method: CopyMethod) {
+        match method {
+            CopyMethod::Reflink => self.paths_reflinked.fetch_add(1, Ordering::Relaxed),
+            CopyMethod::Hardlink => self.paths_hardlinked.fetch_add(1, Ordering::Relaxed),
+            CopyMethod::Copy => 0,
+        };
+        self.forward(|x| x.on_method(method));
+    }
+
+    fn on_skipped(&self, paths: usize) {
+        self.paths_skipped.fetch_add(paths, Ordering::Relaxed);
+        self.forward(|x| x.on_skipped(paths));
+    }
+
+    fn on_renamed(&self, paths: usize) {
+        self.paths_renamed.fetch_add(paths, Ordering::Relaxed);
+        self.forward(|x| x.on_renamed(paths));
+    }
+
+    fn on_conflict(&self, paths: usize) {
+        self.paths_conflicted.fetch_add(paths, Ordering::Relaxed);
+        self.forward(|x| x.on_conflict(paths));
+    }
+
+    fn on_deleted(&self, paths: usize) {
+        self.paths_deleted.fetch_add(paths, Ordering::Relaxed);
+        self.forward(|x| x.on_deleted(paths));
+    }
+
+    fn on_bytes_copied(&self, bytes: u64) {
+        self.bytes_copied.fetch_add(bytes, Ordering::Relaxed);
+        self.count(|x| &x.bytes_copied, bytes);
+        self.forward(|x| x.on_bytes_copied(bytes));
+    }
+
+    fn on_file_progress(&self, path: &Path, copied: u64, len: u64) {
+        self.forward(|x| x.on_file_progress(path, copied, len));
+    }
+
+    fn on_change(&self, change: &Change) {
+        match change.kind {
+            ChangeKind::Copy => self.record(format_args!(
+                "{} {:?} {} bytes",
+                change.kind.name(),
+                change.path,
+                change.bytes
+            )),
+            _ => self.record(format_args!("{} {:?}", change.kind.name(), change.path)),
+        }
+        match self.output {
+            OutputFormat::Text => {}
+            OutputFormat::Ndjson => self.emit(&progress::Event {
+                event: change.kind.name(),
+                path: &change.path.to_string_lossy(),
+                bytes: Some(change.bytes),
+                cause: None,
+                method: change.method.map(|x| x.name()),
+            }),
+            OutputFormat::Itemize => {
+                let rel = self
+                    .roots
+                    .iter()
+                    .find_map(|x| change.path.strip_prefix(x).ok())
+                    .unwrap_or(change.path);
+                let _ = writeln!(io::stdout().lock(), "{}", change.itemize(rel));
+            }
+        }
+        self.forward(|x| x.on_change(change));
+    }
+
+    fn on_error(&self, path: &Path, cause: &dyn std::fmt::Display) {
+        if self.output == OutputFormat::Ndjson {
+            self.emit(&progress::Event {
+                event: "error",
+                path: &path.to_string_lossy(),
+                bytes: None,
+                cause: Some(&cause.to_string()),
+                method: None,
+            });
+        }
+        self.count(|x| &x.errors, 1);
+        let message = format!("Error syncing {:?}: {}", path, cause);
+        match &self.reporter {
+            Some(reporter) => {
+                self.record(format_args!("{}", message));
+                reporter.on_error(path, cause);
+            }
+            None => self.println(message),
+        }
+        self.errors.lock().unwrap().push(FileError {
+            path: path.to_path_buf(),
+            cause: cause.to_string(),
+        });
+    }
+
+    fn on_unsupported(&self, path: &Path) {
+        if self.output == OutputFormat::Ndjson {
+            self.emit(&progress::Event {
+                event: "unsupported",
+                path: &path.to_string_lossy(),
+                bytes: None,
+                cause: None,
+                method: None,
+            });
+        }
+        let message = format!("Skipped {:?}: unsupported file type", path);
+        match self.verbose {
+            true => self.println(message),
+            false => self.record(format_args!("{}", message)),
+        }
+        self.unsupported.lock().unwrap().push(path.to_path_buf());
+        self.forward(|x| x.on_unsupported(path));
+    }
+
+    fn on_message(&self, message: &str) {
+        self.println(message);
+    }
+
+    fn on_finish(&self, report: &SyncReport) {
+        self.record(format_args!(
+            "Finished: copied {}, skipped {}, deleted {}, errors {}, unsupported {}, {} bytes in {:.2?}{}",
+            report.copied,
+            report.skipped,
+            report.deleted,
+            report.errors.len(),
+            report.unsupported.len(),
+            report.bytes_copied,
+            report.duration,
+            if report.cancelled { ", cancelled" } else { "" }
+        ));
+        self.forward(|x| x.on_finish(report));
+    }
+}
+
+impl Progress {
+    /// Report to `log`, or line by line to stderr when there isn't one
+    fn to_log(log: Option<fs::File>) -> Self {
+        Self {
+            interactive: false,
+            log: log.map(Mutex::new),
+            #[cfg(feature = "progress-bars")]
+            bars: None,
+            ..Self::default()
+        }
+    }
+
+    /// Add `n` to one of the metrics, when there are any
+    fn count(&self, metric: impl FnOnce(&Metrics) -> &AtomicU64, n: u64) {
+        if let Some(metrics) = &self.metrics {
+            metric(metrics).fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    fn changed(&self, kind: ChangeKind, path: &Path, bytes: u64, differences: Differences) {
+        self.on_change(&Change::new(kind, path, bytes, differences));
+    }
+
+    /// Report a file whose data was written with `method`
+    fn copied(&self, method: CopyMethod, path: &Path, bytes: u64, differences: Differences) {
+        let change = Change::new(ChangeKind::Copy, path, bytes, differences);
+        self.on_change(&change.with_method(method));
+    }
+
+    /// Append a timestamped line to the log file, if there is one
+    fn record(&self, message: std::fmt::Arguments) {
+        if let Some(records) = &self.records {
+            let time = daemon::timestamp(std::time::SystemTime::now());
+            let _ = writeln!(records.lock().unwrap(), "{} {}", time, message);
+        }
+    }
+
+    /// Write an event to stdout as a line of JSON
+    fn emit(&self, event: &progress::Event) {
+        if let Ok(line) = serde_json::to_string(event) {
+            let _ = writeln!(io::stdout().lock(), "{}", line);
+        }
+    }
+
+    /// Pass an event on to the reporter set with [`Synchronize::progress_reporter`], or redraw
+    /// the progress on stderr when there isn't one
+    fn forward(&self, event: impl FnOnce(&dyn ProgressReporter)) {
+        match &self.reporter {
+            Some(reporter) => event(reporter.as_ref()),
+            None => self.tick(),
+        }
+    }
+
+    fn start_file(&self, path: &Path, len: u64) -> Arc<FileProgress> {
+        let file = Arc::new(FileProgress {
+            path: path.to_path_buf(),
+            len,
+            copied: AtomicU64::default(),
+            #[cfg(feature = "progress-bars")]
+            bar: self.bars.as_ref().map(|x| x.file(path, len)),
+        });
+        self.active.lock().unwrap().push(file.clone());
+        file
+    }
+
+    fn file_copied(&self, file: &FileProgress, bytes: u64) {
+        file.copied.fetch_add(bytes, Ordering::Relaxed);
+        #[cfg(feature = "progress-bars")]
+        if let Some(bar) = &file.bar {
+            bar.set_position(file.copied());
+        }
+        self.on_file_progress(&file.path, file.copied(), file.len);
+    }
+
+    /// Stop showing `file`, the bytes it copied are counted by [`Progress::on_bytes_copied`]
+    fn finish_file(&self, file: &Arc<FileProgress>) {
+        self.active
+            .lock()
+            .unwrap()
+            .retain(|x| !Arc::ptr_eq(x, file));
+        #[cfg(feature = "progress-bars")]
+        if let Some(bar) = &file.bar {
+            bar.finish_and_clear();
+        }
+    }
+
+    fn report(&self) -> SyncReport {
+        SyncReport {
+            files_scanned: self.paths.load(Ordering::Relaxed),
+            copied: self.paths_copied.load(Ordering::Relaxed),
+            created: self.paths_created.load(Ordering::Relaxed),
+            updated: self.paths_updated.load(Ordering::Relaxed),
+            skipped: self.paths_skipped.load(Ordering::Relaxed),
+            renamed: self.paths_renamed.load(Ordering::Relaxed),
+            deleted: self.paths_deleted.load(Ordering::Relaxed),
+            conflicts: self.paths_conflicted.load(Ordering::Relaxed),
+            reflinked: self.paths_reflinked.load(Ordering::Relaxed),
+            hardlinked: self.paths_hardlinked.load(Ordering::Relaxed),
+            bytes_copied: self.bytes_copied.load(Ordering::Relaxed),
+            errors: self.errors.lock().unwrap().clone(),
+            unsupported: self.unsupported.lock().unwrap().clone(),
+            cancelled: false,
+            duration: self.start.elapsed(),
+        }
+    }
+
+    fn println<S: Borrow<str>>(&self, s: S) {
+        self.record(format_args!("{}", s.borrow()));
+        if let Some(reporter) = &self.reporter {
+            reporter.on_message(s.borrow());
+            return;
+        }
+        if let Some(log) = &self.log {
+            let _ = writeln!(log.lock().unwrap(), "{}", s.borrow());
+            return;
+        }
+        #[cfg(feature = "progress-bars")]
+        if let Some(bars) = &self.bars {
+            bars.println(s.borrow());
+            return;
+        }
+        eprintln!("\r{}", s.borrow());
+        if self.interactive {
+            self.print();
+        }
+    }
+
+    fn tick(&self) {
+        let mut last_tick = self.last_tick.lock().unwrap();
+
+        // Redirected output gets an occasional line instead of a stream of carriage returns
+        let interval = match self.interactive {
+            true => Duration::from_millis(120),
+            false => Duration::from_secs(10),
+        };
+        if last_tick.elapsed() > interval {
+            *last_tick = std::time::Instant::now();
+            self.print();
+        }
+    }
+
+    fn print(&self) {
+        if self.reporter.is_some() {
+            return;
+        }
+        let paths = self.paths.load(Ordering::Relaxed);
+        let paths_copied = self.paths_copied.load(Ordering::Relaxed);
+        let paths_created = self.paths_created.load(Ordering::Relaxed);
+        let paths_updated = self.paths_updated.load(Ordering::Relaxed);
+        let paths_skipped = self.paths_skipped.load(Ordering::Relaxed);
+        let paths_deleted = self.paths_deleted.load(Ordering::Relaxed);
+        let paths_reflinked = self.paths_reflinked.load(Ordering::Relaxed);
+        let paths_hardlinked = self.paths_hardlinked.load(Ordering::Relaxed);
+        let paths_conflicted = self.paths_conflicted.load(Ordering::Relaxed);
+        let paths_renamed = self.paths_renamed.load(Ordering::Relaxed);
+        let active = self.active.lock().unwrap().clone();
+        let in_flight = active.iter().map(|x| x.copied()).sum::<u64>();
+        let bytes_copied = self.bytes_copied.load(Ordering::Relaxed) + in_flight;
+        let elapsed = self.start.elapsed();
+
+        let del = match paths_deleted > 0 {
+            true => format!("Deleted {:?} ", paths_deleted),
+            false => "".to_string(),
+        };
+        let renamed = match paths_renamed > 0 {
+            true => format!("Renamed: {}, ", paths_renamed),
+            false => "".to_string(),
+        };
+        let conflicts = match paths_conflicted > 0 {
+            true => format!("Conflicts: {}, ", paths_conflicted),
+            false => "".to_string(),
+        };
+        let errors = match self.errors.lock().unwrap().len() {
+            0 => "".to_string(),
+            n => format!("Errors: {}, ", n),
+        };
+        let unsupported = match self.unsupported.lock().unwrap().len() {
+            0 => "".to_string(),
+            n => format!("Unsupported: {}, ", n),
+        };
+        let linked = match paths_reflinked + paths_hardlinked > 0 {
+            true => format!(
+                "Reflinked: {}, Hardlinked: {}, ",
+                paths_reflinked, paths_hardlinked
+            ),
+            false => "".to_string(),
+        };
+        #[cfg(feature = "progress-bars")]
+        let active = match self.bars {
+            // Each file has its own bar
+            Some(_) => Vec::new(),
+            None => active,
+        };
+        // The file copying the longest
+        let current = match active.first() {
+            Some(file) => format!(
+                ", {}: {}/{}",
+                file.path.file_name().unwrap_or_default().to_string_lossy(),
+                human_bytes::human_bytes(file.copied() as f64),
+                human_bytes::human_bytes(file.len as f64),
+            ),
+            None => "".to_string(),
+        };
+
+        let line = format!(
+            "Files: {}, Copied: {} (Created: {}, Updated: {}), Skipped: {}, {}{}{}{}{}Transfered {}, {}Elapsed: {:.2?}{} ",
+            paths,
+            paths_copied,
+            paths_created,
+            paths_updated,
+            paths_skipped,
+            renamed,
+            errors,
+            unsupported,
+            conflicts,
+            linked,
+            human_bytes::human_bytes(bytes_copied as f64),
+            del,
+            elapsed,
+            current,
+        );
+        #[cfg(feature = "progress-bars")]
+        if let Some(bars) = &self.bars {
+            bars.set_totals(line.trim_end().to_string());
+            return;
+        }
+        match (&self.log, self.interactive) {
+            (Some(log), _) => {
+                let _ = writeln!(log.lock().unwrap(), "{}", line.trim_end());
+            }
+            (None, true) => eprint!("\r{}", line),
+            (None, false) => eprintln!("{}", line.trim_end()),
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! temp_fs {
+    ($($($dir:ident)/+: $file:expr),+ $(,)?) => {{
+        use std::io::Write;
+        let temp = tempfile::tempdir().unwrap();
+        $(
+            {
+                let path = concat!($(stringify!($dir), "/",)+);
+                let path = temp.path().join(format!("{}.text", &path[0..path.len() - 1]));
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                let mut file = std::fs::File::create(&path).unwrap();
+                file.write_all(&[b'a'; $file]).unwrap();
+            }
+        )+
+        temp
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::Synchronize;
+    use jwalk::WalkDir;
+
+    pub fn paths<P: AsRef<Path>>(walk: WalkDir, rel: P) -> Vec<String> {
+        walk.sort(true)
+            .into_iter()
+            .map(|x| {
+                x.unwrap()
+                    .path()
                     .strip_prefix(&rel)
                     .unwrap()
                     .to_str()
@@ -471,12 +2927,823 @@ mod tests {
             paths,
             vec![
                 "output".to_string(),
+                "output/bar.text".to_string(),
                 "output/baz".to_string(),
                 "output/baz/foo".to_string(),
-                "output/baz/foo/bean.text".to_string(),
                 "output/baz/foo/bar.text".to_string(),
+                "output/baz/foo/bean.text".to_string(),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sync_report_counts() {
+        let temp = temp_fs!(input / bar: 4, input / baz / foo: 2);
+        let sync = || Synchronize::new(temp.path().join("input"), temp.path().join("output"));
+        let report = sync().sync().unwrap();
+        assert_eq!(report.created, 2);
+        assert_eq!(report.bytes_copied, 6);
+        assert!(report.errors.is_empty());
+
+        std::fs::write(temp.path().join("input/bar.text"), b"changed").unwrap();
+        let report = sync().sync().unwrap();
+        assert_eq!((report.created, report.updated), (0, 1));
+        assert_eq!(report.files_scanned, 3);
+        assert_eq!(report.bytes_copied, 7);
+    }
+
+    #[test]
+    fn test_ignore_errors_reports_failed_directories() {
+        let temp = temp_fs!(input / a / x: 1, input / sub / y: 1, output / sub: 1);
+        std::fs::rename(
+            temp.path().join("output/sub.text"),
+            temp.path().join("output/sub"),
+        )
+        .unwrap();
+        let sync =
+            || Synchronize::new(temp.path().join("input"), temp.path().join("output")).delete(true);
+        assert!(sync().sync().is_err());
+
+        let report = sync().ignore_errors(true).sync().unwrap();
+        assert!(temp.path().join("output/a/x.text").exists());
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].path, temp.path().join("input/sub"));
+    }
+
+    #[test]
+    fn test_destination_directory_errors() {
+        let temp = temp_fs!(input / sub / bar: 1, blocked: 1);
+        let input = temp.path().join("input");
+        Synchronize::new(&input, temp.path().join("missing/parents"))
+            .sync()
+            .unwrap();
+        assert!(temp.path().join("missing/parents/sub/bar.text").exists());
+
+        let result = Synchronize::new(&input, temp.path().join("blocked.text/output")).sync();
+        assert!(matches!(
+            result,
+            Err(crate::Error::DestinationUnwritable { .. })
+        ));
+    }
+
+    #[test]
+    fn test_cancelled_sync_stops_early() {
+        let temp = temp_fs!(input / bar: 1, input / sub / baz: 1, output / old: 1);
+        let token = crate::CancelToken::new();
+        token.cancel();
+        let report = Synchronize::new(temp.path().join("input"), temp.path().join("output"))
+            .delete(true)
+            .cancel_token(token.clone())
+            .sync()
+            .unwrap();
+        assert!(report.cancelled);
+        assert_eq!(report.created, 0);
+        assert!(temp.path().join("output/old.text").exists());
+        assert!(!temp.path().join("output/sub").exists());
+    }
+
+    #[test]
+    fn test_resume_skips_journaled_directories() {
+        let temp = temp_fs!(input / bar: 1, input / done / baz: 1, input / todo / foo: 1);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        std::fs::create_dir(&output).unwrap();
+        let journal = crate::journal::Journal::open(
+            &output.join(crate::journal::JOURNAL_FILE),
+            &input,
+            false,
+        )
+        .unwrap();
+        journal.record(Path::new("done")).unwrap();
+        drop(journal);
+
+        let report = Synchronize::new(&input, &output)
+            .resume(true)
+            .sync()
+            .unwrap();
+        assert_eq!(report.created, 2);
+        assert!(!output.join("done/baz.text").exists());
+        assert!(output.join("todo/foo.text").exists());
+        assert!(!output.join(crate::journal::JOURNAL_FILE).exists());
+
+        // Without the journal the next run picks up what was skipped
+        let report = Synchronize::new(&input, &output)
+            .resume(true)
+            .sync()
+            .unwrap();
+        assert_eq!(report.created, 1);
+        assert!(output.join("done/baz.text").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_atomic_writes_replace_files() {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp = temp_fs!(input / bar: 8, output / bar: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let inode = || output.join("bar.text").metadata().unwrap().ino();
+        let before = inode();
+        Synchronize::new(&input, &output).sync().unwrap();
+        assert_ne!(inode(), before);
+        assert_eq!(std::fs::read_dir(&output).unwrap().count(), 1);
+
+        std::fs::write(input.join("bar.text"), b"in place").unwrap();
+        let before = inode();
+        Synchronize::new(&input, &output)
+            .atomic_writes(false)
+            .sync()
+            .unwrap();
+        assert_eq!(inode(), before);
+        assert_eq!(std::fs::read(output.join("bar.text")).unwrap(), b"in place");
+    }
+
+    #[test]
+    fn test_modify_window() {
+        let temp = temp_fs!(input / a: 4, output / a: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        std::fs::write(output.join("a.text"), b"same").unwrap();
+        let time = |secs: i64| filetime::FileTime::from_unix_time(1_700_000_000 + secs, 0);
+        filetime::set_file_mtime(input.join("a.text"), time(0)).unwrap();
+        filetime::set_file_mtime(output.join("a.text"), time(1)).unwrap();
+        Synchronize::new(&input, &output)
+            .modify_window(std::time::Duration::from_secs(2))
+            .sync()
+            .unwrap();
+        assert_eq!(std::fs::read(output.join("a.text")).unwrap(), b"same");
+        Synchronize::new(&input, &output).sync().unwrap();
+        assert_eq!(
+            std::fs::read(output.join("a.text")).unwrap(),
+            std::fs::read(input.join("a.text")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_size_only() {
+        let temp = temp_fs!(input / a: 4, output / a: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        std::fs::write(output.join("a.text"), b"same").unwrap();
+        Synchronize::new(&input, &output)
+            .size_only(true)
+            .sync()
+            .unwrap();
+        assert_eq!(std::fs::read(output.join("a.text")).unwrap(), b"same");
+    }
+
+    #[test]
+    fn test_update_keeps_newer_files() {
+        let temp =
+            temp_fs!(input / newer: 4, input / older: 4, output / newer: 4, output / older: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let time = |secs: i64| filetime::FileTime::from_unix_time(1_700_000_000 + secs, 0);
+        for (name, src, dest) in [("newer.text", 0, 10), ("older.text", 10, 0)] {
+            filetime::set_file_mtime(input.join(name), time(src)).unwrap();
+            filetime::set_file_mtime(output.join(name), time(dest)).unwrap();
+        }
+        let before = std::fs::read(output.join("newer.text")).unwrap();
+        Synchronize::new(&input, &output)
+            .update(true)
+            .sync()
+            .unwrap();
+        assert_eq!(std::fs::read(output.join("newer.text")).unwrap(), before);
+        assert_eq!(
+            std::fs::read(output.join("older.text")).unwrap(),
+            std::fs::read(input.join("older.text")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_ignore_existing_and_existing() {
+        let temp = temp_fs!(input / old: 4, input / new: 4, input / sub / a: 4, output / old: 2);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        Synchronize::new(&input, &output)
+            .existing(true)
+            .sync()
+            .unwrap();
+        assert_eq!(std::fs::read(output.join("old.text")).unwrap().len(), 4);
+        assert!(!output.join("new.text").exists());
+        assert!(!output.join("sub").exists());
+
+        std::fs::write(output.join("old.text"), b"kept").unwrap();
+        std::fs::write(input.join("old.text"), b"changed").unwrap();
+        Synchronize::new(&input, &output)
+            .ignore_existing(true)
+            .sync()
+            .unwrap();
+        assert_eq!(std::fs::read(output.join("old.text")).unwrap(), b"kept");
+        assert!(output.join("new.text").exists());
+        assert!(output.join("sub/a.text").exists());
+    }
+
+    #[test]
+    fn test_preserve_times() {
+        let temp = temp_fs!(input / a: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let src = input.join("a.text");
+        let old = filetime::FileTime::from_unix_time(1_700_000_000, 0);
+        filetime::set_file_times(&src, old, old).unwrap();
+        let mtime = |path: &Path| {
+            filetime::FileTime::from_last_modification_time(&path.metadata().unwrap())
+        };
+        Synchronize::new(&input, &output)
+            .preserve_times(crate::PreserveTimes::None)
+            .sync()
+            .unwrap();
+        assert_ne!(mtime(&output.join("a.text")), old);
+
+        // Reading the source the first time updated its access time
+        filetime::set_file_atime(&src, old).unwrap();
+        Synchronize::new(&input, &output)
+            .preserve_times(crate::PreserveTimes::Mtime)
+            .noatime(true)
+            .sync()
+            .unwrap();
+        let meta = output.join("a.text").metadata().unwrap();
+        assert_eq!(filetime::FileTime::from_last_modification_time(&meta), old);
+        assert_ne!(filetime::FileTime::from_last_access_time(&meta), old);
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            filetime::FileTime::from_last_access_time(&src.metadata().unwrap()),
+            old
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_noatime_chunked_copy() {
+        let temp = temp_fs!(input / a: 64);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let src = input.join("a.text");
+        let old = filetime::FileTime::from_unix_time(1_700_000_000, 0);
+        let data = std::fs::read(&src).unwrap();
+        filetime::set_file_times(&src, old, old).unwrap();
+        Synchronize::new(&input, &output)
+            .chunk_threshold(Some(1))
+            .noatime(true)
+            .sync()
+            .unwrap();
+        assert_eq!(
+            filetime::FileTime::from_last_access_time(&src.metadata().unwrap()),
+            old
+        );
+        assert_eq!(std::fs::read(output.join("a.text")).unwrap(), data);
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let temp =
+            temp_fs!(input / small: 2, input / medium: 8, input / large: 32, output / large: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        Synchronize::new(&input, &output)
+            .min_size(Some(4))
+            .max_size(Some(16))
+            .delete(true)
+            .sync()
+            .unwrap();
+        assert!(!output.join("small.text").exists());
+        assert!(output.join("medium.text").exists());
+        // Files too large are neither updated nor deleted
+        assert_eq!(output.join("large.text").metadata().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_age_limits() {
+        let temp = temp_fs!(input / old: 2, input / recent: 2, input / new: 2);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let ago = |secs: i64| {
+            filetime::FileTime::from_unix_time(filetime::FileTime::now().unix_seconds() - secs, 0)
+        };
+        filetime::set_file_mtime(input.join("old.text"), ago(30 * 86400)).unwrap();
+        filetime::set_file_mtime(input.join("recent.text"), ago(3600)).unwrap();
+        Synchronize::new(&input, &output)
+            .min_age(Some(std::time::Duration::from_secs(60)))
+            .max_age(Some(std::time::Duration::from_secs(7 * 86400)))
+            .sync()
+            .unwrap();
+        assert!(!output.join("old.text").exists());
+        assert!(output.join("recent.text").exists());
+        assert!(!output.join("new.text").exists());
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let temp = temp_fs!(input / a: 2, input / sub / b: 2, input / sub / deep / c: 2, output / sub / old: 2);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        Synchronize::new(&input, &output)
+            .max_depth(Some(2))
+            .delete(true)
+            .sync()
+            .unwrap();
+        assert!(output.join("a.text").exists());
+        assert!(output.join("sub/b.text").exists());
+        assert!(output.join("sub/deep").is_dir());
+        assert!(!output.join("sub/deep/c.text").exists());
+        assert!(!output.join("sub/old.text").exists());
+
+        // Directories at the last level keep what's in them
+        std::fs::write(output.join("sub/deep/kept"), b"").unwrap();
+        Synchronize::new(&input, &output)
+            .max_depth(Some(2))
+            .delete(true)
+            .delete_timing(crate::DeleteTiming::After)
+            .sync()
+            .unwrap();
+        assert!(output.join("sub/deep/kept").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_one_file_system() {
+        let temp = temp_fs!(input / sub / a: 2);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let mut sync = Synchronize::new(&input, &output).one_file_system(true);
+        sync.prepare().unwrap();
+        assert!(!sync.skips_contents(&input.join("sub")));
+        // Proc is a file system of its own on Linux and macOS has devfs at /dev
+        let other = ["/proc", "/dev"]
+            .map(Path::new)
+            .into_iter()
+            .find(|x| x.is_dir());
+        if let Some(other) = other {
+            assert!(sync.skips_contents(other));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_links() {
+        use std::os::unix::fs::symlink;
+
+        let temp = temp_fs!(input / a: 4, input / sub / b: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        symlink("a.text", input.join("file")).unwrap();
+        symlink("sub", input.join("dir")).unwrap();
+        symlink("..", input.join("sub/loop")).unwrap();
+        symlink("missing", input.join("dangling")).unwrap();
+        Synchronize::new(&input, &output)
+            .copy_links(true)
+            .sync()
+            .unwrap();
+        let file = output.join("file").symlink_metadata().unwrap();
+        assert!(file.is_file());
+        assert_eq!(
+            std::fs::read(output.join("file")).unwrap(),
+            std::fs::read(input.join("a.text")).unwrap()
+        );
+        assert!(output.join("dir").symlink_metadata().unwrap().is_dir());
+        assert!(output.join("dir/b.text").is_file());
+        assert!(output.join("sub/loop").symlink_metadata().is_err());
+        assert!(output.join("dangling").symlink_metadata().is_err());
+    }
+
+    #[test]
+    fn test_times_keep_nanoseconds() {
+        let temp = temp_fs!(input / a: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let src = input.join("a.text");
+        let time = filetime::FileTime::from_unix_time(1_700_000_000, 123_456_789);
+        filetime::set_file_mtime(&src, time).unwrap();
+        Synchronize::new(&input, &output).sync().unwrap();
+        let (src_meta, dest_meta) = (
+            src.metadata().unwrap(),
+            output.join("a.text").metadata().unwrap(),
+        );
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&dest_meta),
+            time
+        );
+        if cfg!(any(windows, target_os = "macos")) {
+            assert_eq!(dest_meta.created().unwrap(), src_meta.created().unwrap());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_directory_metadata_is_preserved() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = temp_fs!(input / sub / a: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let sub = input.join("sub");
+        let time = filetime::FileTime::from_unix_time(1_700_000_000, 0);
+        let readonly = std::fs::Permissions::from_mode(0o555);
+        std::fs::set_permissions(&sub, readonly.clone()).unwrap();
+        filetime::set_file_mtime(&sub, time).unwrap();
+        let check = || {
+            let meta = output.join("sub").metadata().unwrap();
+            assert_eq!(filetime::FileTime::from_last_modification_time(&meta), time);
+            assert_eq!(meta.permissions().mode() & 0o777, 0o555);
+        };
+        Synchronize::new(&input, &output).sync().unwrap();
+        check();
+
+        // Files are still written into the readonly directory of the earlier sync
+        std::fs::set_permissions(&sub, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(sub.join("b.text"), b"new").unwrap();
+        std::fs::set_permissions(&sub, readonly).unwrap();
+        filetime::set_file_mtime(&sub, time).unwrap();
+        Synchronize::new(&input, &output).sync().unwrap();
+        assert_eq!(std::fs::read(output.join("sub/b.text")).unwrap(), b"new");
+        check();
+        for dir in [&sub, &output.join("sub")] {
+            std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_partial_dir_resumes_copies() {
+        let temp = temp_fs!(input / big: 4096, input / sub / stale: 16);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let partial = output.join(".fsync-partial");
+        std::fs::create_dir_all(partial.join("sub")).unwrap();
+        std::fs::write(partial.join("big.text"), [b'a'; 1000]).unwrap();
+        std::fs::write(partial.join("sub/stale.text"), b"bbbb").unwrap();
+
+        let report = Synchronize::new(&input, &output)
+            .partial_dir(Some(".fsync-partial"))
+            .delete(true)
+            .sync()
+            .unwrap();
+        // Only the rest of the matching partial copy is written, the stale one starts over
+        assert_eq!(report.bytes_copied, 3096 + 16);
+        assert_eq!(
+            std::fs::read(output.join("big.text")).unwrap(),
+            [b'a'; 4096]
+        );
+        assert_eq!(
+            std::fs::read(output.join("sub/stale.text")).unwrap(),
+            [b'a'; 16]
+        );
+        assert!(!partial.exists());
+    }
+
+    #[test]
+    fn test_backup_overwritten_and_deleted_files() {
+        let temp = temp_fs!(input / bar: 8, output / bar: 4, output / sub / old: 2);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        Synchronize::new(&input, &output)
+            .delete(true)
+            .backup(true)
+            .sync()
+            .unwrap();
+        assert_eq!(std::fs::read(output.join("bar.text")).unwrap(), [b'a'; 8]);
+        assert_eq!(std::fs::read(output.join("bar.text~")).unwrap(), [b'a'; 4]);
+        assert_eq!(
+            std::fs::read(output.join("sub/old.text~")).unwrap(),
+            [b'a'; 2]
+        );
+
+        // The backups aren't in the source but aren't deleted and backed up again
+        let report = Synchronize::new(&input, &output)
+            .delete(true)
+            .backup(true)
+            .sync()
+            .unwrap();
+        assert_eq!(report.deleted, 0);
+        assert_eq!(std::fs::read(output.join("bar.text~")).unwrap(), [b'a'; 4]);
+        assert!(output.join("sub/old.text~").exists());
+        assert!(!output.join("bar.text~~").exists());
+
+        std::fs::write(input.join("bar.text"), b"new").unwrap();
+        Synchronize::new(&input, &output)
+            .delete(true)
+            .backup_dir(Some("backups"))
+            .backup_suffix(Some(".old"))
+            .sync()
+            .unwrap();
+        let backups = output.join("backups");
+        assert_eq!(
+            std::fs::read(backups.join("bar.text.old")).unwrap(),
+            [b'a'; 8]
+        );
+        assert_eq!(
+            std::fs::read(backups.join("bar.text~.old")).unwrap(),
+            [b'a'; 4]
+        );
+        assert!(backups.join("sub/old.text~.old").exists());
+        assert!(!output.join("sub/old.text~").exists());
+    }
+
+    #[test]
+    fn test_move_files_removes_sources() {
+        let temp = temp_fs!(input / new: 4, input / sub / same: 2, output / sub / same: 2);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let sync = |dry_run| {
+            Synchronize::new(&input, &output)
+                .move_files(true)
+                .check_content(true)
+                .dry_run(dry_run)
+                .sync()
+                .unwrap()
+        };
+        sync(true);
+        assert!(input.join("new.text").exists());
+
+        let report = sync(false);
+        assert_eq!((report.created, report.skipped), (1, 3));
+        assert_eq!(std::fs::read(output.join("new.text")).unwrap(), [b'a'; 4]);
+        assert!(output.join("sub/same.text").exists());
+        assert!(!input.join("new.text").exists());
+        assert!(!input.join("sub/same.text").exists());
+        assert!(input.join("sub").is_dir());
+    }
+
+    #[test]
+    fn test_created_and_updated_are_counted_apart() {
+        let temp = temp_fs!(input / old: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let sync = || Synchronize::new(&input, &output).sync().unwrap();
+        sync();
+
+        std::fs::write(input.join("new.text"), b"new").unwrap();
+        std::fs::write(input.join("old.text"), b"changed").unwrap();
+        // A dry run counts what it would create and update too
+        let report = Synchronize::new(&input, &output)
+            .dry_run(true)
+            .sync()
+            .unwrap();
+        assert_eq!((report.created, report.updated), (1, 1));
+        let report = sync();
+        assert_eq!((report.created, report.updated), (1, 1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_require_symlink_target() {
+        let temp = temp_fs!(input / bar: 0);
+        let input = temp.path().join("input");
+        std::os::unix::fs::symlink("bar.text", input.join("inside")).unwrap();
+        std::os::unix::fs::symlink("missing.text", input.join("dangling")).unwrap();
+        std::os::unix::fs::symlink("../input/bar.text", input.join("outside")).unwrap();
+
+        Synchronize::new(&input, temp.path().join("output"))
+            .require_symlink_target(true)
+            .sync()
+            .unwrap();
+        let paths = paths(jwalk::WalkDir::new(temp.path().join("output")), temp.path());
+        assert_eq!(
+            paths,
+            vec![
+                "output".to_string(),
+                "output/bar.text".to_string(),
+                "output/inside".to_string(),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_require_symlink_target_skips_filtered_targets() {
+        let temp = temp_fs!(input / bar: 0, input / secret: 0, input / sub / hidden: 0);
+        let input = temp.path().join("input");
+        std::fs::rename(input.join("sub"), input.join(".sub")).unwrap();
+        std::os::unix::fs::symlink("bar.text", input.join("kept")).unwrap();
+        std::os::unix::fs::symlink("secret.text", input.join("excluded")).unwrap();
+        std::os::unix::fs::symlink(".sub/hidden.text", input.join("hidden")).unwrap();
+
+        Synchronize::new(&input, temp.path().join("output"))
+            .require_symlink_target(true)
+            .exclude("secret.text")
+            .skip_hidden(true)
+            .sync()
+            .unwrap();
+        let paths = paths(jwalk::WalkDir::new(temp.path().join("output")), temp.path());
+        assert_eq!(
+            paths,
+            vec![
+                "output".to_string(),
+                "output/bar.text".to_string(),
+                "output/kept".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sendfile_overwrites_changed_file() {
+        let temp = temp_fs!(input / bar: 64, output / bar: 32);
+        Synchronize::new(temp.path().join("input"), temp.path().join("output"))
+            .check_content(true)
+            .sendfile(true)
+            .sync()
+            .unwrap();
+        let copied = std::fs::read(temp.path().join("output/bar.text")).unwrap();
+        assert_eq!(copied, vec![b'a'; 64]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_strategy_falls_back() {
+        use crate::CopyMethod;
+        use std::os::unix::fs::MetadataExt;
+
+        let temp = temp_fs!(input / bar: 8);
+        let sync =
+            Synchronize::new(temp.path().join("input"), temp.path().join("output")).copy_strategy(
+                vec![CopyMethod::Reflink, CopyMethod::Hardlink, CopyMethod::Copy],
+            );
+        sync.sync().unwrap();
+
+        // Reflinks are unsupported on tmpfs and ext4, so the hardlink should be used there
+        let src = temp.path().join("input/bar.text").metadata().unwrap();
+        let dest = temp.path().join("output/bar.text").metadata().unwrap();
+        assert!(src.ino() == dest.ino() || src.nlink() == 1);
+        assert_eq!(dest.len(), 8);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hardlink_strategy_leaves_source_alone() {
+        use crate::CopyMethod;
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let temp = temp_fs!(input / bar: 8);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let src = input.join("bar.text");
+        std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let before = src.metadata().unwrap();
+        Synchronize::new(&input, &output)
+            .copy_strategy(vec![CopyMethod::Hardlink])
+            .chmod("F600".parse().unwrap())
+            .preserve_times(crate::PreserveTimes::Mtime)
+            .sync()
+            .unwrap();
+        let after = src.metadata().unwrap();
+        assert_eq!(
+            output.join("bar.text").metadata().unwrap().ino(),
+            after.ino()
+        );
+        assert_eq!(after.mode(), before.mode());
+        assert_eq!(after.mtime(), before.mtime());
+    }
+
+    #[test]
+    fn test_dry_run_leaves_destination_untouched() {
+        let temp = temp_fs!(
+            input / bar: 4,
+            input / baz / foo: 4,
+            output / old: 4,
+        );
+        Synchronize::new(temp.path().join("input"), temp.path().join("output"))
+            .delete(true)
+            .dry_run(true)
+            .sync()
+            .unwrap();
+        let paths = paths(jwalk::WalkDir::new(temp.path().join("output")), temp.path());
+        assert_eq!(
+            paths,
+            vec!["output".to_string(), "output/old.text".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_exclude_skips_subtrees() {
+        let temp = temp_fs!(
+            input / bar: 4,
+            input / baz: 4,
+            input / target / foo: 4,
+            output / target / old: 4,
+        );
+        Synchronize::new(temp.path().join("input"), temp.path().join("output"))
+            .exclude("target/**")
+            .exclude("baz.text")
+            .delete(true)
+            .sync()
+            .unwrap();
+        let paths = paths(jwalk::WalkDir::new(temp.path().join("output")), temp.path());
+        assert_eq!(
+            paths,
+            vec![
+                "output".to_string(),
+                "output/bar.text".to_string(),
+                "output/target".to_string(),
+                "output/target/old.text".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remote_sync() {
+        let temp = temp_fs!(
+            input / bar: 4,
+            input / baz / foo: 8,
+            output / bar: 2,
+            output / old: 4,
+        );
+        Synchronize::new(temp.path().join("input"), temp.path().join("output"))
+            .backend(crate::remote::Remote::spawn_local().unwrap())
+            .delete(true)
+            .sync()
+            .unwrap();
+        let paths = paths(jwalk::WalkDir::new(temp.path().join("output")), temp.path());
+        assert_eq!(
+            paths,
+            vec![
+                "output".to_string(),
                 "output/bar.text".to_string(),
+                "output/baz".to_string(),
+                "output/baz/foo.text".to_string(),
             ]
         );
+        let copied = std::fs::read(temp.path().join("output/bar.text")).unwrap();
+        assert_eq!(copied, vec![b'a'; 4]);
+    }
+
+    #[test]
+    fn test_exclude_from_skips_comments() {
+        let sync =
+            Synchronize::new("a", "b").exclude_from("# objects\n*.o\n\n; logs\n*.log\r\n".lines());
+        assert_eq!(sync.exclude, vec!["*.o".to_string(), "*.log".to_string()]);
+    }
+
+    #[test]
+    fn test_progress_reporter_receives_events() {
+        use crate::ProgressReporter;
+        use std::sync::{
+            atomic::{AtomicU64, AtomicUsize, Ordering},
+            Arc,
+        };
+
+        #[derive(Default)]
+        struct Counts(AtomicUsize, AtomicU64, AtomicUsize);
+        impl ProgressReporter for Arc<Counts> {
+            fn on_copied(&self, paths: usize) {
+                self.0.fetch_add(paths, Ordering::Relaxed);
+            }
+            fn on_bytes_copied(&self, bytes: u64) {
+                self.1.fetch_add(bytes, Ordering::Relaxed);
+            }
+            fn on_finish(&self, _report: &crate::SyncReport) {
+                self.2.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let temp = temp_fs!(input / one: 4, input / a / two: 8);
+        let counts = Arc::new(Counts::default());
+        let report = Synchronize::new(temp.path().join("input"), temp.path().join("output"))
+            .progress_reporter(counts.clone())
+            .sync()
+            .unwrap();
+        assert_eq!(counts.0.load(Ordering::Relaxed), report.copied);
+        assert_eq!(counts.1.load(Ordering::Relaxed), 12);
+        assert_eq!(counts.2.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_report_as_json() {
+        let report = super::SyncReport {
+            copied: 2,
+            deleted: 1,
+            bytes_copied: 12,
+            errors: vec![super::FileError {
+                path: "a/b".into(),
+                cause: "denied".to_string(),
+            }],
+            ..Default::default()
+        };
+        let json = serde_json::from_str::<serde_json::Value>(&report.to_json()).unwrap();
+        assert_eq!(json["copied"], 2);
+        assert_eq!(json["bytes_copied"], 12);
+        assert_eq!(json["breakdown"]["deleted"], 1);
+        assert_eq!(json["errors"][0]["path"], "a/b");
+        assert_eq!(json["errors"][0]["cause"], "denied");
+    }
+
+    #[test]
+    fn test_log_file_records_changes() {
+        let temp = temp_fs!(input / a / one: 4, output / old: 2);
+        let log = temp.path().join("sync.log");
+        Synchronize::new(temp.path().join("input"), temp.path().join("output"))
+            .delete(true)
+            .log_file(Some(&log))
+            .sync()
+            .unwrap();
+        let lines = std::fs::read_to_string(&log).unwrap();
+        let lines = lines.lines().map(|x| &x[21..]).collect::<Vec<_>>();
+        assert!(lines[0].starts_with("Syncing "));
+        assert!(lines
+            .iter()
+            .any(|x| x.starts_with("copy ") && x.ends_with(" 4 bytes")));
+        assert!(lines.iter().any(|x| x.starts_with("delete ")));
+        assert!(lines.last().unwrap().starts_with("Finished: copied 2,"));
+    }
+
+    #[test]
+    fn test_large_file_progress() {
+        let progress = super::Progress::default();
+        let file = progress.start_file(Path::new("big"), 100);
+        progress.file_copied(&file, 40);
+        assert_eq!(file.copied(), 40);
+        progress.file_copied(&file, 80);
+        assert_eq!(file.copied(), 100);
+        progress.finish_file(&file);
+        assert!(progress.active.lock().unwrap().is_empty());
+
+        // The bytes of a large file are counted once it's done
+        const SIZE: usize = super::LARGE_FILE as usize + 1;
+        let temp = temp_fs!(input / big: SIZE);
+        let report = Synchronize::new(temp.path().join("input"), temp.path().join("output"))
+            .sync()
+            .unwrap();
+        assert_eq!(report.bytes_copied, SIZE as u64);
     }
 }
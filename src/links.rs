@@ -0,0 +1,224 @@
+//! What symlinks copied to the destination point to
+
+use crate::{normalize_path, Error, ProgressReporter, Result, Synchronize};
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+/// What a copied symlink with an absolute target inside the source points to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AbsoluteLinks {
+    /// The same path, back in the source
+    #[default]
+    Keep,
+    /// The absolute path of the same file in the destination
+    Dest,
+    /// The same file in the destination by a relative path, which also stays right when the
+    /// destination moves
+    Relative,
+}
+
+impl FromStr for AbsoluteLinks {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(AbsoluteLinks::Keep),
+            "dest" => Ok(AbsoluteLinks::Dest),
+            "relative" => Ok(AbsoluteLinks::Relative),
+            _ => Err(Error::Unsupported(format!(
+                "Unknown absolute link rewrite {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// What happens to source symlinks whose target doesn't exist
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DanglingLinks {
+    /// Copy them as they are
+    #[default]
+    Copy,
+    /// Leave them out with a warning
+    Skip,
+    /// Stop the sync, or report an error with [`crate::Synchronize::ignore_errors`]
+    Fail,
+}
+
+impl FromStr for DanglingLinks {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "copy" => Ok(DanglingLinks::Copy),
+            "skip" => Ok(DanglingLinks::Skip),
+            "fail" => Ok(DanglingLinks::Fail),
+            _ => Err(Error::Unsupported(format!(
+                "Unknown dangling symlink policy {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+impl Synchronize {
+    /// The target of the source symlink `link`, or none when it's skipped by
+    /// [`Synchronize::dangling_links`] as the target doesn't exist
+    pub(crate) fn read_link(&self, link: &Path) -> Result<Option<PathBuf>> {
+        let target = fs::read_link(link).map_err(|e| Error::with_path(e, link))?;
+        if self.dangling_links == DanglingLinks::Copy {
+            return Ok(Some(target));
+        }
+        match link.metadata() {
+            Ok(_) => Ok(Some(target)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match self.dangling_links {
+                DanglingLinks::Fail => Err(Error::DanglingSymlink {
+                    path: link.to_path_buf(),
+                    target,
+                }),
+                _ => {
+                    self.progress.println(format!(
+                        "Skipping symlink {:?} -> {:?}: target doesn't exist",
+                        link, target
+                    ));
+                    self.progress.on_skipped(1);
+                    Ok(None)
+                }
+            },
+            Err(e) => Err(Error::with_path(e, link)),
+        }
+    }
+
+    /// The target the copy of the source symlink `link` is given for its `target`, rewritten
+    /// by [`Synchronize::absolute_links`] when it's an absolute path inside the source
+    pub(crate) fn link_target(&self, link: &Path, target: PathBuf) -> PathBuf {
+        if self.absolute_links == AbsoluteLinks::Keep || !target.is_absolute() {
+            return target;
+        }
+        let target = normalize_path(&target);
+        // The source may be given through a symlink or as a relative path
+        let roots = [
+            std::path::absolute(&self.src).map(|x| normalize_path(&x)),
+            fs::canonicalize(&self.src),
+        ];
+        let Some(rel) = roots
+            .into_iter()
+            .flatten()
+            .find_map(|root| target.strip_prefix(root).ok().map(Path::to_path_buf))
+        else {
+            return target;
+        };
+        match self.absolute_links {
+            AbsoluteLinks::Keep => target,
+            AbsoluteLinks::Dest => match std::path::absolute(&self.dest) {
+                Ok(dest) => normalize_path(&dest).join(rel),
+                Err(_) => target,
+            },
+            AbsoluteLinks::Relative => {
+                let dir = link
+                    .strip_prefix(&self.src)
+                    .ok()
+                    .and_then(Path::parent)
+                    .unwrap_or(Path::new(""));
+                let mut out = PathBuf::new();
+                for component in dir.components() {
+                    if let Component::Normal(_) = component {
+                        out.push("..");
+                    }
+                }
+                out.push(rel);
+                match out.as_os_str().is_empty() {
+                    true => PathBuf::from("."),
+                    false => out,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{AbsoluteLinks, DanglingLinks};
+    use crate::{temp_fs, Error, Synchronize};
+    use std::{fs, os::unix::fs::symlink, path::PathBuf};
+
+    #[test]
+    fn test_absolute_links_are_rewritten() {
+        let temp = temp_fs!(input / a: 4, input / sub / b: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        symlink(input.join("a.text"), input.join("sub/link")).unwrap();
+        symlink(&input, input.join("root")).unwrap();
+        symlink("/etc/hosts", input.join("outside")).unwrap();
+        let sync = |links| {
+            Synchronize::new(&input, &output)
+                .absolute_links(links)
+                .sync()
+                .unwrap();
+            ["sub/link", "root", "outside"].map(|x| fs::read_link(output.join(x)).unwrap())
+        };
+        assert_eq!(
+            sync(AbsoluteLinks::Relative),
+            [
+                PathBuf::from("../a.text"),
+                PathBuf::from("."),
+                PathBuf::from("/etc/hosts")
+            ]
+        );
+        fs::remove_dir_all(&output).unwrap();
+        let dest = std::path::absolute(&output).unwrap();
+        assert_eq!(
+            sync(AbsoluteLinks::Dest),
+            [dest.join("a.text"), dest, PathBuf::from("/etc/hosts")]
+        );
+        assert!(output.join("sub/link").is_file());
+    }
+
+    #[test]
+    fn test_dangling_links() {
+        let temp = temp_fs!(input / a: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        symlink("missing", input.join("dangling")).unwrap();
+        symlink("a.text", input.join("link")).unwrap();
+        let sync = |policy| {
+            Synchronize::new(&input, &output)
+                .dangling_links(policy)
+                .sync()
+        };
+        assert!(matches!(
+            sync(DanglingLinks::Fail).unwrap_err(),
+            Error::DanglingSymlink { .. }
+        ));
+        sync(DanglingLinks::Skip).unwrap();
+        assert!(output.join("dangling").symlink_metadata().is_err());
+        assert!(output.join("link").symlink_metadata().is_ok());
+        sync(DanglingLinks::Copy).unwrap();
+        assert_eq!(
+            fs::read_link(output.join("dangling")).unwrap(),
+            PathBuf::from("missing")
+        );
+    }
+
+    #[test]
+    fn test_links_are_compared_by_target() {
+        let temp = temp_fs!(input / a: 4, input / b: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        symlink("a.text", input.join("link")).unwrap();
+        let sync = || Synchronize::new(&input, &output).sync().unwrap();
+        assert_eq!(sync().copied, 3);
+
+        // The root directory is skipped along with the files and the link
+        let report = sync();
+        assert_eq!((report.copied, report.skipped), (0, 4));
+
+        fs::remove_file(input.join("link")).unwrap();
+        symlink("b.text", input.join("link")).unwrap();
+        assert_eq!(sync().copied, 1);
+        assert_eq!(
+            fs::read_link(output.join("link")).unwrap(),
+            PathBuf::from("b.text")
+        );
+    }
+}
@@ -1,67 +1,1284 @@
-use clap::{Arg, ArgAction, Command};
-use fsync::Synchronize;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use clap_complete::Shell;
+use fsync::{
+    daemon, merkle, metrics, profile::Profiles, remote, AbsoluteLinks, CancelToken, Chmod,
+    Comparison, ConflictPolicy, ContentCheck, CopyEngine, CopyMethod, DanglingLinks, DeleteLimit,
+    DeleteTiming, IdMap, Junctions, Metrics, Mismatch, OutputFormat, PreserveTimes, Retention,
+    SchedulingPolicy, Synchronize,
+};
+use std::{
+    ffi::OsString,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Exit code after Ctrl+C, following the shell convention of 128 + SIGINT
+const INTERRUPTED: i32 = 130;
+
+const SOURCE_HELP: &str = "Source directory or sftp://user@host/path";
+const DESTINATION_HELP: &str =
+    "Destination directory, user@host:/path, sftp://, s3:// or webdav:// url";
 
 fn main() {
-    let matches = Command::new("fsync")
+    let args = std::env::args_os().collect::<Vec<_>>();
+    let mut matches = command().get_matches_from(&args);
+    if let Some(full) = with_profile(&args, &matches) {
+        matches = command().get_matches_from(full);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("completions") {
+        let shell = *matches.get_one::<Shell>("shell").unwrap();
+        clap_complete::generate(shell, &mut command(), "fsync", &mut std::io::stdout());
+        return;
+    }
+    if matches.get_flag("server") {
+        if let Err(e) = remote::serve(std::io::stdin().lock(), std::io::stdout().lock()) {
+            eprintln!("{:?}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("manifest") {
+        std::process::exit(manifest(matches));
+    }
+    if let Some(matches) = matches.subcommand_matches("scrub") {
+        std::process::exit(scrub(matches));
+    }
+    if let Some(matches) = matches.subcommand_matches("daemon") {
+        let config = matches.get_one::<String>("config").unwrap();
+        if let Err(e) = daemon::Config::load(config.as_ref()).and_then(|x| x.run()) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let (matches, command) = match matches.subcommand() {
+        Some((name @ ("sync" | "verify" | "diff" | "watch" | "snapshot"), matches)) => {
+            (matches, name)
+        }
+        _ => (&matches, "sync"),
+    };
+
+    if command == "verify" {
+        if let (Some(dir), Some(manifest)) = (
+            matches.get_one::<String>("source"),
+            matches.get_one::<String>("manifest"),
+        ) {
+            let comparisons = manifest_sync(dir, matches).verify_manifest(manifest);
+            std::process::exit(report_stale(
+                comparisons,
+                true,
+                "The files match the manifest",
+            ));
+        }
+    }
+    let (Some(source), Some(destination)) = (
+        matches.get_one::<String>("source"),
+        matches.get_one::<String>("destination"),
+    ) else {
+        eprintln!("The profile doesn't give a source and destination");
+        std::process::exit(2);
+    };
+    let delete = ["delete", "delete-before", "delete-after"]
+        .iter()
+        .any(|x| matches.get_flag(x));
+    let check_content = matches.get_flag("check-content");
+    let dry_run = matches.get_flag("dry-run");
+    let gitignore = matches.get_flag("gitignore");
+    let skip_permissions = matches.get_flag("skip-permissions");
+    let modify_window = *matches.get_one::<u64>("modify-window").unwrap();
+    let times = matches
+        .get_one::<String>("times")
+        .map(|x| x.parse::<PreserveTimes>().unwrap())
+        .unwrap_or_default();
+    let noatime = matches.get_flag("noatime");
+    let size_only = matches.get_flag("size-only");
+    let min_size = matches.get_one::<u64>("min-size").copied();
+    let max_size = matches.get_one::<u64>("max-size").copied();
+    let min_age = matches.get_one::<Duration>("min-age").copied();
+    let max_age = matches.get_one::<Duration>("max-age").copied();
+    let max_depth = matches.get_one::<usize>("max-depth").copied();
+    let copy_links = matches.get_flag("copy-links");
+    let specials = matches.get_flag("specials");
+    let devices = matches.get_flag("devices");
+    let fail_on_unsupported = matches.get_flag("fail-on-unsupported");
+    let verbose = matches.get_flag("verbose");
+    let dangling_links = matches
+        .get_one::<String>("dangling-links")
+        .map(|x| x.parse::<DanglingLinks>().unwrap())
+        .unwrap_or_default();
+    let junctions = matches
+        .get_one::<String>("junctions")
+        .map(|x| x.parse::<Junctions>().unwrap())
+        .unwrap_or_default();
+    let absolute_links = matches
+        .get_one::<String>("absolute-links")
+        .map(|x| x.parse::<AbsoluteLinks>().unwrap())
+        .unwrap_or_default();
+    let one_file_system = matches.get_flag("one-file-system");
+    let update = matches.get_flag("update");
+    let ignore_existing = matches.get_flag("ignore-existing");
+    let existing = matches.get_flag("existing");
+    let fat = matches.get_flag("fat");
+    let require_symlink_target = matches.get_flag("require-symlink-target");
+    let sendfile = matches.get_flag("sendfile");
+    let merkle_tree = matches.get_one::<String>("merkle-tree");
+    let keep_partial = matches.get_flag("keep-partial");
+    let checksum_cache = matches.get_one::<String>("checksum-cache");
+    let delta = matches.get_flag("delta");
+    let preserve_attributes = matches.get_flag("preserve-attributes");
+    let owner = matches.get_flag("owner");
+    let group = matches.get_flag("group");
+    let usermap = matches.get_one::<IdMap>("usermap");
+    let groupmap = matches.get_one::<IdMap>("groupmap");
+    let xattrs = matches.get_flag("xattrs");
+    let acls = matches.get_flag("acls");
+    let store_checksums = matches.get_flag("store-checksums");
+    let copy_strategy = match matches.get_one::<String>("reflink").map(|x| x.as_str()) {
+        Some("always") => vec![CopyMethod::Reflink],
+        Some("auto") => vec![CopyMethod::Reflink, CopyMethod::Copy],
+        Some(_) => vec![CopyMethod::Copy],
+        None => matches
+            .get_many::<String>("copy-strategy")
+            .map(|x| {
+                x.map(|y| y.parse::<CopyMethod>().unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_else(|| vec![CopyMethod::Copy]),
+    };
+    let engine = matches
+        .get_one::<String>("engine")
+        .map(|x| x.parse::<CopyEngine>().unwrap())
+        .unwrap_or_default();
+    let chunk_threshold = matches.get_one::<u64>("chunk-threshold").copied();
+    let schedule = matches
+        .get_one::<String>("schedule")
+        .map(|x| x.parse::<SchedulingPolicy>().unwrap())
+        .unwrap_or_default();
+    let output = match matches.get_flag("itemize-changes") {
+        true => OutputFormat::Itemize,
+        false => matches
+            .get_one::<String>("output")
+            .map(|x| x.parse::<OutputFormat>().unwrap())
+            .unwrap_or_default(),
+    };
+    let json = matches.get_flag("json");
+    let log_file = matches.get_one::<String>("log-file");
+    let webhook = matches.get_one::<String>("webhook");
+    let pre_hook = matches.get_one::<String>("pre-hook");
+    let post_hook = matches.get_one::<String>("post-hook");
+    let ignore_errors = matches.get_flag("ignore-errors");
+    let resume = matches.get_flag("resume");
+    let inplace = matches.get_flag("inplace");
+    let partial_dir = matches.get_one::<String>("partial-dir");
+    let backup = matches.get_flag("backup");
+    let backup_dir = matches.get_one::<String>("backup-dir");
+    let suffix = matches.get_one::<String>("suffix");
+    let trash = matches.get_flag("trash");
+    let remove_source_files = matches.get_flag("remove-source-files");
+    let detect_renames = matches.get_flag("detect-renames");
+    let hard_links = matches.get_flag("hard-links");
+    let max_delete = matches.get_one::<DeleteLimit>("max-delete").copied();
+    let delete_timing = match () {
+        _ if matches.get_flag("delete-before") => DeleteTiming::Before,
+        _ if matches.get_flag("delete-after") => DeleteTiming::After,
+        _ => DeleteTiming::During,
+    };
+    let interactive = matches.get_flag("interactive") && !matches.get_flag("yes");
+    let retries = *matches.get_one::<u32>("retries").unwrap();
+    let retry_delay = *matches.get_one::<u64>("retry-delay").unwrap();
+    let bidirectional = matches.get_flag("bidirectional");
+    let state_file = matches.get_one::<String>("state-file");
+    let conflict = matches
+        .get_one::<String>("conflict")
+        .map(|x| x.parse::<ConflictPolicy>().unwrap())
+        .unwrap_or_default();
+    let threads = matches
+        .get_one::<String>("threads")
+        .and_then(|x| x.parse::<u8>().ok());
+
+    if let Some(tree) = matches.get_one::<String>("verify-merkle") {
+        match merkle::verify(destination.as_ref(), tree.as_ref()) {
+            Ok(changed) if changed.is_empty() => {}
+            Ok(changed) => {
+                for path in changed {
+                    println!("{}", path.display());
+                }
+                std::process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("{:?}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let checksum = matches
+        .get_one::<String>("checksum")
+        .map(|x| x.parse::<ContentCheck>().unwrap());
+
+    let mut sync = Synchronize::new(source, destination)
+        .delete(delete)
+        .dry_run(dry_run)
+        .respect_gitignore(gitignore)
+        .num_threads(threads)
+        .check_content(check_content)
+        .display_progress(true)
+        .skip_permissions(skip_permissions)
+        .modify_window(Duration::from_secs(modify_window))
+        .preserve_times(times)
+        .noatime(noatime)
+        .size_only(size_only)
+        .min_size(min_size)
+        .max_size(max_size)
+        .min_age(min_age)
+        .max_age(max_age)
+        .max_depth(max_depth)
+        .copy_links(copy_links)
+        .absolute_links(absolute_links)
+        .dangling_links(dangling_links)
+        .junctions(junctions)
+        .specials(specials)
+        .devices(devices)
+        .fail_on_unsupported(fail_on_unsupported)
+        .verbose(verbose)
+        .one_file_system(one_file_system)
+        .update(update)
+        .ignore_existing(ignore_existing)
+        .existing(existing)
+        .fat(fat)
+        .require_symlink_target(require_symlink_target)
+        .sendfile(sendfile)
+        .merkle_tree(merkle_tree)
+        .keep_partial(keep_partial)
+        .checksum_cache(checksum_cache)
+        .delta(delta)
+        .copy_strategy(copy_strategy)
+        .copy_engine(engine)
+        .chunk_threshold(chunk_threshold)
+        .scheduling(schedule)
+        .output(output)
+        .log_file(log_file)
+        .webhook(webhook)
+        .pre_hook(pre_hook)
+        .post_hook(post_hook)
+        .preserve_attributes(preserve_attributes)
+        .owner(owner)
+        .group(group)
+        .xattrs(xattrs)
+        .acls(acls)
+        .store_checksums(store_checksums)
+        .ignore_errors(ignore_errors)
+        .resume(resume)
+        .atomic_writes(!inplace)
+        .partial_dir(partial_dir)
+        .backup(backup)
+        .backup_dir(backup_dir)
+        .backup_suffix(suffix)
+        .trash(trash)
+        .max_delete(max_delete)
+        .move_files(remove_source_files)
+        .detect_renames(detect_renames)
+        .hard_links(hard_links)
+        .delete_timing(delete_timing)
+        .retries(retries)
+        .retry_delay(Duration::from_millis(retry_delay))
+        .bidirectional(bidirectional)
+        .state_file(state_file)
+        .conflict_policy(conflict)
+        .remote_shell(matches.get_one::<String>("rsh").unwrap())
+        .remote_command(matches.get_one::<String>("remote-fsync").unwrap());
+    if let Some(checksum) = checksum {
+        sync = sync.content_check(checksum);
+    }
+    for rules in matches.get_many::<Chmod>("chmod").into_iter().flatten() {
+        sync = sync.chmod(rules.clone());
+    }
+    if let Some(map) = usermap {
+        sync = sync.usermap(map.clone());
+    }
+    if let Some(map) = groupmap {
+        sync = sync.groupmap(map.clone());
+    }
+    sync = with_filters(sync, matches);
+    for pattern in matches.get_many::<String>("protect").into_iter().flatten() {
+        sync = sync.protect(pattern);
+    }
+    for dir in matches
+        .get_many::<String>("link-dest")
+        .into_iter()
+        .flatten()
+    {
+        sync = sync.link_dest(dir);
+    }
+    if interactive {
+        sync = sync.confirm_deletes(confirm_deletes);
+    }
+
+    match command {
+        "verify" => std::process::exit(verify(sync, delete)),
+        "diff" => std::process::exit(diff(sync, source, destination, json)),
+        _ => {}
+    }
+
+    // The first Ctrl+C lets files being copied finish, a second one stops straight away
+    let token = CancelToken::new();
+    sync = sync.cancel_token(token.clone());
+    let handler_token = token.clone();
+    let handler = ctrlc::set_handler(move || {
+        if handler_token.is_cancelled() {
+            std::process::exit(INTERRUPTED);
+        }
+        eprintln!("\nInterrupted, finishing files in progress. Press Ctrl+C again to stop now");
+        handler_token.cancel();
+    });
+    if let Err(e) = handler {
+        eprintln!("Failed to handle Ctrl+C: {}", e);
+    }
+
+    let result = match command {
+        "watch" => match matches.get_one::<String>("metrics") {
+            Some(addr) => {
+                let metrics = Metrics::new();
+                metrics::serve(addr, vec![(String::new(), metrics.clone())])
+                    .and_then(|_| sync.metrics(metrics).watch())
+                    .map(|_| None)
+            }
+            None => sync.watch().map(|_| None),
+        },
+        "snapshot" => {
+            let keep = |name| *matches.get_one::<usize>(name).unwrap();
+            let retention = Retention {
+                daily: keep("keep-daily"),
+                weekly: keep("keep-weekly"),
+                monthly: keep("keep-monthly"),
+            };
+            sync.snapshot(retention).map(Some)
+        }
+        _ => sync.sync().map(Some),
+    };
+    if let (true, Ok(Some(report))) = (json, &result) {
+        println!("{}", report.to_json());
+    }
+    match result.map(|x| x.map_or(0, |report| report.errors.len())) {
+        Ok(_) if token.is_cancelled() => std::process::exit(INTERRUPTED),
+        Ok(0) => {}
+        Ok(errors) => {
+            eprintln!("Failed to sync {} paths", errors);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn command() -> Command {
+    Command::new("fsync")
         .arg_required_else_help(true)
         .about("Synchronizes files between two directories")
-        .arg(
-            Arg::new("source")
-                .required(true)
-                .index(1)
-                .help("Source directory"),
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        // Options given by a profile can be given again on the command line
+        .args_override_self(true)
+        .subcommand(
+            Command::new("sync")
+                .about("Sync the source into the destination, the same as fsync <source> <destination>")
+                .args_override_self(true)
+                .args(path_args(DESTINATION_HELP, &["profile"]))
+                .args(sync_args()),
         )
-        .arg(
-            Arg::new("destination")
-                .required(true)
-                .index(2)
-                .help("Destination directory"),
+        .subcommand(
+            Command::new("verify")
+                .about("Check that the destination is an up to date mirror of the source, without changing it")
+                .args_override_self(true)
+                .args(path_args(DESTINATION_HELP, &["profile", "manifest"]))
+                .arg(
+                    Arg::new("manifest")
+                        .long("manifest")
+                        .value_name("FILE")
+                        .conflicts_with("destination")
+                        .help("Check the source against a checksum manifest instead, such as SHA256SUMS"),
+                )
+                .args(sync_args()),
         )
-        .arg(
-            Arg::new("delete")
-                .long("delete")
-                .short('d')
-                .action(ArgAction::SetTrue)
-                .help("Delete files in the destination that are not in the source"),
+        .subcommand(
+            Command::new("diff")
+                .about("List the paths only in one of two trees and the files that differ, without changing either")
+                .args_override_self(true)
+                .args(path_args(DESTINATION_HELP, &["profile"]))
+                .args(sync_args()),
         )
-        .arg(
-            Arg::new("check-content")
-                .long("checkout-content")
-                .short('c')
-                .action(ArgAction::SetTrue)
-                .help("Use checksums to compare files instead of modified time"),
+        .subcommand(
+            Command::new("watch")
+                .about("Sync, then keep syncing paths as they change in the source")
+                .args_override_self(true)
+                .args(path_args(DESTINATION_HELP, &["profile"]))
+                .arg(
+                    Arg::new("metrics")
+                        .long("metrics")
+                        .value_name("ADDR")
+                        .help("Serve Prometheus metrics at /metrics on this address, e.g. 127.0.0.1:9100"),
+                )
+                .args(sync_args()),
         )
-        .arg(
-            Arg::new("skip-permissions")
-                .long("skip-permissions")
-                .action(ArgAction::SetTrue)
-                .help("Skip copying file permissions"),
+        .subcommand(
+            Command::new("snapshot")
+                .about("Sync into a new dated directory, hardlinking files unchanged since the last one")
+                .args_override_self(true)
+                .args(path_args("Local directory holding the snapshots", &["profile"]))
+                .args(retention_args())
+                .args(sync_args()),
+        )
+        .subcommand(
+            Command::new("manifest")
+                .about("Write the checksum of every file below a directory, in the format of sha256sum")
+                .arg(Arg::new("dir").required(true).help("Directory to checksum"))
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .value_name("FILE")
+                        .required(true)
+                        .help("Manifest to write, such as SHA256SUMS"),
+                )
+                .arg(
+                    Arg::new("checksum")
+                        .long("checksum")
+                        .value_name("ALGO")
+                        .value_parser(["sha256", "blake3", "xxh3"])
+                        .default_value("sha256")
+                        .help("Algorithm to checksum files with"),
+                )
+                .args(sync_args().into_iter().filter(|x| {
+                    ["exclude", "exclude-from", "include", "gitignore", "threads"]
+                        .contains(&x.get_id().as_str())
+                })),
         )
+        .subcommand(
+            Command::new("scrub")
+                .about("Hash files again to find ones corrupted since a sync with --store-checksums")
+                .arg(Arg::new("dir").required(true).help("Destination of the earlier syncs"))
+                .args(sync_args().into_iter().filter(|x| {
+                    ["exclude", "exclude-from", "include", "gitignore", "threads"]
+                        .contains(&x.get_id().as_str())
+                })),
+        )
+        .subcommand(
+            Command::new("daemon")
+                .about("Run the sync jobs defined in a config file on their schedules")
+                .arg(
+                    Arg::new("config")
+                        .long("config")
+                        .value_name("FILE")
+                        .required(true)
+                        .help("Toml file with a [[job]] table for each source and destination"),
+                ),
+        )
+        .subcommand(
+            Command::new("completions")
+                .about("Print a script completing fsync's options in a shell")
+                .arg(
+                    Arg::new("shell")
+                        .required(true)
+                        .value_parser(clap::value_parser!(Shell)),
+                ),
+        )
+        .args(path_args(DESTINATION_HELP, &["server", "profile"]))
         .arg(
-            Arg::new("threads")
-                .long("threads")
-                .help("Number of threads to use defaults to rayon default threadpool"),
+            Arg::new("server")
+                .long("server")
+                .action(ArgAction::SetTrue)
+                .hide(true)
+                .help("Serve a remote sync over stdin and stdout"),
         )
-        .get_matches();
+        .args(sync_args())
+}
 
-    let source = matches.get_one::<String>("source").unwrap();
-    let destination = matches.get_one::<String>("destination").unwrap();
-    let delete = matches.get_flag("delete");
-    let check_content = matches.get_flag("check-content");
-    let skip_permissions = matches.get_flag("skip-permissions");
+/// The command line with the options and paths of the profile it names added, exiting when the
+/// profile can't be read
+fn with_profile(args: &[OsString], matches: &ArgMatches) -> Option<Vec<OsString>> {
+    // Options of a subcommand go after its name
+    let (matches, at) = match matches.subcommand() {
+        Some(("sync" | "verify" | "diff" | "watch" | "snapshot", matches)) => (matches, 2),
+        _ => (matches, 1),
+    };
+    let name = matches.get_one::<String>("profile")?;
+    let path = match matches.get_one::<String>("config") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => Profiles::default_path(),
+    };
+    let profile = path
+        .ok_or_else(|| fsync::Error::Config("No config file to read profiles from".into()))
+        .and_then(|path| Profiles::load(&path))
+        .and_then(|profiles| profiles.args(name));
+    let profile = match profile {
+        Ok(profile) => profile,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    // The profile's options come first so the command line overrides them, and its paths
+    // fill in the ones the command line doesn't give. Options set in the environment are
+    // left out, as they override the profile.
+    let mut full = args[..at].to_vec();
+    for (option, value) in profile.options.into_iter().filter(|(x, _)| !in_env(x)) {
+        // A single `--option=value` keeps values like `-old` from being read as options
+        let mut arg = OsString::from(format!("--{}", option));
+        if let Some(value) = value {
+            arg.push("=");
+            arg.push(value);
+        }
+        full.push(arg);
+    }
+    full.extend_from_slice(&args[at..]);
+    let paths = [profile.source, profile.destination];
+    let given = ["source", "destination"]
+        .iter()
+        .filter(|x| matches.get_one::<String>(x).is_some())
+        .count();
+    full.extend(paths.into_iter().skip(given).flatten());
+    Some(full)
+}
+
+/// Print the paths of the destination `sync` would change, returning the exit code: 0 when
+/// it's up to date, 1 when it isn't and 2 when comparing failed. Paths only in the destination
+/// count when they would be deleted.
+fn verify(sync: Synchronize, delete: bool) -> i32 {
+    report_stale(sync.compare(), delete, "The destination is up to date")
+}
+
+/// Print the paths in `comparisons`, with the extra ones only when `extra` is set, returning
+/// the exit code of [`verify`]. `up_to_date` is printed when there are none.
+fn report_stale(comparisons: fsync::Result<Vec<Comparison>>, extra: bool, up_to_date: &str) -> i32 {
+    let comparisons = match comparisons {
+        Ok(comparisons) => comparisons,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    let mut stdout = std::io::stdout().lock();
+    let mut stale = 0;
+    for comparison in comparisons.iter() {
+        let path = display(&comparison.path);
+        let _ = match &comparison.mismatch {
+            Mismatch::Missing => writeln!(stdout, "missing  {}", path),
+            Mismatch::Extra if extra => writeln!(stdout, "extra    {}", path),
+            Mismatch::Extra => continue,
+            Mismatch::Differs(reasons) => {
+                let reasons = reasons.iter().map(|x| x.name()).collect::<Vec<_>>();
+                writeln!(stdout, "differs  {} ({})", path, reasons.join(", "))
+            }
+        };
+        stale += 1;
+    }
+    match stale {
+        0 => {
+            eprintln!("{}", up_to_date);
+            0
+        }
+        _ => {
+            eprintln!("{} paths are out of date", stale);
+            1
+        }
+    }
+}
+
+/// Print the paths only in the `source` tree or the `destination` one and the ones that differ
+/// in both, as text or JSON. Returns the exit code: 0 when the trees are the same, 1 when they
+/// aren't and 2 when comparing failed.
+fn diff(sync: Synchronize, source: &str, destination: &str, json: bool) -> i32 {
+    let comparisons = match sync.compare() {
+        Ok(comparisons) => comparisons,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    let mut stdout = std::io::stdout().lock();
+    if json {
+        let paths = |mismatch: Mismatch| {
+            comparisons
+                .iter()
+                .filter(|x| x.mismatch == mismatch)
+                .map(|x| x.path.to_string_lossy())
+                .collect::<Vec<_>>()
+        };
+        let differs = comparisons.iter().filter_map(|x| match &x.mismatch {
+            Mismatch::Differs(reasons) => Some(serde_json::json!({
+                "path": x.path.to_string_lossy(),
+                "reasons": reasons.iter().map(|x| x.name()).collect::<Vec<_>>(),
+            })),
+            _ => None,
+        });
+        let report = serde_json::json!({
+            "only_in_a": paths(Mismatch::Missing),
+            "only_in_b": paths(Mismatch::Extra),
+            "differs": differs.collect::<Vec<_>>(),
+        });
+        let _ = writeln!(stdout, "{}", report);
+    } else {
+        for comparison in comparisons.iter() {
+            let path = display(&comparison.path);
+            let _ = match &comparison.mismatch {
+                Mismatch::Missing => writeln!(stdout, "Only in {}: {}", source, path),
+                Mismatch::Extra => writeln!(stdout, "Only in {}: {}", destination, path),
+                Mismatch::Differs(reasons) => {
+                    let reasons = reasons.iter().map(|x| x.name()).collect::<Vec<_>>();
+                    writeln!(stdout, "Differs: {} ({})", path, reasons.join(", "))
+                }
+            };
+        }
+    }
+    (!comparisons.is_empty()) as i32
+}
+
+/// Write the checksum manifest the `manifest` subcommand asks for, returning the exit code
+fn manifest(matches: &ArgMatches) -> i32 {
+    let dir = matches.get_one::<String>("dir").unwrap();
+    let output = matches.get_one::<String>("output").unwrap();
+    match manifest_sync(dir, matches).manifest(output) {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+/// Print the files below the directory the `scrub` subcommand is given that no longer match
+/// their stored checksums, returning 0 when there are none, 1 when there are and 2 when
+/// checking failed
+fn scrub(matches: &ArgMatches) -> i32 {
+    let dir = matches.get_one::<String>("dir").unwrap();
+    let corrupt = match manifest_sync(dir, matches).scrub() {
+        Ok(corrupt) => corrupt,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    for comparison in corrupt.iter() {
+        println!("corrupt  {}", comparison.path.display());
+    }
+    match corrupt.len() {
+        0 => {
+            eprintln!("Every file matches its stored checksum");
+            0
+        }
+        n => {
+            eprintln!("{} files are corrupt", n);
+            1
+        }
+    }
+}
+
+/// A sync of `dir` for making or checking a manifest or scrubbing, with the threads, checksum and
+/// filters given on the command line
+fn manifest_sync(dir: &str, matches: &ArgMatches) -> Synchronize {
     let threads = matches
         .get_one::<String>("threads")
         .and_then(|x| x.parse::<u8>().ok());
-
-    let sync = Synchronize::new(source, destination)
-        .delete(delete)
+    let mut sync = Synchronize::new(dir, dir)
+        .respect_gitignore(matches.get_flag("gitignore"))
         .num_threads(threads)
-        .check_content(check_content)
-        .display_progress(true)
-        .skip_permissions(skip_permissions);
+        .display_progress(true);
+    // Scrubbing always hashes with sha256, so it takes no checksum
+    if let Some(check) = matches.try_get_one::<String>("checksum").ok().flatten() {
+        sync = sync.content_check(check.parse::<ContentCheck>().unwrap());
+    }
+    with_filters(sync, matches)
+}
 
-    match sync.sync() {
-        Ok(_) => {}
-        Err(e) => eprintln!("{:?}", e),
+/// `sync` with the exclude and include patterns given on the command line, exiting when an
+/// exclude file can't be read
+fn with_filters(mut sync: Synchronize, matches: &ArgMatches) -> Synchronize {
+    for pattern in matches.get_many::<String>("exclude").into_iter().flatten() {
+        sync = sync.exclude(pattern);
+    }
+    for path in matches
+        .get_many::<String>("exclude-from")
+        .into_iter()
+        .flatten()
+    {
+        match std::fs::read_to_string(path) {
+            Ok(patterns) => sync = sync.exclude_from(patterns.lines()),
+            Err(e) => {
+                eprintln!("Failed to read exclude file {:?}: {:?}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    for pattern in matches.get_many::<String>("include").into_iter().flatten() {
+        sync = sync.include(pattern);
+    }
+    sync
+}
+
+/// A path relative to the trees being compared, where an empty one is the root
+fn display(path: &Path) -> std::path::Display<'_> {
+    match path.as_os_str().is_empty() {
+        true => Path::new(".").display(),
+        false => path.display(),
+    }
+}
+
+/// List the paths a sync would delete and ask whether to go ahead
+fn confirm_deletes(paths: &[PathBuf]) -> bool {
+    eprintln!("The sync will delete:");
+    for path in paths {
+        eprintln!("  {}", path.display());
+    }
+    eprint!("Delete {} paths? [y/N] ", paths.len());
+    let mut answer = String::new();
+    match std::io::stdin().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim(), "y" | "Y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+/// Parse a number of bytes with an optional K, M, G or T suffix in powers of 1024
+fn parse_size(value: &str) -> Result<u64, String> {
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let shift = match value[digits.len()..].to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        unit => return Err(format!("unknown size unit {:?}", unit)),
+    };
+    let number = digits.parse::<u64>().map_err(|e| e.to_string())?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("{} is too large", value))
+}
+
+/// How many snapshots the snapshot command keeps
+fn retention_args() -> Vec<Arg> {
+    [
+        ("keep-daily", "days"),
+        ("keep-weekly", "weeks"),
+        ("keep-monthly", "months"),
+    ]
+    .map(|(name, period)| {
+        Arg::new(name)
+            .long(name)
+            .value_name("N")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("0")
+            .help(format!(
+                "Keep the newest snapshot of each of the last N {} with one",
+                period
+            ))
+    })
+    .into()
+}
+
+/// The source and destination, needed unless one of `unless` is given, and the profile that
+/// can give them instead
+fn path_args(destination_help: &'static str, unless: &[&'static str]) -> Vec<Arg> {
+    vec![
+        Arg::new("source")
+            .required_unless_present_any(unless.to_vec())
+            .index(1)
+            .help(SOURCE_HELP),
+        Arg::new("destination")
+            .required_unless_present_any(unless.to_vec())
+            .index(2)
+            .help(destination_help),
+        Arg::new("profile")
+            .long("profile")
+            .value_name("NAME")
+            .help("Take the paths and options from a profile in the config file"),
+        Arg::new("config")
+            .long("config")
+            .value_name("FILE")
+            .requires("profile")
+            .help("Toml file with a [profile.NAME] table for each profile [default: ~/.config/fsync/config.toml]"),
+    ]
+}
+
+/// Options shared by a one-off sync, watch mode and snapshots
+fn sync_args() -> Vec<Arg> {
+    [
+        Arg::new("delete")
+            .long("delete")
+            .short('d')
+            .action(ArgAction::SetTrue)
+            .help("Delete files in the destination that are not in the source"),
+        Arg::new("remove-source-files")
+            .long("remove-source-files")
+            .action(ArgAction::SetTrue)
+            .help("Remove source files once the destination matches them, moving them"),
+        Arg::new("detect-renames")
+            .long("detect-renames")
+            .action(ArgAction::SetTrue)
+            .help("Rename files --delete would remove into place when they moved in the source"),
+        Arg::new("delete-before")
+            .long("delete-before")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("delete-after")
+            .help("Delete before copying anything, implies --delete"),
+        Arg::new("delete-after")
+            .long("delete-after")
+            .action(ArgAction::SetTrue)
+            .help("Delete once everything has been copied, implies --delete"),
+        Arg::new("interactive")
+            .long("interactive")
+            .short('i')
+            .action(ArgAction::SetTrue)
+            .help("List the paths --delete would remove and ask before changing anything"),
+        Arg::new("yes")
+            .long("yes")
+            .short('y')
+            .action(ArgAction::SetTrue)
+            .help("Answer yes to --interactive prompts, for scripts"),
+        Arg::new("dry-run")
+            .long("dry-run")
+            .short('n')
+            .action(ArgAction::SetTrue)
+            .help("Report what would be copied and deleted without making changes"),
+        Arg::new("exclude")
+            .long("exclude")
+            .value_name("PATTERN")
+            .action(ArgAction::Append)
+            .help("Skip paths matching a glob pattern, e.g. '*.o' or 'target/**'"),
+        Arg::new("protect")
+            .long("protect")
+            .value_name("PATTERN")
+            .action(ArgAction::Append)
+            .help("Never delete destination paths matching a glob pattern, e.g. 'logs/**'"),
+        Arg::new("exclude-from")
+            .long("exclude-from")
+            .value_name("FILE")
+            .action(ArgAction::Append)
+            .help("Read exclude patterns from a file, one per line"),
+        Arg::new("include")
+            .long("include")
+            .value_name("PATTERN")
+            .action(ArgAction::Append)
+            .help("Only sync files matching a glob pattern"),
+        Arg::new("gitignore")
+            .long("gitignore")
+            .action(ArgAction::SetTrue)
+            .help("Skip paths ignored by .gitignore files in the source"),
+        Arg::new("check-content")
+            .long("checkout-content")
+            .short('c')
+            .action(ArgAction::SetTrue)
+            .help("Use checksums to compare files instead of modified time"),
+        Arg::new("checksum")
+            .long("checksum")
+            .value_name("ALGO")
+            .value_parser(["bytes", "xxh3", "blake3", "sha256"])
+            .help("Compare file contents with this algorithm instead of modified time"),
+        Arg::new("checksum-cache")
+            .long("checksum-cache")
+            .value_name("FILE")
+            .help("Cache checksums between runs, only rehashing files that changed"),
+        Arg::new("delta")
+            .long("delta")
+            .action(ArgAction::SetTrue)
+            .help("Only write the changed blocks of files that already exist"),
+        Arg::new("skip-permissions")
+            .long("skip-permissions")
+            .action(ArgAction::SetTrue)
+            .help("Skip copying file permissions"),
+        Arg::new("modify-window")
+            .long("modify-window")
+            .value_name("SECS")
+            .value_parser(clap::value_parser!(u64))
+            .default_value("0")
+            .help("Treat modified times this many seconds apart as the same, e.g. 2 for FAT"),
+        Arg::new("times")
+            .long("times")
+            .value_name("TIMES")
+            .value_parser(["all", "mtime", "none"])
+            .default_value("all")
+            .help("Times of the source copies get: modified and access, only modified, or none"),
+        Arg::new("noatime")
+            .long("noatime")
+            .action(ArgAction::SetTrue)
+            .help("Read source files without updating their access times, where allowed"),
+        Arg::new("min-size")
+            .long("min-size")
+            .value_name("SIZE")
+            .value_parser(parse_size)
+            .help("Skip files smaller than this, e.g. 100K"),
+        Arg::new("max-size")
+            .long("max-size")
+            .value_name("SIZE")
+            .value_parser(parse_size)
+            .help("Skip files larger than this, e.g. 4G"),
+        Arg::new("min-age")
+            .long("min-age")
+            .value_name("AGE")
+            .value_parser(daemon::parse_duration)
+            .help("Skip files modified less than this long ago, e.g. 1m for files still written"),
+        Arg::new("max-age")
+            .long("max-age")
+            .value_name("AGE")
+            .value_parser(daemon::parse_duration)
+            .help("Skip files modified more than this long ago, e.g. 7d"),
+        Arg::new("max-depth")
+            .long("max-depth")
+            .value_name("N")
+            .value_parser(clap::value_parser!(usize))
+            .help("Only sync this many levels below the source"),
+        Arg::new("copy-links")
+            .long("copy-links")
+            .short('L')
+            .action(ArgAction::SetTrue)
+            .help("Copy the files and directories symlinks point to instead of the symlinks"),
+        Arg::new("specials")
+            .long("specials")
+            .action(ArgAction::SetTrue)
+            .help("Recreate named pipes"),
+        Arg::new("devices")
+            .long("devices")
+            .action(ArgAction::SetTrue)
+            .help("Recreate block and character devices, which needs root"),
+        Arg::new("fail-on-unsupported")
+            .long("fail-on-unsupported")
+            .action(ArgAction::SetTrue)
+            .help("Fail on source paths of unsupported types, such as sockets, instead of skipping them"),
+        Arg::new("verbose")
+            .long("verbose")
+            .short('v')
+            .action(ArgAction::SetTrue)
+            .help("Print each path skipped as its type isn't supported"),
+        Arg::new("absolute-links")
+            .long("absolute-links")
+            .value_name("TARGET")
+            .value_parser(["keep", "dest", "relative"])
+            .default_value("keep")
+            .help("Rewrite absolute symlinks into the source to point into the destination"),
+        Arg::new("dangling-links")
+            .long("dangling-links")
+            .value_name("POLICY")
+            .value_parser(["copy", "skip", "fail"])
+            .default_value("copy")
+            .help("Copy, skip or fail on source symlinks whose target doesn't exist"),
+        Arg::new("junctions")
+            .long("junctions")
+            .value_name("HANDLING")
+            .value_parser(["recreate", "follow", "skip"])
+            .default_value("recreate")
+            .help("Recreate, follow or skip NTFS junctions in the source on Windows"),
+        Arg::new("one-file-system")
+            .long("one-file-system")
+            .short('x')
+            .action(ArgAction::SetTrue)
+            .help("Don't descend into directories on other file systems than the source"),
+        Arg::new("size-only")
+            .long("size-only")
+            .action(ArgAction::SetTrue)
+            .help("Skip files whose size matches the destination, whatever their modified time"),
+        Arg::new("update")
+            .long("update")
+            .short('u')
+            .action(ArgAction::SetTrue)
+            .help("Skip files modified later in the destination than in the source"),
+        Arg::new("ignore-existing")
+            .long("ignore-existing")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("existing")
+            .help("Only create files missing from the destination, leaving existing ones alone"),
+        Arg::new("existing")
+            .long("existing")
+            .action(ArgAction::SetTrue)
+            .help("Only update files already in the destination, without creating new ones"),
+        Arg::new("fat")
+            .long("fat")
+            .action(ArgAction::SetTrue)
+            .help("Sync to FAT or exFAT without permissions or symlinks, renaming invalid names"),
+        Arg::new("require-symlink-target")
+            .long("require-symlink-target")
+            .action(ArgAction::SetTrue)
+            .help("Skip symlinks whose target is not inside the destination"),
+        Arg::new("sendfile")
+            .long("sendfile")
+            .action(ArgAction::SetTrue)
+            .help("Copy with sendfile, reusing the descriptor opened to check content (Linux)"),
+        Arg::new("merkle-tree")
+            .long("merkle-tree")
+            .value_name("FILE")
+            .help("Store a merkle tree of the destination after syncing"),
+        Arg::new("verify-merkle")
+            .long("verify-merkle")
+            .value_name("FILE")
+            .help("Verify the destination against a stored merkle tree instead of syncing"),
+        Arg::new("keep-partial")
+            .long("keep-partial")
+            .action(ArgAction::SetTrue)
+            .help("Keep partially copied files when a copy fails"),
+        Arg::new("copy-strategy")
+            .long("copy-strategy")
+            .value_delimiter(',')
+            .value_parser(["reflink", "hardlink", "copy"])
+            .help("Comma separated copy methods to try in order, defaults to copy"),
+        Arg::new("engine")
+            .long("engine")
+            .value_name("ENGINE")
+            .value_parser(["std", "io-uring"])
+            .help("How copies read and write data, io-uring batches them on Linux"),
+        Arg::new("chunk-threshold")
+            .long("chunk-threshold")
+            .value_name("SIZE")
+            .value_parser(parse_size)
+            .help("Copy files of at least SIZE, e.g. 1G, in chunks on several threads at once"),
+        Arg::new("schedule")
+            .long("schedule")
+            .value_name("POLICY")
+            .value_parser(["walk", "smallest-first", "largest-first"])
+            .help("Order files are copied in, defaults to the order they're found in"),
+        Arg::new("output")
+            .long("output")
+            .value_name("FORMAT")
+            .value_parser(["text", "ndjson", "itemize"])
+            .help("With ndjson, print a JSON object per change and error on stdout"),
+        Arg::new("itemize-changes")
+            .long("itemize-changes")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("output")
+            .help("Print a line per changed path with a code for what changed, like rsync -i"),
+        Arg::new("log-file")
+            .long("log-file")
+            .value_name("PATH")
+            .help("Append a timestamped record of every change and error to PATH"),
+        Arg::new("webhook")
+            .long("webhook")
+            .value_name("URL")
+            .help("Post the outcome of the sync as JSON to URL once it finishes or fails"),
+        Arg::new("pre-hook")
+            .long("pre-hook")
+            .value_name("COMMAND")
+            .help("Run COMMAND through the shell before syncing, not syncing when it fails"),
+        Arg::new("post-hook")
+            .long("post-hook")
+            .value_name("COMMAND")
+            .help("Run COMMAND through the shell after syncing, with the outcome in FSYNC_* variables"),
+        Arg::new("json")
+            .long("json")
+            .action(ArgAction::SetTrue)
+            .help("Print a summary of the sync as JSON on stdout once it's done"),
+        Arg::new("reflink")
+            .long("reflink")
+            .value_name("WHEN")
+            .value_parser(["auto", "always", "never"])
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("auto")
+            .conflicts_with("copy-strategy")
+            .help("Clone files copy-on-write on btrfs, XFS and APFS, auto falls back to copying"),
+        Arg::new("hard-links")
+            .long("hard-links")
+            .short('H')
+            .action(ArgAction::SetTrue)
+            .help("Recreate hardlinks between source files instead of copying each link"),
+        Arg::new("link-dest")
+            .long("link-dest")
+            .value_name("DIR")
+            .action(ArgAction::Append)
+            .help("Hardlink unchanged files to DIR, relative to the destination, instead of copying them"),
+        Arg::new("inplace")
+            .long("inplace")
+            .action(ArgAction::SetTrue)
+            .help("Write files in place instead of renaming a finished temporary copy over them"),
+        Arg::new("partial-dir")
+            .long("partial-dir")
+            .value_name("DIR")
+            .num_args(0..=1)
+            .default_missing_value(".fsync-partial")
+            .help("Keep interrupted copies in DIR, relative to the destination, to continue them on the next run"),
+        Arg::new("backup")
+            .long("backup")
+            .short('b')
+            .action(ArgAction::SetTrue)
+            .help("Keep destination files that are overwritten or deleted, renamed with a suffix"),
+        Arg::new("backup-dir")
+            .long("backup-dir")
+            .value_name("DIR")
+            .help("Move backups into DIR, relative to the destination, implies --backup"),
+        Arg::new("suffix")
+            .long("suffix")
+            .value_name("SUFFIX")
+            .help("Suffix of backups, defaults to ~ without --backup-dir"),
+        Arg::new("trash")
+            .long("trash")
+            .action(ArgAction::SetTrue)
+            .help("Move files removed by --delete to the system trash"),
+        Arg::new("max-delete")
+            .long("max-delete")
+            .value_name("N[%]")
+            .value_parser(|x: &str| x.parse::<DeleteLimit>())
+            .help("Abort before deleting anything when --delete would remove more files than this"),
+        Arg::new("preserve-attributes")
+            .long("preserve-attributes")
+            .action(ArgAction::SetTrue)
+            .help("Preserve hidden, system, archive and readonly attributes (Windows)"),
+        Arg::new("owner")
+            .long("owner")
+            .action(ArgAction::SetTrue)
+            .help("Give destination paths the owner of the source's, which usually needs root"),
+        Arg::new("group")
+            .long("group")
+            .action(ArgAction::SetTrue)
+            .help("Give destination paths the group of the source's"),
+        Arg::new("usermap")
+            .long("usermap")
+            .value_name("RULES")
+            .value_parser(IdMap::users)
+            .help("Translate owners with comma separated FROM:TO rules, e.g. 1000:2000,www-data:nginx or *:backup"),
+        Arg::new("groupmap")
+            .long("groupmap")
+            .value_name("RULES")
+            .value_parser(IdMap::groups)
+            .help("Translate groups with comma separated FROM:TO rules like --usermap"),
+        Arg::new("chmod")
+            .long("chmod")
+            .value_name("RULES")
+            .action(ArgAction::Append)
+            .value_parser(|x: &str| x.parse::<Chmod>())
+            .help("Rewrite copied permissions with rules like rsync's, e.g. D755,F644 or Fgo-w"),
+        Arg::new("xattrs")
+            .long("xattrs")
+            .action(ArgAction::SetTrue)
+            .help("Copy the user and trusted extended attributes of files and directories (Linux, macOS)"),
+        Arg::new("acls")
+            .long("acls")
+            .action(ArgAction::SetTrue)
+            .help("Copy the POSIX ACLs of files and directories (Linux)"),
+        Arg::new("store-checksums")
+            .long("store-checksums")
+            .action(ArgAction::SetTrue)
+            .help("Store the sha256 of each copied file in its user.fsync.sha256 xattr, for fsync scrub"),
+        Arg::new("ignore-errors")
+            .long("ignore-errors")
+            .action(ArgAction::SetTrue)
+            .help("Keep going when a directory fails to sync, exiting non-zero at the end"),
+        Arg::new("resume")
+            .long("resume")
+            .action(ArgAction::SetTrue)
+            .help("Skip directories an interrupted sync to the same destination finished"),
+        Arg::new("retries")
+            .long("retries")
+            .value_name("N")
+            .value_parser(clap::value_parser!(u32))
+            .default_value("0")
+            .help("Retry operations failing with transient errors such as EBUSY this many times"),
+        Arg::new("retry-delay")
+            .long("retry-delay")
+            .value_name("MS")
+            .value_parser(clap::value_parser!(u64))
+            .default_value("100")
+            .help("Milliseconds before the first retry, doubling with each attempt"),
+        Arg::new("bidirectional")
+            .long("bidirectional")
+            .action(ArgAction::SetTrue)
+            .help("Propagate changes and deletions made on either side to the other"),
+        Arg::new("state-file")
+            .long("state-file")
+            .value_name("FILE")
+            .help("State of the previous bidirectional sync, defaults to DEST/.fsync-bisync"),
+        Arg::new("conflict")
+            .long("conflict")
+            .value_name("POLICY")
+            .value_parser(["newest", "source", "keep-both", "fail"])
+            .help("Resolve paths changed on both sides of a bidirectional sync, defaults to keep-both"),
+        Arg::new("rsh")
+            .long("rsh")
+            .short('e')
+            .value_name("COMMAND")
+            .default_value("ssh")
+            .help("Remote shell used for user@host:/path destinations"),
+        Arg::new("remote-fsync")
+            .long("remote-fsync")
+            .value_name("PATH")
+            .default_value("fsync")
+            .help("Path of fsync on the remote host"),
+        Arg::new("threads")
+            .long("threads")
+            .help("Number of threads to use defaults to rayon default threadpool"),
+    ]
+    .into_iter()
+    .map(with_env)
+    .collect()
+}
+
+/// Let `arg` be given by an environment variable named after it, such as `FSYNC_DRY_RUN` for
+/// `--dry-run`, which the command line overrides and which overrides profiles. Flags take
+/// `1`, `yes` or `on` as well as `true`, and their opposites.
+fn with_env(arg: Arg) -> Arg {
+    let Some(long) = arg.get_long() else {
+        return arg;
+    };
+    let name = format!("FSYNC_{}", long.to_uppercase().replace('-', "_"));
+    match arg.get_action() {
+        ArgAction::SetTrue => arg
+            .env(name)
+            .value_parser(clap::builder::BoolishValueParser::new()),
+        _ => arg.env(name),
+    }
+}
+
+/// Whether the option `--long` of the sync is given by its environment variable
+fn in_env(long: &str) -> bool {
+    command()
+        .get_arguments()
+        .find(|x| x.get_long() == Some(long))
+        .and_then(|x| x.get_env())
+        .is_some_and(|x| std::env::var_os(x).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{command, with_profile};
+    use std::ffi::OsString;
+
+    #[test]
+    fn test_options_from_the_environment() {
+        let parse = |args: &[&str]| command().try_get_matches_from(args);
+        std::env::set_var("FSYNC_DRY_RUN", "1");
+        std::env::set_var("FSYNC_THREADS", "3");
+        let matches = parse(&["fsync", "a", "b"]).unwrap();
+        assert!(matches.get_flag("dry-run"));
+        assert_eq!(matches.get_one::<String>("threads").unwrap(), "3");
+        // The command line overrides the environment
+        let matches = parse(&["fsync", "--threads", "5", "a", "b"]).unwrap();
+        assert_eq!(matches.get_one::<String>("threads").unwrap(), "5");
+
+        std::env::set_var("FSYNC_DRY_RUN", "off");
+        assert!(!parse(&["fsync", "a", "b"]).unwrap().get_flag("dry-run"));
+        std::env::remove_var("FSYNC_DRY_RUN");
+        std::env::remove_var("FSYNC_THREADS");
+    }
+
+    #[test]
+    fn test_profile_options_are_parsed() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = temp.path().join("config.toml");
+        std::fs::write(
+            &config,
+            "[profile.x]\nsource = \"src\"\ndestination = \"dst\"\nreflink = \"never\"\nsuffix = \"-old\"\n",
+        )
+        .unwrap();
+        let args = ["fsync", "--profile", "x", "--config"]
+            .into_iter()
+            .map(OsString::from)
+            .chain([config.into_os_string()])
+            .collect::<Vec<_>>();
+        let full = with_profile(&args, &command().get_matches_from(&args)).unwrap();
+        let matches = command().try_get_matches_from(full).unwrap();
+        let value = |id| matches.get_one::<String>(id).map(String::as_str);
+        assert_eq!(value("reflink"), Some("never"));
+        assert_eq!(value("suffix"), Some("-old"));
+        assert_eq!(value("destination"), Some("dst"));
     }
 }
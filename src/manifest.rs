@@ -0,0 +1,311 @@
+//! Checksum manifests of a tree in the format of `sha256sum` and similar tools, so a mirror
+//! can be checked later by fsync or anything else that reads them
+
+use crate::{
+    encoding::{from_hex, invalid_data, os_bytes, os_from_bytes, to_hex, unescape, write_escaped},
+    sftp, Comparison, ContentCheck, Error, Mismatch, ProgressReporter, Reason, Result, Synchronize,
+};
+use rayon::{prelude::*, ThreadPool};
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    fs,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+impl Synchronize {
+    /// Write a checksum manifest of the source to `out`, with a line for each file of its
+    /// digest in hex, two spaces and its path relative to the source. Files are hashed on the
+    /// sync's threads with its content check, sha256 unless another algorithm is set, and the
+    /// paths its filters exclude are left out. Returns the number of files in the manifest.
+    pub fn manifest(mut self, out: impl AsRef<Path>) -> Result<usize> {
+        let out = out.as_ref();
+        let check = self.prepare_manifest()?;
+        let pool = Arc::new(self.get_thread_pool()?);
+        let sync = Arc::new(self);
+        let files = sync.manifest_files(&pool, Some(out))?;
+        let digests = sync.digests(&pool, check, &files)?;
+        let mut writer =
+            BufWriter::new(fs::File::create(out).map_err(|e| Error::with_path(e, out))?);
+        let mut written = 0;
+        // Files deleted since the walk are left out
+        for (path, digest) in files.iter().zip(digests) {
+            let Some(digest) = digest else { continue };
+            let rel = path
+                .strip_prefix(&sync.src)
+                .expect("walked below the source");
+            write_line(&mut writer, &digest, &manifest_name(rel))
+                .map_err(|e| Error::with_path(e, out))?;
+            written += 1;
+        }
+        writer.flush().map_err(|e| Error::with_path(e, out))?;
+        sync.progress.on_message(&format!(
+            "Wrote {} checksums of {} files to {:?}",
+            check.name(),
+            written,
+            out
+        ));
+        Ok(written)
+    }
+
+    /// Hash the files listed in `manifest` again and compare them with the source, returning
+    /// the paths that differ ordered by path: [`Mismatch::Missing`] for files in the manifest
+    /// but not the source, [`Mismatch::Extra`] for files the filters don't exclude that aren't
+    /// in the manifest, and [`Reason::Content`] for files whose contents changed.
+    pub fn verify_manifest(mut self, manifest: impl AsRef<Path>) -> Result<Vec<Comparison>> {
+        let manifest = manifest.as_ref();
+        let check = self.prepare_manifest()?;
+        let entries = read_manifest(manifest)?;
+        let pool = Arc::new(self.get_thread_pool()?);
+        let sync = Arc::new(self);
+        let paths = entries
+            .iter()
+            .map(|(_, name)| sync.src.join(name))
+            .collect::<Vec<_>>();
+        let digests = sync.digests(&pool, check, &paths)?;
+        let mut out = Vec::new();
+        for ((expected, name), digest) in entries.iter().zip(digests) {
+            let mismatch = match digest {
+                None => Mismatch::Missing,
+                Some(digest) if digest != *expected => Mismatch::Differs(vec![Reason::Content]),
+                Some(_) => continue,
+            };
+            out.push(Comparison {
+                path: PathBuf::from(name),
+                mismatch,
+            });
+        }
+        let listed = entries.iter().map(|(_, name)| name).collect::<HashSet<_>>();
+        for path in sync.manifest_files(&pool, Some(manifest))? {
+            let rel = path
+                .strip_prefix(&sync.src)
+                .expect("walked below the source");
+            let name = manifest_name(rel);
+            if !listed.contains(&name) {
+                out.push(Comparison {
+                    path: PathBuf::from(name),
+                    mismatch: Mismatch::Extra,
+                });
+            }
+        }
+        out.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(out)
+    }
+
+    /// Check the source can have a manifest and build its filters, returning the algorithm
+    /// to hash with
+    pub(crate) fn prepare_manifest(&mut self) -> Result<ContentCheck> {
+        if sftp::parse_url(&self.src).is_some() {
+            return Err(Error::Unsupported(
+                "manifests can only be made of local directories".to_string(),
+            ));
+        }
+        self.src = fs::canonicalize(&self.src).map_err(|e| Error::with_path(e, &self.src))?;
+        self.build_filter()?;
+        Ok(match self.check_content {
+            None | Some(ContentCheck::Bytes) => ContentCheck::Sha256,
+            Some(check) => check,
+        })
+    }
+
+    /// The files below the source the filters don't exclude, in the order of a sorted walk.
+    /// A `manifest` inside the tree is left out, so it doesn't list an older version of
+    /// itself.
+    pub(crate) fn manifest_files(
+        self: &Arc<Self>,
+        pool: &Arc<ThreadPool>,
+        manifest: Option<&Path>,
+    ) -> Result<Vec<PathBuf>> {
+        let own = match manifest {
+            Some(manifest) => {
+                let dir = manifest.parent().filter(|x| !x.as_os_str().is_empty());
+                let dir = fs::canonicalize(dir.unwrap_or(Path::new(".")))
+                    .map_err(|e| Error::with_path(e, manifest))?;
+                Some(dir.join(manifest.file_name().unwrap_or_default()))
+            }
+            None => None,
+        };
+        let sync = self.clone();
+        let walk = jwalk::WalkDir::new(&self.src)
+            .skip_hidden(self.skip_hidden)
+            .sort(true)
+            .parallelism(jwalk::Parallelism::RayonExistingPool {
+                pool: pool.clone(),
+                busy_timeout: None,
+            })
+            .process_read_dir(move |_, _, _, children| {
+                children.retain(|entry| match entry {
+                    Ok(entry) => !sync.is_excluded(&entry.path(), entry.file_type().is_dir()),
+                    Err(_) => true,
+                });
+            });
+        let mut files = Vec::new();
+        for entry in walk {
+            let entry = entry?;
+            if entry.file_type().is_file() && Some(entry.path()) != own {
+                files.push(entry.path());
+            }
+        }
+        Ok(files)
+    }
+
+    /// The digest of each of `files` on the thread pool, or none for paths that aren't files
+    fn digests(
+        &self,
+        pool: &ThreadPool,
+        check: ContentCheck,
+        files: &[PathBuf],
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        pool.install(|| {
+            files
+                .par_iter()
+                .map(|path| {
+                    match fs::metadata(path) {
+                        Ok(meta) if meta.is_file() => {}
+                        Ok(_) => return Ok(None),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                        Err(e) => return Err(Error::with_path(e, path)),
+                    }
+                    let mut file = fs::File::open(path).map_err(|e| Error::with_path(e, path))?;
+                    let digest = check
+                        .digest(&mut file)
+                        .map_err(|e| Error::with_path(e, path))?;
+                    self.progress.on_scanned(1);
+                    Ok(Some(digest))
+                })
+                .collect()
+        })
+    }
+}
+
+/// The digest and path of each line of the manifest at `path`, in the format of `sha256sum`
+/// with or without its binary mode marker
+fn read_manifest(path: &Path) -> Result<Vec<(Vec<u8>, OsString)>> {
+    let text = fs::read(path).map_err(|e| Error::with_path(e, path))?;
+    let mut entries = Vec::new();
+    for (i, line) in text.split(|x| *x == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+        let parsed = (|| {
+            let (escaped, line) = match line.strip_prefix(b"\\") {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let split = line.iter().position(|x| *x == b' ')?;
+            let (hex, rest) = line.split_at(split);
+            let name = rest
+                .strip_prefix(b"  ")
+                .or_else(|| rest.strip_prefix(b" *"))?;
+            let name = match escaped {
+                true => unescape(name),
+                false => os_from_bytes(name.to_vec()),
+            };
+            Some((from_hex(hex)?, name))
+        })();
+        match parsed {
+            Some(entry) => entries.push(entry),
+            None => {
+                let msg = format!("line {} isn't a checksum and path", i + 1);
+                return Err(Error::with_path(invalid_data(&msg), path));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// The path of the file at `rel` in a manifest, with forward slashes as separators
+fn manifest_name(rel: &Path) -> OsString {
+    let mut name = OsString::new();
+    for (i, component) in rel.components().enumerate() {
+        if i > 0 {
+            name.push("/");
+        }
+        name.push(component.as_os_str());
+    }
+    name
+}
+
+/// Write the manifest line of the file called `name`. Like `sha256sum`, names with a
+/// backslash or newline are escaped and their line starts with a backslash.
+fn write_line(out: &mut impl Write, digest: &[u8], name: &OsStr) -> io::Result<()> {
+    if os_bytes(name).iter().any(|x| matches!(x, b'\\' | b'\n')) {
+        out.write_all(b"\\")?;
+    }
+    write!(out, "{}  ", to_hex(digest))?;
+    write_escaped(out, name)?;
+    writeln!(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{temp_fs, Comparison, Mismatch, Reason, Synchronize};
+    use std::{fs, path::PathBuf};
+
+    // Backslashes aren't allowed in Windows file names
+    #[cfg(unix)]
+    #[test]
+    fn test_manifest_lists_checksums() {
+        let temp = temp_fs!(input / skip: 2);
+        let input = temp.path().join("input");
+        fs::create_dir(input.join("sub")).unwrap();
+        fs::write(input.join("hello"), "hello\n").unwrap();
+        fs::write(input.join("sub/empty"), "").unwrap();
+        fs::write(input.join("back\\slash"), "").unwrap();
+        let out = input.join("SHA256SUMS");
+        let manifest = || {
+            Synchronize::new(&input, temp.path().join("unused"))
+                .exclude("skip.text")
+                .manifest(&out)
+                .unwrap()
+        };
+        assert_eq!(manifest(), 3);
+
+        // Written again, the manifest doesn't list itself
+        assert_eq!(manifest(), 3);
+        let text = fs::read_to_string(&out).unwrap();
+        let empty = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(
+            text,
+            format!(
+                "\\{empty}  back\\\\slash\n\
+                 5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03  hello\n\
+                 {empty}  sub/empty\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_verify_manifest_finds_changes() {
+        let temp = temp_fs!(input / same: 4, input / changed: 4, input / removed: 4);
+        let input = temp.path().join("input");
+        let out = temp.path().join("SHA256SUMS");
+        let sync = || Synchronize::new(&input, &input);
+        sync().manifest(&out).unwrap();
+        assert_eq!(sync().verify_manifest(&out).unwrap(), []);
+
+        fs::write(input.join("changed.text"), "diff").unwrap();
+        fs::remove_file(input.join("removed.text")).unwrap();
+        fs::write(input.join("added"), "").unwrap();
+        let comparison = |path: &str, mismatch| Comparison {
+            path: PathBuf::from(path),
+            mismatch,
+        };
+        assert_eq!(
+            sync().verify_manifest(&out).unwrap(),
+            [
+                comparison("added", Mismatch::Extra),
+                comparison("changed.text", Mismatch::Differs(vec![Reason::Content])),
+                comparison("removed.text", Mismatch::Missing),
+            ]
+        );
+        // Files the filters exclude aren't extra
+        let excluded = sync().exclude("added").verify_manifest(&out).unwrap();
+        assert_eq!(excluded.len(), 2);
+        fs::write(&out, "not a manifest\n").unwrap();
+        assert!(sync().verify_manifest(&out).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+use crate::encoding::{invalid_data, os_bytes, unescape, write_escaped};
+use rayon::prelude::*;
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    fs,
+    io::{self, BufRead, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+const HEADER: &str = "fsync-merkle 1";
+
+/// A tree of content hashes where each directory hash is composed from its children
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    root: Node,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Node {
+    kind: Kind,
+    hash: blake3::Hash,
+    children: BTreeMap<OsString, Node>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    File,
+    Dir,
+    Symlink,
+}
+
+impl MerkleTree {
+    /// Hash every entry below `root`, skipping any path in `ignore`
+    pub fn build(root: &Path, ignore: &[PathBuf]) -> io::Result<Self> {
+        Ok(Self {
+            root: build_node(root, ignore)?,
+        })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let file = io::BufReader::new(fs::File::open(path)?);
+        let mut lines = file.split(b'\n');
+        match lines.next().transpose()? {
+            Some(header) if header == HEADER.as_bytes() => {}
+            _ => return Err(invalid_data("missing merkle tree header")),
+        }
+        let mut entries = lines
+            .map(|line| parse_line(&line?))
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            .peekable();
+        let root = match entries.next() {
+            Some((0, kind, hash, _)) => read_children(&mut entries, 1, kind, hash)?,
+            _ => return Err(invalid_data("missing merkle tree root")),
+        };
+        Ok(Self { root })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(fs::File::create(path)?);
+        writeln!(out, "{}", HEADER)?;
+        write_node(&mut out, 0, OsStr::new(""), &self.root)?;
+        out.flush()
+    }
+
+    /// Paths that differ between the two trees, only descending into subtrees whose hashes diverge
+    pub fn diff(&self, other: &MerkleTree) -> Vec<PathBuf> {
+        let mut out = Vec::new();
+        diff_node(PathBuf::new(), &self.root, &other.root, &mut out);
+        out
+    }
+}
+
+/// Rebuild the tree for `root` and compare it with the one stored at `tree`
+pub fn verify(root: &Path, tree: &Path) -> io::Result<Vec<PathBuf>> {
+    let stored = MerkleTree::load(tree)?;
+    let current = MerkleTree::build(root, &[tree.to_path_buf()])?;
+    Ok(stored.diff(&current))
+}
+
+fn build_node(path: &Path, ignore: &[PathBuf]) -> io::Result<Node> {
+    let meta = fs::symlink_metadata(path)?;
+    if meta.file_type().is_symlink() {
+        let target = fs::read_link(path)?;
+        return Ok(Node::leaf(
+            Kind::Symlink,
+            blake3::hash(&os_bytes(target.as_os_str())),
+        ));
+    }
+    if !meta.is_dir() {
+        let mut hasher = blake3::Hasher::new();
+        io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+        return Ok(Node::leaf(Kind::File, hasher.finalize()));
+    }
+
+    let entries = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+    let children = entries
+        .par_iter()
+        .filter(|entry| !ignore.contains(&entry.path()))
+        .map(|entry| Ok((entry.file_name(), build_node(&entry.path(), ignore)?)))
+        .collect::<io::Result<BTreeMap<_, _>>>()?;
+    Ok(Node::dir(children))
+}
+
+impl Node {
+    fn leaf(kind: Kind, hash: blake3::Hash) -> Self {
+        Self {
+            kind,
+            hash,
+            children: BTreeMap::new(),
+        }
+    }
+
+    fn dir(children: BTreeMap<OsString, Node>) -> Self {
+        let mut hasher = blake3::Hasher::new();
+        for (name, child) in children.iter() {
+            hasher.update(&os_bytes(name));
+            hasher.update(&[0, child.kind.as_byte()]);
+            hasher.update(child.hash.as_bytes());
+        }
+        Self {
+            kind: Kind::Dir,
+            hash: hasher.finalize(),
+            children,
+        }
+    }
+}
+
+impl Kind {
+    fn as_byte(self) -> u8 {
+        match self {
+            Kind::File => b'f',
+            Kind::Dir => b'd',
+            Kind::Symlink => b'l',
+        }
+    }
+}
+
+fn diff_node(path: PathBuf, a: &Node, b: &Node, out: &mut Vec<PathBuf>) {
+    if a.hash == b.hash && a.kind == b.kind {
+        return;
+    }
+    if a.kind != Kind::Dir || b.kind != Kind::Dir {
+        out.push(path);
+        return;
+    }
+    for (name, child) in a.children.iter() {
+        match b.children.get(name) {
+            Some(other) => diff_node(path.join(name), child, other, out),
+            None => out.push(path.join(name)),
+        }
+    }
+    for name in b.children.keys() {
+        if !a.children.contains_key(name) {
+            out.push(path.join(name));
+        }
+    }
+}
+
+fn write_node(out: &mut impl Write, depth: usize, name: &OsStr, node: &Node) -> io::Result<()> {
+    write!(
+        out,
+        "{} {} {} ",
+        depth,
+        node.kind.as_byte() as char,
+        node.hash.to_hex()
+    )?;
+    write_escaped(out, name)?;
+    out.write_all(b"\n")?;
+    for (name, child) in node.children.iter() {
+        write_node(out, depth + 1, name, child)?;
+    }
+    Ok(())
+}
+
+type Entry = (usize, Kind, blake3::Hash, OsString);
+
+fn parse_line(line: &[u8]) -> io::Result<Entry> {
+    let mut parts = line.splitn(4, |b| *b == b' ');
+    let mut next = || {
+        parts
+            .next()
+            .ok_or_else(|| invalid_data("truncated merkle entry"))
+    };
+    let depth = std::str::from_utf8(next()?)
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| invalid_data("invalid merkle depth"))?;
+    let kind = match next()? {
+        b"f" => Kind::File,
+        b"d" => Kind::Dir,
+        b"l" => Kind::Symlink,
+        _ => return Err(invalid_data("invalid merkle entry kind")),
+    };
+    let hash = blake3::Hash::from_hex(next()?).map_err(|_| invalid_data("invalid merkle hash"))?;
+
+    Ok((depth, kind, hash, unescape(next()?)))
+}
+
+fn read_children(
+    entries: &mut std::iter::Peekable<impl Iterator<Item = Entry>>,
+    depth: usize,
+    kind: Kind,
+    hash: blake3::Hash,
+) -> io::Result<Node> {
+    let mut children = BTreeMap::new();
+    while let Some((d, ..)) = entries.peek() {
+        if *d < depth {
+            break;
+        }
+        if *d > depth {
+            return Err(invalid_data("merkle entry is missing its parent"));
+        }
+        let (_, kind, hash, name) = entries.next().unwrap();
+        children.insert(name, read_children(entries, depth + 1, kind, hash)?);
+    }
+    Ok(Node {
+        kind,
+        hash,
+        children,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify, MerkleTree};
+    use crate::temp_fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_verify_reports_changed_subtree() {
+        let temp = temp_fs!(
+            one / bar: 4,
+            one / two / bar: 4,
+            three / bar: 4,
+        );
+        let tree = temp.path().join("tree");
+        let stored = MerkleTree::build(temp.path(), &[]).unwrap();
+        stored.save(&tree).unwrap();
+        assert_eq!(MerkleTree::load(&tree).unwrap(), stored);
+
+        std::fs::write(temp.path().join("one/two/bar.text"), b"bbbb").unwrap();
+        std::fs::write(temp.path().join("three/baz.text"), b"").unwrap();
+        let mut changed = verify(temp.path(), &tree).unwrap();
+        changed.sort();
+        assert_eq!(
+            changed,
+            vec![
+                PathBuf::from("one/two/bar.text"),
+                PathBuf::from("three/baz.text")
+            ]
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Counters of what long running syncs have done, served over HTTP in the Prometheus text
+//! format so a mirror can be monitored with standard tooling
+
+use crate::Result;
+use std::{
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// How long a scrape may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Totals of every sync given these metrics, see [`crate::Synchronize::metrics`]
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub(crate) files_copied: AtomicU64,
+    pub(crate) bytes_copied: AtomicU64,
+    pub(crate) errors: AtomicU64,
+    /// Files waiting for a copy worker
+    pub(crate) queued: AtomicUsize,
+    /// Copy workers in the middle of a file
+    pub(crate) active_workers: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    fn values(&self) -> [u64; 5] {
+        [
+            self.files_copied.load(Ordering::Relaxed),
+            self.bytes_copied.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+            self.queued.load(Ordering::Relaxed) as u64,
+            self.active_workers.load(Ordering::Relaxed) as u64,
+        ]
+    }
+}
+
+/// Name, type and help of each of [`Metrics::values`]
+const FAMILIES: [(&str, &str, &str); 5] = [
+    (
+        "fsync_files_copied_total",
+        "counter",
+        "Destination paths brought up to date",
+    ),
+    (
+        "fsync_bytes_copied_total",
+        "counter",
+        "Bytes of file data written to the destination",
+    ),
+    ("fsync_errors_total", "counter", "Paths that failed to sync"),
+    (
+        "fsync_queue_depth",
+        "gauge",
+        "Files waiting for a copy worker",
+    ),
+    (
+        "fsync_active_workers",
+        "gauge",
+        "Copy workers in the middle of a file",
+    ),
+];
+
+/// The metrics of each job in the Prometheus text format, labelled with the job's name unless
+/// it's empty
+fn render(jobs: &[(String, Arc<Metrics>)]) -> String {
+    let values = jobs.iter().map(|(_, x)| x.values()).collect::<Vec<_>>();
+    let mut out = String::new();
+    for (i, (name, kind, help)) in FAMILIES.iter().enumerate() {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+        for ((job, _), values) in jobs.iter().zip(values.iter()) {
+            match job.is_empty() {
+                true => writeln!(out, "{} {}", name, values[i]),
+                false => writeln!(out, "{}{{job={:?}}} {}", name, job, values[i]),
+            }
+            .unwrap();
+        }
+    }
+    out
+}
+
+/// Serve the metrics of `jobs` at `/metrics` on `addr` from a background thread, returning
+/// the address it listens on. Each job's metrics are labelled with its name, so a single
+/// unnamed job has no labels.
+pub fn serve(addr: &str, jobs: Vec<(String, Arc<Metrics>)>) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let addr = listener.local_addr()?;
+    std::thread::Builder::new()
+        .name("fsync-metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // A scraper that hangs up early doesn't stop the next
+                let _ = respond(stream, &jobs);
+            }
+        })?;
+    Ok(addr)
+}
+
+fn respond(mut stream: TcpStream, jobs: &[(String, Arc<Metrics>)]) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|x| x == b"\r\n\r\n") && request.len() < 16 * 1024 {
+        match stream.read(&mut buf)? {
+            0 => break,
+            n => request.extend_from_slice(&buf[..n]),
+        }
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut line = request.lines().next().unwrap_or_default().split(' ');
+    let (status, body) = match (line.next(), line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(jobs)),
+        (Some("GET"), _) => ("404 Not Found", "Not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "Only GET is allowed\n".to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, serve, Metrics};
+    use crate::{temp_fs, Synchronize};
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        sync::atomic::Ordering,
+    };
+
+    #[test]
+    fn test_render_labels_jobs() {
+        let (a, b) = (Metrics::new(), Metrics::new());
+        a.errors.store(2, Ordering::Relaxed);
+        b.queued.store(5, Ordering::Relaxed);
+        let text = render(&[("a".to_string(), a), ("b".to_string(), b)]);
+        assert!(text.contains("# TYPE fsync_errors_total counter\n"));
+        assert!(text.contains("fsync_errors_total{job=\"a\"} 2\nfsync_errors_total{job=\"b\"} 0\n"));
+        assert!(text.contains("fsync_queue_depth{job=\"b\"} 5\n"));
+    }
+
+    #[test]
+    fn test_metrics_are_served() {
+        let temp = temp_fs!(input / a: 4, input / b: 6);
+        let metrics = Metrics::new();
+        Synchronize::new(temp.path().join("input"), temp.path().join("output"))
+            .metrics(metrics.clone())
+            .sync()
+            .unwrap();
+        let addr = serve("127.0.0.1:0", vec![(String::new(), metrics)]).unwrap();
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\nfsync_files_copied_total 2\n"));
+        assert!(response.contains("\nfsync_bytes_copied_total 10\n"));
+        assert!(response.contains("\nfsync_active_workers 0\n"));
+        assert!(get("/other").starts_with("HTTP/1.1 404"));
+    }
+}
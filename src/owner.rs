@@ -0,0 +1,199 @@
+//! Owners and groups of destination paths set to the source's, for syncs run with the
+//! privileges to change them
+
+use crate::{Error, Result, Synchronize};
+use std::{fs::Metadata, path::Path};
+
+/// Rules translating the owners or groups of source paths for the destination, such as
+/// `1000:2000,www-data:nginx`. Each rule maps an id or name to another, or `*` to map every
+/// id, and the first rule matching an id applies.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap {
+    /// The id each rule matches, or none for every id, and the id it becomes
+    rules: Vec<(Option<u32>, u32)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum IdKind {
+    User,
+    Group,
+}
+
+impl IdMap {
+    /// Parse rules mapping owners, with names resolved to the ids of this system's users
+    pub fn users(rules: &str) -> Result<Self> {
+        Self::parse(rules, IdKind::User)
+    }
+
+    /// Parse rules mapping groups, with names resolved to the ids of this system's groups
+    pub fn groups(rules: &str) -> Result<Self> {
+        Self::parse(rules, IdKind::Group)
+    }
+
+    fn parse(rules: &str, kind: IdKind) -> Result<Self> {
+        let rules = rules
+            .split(',')
+            .map(|rule| {
+                let (from, to) = rule
+                    .split_once(':')
+                    .ok_or_else(|| Error::Unsupported(format!("Invalid id rule {:?}", rule)))?;
+                let from = match from {
+                    "*" => None,
+                    from => Some(resolve(from, kind)?),
+                };
+                Ok((from, resolve(to, kind)?))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// The id the source's `id` becomes in the destination
+    pub fn map(&self, id: u32) -> u32 {
+        self.rules
+            .iter()
+            .find(|(from, _)| from.is_none_or(|x| x == id))
+            .map_or(id, |(_, to)| *to)
+    }
+}
+
+/// The id of the user or group `name`, which may be the id itself
+fn resolve(name: &str, kind: IdKind) -> Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    lookup(name, kind).ok_or_else(|| {
+        let kind = match kind {
+            IdKind::User => "user",
+            IdKind::Group => "group",
+        };
+        Error::Unsupported(format!("Unknown {} {:?}", kind, name))
+    })
+}
+
+#[cfg(unix)]
+fn lookup(name: &str, kind: IdKind) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    // Safety: the entries only point into `buf`, which outlives them
+    unsafe {
+        match kind {
+            IdKind::User => {
+                let mut entry = std::mem::zeroed::<libc::passwd>();
+                let mut found = std::ptr::null_mut();
+                let code = libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut entry,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut found,
+                );
+                (code == 0 && !found.is_null()).then_some(entry.pw_uid)
+            }
+            IdKind::Group => {
+                let mut entry = std::mem::zeroed::<libc::group>();
+                let mut found = std::ptr::null_mut();
+                let code = libc::getgrnam_r(
+                    name.as_ptr(),
+                    &mut entry,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut found,
+                );
+                (code == 0 && !found.is_null()).then_some(entry.gr_gid)
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lookup(_name: &str, _kind: IdKind) -> Option<u32> {
+    None
+}
+
+impl Synchronize {
+    /// Whether the owner or group of destination paths is set
+    pub(crate) fn copies_owner(&self) -> bool {
+        self.owner || self.group || self.usermap.is_some() || self.groupmap.is_some()
+    }
+
+    /// Give `dest` the owner and group of the source `meta` that the sync preserves, warning
+    /// instead of failing when that isn't allowed
+    #[cfg(unix)]
+    pub(crate) fn copy_owner(&self, meta: &Metadata, dest: &Path) {
+        use std::os::unix::fs::MetadataExt;
+
+        if !self.copies_owner() {
+            return;
+        }
+        // A map applies without the owner or group being preserved otherwise
+        let id = |id: u32, preserve: bool, map: &Option<IdMap>| match map {
+            Some(map) => Some(map.map(id)),
+            None => Some(id).filter(|_| preserve),
+        };
+        let uid = id(meta.uid(), self.owner, &self.usermap);
+        let gid = id(meta.gid(), self.group, &self.groupmap);
+        // Paths that already match are left alone, which needs no privileges
+        if let Ok(current) = dest.symlink_metadata() {
+            if uid.is_none_or(|x| x == current.uid()) && gid.is_none_or(|x| x == current.gid()) {
+                return;
+            }
+        }
+        if let Err(e) = std::os::unix::fs::lchown(dest, uid, gid) {
+            self.progress
+                .println(format!("Failed to set the owner of {:?}: {}", dest, e));
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn copy_owner(&self, _meta: &Metadata, _dest: &Path) {}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::IdMap;
+    use crate::{temp_fs, Synchronize};
+    use std::os::unix::fs::{lchown, symlink, MetadataExt};
+
+    #[test]
+    fn test_id_maps() {
+        let users = IdMap::users("1000:2000,root:5,*:7").unwrap();
+        assert_eq!(users.map(1000), 2000);
+        assert_eq!(users.map(0), 5);
+        assert_eq!(users.map(42), 7);
+        assert_eq!(IdMap::groups("3:4").unwrap().map(5), 5);
+        assert!(IdMap::users("1000").is_err());
+        assert!(IdMap::users("nobody-by-this-name:1").is_err());
+    }
+
+    #[test]
+    fn test_owner_and_group_are_copied() {
+        let temp = temp_fs!(input / sub / a: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        symlink("sub/a.text", input.join("link")).unwrap();
+        let paths = ["sub", "sub/a.text", "link"];
+        for path in paths {
+            if lchown(input.join(path), Some(1234), Some(5678)).is_err() {
+                // Only root can give files away
+                return;
+            }
+        }
+        Synchronize::new(&input, &output)
+            .owner(true)
+            .sync()
+            .unwrap();
+        for path in paths {
+            let meta = output.join(path).symlink_metadata().unwrap();
+            assert_eq!(meta.uid(), 1234, "{}", path);
+            assert_ne!(meta.gid(), 5678, "{}", path);
+        }
+
+        // Mapped ids are set without --owner
+        let mapped = temp.path().join("mapped");
+        Synchronize::new(&input, &mapped)
+            .groupmap(IdMap::groups("5678:42").unwrap())
+            .sync()
+            .unwrap();
+        let meta = mapped.join("sub/a.text").symlink_metadata().unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (0, 42));
+    }
+}
@@ -0,0 +1,342 @@
+//! Copying files on dedicated worker threads fed through a bounded queue, so walking the source
+//! doesn't wait on slow copies and a deep tree can't queue up unbounded work
+
+use crate::{Error, Metrics, ProgressReporter, Result, Synchronize};
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    io,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+/// Files waiting for a copier before walking the source blocks
+const QUEUE: usize = 1024;
+
+/// Order the copiers take queued files in. Only the files queued at the same time are
+/// reordered, as the queue is bounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SchedulingPolicy {
+    /// In the order the walk finds them
+    #[default]
+    Walk,
+    /// Smallest first, getting through many tiny files sooner
+    SmallestFirst,
+    /// Largest first, overlapping big copies with the rest of the walk
+    LargestFirst,
+}
+
+impl SchedulingPolicy {
+    /// Queued files with a higher priority are copied first
+    fn priority(&self, len: u64) -> u64 {
+        match self {
+            SchedulingPolicy::Walk => 0,
+            SchedulingPolicy::SmallestFirst => u64::MAX - len,
+            SchedulingPolicy::LargestFirst => len,
+        }
+    }
+}
+
+impl FromStr for SchedulingPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "walk" => Ok(SchedulingPolicy::Walk),
+            "smallest-first" => Ok(SchedulingPolicy::SmallestFirst),
+            "largest-first" => Ok(SchedulingPolicy::LargestFirst),
+            _ => Err(Error::Unsupported(format!(
+                "Unknown scheduling policy {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// A source file to bring its destination up to date with
+pub(crate) struct FileJob {
+    pub(crate) src: PathBuf,
+    pub(crate) dest: PathBuf,
+    pub(crate) dir: Arc<DirWork>,
+}
+
+/// Files of a source directory still being synced, so the directory is journaled only once the
+/// last of them is done
+#[derive(Debug)]
+pub(crate) struct DirWork {
+    /// Path relative to the source
+    rel: PathBuf,
+    /// Queued files, plus one for the directory's own part until it's done
+    pending: AtomicUsize,
+    failed: AtomicBool,
+}
+
+impl DirWork {
+    pub(crate) fn new(rel: PathBuf) -> Arc<Self> {
+        Arc::new(Self {
+            rel,
+            pending: AtomicUsize::new(1),
+            failed: AtomicBool::new(false),
+        })
+    }
+}
+
+/// A queued file, ordered by priority and then by when it was queued
+struct Queued {
+    key: (u64, Reverse<u64>),
+    job: FileJob,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    jobs: BinaryHeap<Queued>,
+    queued: u64,
+    closed: bool,
+}
+
+/// Bounded queue of files handing out the highest priority one first
+#[derive(Default)]
+struct Queue {
+    state: Mutex<QueueState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+impl Queue {
+    /// Returns the job back when the queue was closed
+    fn push(&self, priority: u64, job: FileJob) -> std::result::Result<(), FileJob> {
+        let mut state = self.state.lock().unwrap();
+        while state.jobs.len() >= QUEUE && !state.closed {
+            state = self.not_full.wait(state).unwrap();
+        }
+        if state.closed {
+            return Err(job);
+        }
+        let key = (priority, Reverse(state.queued));
+        state.queued += 1;
+        state.jobs.push(Queued { key, job });
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// The next job, waiting for one until the queue is closed and empty
+    fn pop(&self) -> Option<FileJob> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(queued) = state.jobs.pop() {
+                self.not_full.notify_one();
+                return Some(queued.job);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.not_empty.wait(state).unwrap();
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+}
+
+/// Worker threads copying the files the walk of the source queues
+pub(crate) struct Copiers {
+    queue: Arc<Queue>,
+    policy: SchedulingPolicy,
+    metrics: Option<Arc<Metrics>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Copiers {
+    /// Start a worker for each thread the sync is configured to use
+    pub(crate) fn start(sync: &Arc<Synchronize>) -> Result<Self> {
+        let queue = Arc::new(Queue::default());
+        let threads = match sync.num_threads {
+            Some(threads) => threads as usize,
+            None => std::thread::available_parallelism().map_or(1, |x| x.get()),
+        };
+        // Files are copied under the span of the sync that queued them
+        #[cfg(feature = "tracing")]
+        let span = tracing::Span::current();
+        let workers = (0..threads.max(1))
+            .map(|i| {
+                let (sync, queue) = (sync.clone(), queue.clone());
+                #[cfg(feature = "tracing")]
+                let span = span.clone();
+                std::thread::Builder::new()
+                    .name(format!("fsync-copy-{}", i))
+                    .spawn(move || {
+                        #[cfg(feature = "tracing")]
+                        let _span = span.enter();
+                        sync.copy_worker(&queue)
+                    })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self {
+            queue,
+            policy: sync.scheduling,
+            metrics: sync.progress.metrics.clone(),
+            workers: Mutex::new(workers),
+        })
+    }
+
+    /// Queue `job` for a file of `len` bytes, waiting while the queue is full
+    pub(crate) fn send(&self, job: FileJob, len: u64) -> Result<()> {
+        job.dir.pending.fetch_add(1, Ordering::AcqRel);
+        // Counted before the push so a worker taking it straight away can't go below zero
+        if let Some(metrics) = &self.metrics {
+            metrics.queued.fetch_add(1, Ordering::Relaxed);
+        }
+        self.queue
+            .push(self.policy.priority(len), job)
+            .map_err(|_| {
+                if let Some(metrics) = &self.metrics {
+                    metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                }
+                Error::Io(io::Error::other("the copy workers have stopped"))
+            })
+    }
+
+    /// Whether queueing files needs their size
+    pub(crate) fn by_size(&self) -> bool {
+        self.policy != SchedulingPolicy::Walk
+    }
+
+    /// Wait for the queued files to be copied once nothing more will be queued
+    pub(crate) fn finish(&self) {
+        self.queue.close();
+        for worker in std::mem::take(&mut *self.workers.lock().unwrap()) {
+            if let Err(panic) = worker.join() {
+                std::panic::resume_unwind(panic);
+            }
+        }
+    }
+}
+
+// A sync stopped by an error still waits for the files it queued
+impl Drop for Copiers {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl Synchronize {
+    fn copy_worker(&self, queue: &Queue) {
+        while let Some(job) = queue.pop() {
+            let metrics = self.progress.metrics.as_deref();
+            if let Some(metrics) = metrics {
+                metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                metrics.active_workers.fetch_add(1, Ordering::Relaxed);
+            }
+            if self.cancel.is_cancelled() {
+                job.dir.failed.store(true, Ordering::Release);
+            } else if let Err(e) = self.sync_file(&job.src, &job.dest) {
+                trace!(warn, src = ?job.src, error = %e, "failed to sync file");
+                self.progress.on_error(&job.src, &e);
+                job.dir.failed.store(true, Ordering::Release);
+            }
+            if let Some(metrics) = metrics {
+                metrics.active_workers.fetch_sub(1, Ordering::Relaxed);
+            }
+            if let Err(e) = self.finish_work(&job.dir) {
+                self.progress.on_error(&job.src, &e);
+            }
+        }
+    }
+
+    /// Count one of the files of `dir`, or the directory's own part, as done, journaling the
+    /// directory after the last one when none of them failed
+    pub(crate) fn finish_work(&self, dir: &DirWork) -> Result<()> {
+        if dir.pending.fetch_sub(1, Ordering::AcqRel) != 1 || dir.failed.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        match &self.journal {
+            Some(journal) => Ok(journal.record(&dir.rel)?),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DirWork, FileJob, Queue, SchedulingPolicy};
+    use crate::Synchronize;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_queue_orders_by_policy() {
+        let dir = DirWork::new(PathBuf::new());
+        let order = |policy: SchedulingPolicy| {
+            let queue = Queue::default();
+            for (name, len) in [("b", 20), ("a", 10), ("c", 30), ("d", 10)] {
+                let job = FileJob {
+                    src: PathBuf::from(name),
+                    dest: PathBuf::new(),
+                    dir: dir.clone(),
+                };
+                assert!(queue.push(policy.priority(len), job).is_ok());
+            }
+            queue.close();
+            std::iter::from_fn(|| queue.pop())
+                .map(|x| x.src.display().to_string())
+                .collect::<String>()
+        };
+        assert_eq!(order(SchedulingPolicy::Walk), "bacd");
+        assert_eq!(order(SchedulingPolicy::SmallestFirst), "adbc");
+        assert_eq!(order(SchedulingPolicy::LargestFirst), "cbad");
+        assert_eq!(
+            "largest-first".parse::<SchedulingPolicy>().unwrap(),
+            SchedulingPolicy::LargestFirst
+        );
+    }
+
+    #[test]
+    fn test_more_files_than_the_queue_holds() {
+        let temp = tempfile::tempdir().unwrap();
+        let input = temp.path().join("input");
+        let files = super::QUEUE / 2 + 64;
+        for dir in 0..2 {
+            std::fs::create_dir_all(input.join(dir.to_string())).unwrap();
+            for file in 0..files {
+                std::fs::write(input.join(format!("{}/{}", dir, file)), file.to_string()).unwrap();
+            }
+        }
+        // A single thread still walks while the copier catches up
+        let report = Synchronize::new(&input, temp.path().join("output"))
+            .num_threads(Some(1))
+            .sync()
+            .unwrap();
+        assert_eq!(report.created, 2 * files);
+        assert_eq!(
+            std::fs::read_to_string(temp.path().join("output/1/500")).unwrap(),
+            "500"
+        );
+    }
+}
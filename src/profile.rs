@@ -0,0 +1,138 @@
+//! Named sets of command line options read from a toml file, so a long invocation can be
+//! written once and run as `fsync --profile nightly`
+
+use crate::{sftp, Error, Result};
+use std::{collections::BTreeMap, ffi::OsString, fs, path::Path, path::PathBuf};
+
+/// Profiles read from a toml file with a `[profile.<name>]` table for each. Every key of a
+/// profile is the long name of a command line option, such as `delete = true` for `--delete`
+/// or `exclude = ["*.tmp"]` for `--exclude '*.tmp'`, while `source` and `destination` are the
+/// paths to sync.
+#[derive(Debug)]
+pub struct Profiles {
+    profiles: BTreeMap<String, toml::Table>,
+}
+
+/// Options of a profile as command line arguments
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ProfileArgs {
+    pub source: Option<OsString>,
+    pub destination: Option<OsString>,
+    /// Long name of each option given, with its value unless it's a flag
+    pub options: Vec<(String, Option<OsString>)>,
+}
+
+impl Profiles {
+    /// Where profiles are read from without `--config`, `$XDG_CONFIG_HOME/fsync/config.toml`
+    /// or `~/.config/fsync/config.toml`
+    pub fn default_path() -> Option<PathBuf> {
+        let config = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| Some(sftp::home_dir()?.join(".config")))?;
+        Some(config.join("fsync/config.toml"))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(|e| Error::with_path(e, path))?;
+        Self::parse(&text)
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let mut table: toml::Table = toml::from_str(text)?;
+        let profiles = match table.remove("profile") {
+            Some(toml::Value::Table(profiles)) => profiles,
+            Some(_) => return Err(Error::Config("`profile` must be a table".to_string())),
+            None => toml::Table::new(),
+        };
+        if let Some(key) = table.keys().next() {
+            return Err(Error::Config(format!("Unknown key {:?}", key)));
+        }
+        let profiles = profiles
+            .into_iter()
+            .map(|(name, value)| match value {
+                toml::Value::Table(options) => Ok((name, options)),
+                _ => Err(Error::Config(format!("Profile {:?} must be a table", name))),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { profiles })
+    }
+
+    /// The options of the profile called `name` as command line arguments
+    pub fn args(&self, name: &str) -> Result<ProfileArgs> {
+        let profile = self
+            .profiles
+            .get(name)
+            .ok_or_else(|| Error::Config(format!("No profile called {:?}", name)))?;
+        let mut args = ProfileArgs::default();
+        for (key, value) in profile {
+            let invalid = || Error::Config(format!("Invalid value for {:?} in {:?}", key, name));
+            match (key.as_str(), value) {
+                ("source", toml::Value::String(path)) => args.source = Some(path.into()),
+                ("destination", toml::Value::String(path)) => args.destination = Some(path.into()),
+                ("source" | "destination", _) => return Err(invalid()),
+                (_, toml::Value::Boolean(true)) => args.options.push((key.clone(), None)),
+                (_, toml::Value::Boolean(false)) => {}
+                (_, value) => {
+                    let values = match value {
+                        toml::Value::Array(values) => values.as_slice(),
+                        value => std::slice::from_ref(value),
+                    };
+                    for value in values {
+                        let value = option_value(value).ok_or_else(invalid)?;
+                        args.options.push((key.clone(), Some(value.into())));
+                    }
+                }
+            }
+        }
+        Ok(args)
+    }
+}
+
+fn option_value(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(x) => Some(x.clone()),
+        toml::Value::Integer(x) => Some(x.to_string()),
+        toml::Value::Float(x) => Some(x.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ProfileArgs, Profiles};
+
+    #[test]
+    fn test_profiles_become_arguments() {
+        let profiles = Profiles::parse(
+            r#"
+            [profile.nightly]
+            source = "/home/me"
+            destination = "backup:/srv/me"
+            delete = true
+            dry-run = false
+            threads = 4
+            exclude = ["*.tmp", "cache/"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            profiles.args("nightly").unwrap(),
+            ProfileArgs {
+                source: Some("/home/me".into()),
+                destination: Some("backup:/srv/me".into()),
+                options: vec![
+                    ("delete".to_string(), None),
+                    ("exclude".to_string(), Some("*.tmp".into())),
+                    ("exclude".to_string(), Some("cache/".into())),
+                    ("threads".to_string(), Some("4".into())),
+                ],
+            }
+        );
+        assert!(profiles.args("weekly").is_err());
+        assert!(Profiles::parse("[profile.x]\nexclude = [[1]]")
+            .unwrap()
+            .args("x")
+            .is_err());
+        assert!(Profiles::parse("nightly = 1").is_err());
+    }
+}
@@ -0,0 +1,324 @@
+//! Events a sync reports as it goes, so its progress can be shown somewhere other than stderr
+
+use crate::{backend::Meta, CopyMethod, Error, SyncReport};
+use serde::Serialize;
+use std::{fmt::Display, path::Path, str::FromStr};
+
+/// How a sync reports what it does on stdout, besides the progress on stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Nothing on stdout
+    #[default]
+    Text,
+    /// A JSON object per line for each change and error, such as
+    /// `{"event":"copy","path":"out/a.txt","bytes":12,"method":"copy"}`
+    Ndjson,
+    /// A line per changed path, starting with a code for what changed like `rsync -i`
+    Itemize,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "itemize" => Ok(OutputFormat::Itemize),
+            _ => Err(Error::Unsupported(format!("Unknown output format {:?}", s))),
+        }
+    }
+}
+
+/// What a sync did to a destination path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub enum ChangeKind {
+    /// File data was written
+    Copy,
+    Hardlink,
+    Symlink,
+    /// A named pipe or device node was created
+    Special,
+    /// A directory was created
+    Mkdir,
+    /// A destination file was moved into place
+    Rename,
+    Delete,
+}
+
+impl ChangeKind {
+    /// Name of the event in the NDJSON output
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChangeKind::Copy => "copy",
+            ChangeKind::Hardlink => "hardlink",
+            ChangeKind::Symlink => "symlink",
+            ChangeKind::Special => "special",
+            ChangeKind::Mkdir => "mkdir",
+            ChangeKind::Rename => "rename",
+            ChangeKind::Delete => "delete",
+        }
+    }
+}
+
+/// How a changed path differs from what was in the destination before
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Differences {
+    /// Nothing was there
+    pub created: bool,
+    pub size: bool,
+    pub mtime: bool,
+    pub permissions: bool,
+}
+
+impl Differences {
+    pub(crate) const CREATED: Differences = Differences {
+        created: true,
+        size: false,
+        mtime: false,
+        permissions: false,
+    };
+
+    /// Differences between the source `src` and the destination `dest` it replaces
+    pub(crate) fn between(src: &Meta, dest: Option<&Meta>) -> Self {
+        match dest {
+            Some(dest) => Differences {
+                created: false,
+                size: src.len != dest.len,
+                mtime: src.mtime != dest.mtime,
+                permissions: src.mode != dest.mode,
+            },
+            None => Differences::CREATED,
+        }
+    }
+}
+
+/// A destination path a sync changed, or would change in a dry run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Change<'a> {
+    pub kind: ChangeKind,
+    pub path: &'a Path,
+    /// Bytes of file data written, zero for anything but copies
+    pub bytes: u64,
+    pub differences: Differences,
+    /// How the file data was written, for copies made in this run
+    pub method: Option<CopyMethod>,
+}
+
+impl<'a> Change<'a> {
+    pub(crate) fn new(
+        kind: ChangeKind,
+        path: &'a Path,
+        bytes: u64,
+        differences: Differences,
+    ) -> Self {
+        Self {
+            kind,
+            path,
+            bytes,
+            differences,
+            method: None,
+        }
+    }
+
+    pub(crate) fn with_method(mut self, method: CopyMethod) -> Self {
+        self.method = Some(method);
+        self
+    }
+
+    /// The change as a line of itemized output for the path `rel`, such as `>f.st..... a.txt`
+    /// for a file copied over one of another size and modified time. The first character is
+    /// `>` for file data written, `r` for reflinks, `c` for other paths created, `h` for
+    /// hardlinks and `*` for paths deleted or renamed, followed by the type of the path, then
+    /// `+` in every place for new paths or the updated attributes: checksum, size, time,
+    /// permissions.
+    pub fn itemize(&self, rel: &Path) -> String {
+        let (update, kind) = match self.kind {
+            ChangeKind::Delete => return format!("*deleting  {}", rel.display()),
+            ChangeKind::Rename => return format!("*renamed   {}", rel.display()),
+            ChangeKind::Copy => match self.method {
+                Some(CopyMethod::Reflink) => ('r', 'f'),
+                Some(CopyMethod::Hardlink) => ('h', 'f'),
+                _ => ('>', 'f'),
+            },
+            ChangeKind::Hardlink => ('h', 'f'),
+            ChangeKind::Symlink => ('c', 'L'),
+            ChangeKind::Special => ('c', 'D'),
+            ChangeKind::Mkdir => ('c', 'd'),
+        };
+        let d = self.differences;
+        let attributes = match d.created {
+            true => "++++++++".to_string(),
+            false => [
+                (false, 'c'),
+                (d.size, 's'),
+                (d.mtime, 't'),
+                (d.permissions, 'p'),
+            ]
+            .iter()
+            .map(|(changed, code)| if *changed { *code } else { '.' })
+            .chain("....".chars())
+            .collect(),
+        };
+        format!("{}{}{} {}", update, kind, attributes, rel.display())
+    }
+}
+
+/// Receives the progress of a sync, see [`crate::Synchronize::progress_reporter`]. Counts are
+/// in paths unless noted otherwise, and every method does nothing by default. Methods are
+/// called from the threads doing the work, so they should return quickly.
+pub trait ProgressReporter: Send + Sync {
+    /// More source paths were found
+    fn on_scanned(&self, _paths: usize) {}
+
+    /// Destination paths were brought up to date, or would be in a dry run
+    fn on_copied(&self, _paths: usize) {}
+
+    /// Copied paths that didn't exist in the destination
+    fn on_created(&self, _paths: usize) {}
+
+    /// Copied paths that replaced what was there
+    fn on_updated(&self, _paths: usize) {}
+
+    /// How a copied file was written
+    fn on_method(&self, _method: CopyMethod) {}
+
+    /// Paths that were already up to date
+    fn on_skipped(&self, _paths: usize) {}
+
+    /// Destination files moved into place instead of copied
+    fn on_renamed(&self, _paths: usize) {}
+
+    /// Paths changed on both sides of a bidirectional sync
+    fn on_conflict(&self, _paths: usize) {}
+
+    fn on_deleted(&self, _paths: usize) {}
+
+    /// Bytes of file data written to the destination by a finished copy
+    fn on_bytes_copied(&self, _bytes: u64) {}
+
+    /// A large file is `copied` bytes of `len` into its copy, these bytes are reported again
+    /// by [`ProgressReporter::on_bytes_copied`] once it's done
+    fn on_file_progress(&self, _path: &Path, _copied: u64, _len: u64) {}
+
+    /// A path in the destination was changed
+    fn on_change(&self, _change: &Change) {}
+
+    /// `path` failed to sync without stopping the rest of the sync
+    fn on_error(&self, _path: &Path, _cause: &dyn Display) {}
+
+    /// The source path `path` was skipped as fsync can't sync its type, such as a socket
+    fn on_unsupported(&self, _path: &Path) {}
+
+    /// Something worth telling the user, such as what a dry run would do
+    fn on_message(&self, _message: &str) {}
+
+    /// The sync is done
+    fn on_finish(&self, _report: &SyncReport) {}
+}
+
+/// A line of the NDJSON output
+#[derive(Serialize)]
+pub(crate) struct Event<'a> {
+    pub(crate) event: &'a str,
+    pub(crate) path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) cause: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) method: Option<&'a str>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Change, ChangeKind, Differences, ProgressReporter};
+    use crate::{temp_fs, CopyMethod, Synchronize};
+    use std::{
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+    };
+
+    #[derive(Default)]
+    struct Changes(Mutex<Vec<(ChangeKind, PathBuf, u64)>>);
+
+    impl ProgressReporter for Arc<Changes> {
+        fn on_change(&self, change: &Change) {
+            let change = (change.kind, change.path.to_path_buf(), change.bytes);
+            self.0.lock().unwrap().push(change);
+        }
+    }
+
+    #[derive(Default)]
+    struct Methods(Mutex<Vec<Option<CopyMethod>>>);
+
+    impl ProgressReporter for Arc<Methods> {
+        fn on_change(&self, change: &Change) {
+            self.0.lock().unwrap().push(change.method);
+        }
+    }
+
+    #[test]
+    fn test_changes_record_the_copy_method() {
+        let temp = temp_fs!(input / a: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let methods = Arc::new(Methods::default());
+        Synchronize::new(&input, &output)
+            .copy_strategy(vec![CopyMethod::Hardlink])
+            .progress_reporter(methods.clone())
+            .sync()
+            .unwrap();
+        assert_eq!(*methods.0.lock().unwrap(), [Some(CopyMethod::Hardlink)]);
+    }
+
+    #[test]
+    fn test_itemize() {
+        let path = Path::new("out/a.txt");
+        let item = |kind, differences| Change::new(kind, path, 0, differences).itemize(path);
+        assert_eq!(
+            item(ChangeKind::Copy, Differences::CREATED),
+            ">f++++++++ out/a.txt"
+        );
+        let differences = Differences {
+            size: true,
+            mtime: true,
+            ..Differences::default()
+        };
+        assert_eq!(item(ChangeKind::Copy, differences), ">f.st..... out/a.txt");
+        let hardlinked = Change::new(ChangeKind::Copy, path, 0, Differences::CREATED)
+            .with_method(CopyMethod::Hardlink);
+        assert_eq!(hardlinked.itemize(path), "hf++++++++ out/a.txt");
+        assert_eq!(
+            item(ChangeKind::Mkdir, Differences::CREATED),
+            "cd++++++++ out/a.txt"
+        );
+        assert_eq!(
+            item(ChangeKind::Delete, Differences::default()),
+            "*deleting  out/a.txt"
+        );
+    }
+
+    #[test]
+    fn test_changes_are_reported() {
+        let temp = temp_fs!(input / a / one: 4, output / old: 2);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let changes = Arc::new(Changes::default());
+        Synchronize::new(&input, &output)
+            .delete(true)
+            .progress_reporter(changes.clone())
+            .sync()
+            .unwrap();
+        let mut changes = changes.0.lock().unwrap().clone();
+        changes.sort();
+        assert_eq!(
+            changes,
+            [
+                (ChangeKind::Copy, output.join("a/one.text"), 4),
+                (ChangeKind::Mkdir, output.join("a"), 0),
+                (ChangeKind::Delete, output.join("old.text"), 0),
+            ]
+        );
+    }
+}
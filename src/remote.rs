@@ -0,0 +1,536 @@
+//! Remote destinations reached over SSH. The client spawns `fsync --server` on the remote host
+//! through a remote shell and drives it with a small request/response protocol over the
+//! shell's stdin and stdout.
+
+use crate::{
+    backend::{Backend, Kind, Local, Meta},
+    encoding::{os_bytes, os_from_bytes},
+    CopyMethod,
+};
+use filetime::FileTime;
+use std::{
+    ffi::OsString,
+    fs::{self, File, Metadata},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::Mutex,
+};
+
+const HELLO: &[u8] = b"fsync-remote 2\n";
+
+const OP_QUIT: u8 = 0;
+const OP_METADATA: u8 = 1;
+const OP_READ_DIR: u8 = 2;
+const OP_CREATE_DIR: u8 = 3;
+const OP_WRITE_FILE: u8 = 4;
+const OP_SET_TIMES: u8 = 5;
+const OP_SET_PERMISSIONS: u8 = 6;
+const OP_SYMLINK: u8 = 7;
+const OP_READ_LINK: u8 = 8;
+const OP_REMOVE: u8 = 9;
+const OP_RENAME: u8 = 10;
+const OP_REMOVE_DIR: u8 = 11;
+
+/// Split `user@host:/path` into the host and the remote path. Single letter hosts are treated
+/// as Windows drive letters rather than remote destinations.
+pub(crate) fn parse_destination(dest: &Path) -> Option<(String, PathBuf)> {
+    let dest = dest.to_str()?;
+    let (host, path) = dest.split_once(':')?;
+    if host.len() < 2 || host.contains(['/', '\\']) || path.starts_with("//") {
+        return None;
+    }
+    Some((host.to_string(), PathBuf::from(path)))
+}
+
+type Stream = (Box<dyn Write + Send>, Box<dyn Read + Send>);
+
+/// Connection to an `fsync --server` process, requests are serialized over a single stream
+pub(crate) struct Remote {
+    stream: Mutex<Stream>,
+    child: Option<Child>,
+}
+
+impl Remote {
+    /// Run `command --server` on `host` through the remote `shell`, e.g. `ssh -p 2222`
+    pub(crate) fn connect(shell: &str, command: &str, host: &str) -> io::Result<Self> {
+        let mut parts = shell.split_whitespace();
+        let program = parts
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty remote shell"))?;
+        let mut child = Command::new(program)
+            .args(parts)
+            .arg(host)
+            .arg(command)
+            .arg("--server")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let writer = BufWriter::new(child.stdin.take().unwrap());
+        let reader = BufReader::new(child.stdout.take().unwrap());
+        let mut remote = Self::new(Box::new(writer), Box::new(reader))?;
+        remote.child = Some(child);
+        Ok(remote)
+    }
+
+    /// Serve requests from a thread in this process
+    #[cfg(test)]
+    pub(crate) fn spawn_local() -> io::Result<Self> {
+        let (client_read, server_write) = io::pipe()?;
+        let (server_read, client_write) = io::pipe()?;
+        std::thread::spawn(move || serve(server_read, server_write));
+        Self::new(Box::new(client_write), Box::new(client_read))
+    }
+
+    pub(crate) fn new(
+        mut writer: Box<dyn Write + Send>,
+        mut reader: Box<dyn Read + Send>,
+    ) -> io::Result<Self> {
+        writer.write_all(HELLO)?;
+        writer.flush()?;
+        let mut hello = [0; HELLO.len()];
+        reader.read_exact(&mut hello)?;
+        if hello != HELLO {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "remote did not start an fsync server",
+            ));
+        }
+        Ok(Self {
+            stream: Mutex::new((writer, reader)),
+            child: None,
+        })
+    }
+
+    fn call<T>(
+        &self,
+        op: u8,
+        request: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+        response: impl FnOnce(&mut dyn Read) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut stream = self.stream.lock().unwrap();
+        let (writer, reader) = &mut *stream;
+        writer.write_all(&[op])?;
+        request(writer)?;
+        writer.flush()?;
+        match read_u8(reader)? {
+            0 => response(reader),
+            _ => {
+                let msg = read_bytes(reader)?;
+                Err(io::Error::other(String::from_utf8_lossy(&msg).into_owned()))
+            }
+        }
+    }
+}
+
+impl Backend for Remote {
+    fn metadata(&self, path: &Path) -> io::Result<Option<Meta>> {
+        self.call(
+            OP_METADATA,
+            |w| write_path(w, path),
+            |r| match read_u8(r)? {
+                0 => Ok(None),
+                _ => read_meta(r).map(Some),
+            },
+        )
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        self.call(
+            OP_READ_DIR,
+            |w| write_path(w, path),
+            |r| {
+                (0..read_u64(r)?)
+                    .map(|_| read_bytes(r).map(os_from_bytes))
+                    .collect()
+            },
+        )
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.call(OP_CREATE_DIR, |w| write_path(w, path), |_| Ok(()))
+    }
+
+    /// Stream the source into `dest` on the remote
+    fn copy(
+        &self,
+        src: &Path,
+        meta: &Metadata,
+        src_file: Option<File>,
+        dest: &Path,
+    ) -> io::Result<CopyMethod> {
+        let mut src_file = match src_file {
+            Some(file) => file,
+            None => File::open(src)?,
+        };
+        // An open source may have been read comparing contents
+        src_file.seek(SeekFrom::Start(0))?;
+        let len = meta.len();
+        let mut truncated = false;
+        let result = self.call(
+            OP_WRITE_FILE,
+            |w| {
+                write_path(w, dest)?;
+                write_u64(w, len)?;
+                let copied = io::copy(&mut (&mut src_file).take(len), w)?;
+                // Pad a file that shrank while sending so the stream stays in sync, then have
+                // the server discard it
+                io::copy(&mut io::repeat(0).take(len - copied), w)?;
+                truncated = copied < len;
+                w.write_all(&[truncated as u8])
+            },
+            |_| Ok(CopyMethod::Copy),
+        );
+        match truncated {
+            true => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the source was truncated while copying",
+            )),
+            false => result,
+        }
+    }
+
+    fn set_times(&self, path: &Path, atime: FileTime, mtime: FileTime) -> io::Result<()> {
+        self.call(
+            OP_SET_TIMES,
+            |w| {
+                write_path(w, path)?;
+                write_time(w, atime)?;
+                write_time(w, mtime)
+            },
+            |_| Ok(()),
+        )
+    }
+
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.call(
+            OP_SET_PERMISSIONS,
+            |w| {
+                write_path(w, path)?;
+                write_u64(w, mode as u64)
+            },
+            |_| Ok(()),
+        )
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        self.call(
+            OP_SYMLINK,
+            |w| {
+                write_path(w, target)?;
+                write_path(w, path)
+            },
+            |_| Ok(()),
+        )
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        self.call(
+            OP_READ_LINK,
+            |w| write_path(w, path),
+            |r| read_bytes(r).map(|x| os_from_bytes(x).into()),
+        )
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.call(OP_REMOVE, |w| write_path(w, path), |_| Ok(()))
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.call(OP_REMOVE_DIR, |w| write_path(w, path), |_| Ok(()))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.call(
+            OP_RENAME,
+            |w| {
+                write_path(w, from)?;
+                write_path(w, to)
+            },
+            |_| Ok(()),
+        )
+    }
+}
+
+impl Drop for Remote {
+    fn drop(&mut self) {
+        if let Ok(mut stream) = self.stream.lock() {
+            let _ = stream.0.write_all(&[OP_QUIT]);
+            let _ = stream.0.flush();
+        }
+        if let Some(child) = self.child.as_mut() {
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Serve requests from a client until it disconnects
+pub fn serve(reader: impl Read, writer: impl Write) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut hello = [0; HELLO.len()];
+    reader.read_exact(&mut hello)?;
+    if hello != HELLO {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "client is not an fsync client",
+        ));
+    }
+    writer.write_all(HELLO)?;
+    writer.flush()?;
+
+    let local = Local::default();
+    loop {
+        let op = match read_u8(&mut reader) {
+            Ok(op) => op,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut response = Vec::new();
+        let result = match op {
+            OP_QUIT => return Ok(()),
+            OP_METADATA => {
+                let path = read_path(&mut reader)?;
+                local.metadata(&path).and_then(|meta| match meta {
+                    Some(meta) => {
+                        response.push(1);
+                        write_meta(&mut response, &meta)
+                    }
+                    None => {
+                        response.push(0);
+                        Ok(())
+                    }
+                })
+            }
+            OP_READ_DIR => {
+                let path = read_path(&mut reader)?;
+                local.read_dir(&path).and_then(|names| {
+                    write_u64(&mut response, names.len() as u64)?;
+                    for name in names {
+                        write_bytes(&mut response, &os_bytes(&name))?;
+                    }
+                    Ok(())
+                })
+            }
+            OP_CREATE_DIR => local.create_dir(&read_path(&mut reader)?),
+            OP_WRITE_FILE => {
+                let path = read_path(&mut reader)?;
+                let len = read_u64(&mut reader)?;
+                receive_file(&mut reader, &path, len)
+            }
+            OP_SET_TIMES => {
+                let path = read_path(&mut reader)?;
+                let atime = read_time(&mut reader)?;
+                let mtime = read_time(&mut reader)?;
+                local.set_times(&path, atime, mtime)
+            }
+            OP_SET_PERMISSIONS => {
+                let path = read_path(&mut reader)?;
+                let mode = read_u64(&mut reader)? as u32;
+                local.set_permissions(&path, mode)
+            }
+            OP_SYMLINK => {
+                let target = read_path(&mut reader)?;
+                let path = read_path(&mut reader)?;
+                local.symlink(&target, &path)
+            }
+            OP_READ_LINK => local
+                .read_link(&read_path(&mut reader)?)
+                .and_then(|x| write_bytes(&mut response, &os_bytes(x.as_os_str()))),
+            OP_REMOVE => local.remove(&read_path(&mut reader)?),
+            OP_REMOVE_DIR => local.remove_dir(&read_path(&mut reader)?),
+            OP_RENAME => {
+                let from = read_path(&mut reader)?;
+                let to = read_path(&mut reader)?;
+                local.rename(&from, &to)
+            }
+            op => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown remote operation {}", op),
+                ))
+            }
+        };
+        match result {
+            Ok(_) => {
+                writer.write_all(&[0])?;
+                writer.write_all(&response)?;
+            }
+            Err(e) => {
+                writer.write_all(&[1])?;
+                write_bytes(&mut writer, e.to_string().as_bytes())?;
+            }
+        }
+        writer.flush()?;
+    }
+}
+
+/// Write `len` bytes from the stream into `path`, always consuming them so a failed write
+/// doesn't desynchronize the protocol. The trailing status byte is nonzero when the client
+/// padded a source that shrank, and the file is discarded.
+fn receive_file(reader: &mut impl Read, path: &Path, len: u64) -> io::Result<()> {
+    let mut data = reader.by_ref().take(len);
+    let mut result = File::create(path).and_then(|mut file| io::copy(&mut data, &mut file));
+    io::copy(&mut data, &mut io::sink())?;
+    if read_u8(reader)? != 0 {
+        result = Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the source was truncated while copying",
+        ));
+    }
+    if result.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    result.map(|_| ())
+}
+
+fn read_u8(r: &mut (impl Read + ?Sized)) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u64(r: &mut (impl Read + ?Sized)) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn write_u64(w: &mut (impl Write + ?Sized), value: u64) -> io::Result<()> {
+    w.write_all(&value.to_be_bytes())
+}
+
+fn read_bytes(r: &mut (impl Read + ?Sized)) -> io::Result<Vec<u8>> {
+    let len = read_u64(r)?;
+    let mut buf = Vec::new();
+    r.take(len).read_to_end(&mut buf)?;
+    match buf.len() as u64 == len {
+        true => Ok(buf),
+        false => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+fn write_bytes(w: &mut (impl Write + ?Sized), bytes: &[u8]) -> io::Result<()> {
+    write_u64(w, bytes.len() as u64)?;
+    w.write_all(bytes)
+}
+
+fn read_path(r: &mut (impl Read + ?Sized)) -> io::Result<PathBuf> {
+    read_bytes(r).map(|x| os_from_bytes(x).into())
+}
+
+fn write_path(w: &mut (impl Write + ?Sized), path: &Path) -> io::Result<()> {
+    write_bytes(w, &os_bytes(path.as_os_str()))
+}
+
+fn read_time(r: &mut (impl Read + ?Sized)) -> io::Result<FileTime> {
+    let seconds = read_u64(r)? as i64;
+    let nanos = read_u64(r)? as u32;
+    Ok(FileTime::from_unix_time(seconds, nanos))
+}
+
+fn write_time(w: &mut (impl Write + ?Sized), time: FileTime) -> io::Result<()> {
+    write_u64(w, time.unix_seconds() as u64)?;
+    write_u64(w, time.nanoseconds() as u64)
+}
+
+fn read_meta(r: &mut (impl Read + ?Sized)) -> io::Result<Meta> {
+    let kind = match read_u8(r)? {
+        0 => Kind::File,
+        1 => Kind::Dir,
+        2 => Kind::Symlink,
+        _ => Kind::Other,
+    };
+    Ok(Meta {
+        kind,
+        len: read_u64(r)?,
+        mtime: read_time(r)?,
+        mode: read_u64(r)? as u32,
+    })
+}
+
+fn write_meta(w: &mut (impl Write + ?Sized), meta: &Meta) -> io::Result<()> {
+    let kind = match meta.kind {
+        Kind::File => 0,
+        Kind::Dir => 1,
+        Kind::Symlink => 2,
+        Kind::Other => 3,
+    };
+    w.write_all(&[kind])?;
+    write_u64(w, meta.len)?;
+    write_time(w, meta.mtime)?;
+    write_u64(w, meta.mode as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_destination, Remote};
+    use crate::backend::{Backend, Kind};
+    use crate::temp_fs;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_parse_destination() {
+        assert_eq!(
+            parse_destination(Path::new("user@host:/backup")),
+            Some(("user@host".to_string(), PathBuf::from("/backup")))
+        );
+        assert_eq!(parse_destination(Path::new("C:\\backup")), None);
+        assert_eq!(parse_destination(Path::new("./a:b")), None);
+        assert_eq!(parse_destination(Path::new("/backup")), None);
+        assert_eq!(parse_destination(Path::new("s3://bucket/backup")), None);
+    }
+
+    #[test]
+    fn test_remote_operations() {
+        let remote = Remote::spawn_local().unwrap();
+
+        let temp = temp_fs!(src: 16);
+        let dir = temp.path().join("dir");
+        remote.create_dir(&dir).unwrap();
+        let src = temp.path().join("src.text");
+        let meta = src.metadata().unwrap();
+        remote.copy(&src, &meta, None, &dir.join("file")).unwrap();
+
+        let meta = remote.metadata(&dir.join("file")).unwrap().unwrap();
+        assert_eq!((meta.kind, meta.len), (Kind::File, 16));
+        assert_eq!(remote.metadata(&dir.join("missing")).unwrap(), None);
+        assert_eq!(remote.read_dir(&dir).unwrap(), vec!["file"]);
+        assert!(remote.create_dir(&dir).is_err());
+
+        remote.remove(&dir.join("file")).unwrap();
+        assert!(!dir.join("file").exists());
+    }
+
+    #[test]
+    fn test_copy_rewinds_open_source() {
+        use std::io::Read;
+
+        let remote = Remote::spawn_local().unwrap();
+        let temp = temp_fs!(src: 16);
+        let src = temp.path().join("src.text");
+        let mut file = std::fs::File::open(&src).unwrap();
+        std::io::copy(&mut file.by_ref(), &mut std::io::sink()).unwrap();
+
+        let dest = temp.path().join("dest.text");
+        let meta = src.metadata().unwrap();
+        remote.copy(&src, &meta, Some(file), &dest).unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), [b'a'; 16]);
+    }
+
+    #[test]
+    fn test_copy_fails_for_truncated_source() {
+        let remote = Remote::spawn_local().unwrap();
+        let temp = temp_fs!(src: 16);
+        let src = temp.path().join("src.text");
+        let meta = src.metadata().unwrap();
+        std::fs::write(&src, b"short").unwrap();
+
+        let dest = temp.path().join("dest.text");
+        let e = remote.copy(&src, &meta, None, &dest).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(!dest.exists());
+        // The stream is still in sync for the next request
+        assert_eq!(remote.metadata(&dest).unwrap(), None);
+    }
+}
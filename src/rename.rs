@@ -0,0 +1,120 @@
+//! Renaming destination files that moved in the source instead of copying them again
+
+use crate::{
+    backend::{Kind, Meta},
+    delete::DeletePlan,
+    truncate, Result, Synchronize,
+};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Size and modified time at the destination's precision
+type Key = (u64, i128);
+
+/// Destination files about to be deleted, indexed so a new source file can be matched with one
+#[derive(Debug, Default)]
+pub(crate) struct Renames(Mutex<HashMap<Key, Vec<PathBuf>>>);
+
+impl Synchronize {
+    /// Index the files at or below the paths `plan` deletes
+    pub(crate) fn index_renames(&self, plan: &DeletePlan) -> Result<Renames> {
+        let mut files = HashMap::new();
+        for path in plan.paths.iter() {
+            self.index_path(path, &mut files)?;
+        }
+        Ok(Renames(Mutex::new(files)))
+    }
+
+    fn index_path(&self, path: &Path, files: &mut HashMap<Key, Vec<PathBuf>>) -> io::Result<()> {
+        if self.is_protected(path) {
+            return Ok(());
+        }
+        match self.dest_backend().metadata(path)? {
+            Some(meta) if meta.kind == Kind::File => {
+                let key = self.rename_key(&meta);
+                files.entry(key).or_default().push(path.to_path_buf());
+            }
+            Some(meta) if meta.kind == Kind::Dir => {
+                for name in self.dest_backend().read_dir(path)? {
+                    self.index_path(&path.join(name), files)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn rename_key(&self, meta: &Meta) -> Key {
+        let resolution = self.dest_backend().mtime_resolution();
+        (meta.len, truncate(meta.mtime, resolution))
+    }
+
+    /// Take a destination file slated for deletion with the size and modified time of the
+    /// source file `src`, and its contents too when comparing contents
+    pub(crate) fn take_renamed(&self, src: &Path, meta: &Meta) -> Result<Option<PathBuf>> {
+        let Some(renames) = &self.renames else {
+            return Ok(None);
+        };
+        let key = self.rename_key(meta);
+        let candidates = renames.0.lock().unwrap().get(&key).cloned();
+        for candidate in candidates.into_iter().flatten() {
+            if let Some(check) = self
+                .check_content
+                .filter(|_| self.dest_backend().is_local())
+            {
+                if !check.equal(&mut self.open_source(src)?, &mut File::open(&candidate)?)? {
+                    continue;
+                }
+            }
+            // Another thread may have taken it in the meantime
+            let mut files = renames.0.lock().unwrap();
+            let list = files.entry(key).or_default();
+            if let Some(i) = list.iter().position(|x| *x == candidate) {
+                list.swap_remove(i);
+                return Ok(Some(candidate));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{temp_fs, Synchronize};
+
+    #[test]
+    fn test_moved_files_are_renamed() {
+        let temp = temp_fs!(input / one: 4, input / two: 8);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let sync = || {
+            Synchronize::new(&input, &output)
+                .delete(true)
+                .detect_renames(true)
+                .sync()
+                .unwrap()
+        };
+        sync();
+
+        std::fs::create_dir(input.join("moved")).unwrap();
+        std::fs::rename(input.join("one.text"), input.join("moved/one.text")).unwrap();
+        std::fs::rename(input.join("two.text"), input.join("renamed.text")).unwrap();
+        let report = sync();
+        assert_eq!(report.renamed, 2);
+        assert_eq!(report.bytes_copied, 0);
+        assert_eq!(
+            std::fs::read(output.join("moved/one.text")).unwrap(),
+            [b'a'; 4]
+        );
+        assert_eq!(
+            std::fs::read(output.join("renamed.text")).unwrap(),
+            [b'a'; 8]
+        );
+        assert!(!output.join("one.text").exists());
+        assert!(!output.join("two.text").exists());
+    }
+}
@@ -0,0 +1,228 @@
+//! Retrying destination operations that fail for reasons likely to pass, such as a busy file
+//! or a network file system dropping a request
+
+use crate::{
+    backend::{Backend, Meta},
+    copy::{self, OnCopied},
+    CopyMethod,
+};
+use filetime::FileTime;
+use std::{
+    collections::hash_map::RandomState,
+    ffi::OsString,
+    fs::{File, Metadata},
+    hash::{BuildHasher, Hasher},
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Wraps a backend, retrying each failed operation with jittered exponential backoff
+pub(crate) struct Retry {
+    pub(crate) inner: Box<dyn Backend>,
+    pub(crate) retries: u32,
+    pub(crate) delay: Duration,
+    /// Sources reopened for another attempt are read without updating their access time
+    pub(crate) noatime: bool,
+}
+
+impl Retry {
+    /// The source for an attempt at copying it. An open source is only reused by the first
+    /// attempt, later ones reopen it.
+    fn source(&self, src: &Path, src_file: &mut Option<File>) -> io::Result<Option<File>> {
+        match src_file.take() {
+            None if self.noatime => copy::open_noatime(src).map(Some),
+            file => Ok(file),
+        }
+    }
+
+    fn run<T>(&self, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    std::thread::sleep(backoff(self.delay, attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Whether an error is worth retrying rather than a sign the operation can't succeed
+fn is_transient(e: &io::Error) -> bool {
+    use io::ErrorKind::*;
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(libc::ESTALE) {
+        return true;
+    }
+    matches!(
+        e.kind(),
+        Interrupted
+            | WouldBlock
+            | TimedOut
+            | ResourceBusy
+            | ConnectionReset
+            | ConnectionAborted
+            | NotConnected
+            | NetworkDown
+            | UnexpectedEof
+    )
+}
+
+/// `delay` doubled for each previous attempt, then scaled by a random factor between 0.5 and 1.5
+/// so threads that failed together don't retry together
+fn backoff(delay: Duration, attempt: u32) -> Duration {
+    let base = delay.saturating_mul(1 << attempt.min(16));
+    let random = RandomState::new().build_hasher().finish();
+    base.mul_f64(0.5 + (random % 1000) as f64 / 1000.0)
+}
+
+impl Backend for Retry {
+    fn metadata(&self, path: &Path) -> io::Result<Option<Meta>> {
+        self.run(|| self.inner.metadata(path))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        self.run(|| self.inner.read_dir(path))
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.run(|| self.inner.create_dir(path))
+    }
+
+    fn copy(
+        &self,
+        src: &Path,
+        meta: &Metadata,
+        src_file: Option<File>,
+        dest: &Path,
+    ) -> io::Result<CopyMethod> {
+        let mut src_file = src_file;
+        self.run(|| {
+            let file = self.source(src, &mut src_file)?;
+            self.inner.copy(src, meta, file, dest)
+        })
+    }
+
+    fn copy_with_progress(
+        &self,
+        src: &Path,
+        meta: &Metadata,
+        src_file: Option<File>,
+        dest: &Path,
+        on_copied: OnCopied,
+    ) -> io::Result<CopyMethod> {
+        let mut src_file = src_file;
+        self.run(|| {
+            let file = self.source(src, &mut src_file)?;
+            self.inner
+                .copy_with_progress(src, meta, file, dest, on_copied)
+        })
+    }
+
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.run(|| self.inner.set_permissions(path, mode))
+    }
+
+    fn set_times(&self, path: &Path, atime: FileTime, mtime: FileTime) -> io::Result<()> {
+        self.run(|| self.inner.set_times(path, atime, mtime))
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        self.run(|| self.inner.symlink(target, path))
+    }
+
+    fn symlink_dir(&self, target: &Path, path: &Path) -> io::Result<()> {
+        self.run(|| self.inner.symlink_dir(target, path))
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        self.run(|| self.inner.read_link(path))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.run(|| self.inner.remove(path))
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.run(|| self.inner.remove_dir(path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.run(|| self.inner.rename(from, to))
+    }
+
+    fn mtime_resolution(&self) -> Duration {
+        self.inner.mtime_resolution()
+    }
+
+    fn is_local(&self) -> bool {
+        self.inner.is_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backoff, Retry};
+    use crate::backend::{Backend, Local};
+    use std::{
+        io,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_retries_reopen_without_atime() {
+        use std::io::Read;
+
+        let temp = crate::temp_fs!(src: 16);
+        let src = temp.path().join("src.text");
+        let old = filetime::FileTime::from_unix_time(1_700_000_000, 0);
+        filetime::set_file_times(&src, old, old).unwrap();
+        let retry = Retry {
+            inner: Box::new(Local::default()),
+            retries: 1,
+            delay: Duration::from_millis(1),
+            noatime: true,
+        };
+        let mut file = retry.source(&src, &mut None).unwrap().unwrap();
+        file.read_to_end(&mut Vec::new()).unwrap();
+        let meta = src.metadata().unwrap();
+        assert_eq!(filetime::FileTime::from_last_access_time(&meta), old);
+    }
+
+    #[test]
+    fn test_retries_transient_errors() {
+        let retry = Retry {
+            inner: Box::new(Local::default()),
+            retries: 3,
+            delay: Duration::from_millis(1),
+            noatime: false,
+        };
+        let calls = AtomicUsize::new(0);
+        let result = retry.run(|| match calls.fetch_add(1, Ordering::Relaxed) {
+            0 | 1 => Err(io::Error::from(io::ErrorKind::ResourceBusy)),
+            _ => Ok(()),
+        });
+        assert!(result.is_ok());
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+
+        // Permanent failures and exhausted retries are returned as they are
+        let calls = AtomicUsize::new(0);
+        let result = retry.run(|| -> io::Result<()> {
+            calls.fetch_add(1, Ordering::Relaxed);
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        let result = retry.run(|| -> io::Result<()> { Err(io::ErrorKind::TimedOut.into()) });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(retry.is_local());
+
+        let delay = backoff(Duration::from_millis(100), 2);
+        assert!(delay >= Duration::from_millis(200) && delay < Duration::from_millis(600));
+    }
+}
@@ -0,0 +1,286 @@
+//! SFTP sources and destinations, `sftp://user@host:port/path`. Authentication tries the ssh
+//! agent, then the default keys in `~/.ssh`, then the `FSYNC_SFTP_PASSWORD` environment
+//! variable. The host key must already be in `~/.ssh/known_hosts`.
+
+use crate::{
+    backend::{Backend, Kind, Meta},
+    CopyMethod,
+};
+use filetime::FileTime;
+use ssh2::{CheckResult, FileStat, KnownHostFileKind, Session};
+use std::{
+    ffi::OsString,
+    fs::{File, Metadata},
+    io,
+    net::TcpStream,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Where an `sftp://` url points
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Url {
+    pub(crate) user: Option<String>,
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) path: PathBuf,
+}
+
+pub(crate) fn parse_url(path: &Path) -> Option<Url> {
+    let rest = path.to_str()?.strip_prefix("sftp://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (user, host) = match authority.rsplit_once('@') {
+        Some((user, host)) => (Some(user.to_string()), host),
+        None => (None, authority),
+    };
+    let (host, port) = match host.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (host, 22),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some(Url {
+        user,
+        host: host.to_string(),
+        port,
+        path: PathBuf::from(path),
+    })
+}
+
+pub(crate) struct Sftp {
+    sftp: ssh2::Sftp,
+    // Dropping the session closes the connection the sftp channel runs over
+    _session: Session,
+}
+
+impl Sftp {
+    pub(crate) fn connect(url: &Url) -> io::Result<Self> {
+        let mut session = Session::new()?;
+        session.set_tcp_stream(TcpStream::connect((url.host.as_str(), url.port))?);
+        session.handshake()?;
+        verify_host_key(&session, url)?;
+
+        let user = match &url.user {
+            Some(user) => user.clone(),
+            None => std::env::var("USER")
+                .or_else(|_| std::env::var("USERNAME"))
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "no user given for sftp url")
+                })?,
+        };
+        let _ = session.userauth_agent(&user);
+        if !session.authenticated() {
+            if let Some(home) = home_dir() {
+                for key in ["id_ed25519", "id_ecdsa", "id_rsa"] {
+                    let key = home.join(".ssh").join(key);
+                    if key.exists()
+                        && session
+                            .userauth_pubkey_file(&user, None, &key, None)
+                            .is_ok()
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+        if !session.authenticated() {
+            if let Ok(password) = std::env::var("FSYNC_SFTP_PASSWORD") {
+                session.userauth_password(&user, &password)?;
+            }
+        }
+        if !session.authenticated() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("failed to authenticate to {} as {}", url.host, user),
+            ));
+        }
+        Ok(Self {
+            sftp: session.sftp()?,
+            _session: session,
+        })
+    }
+
+    /// Entries of a directory together with their metadata
+    pub(crate) fn entries(&self, path: &Path) -> io::Result<Vec<(OsString, Meta)>> {
+        Ok(self
+            .sftp
+            .readdir(path)?
+            .into_iter()
+            .filter_map(|(path, stat)| Some((path.file_name()?.to_os_string(), to_meta(&stat))))
+            .collect())
+    }
+
+    /// Copy the remote file at `src` into the local file `dest`
+    pub(crate) fn download(&self, src: &Path, dest: &Path) -> io::Result<u64> {
+        let mut remote = self.sftp.open(src)?;
+        io::copy(&mut remote, &mut File::create(dest)?)
+    }
+}
+
+fn verify_host_key(session: &Session, url: &Url) -> io::Result<()> {
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| io::Error::other("sftp server sent no host key"))?;
+    let mut known_hosts = session.known_hosts()?;
+    if let Some(home) = home_dir() {
+        let _ = known_hosts.read_file(&home.join(".ssh/known_hosts"), KnownHostFileKind::OpenSSH);
+    }
+    match known_hosts.check_port(&url.host, url.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "host key for {} does not match ~/.ssh/known_hosts",
+                url.host
+            ),
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("host key for {} is not in ~/.ssh/known_hosts", url.host),
+        )),
+    }
+}
+
+pub(crate) fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+fn to_meta(stat: &FileStat) -> Meta {
+    let kind = match () {
+        _ if stat.file_type().is_symlink() => Kind::Symlink,
+        _ if stat.is_dir() => Kind::Dir,
+        _ if stat.is_file() => Kind::File,
+        _ => Kind::Other,
+    };
+    Meta {
+        kind,
+        len: stat.size.unwrap_or(0),
+        mtime: FileTime::from_unix_time(stat.mtime.unwrap_or(0) as i64, 0),
+        mode: stat.perm.unwrap_or(0o644) & 0o7777,
+    }
+}
+
+fn empty_stat() -> FileStat {
+    FileStat {
+        size: None,
+        uid: None,
+        gid: None,
+        perm: None,
+        atime: None,
+        mtime: None,
+    }
+}
+
+impl Backend for Sftp {
+    fn metadata(&self, path: &Path) -> io::Result<Option<Meta>> {
+        match self.sftp.lstat(path).map_err(io::Error::from) {
+            Ok(stat) => Ok(Some(to_meta(&stat))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        Ok(self.entries(path)?.into_iter().map(|x| x.0).collect())
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        Ok(self.sftp.mkdir(path, 0o755)?)
+    }
+
+    fn copy(
+        &self,
+        src: &Path,
+        meta: &Metadata,
+        src_file: Option<File>,
+        dest: &Path,
+    ) -> io::Result<CopyMethod> {
+        let mut src_file = match src_file {
+            Some(file) => file,
+            None => File::open(src)?,
+        };
+        let result = self
+            .sftp
+            .create(dest)
+            .map_err(io::Error::from)
+            .and_then(|mut remote| {
+                io::copy(&mut io::Read::take(&mut src_file, meta.len()), &mut remote)
+            });
+        if result.is_err() {
+            let _ = self.sftp.unlink(dest);
+        }
+        result.map(|_| CopyMethod::Copy)
+    }
+
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let stat = FileStat {
+            perm: Some(mode),
+            ..empty_stat()
+        };
+        Ok(self.sftp.setstat(path, stat)?)
+    }
+
+    fn set_times(&self, path: &Path, atime: FileTime, mtime: FileTime) -> io::Result<()> {
+        let stat = FileStat {
+            atime: Some(atime.unix_seconds().max(0) as u64),
+            mtime: Some(mtime.unix_seconds().max(0) as u64),
+            ..empty_stat()
+        };
+        Ok(self.sftp.setstat(path, stat)?)
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        Ok(self.sftp.symlink(target, path)?)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        Ok(self.sftp.readlink(path)?)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        Ok(self.sftp.unlink(path)?)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        Ok(self.sftp.rmdir(path)?)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        Ok(self.sftp.rename(from, to, None)?)
+    }
+
+    /// SFTP version 3 only transfers whole seconds
+    fn mtime_resolution(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_url, Url};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            parse_url(Path::new("sftp://me@example.com:2222/srv/data")),
+            Some(Url {
+                user: Some("me".to_string()),
+                host: "example.com".to_string(),
+                port: 2222,
+                path: PathBuf::from("/srv/data"),
+            })
+        );
+        assert_eq!(
+            parse_url(Path::new("sftp://example.com")).map(|x| (x.user, x.port, x.path)),
+            Some((None, 22, PathBuf::from("/")))
+        );
+        assert_eq!(parse_url(Path::new("/srv/data")), None);
+    }
+}
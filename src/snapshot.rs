@@ -0,0 +1,267 @@
+//! Dated snapshots of the source, each a full copy that hardlinks the files unchanged since the
+//! snapshot before it, pruned to a retention policy
+
+use crate::{http, Error, Result, SyncReport, Synchronize};
+use std::{collections::HashSet, fs, io, path::Path, time::SystemTime};
+
+/// How many snapshots [`Synchronize::snapshot`] keeps, as the newest snapshot of each of the
+/// most recent days, weeks and months that have one. Snapshots kept by any count stay, and
+/// with every count at zero nothing is pruned.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    pub daily: usize,
+    /// Weeks start on Monday
+    pub weekly: usize,
+    pub monthly: usize,
+}
+
+impl Retention {
+    /// The snapshots out of `names`, sorted oldest first, that are kept
+    fn keep<'a>(&self, names: &'a [String]) -> HashSet<&'a str> {
+        let mut keep = HashSet::new();
+        if *self == Retention::default() {
+            keep.extend(names.iter().map(|x| x.as_str()));
+            return keep;
+        }
+        let week = |days: i64| (days + 3).div_euclid(7);
+        let month = |days: i64| {
+            let (year, month, _) = http::civil_from_days(days);
+            year * 12 + month
+        };
+        let periods: [(usize, &dyn Fn(i64) -> i64); 3] = [
+            (self.daily, &|days| days),
+            (self.weekly, &week),
+            (self.monthly, &month),
+        ];
+        for (count, period) in periods {
+            let mut seen = Vec::new();
+            for name in names.iter().rev() {
+                let Some(days) = snapshot_days(name) else {
+                    continue;
+                };
+                if seen.last() == Some(&period(days)) {
+                    continue;
+                }
+                if seen.len() == count {
+                    break;
+                }
+                seen.push(period(days));
+                keep.insert(name.as_str());
+            }
+        }
+        // The snapshot just made is never pruned
+        keep.extend(names.last().map(|x| x.as_str()));
+        keep
+    }
+}
+
+impl Synchronize {
+    /// Sync the source into a new directory in the destination named after the current UTC
+    /// time, such as `2024-05-01T12:00`, hardlinking files unchanged since the previous
+    /// snapshot. Once the sync has finished without errors, the snapshots `retention` doesn't
+    /// keep are deleted.
+    pub fn snapshot(self, retention: Retention) -> Result<SyncReport> {
+        self.snapshot_at(retention, SystemTime::now())
+    }
+
+    fn snapshot_at(mut self, retention: Retention, now: SystemTime) -> Result<SyncReport> {
+        let backend = self.connect()?;
+        if !backend.is_local() {
+            return Err(Error::Unsupported(
+                "snapshots can only be made in a local destination".to_string(),
+            ));
+        }
+        self.backend = Some(backend);
+        let root = self.dest.clone();
+        let name = snapshot_name(now);
+        let mut names = list_snapshots(&root)?;
+        // Linked relative to the new snapshot, so a relative destination still works
+        if let Some(previous) = names.iter().rev().find(|x| **x < name) {
+            self.link_dest.insert(0, Path::new("..").join(previous));
+        }
+        self.dest = root.join(&name);
+        if !names.contains(&name) {
+            names.push(name);
+            names.sort();
+        }
+        let keep = retention.keep(&names);
+        self.pruned_snapshots = names
+            .iter()
+            .filter(|x| !keep.contains(x.as_str()))
+            .map(|x| root.join(x))
+            .collect();
+        self.sync()
+    }
+
+    /// Delete the snapshots the retention doesn't keep, once the new one is complete
+    pub(crate) fn prune_snapshots(&self) -> Result<()> {
+        for path in self.pruned_snapshots.iter() {
+            match self.dry_run {
+                true => self.report_dry_run("delete snapshot", path),
+                false => fs::remove_dir_all(path).map_err(|e| Error::with_path(e, path))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Names of the snapshot directories in `root`, oldest first
+fn list_snapshots(root: &Path) -> io::Result<Vec<String>> {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_dir() && snapshot_days(&name).is_some() {
+            names.push(name);
+        }
+    }
+    // The format sorts chronologically
+    names.sort();
+    Ok(names)
+}
+
+/// Name of a snapshot made at `time`, in UTC to the minute
+fn snapshot_name(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (year, month, day) = http::civil_from_days(secs.div_euclid(86400));
+    let secs = secs.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60
+    )
+}
+
+/// Day since the epoch a snapshot called `name` was made on, `None` when it isn't one
+fn snapshot_days(name: &str) -> Option<i64> {
+    let bytes = name.as_bytes();
+    let digits = [0..4, 5..7, 8..10, 11..13, 14..16];
+    let separators = [(4, b'-'), (7, b'-'), (10, b'T'), (13, b':')];
+    if bytes.len() != 16
+        || separators.iter().any(|(i, x)| bytes[*i] != *x)
+        || digits
+            .iter()
+            .any(|x| !bytes[x.clone()].iter().all(u8::is_ascii_digit))
+    {
+        return None;
+    }
+    let field = |i: usize| name[digits[i].clone()].parse::<i64>().ok();
+    let (year, month, day) = (field(0)?, field(1)?, field(2)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    Some(http::days_from_civil(year, month, day))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{snapshot_days, snapshot_name, Retention};
+    use crate::{temp_fs, Synchronize};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_retention_keeps_newest_of_each_period() {
+        let names = [
+            "2024-03-31T08:00",
+            "2024-04-28T08:00",
+            "2024-04-29T08:00",
+            "2024-04-30T08:00",
+            "2024-05-01T08:00",
+            "2024-05-01T12:00",
+        ]
+        .map(String::from);
+        assert_eq!(snapshot_days("2024-05-01T12:00"), snapshot_days(&names[4]));
+        assert_eq!(snapshot_days("2024-13-01T12:00"), None);
+        assert_eq!(snapshot_days("2024-05-01"), None);
+
+        let retention = Retention {
+            daily: 2,
+            weekly: 2,
+            monthly: 3,
+        };
+        let mut keep = retention.keep(&names).into_iter().collect::<Vec<_>>();
+        keep.sort();
+        // Days of May 1st and April 30th, the weeks starting April 29th and 22nd, and the
+        // months of May, April and March
+        assert_eq!(
+            keep,
+            [
+                "2024-03-31T08:00",
+                "2024-04-28T08:00",
+                "2024-04-30T08:00",
+                "2024-05-01T12:00"
+            ]
+        );
+        assert_eq!(Retention::default().keep(&names).len(), names.len());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_snapshots_link_unchanged_files() {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp = temp_fs!(input / same: 4, input / changed: 8);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let day = |n: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(n * 86400);
+        let retention = Retention {
+            daily: 2,
+            ..Retention::default()
+        };
+        let snapshot = |n| {
+            Synchronize::new(&input, &output)
+                .snapshot_at(retention, day(n))
+                .unwrap()
+        };
+        snapshot(0);
+        std::fs::write(input.join("changed.text"), b"changed").unwrap();
+        let report = snapshot(1);
+        assert_eq!(report.bytes_copied, 7);
+
+        let (first, second) = (snapshot_name(day(0)), snapshot_name(day(1)));
+        assert_eq!(first, "1970-01-01T00:00");
+        let inode = |name: &str, file| {
+            std::fs::metadata(output.join(name).join(file))
+                .unwrap()
+                .ino()
+        };
+        assert_eq!(inode(&first, "same.text"), inode(&second, "same.text"));
+        assert_ne!(
+            inode(&first, "changed.text"),
+            inode(&second, "changed.text")
+        );
+
+        // A dry run reports the snapshot it would prune through the progress reporter
+        #[derive(Default)]
+        struct Messages(std::sync::Mutex<Vec<String>>);
+        impl crate::ProgressReporter for std::sync::Arc<Messages> {
+            fn on_message(&self, message: &str) {
+                self.0.lock().unwrap().push(message.to_string());
+            }
+        }
+        let messages = std::sync::Arc::new(Messages::default());
+        Synchronize::new(&input, &output)
+            .dry_run(true)
+            .progress_reporter(messages.clone())
+            .snapshot_at(retention, day(2))
+            .unwrap();
+        let pruned = format!("Would delete snapshot {:?}", output.join(&first));
+        assert!(messages.0.lock().unwrap().contains(&pruned));
+        assert!(output.join(&first).exists());
+
+        // Only the two newest days are kept
+        snapshot(2);
+        assert!(!output.join(&first).exists());
+        assert!(output.join(&second).exists());
+    }
+}
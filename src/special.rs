@@ -0,0 +1,213 @@
+//! Named pipes and device nodes, which hold no data of their own and are recreated in the
+//! destination rather than copied, and the other entries that aren't files, directories or
+//! symlinks
+
+use crate::{Error, ProgressReporter, Result, Synchronize};
+use std::{fs, path::Path};
+
+#[cfg(unix)]
+use crate::{backend::Meta, ChangeKind};
+#[cfg(unix)]
+use filetime::FileTime;
+
+/// Whether an entry of `file_type` is neither a file, a directory nor a symlink
+pub(crate) fn is_special(file_type: fs::FileType) -> bool {
+    !(file_type.is_file() || file_type.is_dir() || file_type.is_symlink())
+}
+
+impl Synchronize {
+    /// Skip the source path `path` as its type can't be synced, or fail with
+    /// [`Synchronize::fail_on_unsupported`]
+    pub(crate) fn skip_unsupported(&self, path: &Path) -> Result<()> {
+        if self.fail_on_unsupported {
+            return Err(Error::UnsupportedType {
+                path: path.to_path_buf(),
+            });
+        }
+        self.progress.on_skipped(1);
+        self.progress.on_unsupported(path);
+        Ok(())
+    }
+
+    /// Whether a source entry of `file_type` is recreated by [`Synchronize::specials`] or
+    /// [`Synchronize::devices`]
+    #[cfg(unix)]
+    pub(crate) fn syncs_special(&self, file_type: fs::FileType) -> bool {
+        use std::os::unix::fs::FileTypeExt;
+
+        (self.specials && file_type.is_fifo())
+            || (self.devices && (file_type.is_block_device() || file_type.is_char_device()))
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn syncs_special(&self, _file_type: fs::FileType) -> bool {
+        false
+    }
+
+    /// Recreate the named pipe or device node `src` at `dest`, unless one of the same type and
+    /// device number is already there
+    #[cfg(unix)]
+    pub(crate) fn sync_special(&self, src: &Path, dest: &Path) -> Result<()> {
+        use std::os::unix::{
+            ffi::OsStrExt,
+            fs::{FileTypeExt, MetadataExt},
+        };
+
+        let meta = src
+            .symlink_metadata()
+            .map_err(|e| Error::with_path(e, src))?;
+        if !self.dest_backend().is_local() {
+            self.progress.println(format!(
+                "Skipping {:?}: special files can only be created in local destinations",
+                src
+            ));
+            self.progress.on_skipped(1);
+            return Ok(());
+        }
+        let existing = dest.symlink_metadata().ok();
+        if let Some(existing) = &existing {
+            if existing.file_type() == meta.file_type() && existing.rdev() == meta.rdev() {
+                self.progress.on_skipped(1);
+                return Ok(());
+            }
+        }
+        let src_meta = Meta::from_local(&meta);
+        let differences =
+            self.differences(&src_meta, existing.as_ref().map(Meta::from_local).as_ref());
+        if self.dry_run {
+            self.report_dry_run("create", dest);
+            self.progress.on_copied(1);
+            self.progress
+                .changed(ChangeKind::Special, dest, 0, differences);
+            return Ok(());
+        }
+        if existing.is_some() {
+            self.remove_all(dest)
+                .map_err(|e| Error::with_path(e, dest))?;
+        }
+
+        let path = std::ffi::CString::new(dest.as_os_str().as_bytes())
+            .map_err(|e| Error::with_path(e.into(), dest))?;
+        let result = match meta.file_type().is_fifo() {
+            true => unsafe { libc::mkfifo(path.as_ptr(), meta.mode() as libc::mode_t) },
+            false => unsafe {
+                libc::mknod(
+                    path.as_ptr(),
+                    meta.mode() as libc::mode_t,
+                    meta.rdev() as libc::dev_t,
+                )
+            },
+        };
+        if result != 0 {
+            let e = std::io::Error::last_os_error();
+            // Only root can create device nodes
+            if e.raw_os_error() == Some(libc::EPERM) {
+                self.progress.println(format!(
+                    "Skipping {:?}: creating device nodes needs root",
+                    src
+                ));
+                self.progress.on_skipped(1);
+                return Ok(());
+            }
+            return Err(Error::with_path(e, dest));
+        }
+
+        self.copy_owner(&meta, dest);
+        if !self.skip_permissions {
+            let mode = self.chmod.apply(src_meta.mode, false);
+            self.dest_backend().set_permissions(dest, mode)?;
+        }
+        let atime = FileTime::from_last_access_time(&meta);
+        self.set_times(dest, atime, src_meta.mtime)?;
+        self.progress.on_copied(1);
+        self.progress
+            .changed(ChangeKind::Special, dest, 0, differences);
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn sync_special(&self, src: &Path, _dest: &Path) -> Result<()> {
+        Err(Error::Unsupported(format!(
+            "Special files like {:?} can only be synced on unix",
+            src
+        )))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::{temp_fs, Synchronize};
+    use std::{
+        os::unix::{
+            ffi::OsStrExt,
+            fs::{FileTypeExt, MetadataExt},
+        },
+        path::Path,
+    };
+
+    fn mknod(path: &Path, mode: libc::mode_t, rdev: libc::dev_t) -> bool {
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        unsafe { libc::mknod(path.as_ptr(), mode, rdev) == 0 }
+    }
+
+    #[test]
+    fn test_specials_and_devices() {
+        let temp = temp_fs!(input / a: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        assert!(mknod(&input.join("pipe"), libc::S_IFIFO | 0o640, 0));
+        let sync = |specials, devices| {
+            Synchronize::new(&input, &output)
+                .specials(specials)
+                .devices(devices)
+                .sync()
+                .unwrap()
+        };
+        sync(false, false);
+        assert!(output.join("pipe").symlink_metadata().is_err());
+
+        assert_eq!(sync(true, false).copied, 1);
+        let meta = output.join("pipe").symlink_metadata().unwrap();
+        assert!(meta.file_type().is_fifo());
+        assert_eq!(meta.mode() & 0o777, 0o640);
+        assert_eq!(sync(true, false).copied, 0);
+
+        // Device nodes can only be made by root, and not in every container
+        let null = Path::new("/dev/null").metadata().unwrap();
+        let (mode, rdev) = (null.mode() as libc::mode_t, null.rdev() as libc::dev_t);
+        if !mknod(&input.join("null"), mode, rdev) {
+            return;
+        }
+        assert_eq!(sync(false, true).copied, 1);
+        let meta = output.join("null").symlink_metadata().unwrap();
+        assert!(meta.file_type().is_char_device());
+        assert_eq!(meta.rdev(), null.rdev());
+    }
+
+    #[test]
+    fn test_unsupported_types_are_reported() {
+        use crate::Error;
+        use std::os::unix::net::UnixListener;
+
+        let temp = temp_fs!(input / a: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        let _listener = UnixListener::bind(input.join("socket")).unwrap();
+        // Named pipes are unsupported too unless they're recreated
+        assert!(mknod(&input.join("pipe"), libc::S_IFIFO | 0o640, 0));
+        let report = Synchronize::new(&input, &output).sync().unwrap();
+        let mut unsupported = report.unsupported.clone();
+        unsupported.sort();
+        assert_eq!(unsupported, [input.join("pipe"), input.join("socket")]);
+        assert!(output.join("a.text").is_file());
+
+        let report = Synchronize::new(&input, &output)
+            .specials(true)
+            .sync()
+            .unwrap();
+        assert_eq!(report.unsupported, [input.join("socket")]);
+
+        let result = Synchronize::new(&input, &output)
+            .fail_on_unsupported(true)
+            .sync();
+        assert!(matches!(result, Err(Error::UnsupportedType { .. })));
+    }
+}
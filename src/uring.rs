@@ -0,0 +1,131 @@
+//! Copying file data through io_uring, submitting the reads and writes of several blocks at a
+//! time into buffers registered with the kernel once per thread
+
+use crate::copy::{self, OnCopied};
+use io_uring::{opcode, squeue, types, IoUring};
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{self, Seek, SeekFrom},
+    os::unix::{fs::FileExt, io::AsRawFd},
+    path::Path,
+};
+
+/// Size of each registered buffer
+const BLOCK: usize = 256 * 1024;
+
+/// Blocks in flight at once
+const DEPTH: usize = 8;
+
+thread_local! {
+    /// `None` when the kernel or a seccomp policy doesn't allow io_uring
+    static RING: Option<RefCell<Ring>> = Ring::new().ok().map(RefCell::new);
+}
+
+struct Ring {
+    ring: IoUring,
+    buffers: Vec<Vec<u8>>,
+}
+
+impl Ring {
+    fn new() -> io::Result<Self> {
+        let ring = IoUring::new(2 * DEPTH as u32)?;
+        let mut buffers = vec![vec![0; BLOCK]; DEPTH];
+        let iovecs = buffers
+            .iter_mut()
+            .map(|x| libc::iovec {
+                iov_base: x.as_mut_ptr().cast(),
+                iov_len: x.len(),
+            })
+            .collect::<Vec<_>>();
+        // The buffers live as long as the ring and are never reallocated
+        unsafe { ring.submitter().register_buffers(&iovecs)? };
+        Ok(Self { ring, buffers })
+    }
+
+    fn copy(&mut self, src: &File, dest: &Path, on_copied: OnCopied) -> io::Result<u64> {
+        let out = File::create(dest)?;
+        let len = src.metadata()?.len();
+        let (src_fd, out_fd) = (types::Fd(src.as_raw_fd()), types::Fd(out.as_raw_fd()));
+        let mut offset = 0;
+        while offset < len {
+            let blocks = (0..DEPTH as u64)
+                .map(|i| offset + i * BLOCK as u64)
+                .take_while(|x| *x < len)
+                .map(|x| (x, (len - x).min(BLOCK as u64) as u32))
+                .collect::<Vec<_>>();
+            for (i, (at, n)) in blocks.iter().enumerate() {
+                let buffer = self.buffers[i].as_mut_ptr();
+                // Each write only runs once the read into its buffer completed in full
+                let read = opcode::ReadFixed::new(src_fd, buffer, *n, i as u16)
+                    .offset(*at)
+                    .build()
+                    .flags(squeue::Flags::IO_LINK)
+                    .user_data(2 * i as u64);
+                let write = opcode::WriteFixed::new(out_fd, buffer, *n, i as u16)
+                    .offset(*at)
+                    .build()
+                    .user_data(2 * i as u64 + 1);
+                let mut queue = self.ring.submission();
+                unsafe {
+                    queue.push(&read).map_err(io::Error::other)?;
+                    queue.push(&write).map_err(io::Error::other)?;
+                }
+            }
+            self.ring.submit_and_wait(2 * blocks.len())?;
+            let mut written = vec![false; blocks.len()];
+            for entry in self.ring.completion() {
+                let i = (entry.user_data() / 2) as usize;
+                if entry.user_data() % 2 == 1 && entry.result() == blocks[i].1 as i32 {
+                    written[i] = true;
+                }
+            }
+            // Blocks cut short or cancelled are copied again without the ring
+            for (i, (at, n)) in blocks.iter().enumerate().filter(|(i, _)| !written[*i]) {
+                let buffer = &mut self.buffers[i][..*n as usize];
+                src.read_exact_at(buffer, *at)?;
+                out.write_all_at(buffer, *at)?;
+            }
+            let end = blocks.last().map_or(len, |(at, n)| at + *n as u64);
+            on_copied(end - offset);
+            offset = end;
+        }
+        // Anything past the length, such as the contents of files in /proc reporting none
+        let (mut src, mut out) = (src, &out);
+        src.seek(SeekFrom::Start(len))?;
+        out.seek(SeekFrom::Start(len))?;
+        Ok(len + copy::copy_stream(&mut src, &mut out, on_copied)?)
+    }
+}
+
+/// Copy an already open file into `dest` through this thread's ring, or with
+/// [`copy::copy_range`] when io_uring isn't available
+pub(crate) fn copy(src: &File, dest: &Path, on_copied: OnCopied) -> io::Result<u64> {
+    RING.with(|ring| match ring {
+        Some(ring) => ring.borrow_mut().copy(src, dest, on_copied),
+        None => copy::copy_range(src, dest, on_copied),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{temp_fs, CopyEngine, Synchronize};
+
+    #[test]
+    fn test_io_uring_copies_files() {
+        // Spans several rounds of blocks, the last one partly filled
+        const SIZE: usize = super::BLOCK * super::DEPTH * 2 + 1234;
+        let temp = temp_fs!(input / big: SIZE, input / small: 10, input / empty: 0);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        Synchronize::new(&input, &output)
+            .copy_engine(CopyEngine::IoUring)
+            .sync()
+            .unwrap();
+        for name in ["big.text", "small.text", "empty.text"] {
+            assert_eq!(
+                std::fs::read(input.join(name)).unwrap(),
+                std::fs::read(output.join(name)).unwrap()
+            );
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Continuous mirroring, syncing only the source paths reported by file system events
+
+use crate::{webhook, Error, ProgressReporter, Result, Synchronize};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use rayon::ThreadPool;
+use std::{
+    collections::BTreeSet,
+    io,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    time::Duration,
+};
+
+/// Events arriving within this long of each other are synced together
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// How often an idle watch checks whether it was cancelled
+const CANCEL_POLL: Duration = Duration::from_secs(1);
+
+impl Synchronize {
+    /// Sync once, then keep the destination up to date by syncing each path that changes in
+    /// the source. Only returns when watching fails or the sync is cancelled, and only calls
+    /// the webhook when it fails. The post hook runs once it returns.
+    pub fn watch(mut self) -> Result<()> {
+        let Some(url) = self.webhook.take() else {
+            return self.with_hooks(Self::watch_until_stopped, |_| None);
+        };
+        let (src, dest) = (self.src.clone(), self.dest.clone());
+        let result = self.with_hooks(Self::watch_until_stopped, |_| None);
+        if let Err(e) = &result {
+            webhook::notify(&url, &src, &dest, Err(e));
+        }
+        result
+    }
+
+    fn watch_until_stopped(mut self) -> Result<()> {
+        if self.prepare()?.is_some() {
+            return Err(Error::Unsupported(
+                "sftp sources can't be watched".to_string(),
+            ));
+        }
+        let deletes = self.check_deletes()?;
+        let sync = Arc::new(self);
+        let thread_pool = Arc::new(sync.get_thread_pool()?);
+        sync.sync_all(&thread_pool, deletes)?;
+        sync.finish(&thread_pool)?;
+
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        // Events carry absolute paths, so map them back onto the source as it was given
+        let root = sync.src.canonicalize()?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+        let paths = |event: notify::Result<Event>| -> notify::Result<Vec<PathBuf>> {
+            let event = event?;
+            // Reading the source to copy it raises access events, which would sync forever
+            if matches!(event.kind, EventKind::Access(_)) {
+                return Ok(Vec::new());
+            }
+            Ok(event
+                .paths
+                .iter()
+                .filter_map(|path| Some(sync.src.join(path.strip_prefix(&root).ok()?)))
+                .collect())
+        };
+        loop {
+            let mut changed = BTreeSet::new();
+            // Wake up regularly to notice a cancelled token while nothing changes
+            let event = loop {
+                if sync.cancel.is_cancelled() {
+                    return Ok(());
+                }
+                match rx.recv_timeout(CANCEL_POLL) {
+                    Ok(event) => break event,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+                }
+            };
+            changed.extend(paths(event)?);
+            while let Ok(event) = rx.recv_timeout(DEBOUNCE) {
+                changed.extend(paths(event)?);
+            }
+            for path in roots(changed) {
+                if let Err(e) = sync.sync_changed(&thread_pool, &path) {
+                    sync.progress.on_error(&path, &e);
+                }
+            }
+            sync.finish(&thread_pool)?;
+        }
+    }
+
+    /// Bring the destination of a single changed source path up to date
+    fn sync_changed(self: &Arc<Self>, thread_pool: &Arc<ThreadPool>, path: &Path) -> Result<()> {
+        // Paths below a directory whose contents aren't synced, too deep or on another file
+        // system
+        let skipped = path
+            .ancestors()
+            .skip(1)
+            .take_while(|x| x.starts_with(&self.src))
+            .any(|x| self.skips_contents(x));
+        if !path.starts_with(&self.src) || skipped || self.is_excluded_below(path) {
+            return Ok(());
+        }
+        let dest = self.get_destination_path(path);
+        let meta = match self.source_metadata(path) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if self.delete && self.dest_backend().metadata(&dest)?.is_some() {
+                    thread_pool.install(|| self.remove_all(&dest))?;
+                }
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if meta.file_type().is_symlink() {
+            self.sync_symlink(path)
+        } else if meta.is_dir() {
+            let dirs = self.sync_tree(thread_pool, path)?;
+            self.finish_dirs(dirs)
+        } else {
+            self.progress.on_scanned(1);
+            self.sync_file(path, &dest)
+        }
+    }
+}
+
+/// Drop paths whose parent directory is also in the set, as syncing the parent covers them
+fn roots(paths: BTreeSet<PathBuf>) -> Vec<PathBuf> {
+    let mut roots: Vec<PathBuf> = Vec::new();
+    for path in paths {
+        // Sorted order puts a directory right before everything below it
+        if roots.last().is_some_and(|x| path.starts_with(x)) {
+            continue;
+        }
+        roots.push(path);
+    }
+    roots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::roots;
+    use crate::{temp_fs, Synchronize};
+    use std::{collections::BTreeSet, path::PathBuf, sync::Arc};
+
+    #[test]
+    fn test_roots_skips_nested_paths() {
+        let paths = ["a/b", "a", "a/b/c", "ab", "c/d"]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<BTreeSet<_>>();
+        assert_eq!(
+            roots(paths),
+            vec![
+                PathBuf::from("a"),
+                PathBuf::from("ab"),
+                PathBuf::from("c/d")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sync_changed_paths() {
+        let temp = temp_fs!(input / bar: 4, input / old: 4);
+        let mut sync = Synchronize::new(temp.path().join("input"), temp.path().join("output"))
+            .exclude("*.tmp")
+            .delete(true);
+        assert!(sync.prepare().unwrap().is_none());
+        let sync = Arc::new(sync);
+        let pool = Arc::new(sync.get_thread_pool().unwrap());
+        sync.sync_tree(&pool, &sync.src).unwrap();
+
+        let input = temp.path().join("input");
+        std::fs::write(input.join("bar.text"), b"changed").unwrap();
+        std::fs::write(input.join("skip.tmp"), b"").unwrap();
+        std::fs::create_dir(input.join("new")).unwrap();
+        std::fs::write(input.join("new/baz.text"), b"").unwrap();
+        std::fs::remove_file(input.join("old.text")).unwrap();
+        for name in ["bar.text", "skip.tmp", "new", "old.text"] {
+            sync.sync_changed(&pool, &input.join(name)).unwrap();
+        }
+
+        let output = temp.path().join("output");
+        assert_eq!(std::fs::read(output.join("bar.text")).unwrap(), b"changed");
+        assert!(output.join("new/baz.text").exists());
+        assert!(!output.join("skip.tmp").exists());
+        assert!(!output.join("old.text").exists());
+    }
+}
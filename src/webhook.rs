@@ -0,0 +1,134 @@
+//! Posting the outcome of a sync to a URL, so backup jobs can alert chat or health check
+//! services without a wrapper script
+
+use crate::{Error, SyncReport};
+use std::{io, path::Path, time::Duration};
+
+/// How long the webhook has to answer before it's given up on
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The JSON body posted for a sync of `src` to `dest` that finished with `outcome`. The
+/// `status` is "finished", "cancelled" or "failed", with the report of syncs that ran to the
+/// end, even when some paths failed, and the error of those that didn't.
+fn payload(src: &Path, dest: &Path, outcome: Result<&SyncReport, &Error>) -> String {
+    let mut body = serde_json::json!({
+        "source": src.to_string_lossy(),
+        "destination": dest.to_string_lossy(),
+    });
+    match outcome {
+        Ok(report) => body["report"] = report.json(),
+        Err(e) => body["error"] = e.to_string().into(),
+    }
+    body["status"] = status(outcome).into();
+    body.to_string()
+}
+
+/// How a sync ended: "finished", "cancelled" or "failed". A sync that ran to the end but
+/// couldn't sync some paths failed, like its exit code says.
+pub(crate) fn status(outcome: Result<&SyncReport, &Error>) -> &'static str {
+    match outcome {
+        Ok(report) if report.cancelled => "cancelled",
+        Ok(report) if !report.errors.is_empty() => "failed",
+        Ok(_) => "finished",
+        Err(_) => "failed",
+    }
+}
+
+/// Post the outcome of a sync to `url`, only warning when that fails as the sync itself is
+/// done
+pub(crate) fn notify(url: &str, src: &Path, dest: &Path, outcome: Result<&SyncReport, &Error>) {
+    if let Err(e) = post(url, src, dest, outcome) {
+        eprintln!("Failed to call the webhook {}: {}", url, e);
+    }
+}
+
+fn post(
+    url: &str,
+    src: &Path,
+    dest: &Path,
+    outcome: Result<&SyncReport, &Error>,
+) -> io::Result<()> {
+    let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+    let request = agent
+        .post(url)
+        .set("Content-Type", "application/json")
+        .send_string(&payload(src, dest, outcome));
+    match request {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(status, _)) => Err(io::Error::other(format!(
+            "webhook answered with status {}",
+            status
+        ))),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{temp_fs, Synchronize};
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+    };
+
+    /// Answer a single request on `listener`, returning its body
+    fn receive(listener: TcpListener) -> serde_json::Value {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut len = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                len = value.trim().parse().unwrap();
+            }
+            if line == "\r\n" {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).unwrap();
+        let response = "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n";
+        reader.get_mut().write_all(response.as_bytes()).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn test_webhook_receives_the_outcome() {
+        let temp = temp_fs!(input / a: 4);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server =
+            std::thread::spawn(move || (receive(listener.try_clone().unwrap()), receive(listener)));
+
+        Synchronize::new(temp.path().join("input"), temp.path().join("output"))
+            .webhook(Some(&url))
+            .sync()
+            .unwrap();
+        assert!(
+            Synchronize::new(temp.path().join("missing"), temp.path().join("output"))
+                .webhook(Some(&url))
+                .sync()
+                .is_err()
+        );
+
+        let (finished, failed) = server.join().unwrap();
+        assert_eq!(finished["status"], "finished");
+        assert_eq!(finished["report"]["copied"], 1);
+        assert_eq!(failed["status"], "failed");
+        assert!(failed["error"].is_string());
+    }
+
+    #[test]
+    fn test_errors_fail_the_sync() {
+        use crate::{FileError, SyncReport};
+
+        let mut report = SyncReport::default();
+        assert_eq!(super::status(Ok(&report)), "finished");
+        report.errors.push(FileError {
+            path: "a".into(),
+            cause: "denied".to_string(),
+        });
+        assert_eq!(super::status(Ok(&report)), "failed");
+    }
+}
@@ -0,0 +1,284 @@
+//! Extended attributes of local files, read and written without following symlinks
+
+use std::{ffi::OsStr, io, path::Path};
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn to_cstring(bytes: &[u8]) -> io::Result<std::ffi::CString> {
+    std::ffi::CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn path_cstring(path: &Path) -> io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    to_cstring(path.as_os_str().as_bytes())
+}
+
+/// Whether `e` means the attribute isn't set
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn is_missing(e: &io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    let missing = libc::ENODATA;
+    #[cfg(target_os = "macos")]
+    let missing = libc::ENOATTR;
+    e.raw_os_error() == Some(missing)
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn get_raw(path: &std::ffi::CStr, name: &std::ffi::CStr, buf: &mut [u8]) -> isize {
+    libc::lgetxattr(
+        path.as_ptr(),
+        name.as_ptr(),
+        buf.as_mut_ptr().cast(),
+        buf.len(),
+    )
+}
+
+#[cfg(target_os = "macos")]
+unsafe fn get_raw(path: &std::ffi::CStr, name: &std::ffi::CStr, buf: &mut [u8]) -> isize {
+    libc::getxattr(
+        path.as_ptr(),
+        name.as_ptr(),
+        buf.as_mut_ptr().cast(),
+        buf.len(),
+        0,
+        libc::XATTR_NOFOLLOW,
+    )
+}
+
+/// The value of the attribute `name` of `path`, or none when it isn't set
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn get(path: &Path, name: impl AsRef<OsStr>) -> io::Result<Option<Vec<u8>>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let (path, name) = (path_cstring(path)?, to_cstring(name.as_ref().as_bytes())?);
+    loop {
+        // Ask for the size first, and again if the value grew in between
+        let len = unsafe { get_raw(&path, &name, &mut []) };
+        if len < 0 {
+            let e = io::Error::last_os_error();
+            return if is_missing(&e) { Ok(None) } else { Err(e) };
+        }
+        let mut value = vec![0; len as usize];
+        match unsafe { get_raw(&path, &name, &mut value) } {
+            len if len >= 0 => {
+                value.truncate(len as usize);
+                return Ok(Some(value));
+            }
+            _ => {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::ERANGE) => continue,
+                    _ if is_missing(&e) => return Ok(None),
+                    _ => return Err(e),
+                }
+            }
+        }
+    }
+}
+
+/// Set the attribute `name` of `path` to `value`
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn set(path: &Path, name: impl AsRef<OsStr>, value: &[u8]) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let (path, name) = (path_cstring(path)?, to_cstring(name.as_ref().as_bytes())?);
+    #[cfg(target_os = "linux")]
+    let result = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    #[cfg(target_os = "macos")]
+    let result = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+            libc::XATTR_NOFOLLOW,
+        )
+    };
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Names of the attributes of `path`
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn list(path: &Path) -> io::Result<Vec<std::ffi::OsString>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = path_cstring(path)?;
+    #[cfg(target_os = "linux")]
+    let list_raw = |buf: &mut [u8]| unsafe {
+        libc::llistxattr(path.as_ptr(), buf.as_mut_ptr().cast(), buf.len())
+    };
+    #[cfg(target_os = "macos")]
+    let list_raw = |buf: &mut [u8]| unsafe {
+        libc::listxattr(
+            path.as_ptr(),
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+            libc::XATTR_NOFOLLOW,
+        )
+    };
+    loop {
+        let len = list_raw(&mut []);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut names = vec![0; len as usize];
+        match list_raw(&mut names) {
+            len if len >= 0 => {
+                names.truncate(len as usize);
+                return Ok(names
+                    .split(|x| *x == 0)
+                    .filter(|x| !x.is_empty())
+                    .map(|x| OsStr::from_bytes(x).to_os_string())
+                    .collect());
+            }
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.raw_os_error() != Some(libc::ERANGE) {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+/// Remove the attribute `name` of `path`
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn remove(path: &Path, name: &OsStr) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let (path, name) = (path_cstring(path)?, to_cstring(name.as_bytes())?);
+    #[cfg(target_os = "linux")]
+    let result = unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) };
+    #[cfg(target_os = "macos")]
+    let result = unsafe { libc::removexattr(path.as_ptr(), name.as_ptr(), libc::XATTR_NOFOLLOW) };
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Attributes holding the POSIX ACL of a file and the default ACL of a directory on Linux
+const ACLS: [&str; 2] = ["system.posix_acl_access", "system.posix_acl_default"];
+
+/// Whether the attribute `name` is copied with `--xattrs`. On Linux those are the user and
+/// trusted namespaces, as system attributes such as ACLs and security labels have their own
+/// meaning, while macOS has no namespaces.
+pub(crate) fn is_user(name: &OsStr) -> bool {
+    let name = name.as_encoded_bytes();
+    cfg!(target_os = "macos") || name.starts_with(b"user.") || name.starts_with(b"trusted.")
+}
+
+/// Whether the attribute `name` holds an ACL
+pub(crate) fn is_acl(name: &OsStr) -> bool {
+    ACLS.iter().any(|x| name == *x)
+}
+
+/// Give `dest` the attributes of `src` that `copied` selects, removing the selected ones
+/// `src` doesn't have
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn copy(src: &Path, dest: &Path, copied: impl Fn(&OsStr) -> bool) -> io::Result<()> {
+    let names = list(src)?
+        .into_iter()
+        .filter(|x| copied(x))
+        .collect::<Vec<_>>();
+    for name in list(dest)?.into_iter().filter(|x| copied(x)) {
+        if !names.contains(&name) {
+            remove(dest, &name)?;
+        }
+    }
+    for name in names {
+        // An attribute removed since it was listed has nothing to copy
+        if let Some(value) = get(src, &name)? {
+            set(dest, &name, &value)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn copy(_src: &Path, _dest: &Path, _copied: impl Fn(&OsStr) -> bool) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn get(_path: &Path, _name: impl AsRef<OsStr>) -> io::Result<Option<Vec<u8>>> {
+    Err(unsupported())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn set(_path: &Path, _name: impl AsRef<OsStr>, _value: &[u8]) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "extended attributes are only available on Linux and macOS",
+    )
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "macos")))]
+mod tests {
+    use super::{get, list, set};
+    use crate::{temp_fs, Synchronize};
+
+    #[test]
+    fn test_xattrs_are_copied() {
+        let temp = temp_fs!(input / sub / a: 4, output / sub / b: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        set(&input.join("sub/a.text"), "user.origin", b"camera").unwrap();
+        set(&input.join("sub"), "user.tag", b"photos").unwrap();
+        set(&output.join("sub"), "user.stale", b"").unwrap();
+        Synchronize::new(&input, &output)
+            .xattrs(true)
+            .sync()
+            .unwrap();
+        assert_eq!(
+            get(&output.join("sub/a.text"), "user.origin").unwrap(),
+            Some(b"camera".to_vec())
+        );
+        // Directories that already existed lose the attributes the source doesn't have
+        assert_eq!(list(&output.join("sub")).unwrap(), ["user.tag"]);
+        assert_eq!(get(&output.join("sub"), "user.missing").unwrap(), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_acls_are_copied() {
+        let temp = temp_fs!(input / a: 4);
+        let (input, output) = (temp.path().join("input"), temp.path().join("output"));
+        // An ACL giving user 1000 read access, in the format the kernel stores it
+        let mut acl = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in [(1u16, 6u16, u32::MAX), (2, 4, 1000), (4, 4, u32::MAX)]
+            .into_iter()
+            .chain([(0x10, 4, u32::MAX), (0x20, 4, u32::MAX)])
+        {
+            acl.extend(tag.to_le_bytes());
+            acl.extend(perm.to_le_bytes());
+            acl.extend(id.to_le_bytes());
+        }
+        let src = input.join("a.text");
+        if set(&src, "system.posix_acl_access", &acl).is_err() {
+            // The file system of the temporary directory doesn't support ACLs
+            return;
+        }
+        set(&src, "user.tag", b"x").unwrap();
+        Synchronize::new(&input, &output).acls(true).sync().unwrap();
+        let dest = output.join("a.text");
+        assert_eq!(get(&dest, "system.posix_acl_access").unwrap(), Some(acl));
+        assert_eq!(get(&dest, "user.tag").unwrap(), None);
+    }
+}
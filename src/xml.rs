@@ -0,0 +1,95 @@
+//! Minimal XML document tree for the listings returned by HTTP backends
+
+use crate::encoding::invalid_data;
+use quick_xml::{escape::unescape, events::Event, Reader};
+use std::io;
+
+/// An element with its namespace prefix stripped and its text content concatenated
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Element {
+    pub(crate) name: String,
+    pub(crate) text: String,
+    pub(crate) children: Vec<Element>,
+}
+
+impl Element {
+    pub(crate) fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|x| x.name == name)
+    }
+
+    pub(crate) fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.children.iter().filter(move |x| x.name == name)
+    }
+
+    /// Text of the first child called `name`
+    pub(crate) fn child_text(&self, name: &str) -> Option<&str> {
+        self.child(name).map(|x| x.text.as_str())
+    }
+}
+
+/// Parse a document, returning its root element
+pub(crate) fn parse(xml: &str) -> io::Result<Element> {
+    let mut reader = Reader::from_str(xml);
+    let mut stack = vec![Element::default()];
+    let error = |e: quick_xml::Error| invalid_data(&e.to_string());
+    loop {
+        match reader.read_event().map_err(error)? {
+            Event::Start(e) => stack.push(Element {
+                name: e.local_name().as_ref().to_string(),
+                ..Element::default()
+            }),
+            Event::Empty(e) => {
+                let element = Element {
+                    name: e.local_name().as_ref().to_string(),
+                    ..Element::default()
+                };
+                stack.last_mut().unwrap().children.push(element);
+            }
+            Event::End(_) => {
+                let element = stack.pop().unwrap();
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => return Err(invalid_data("unbalanced xml end tag")),
+                }
+            }
+            Event::Text(e) => stack.last_mut().unwrap().text += &e.xml10_content(),
+            Event::CData(e) => stack.last_mut().unwrap().text += &e.xml10_content(),
+            Event::GeneralRef(e) => {
+                let text = unescape(&format!("&{};", &*e))
+                    .map_err(|e| invalid_data(&e.to_string()))?
+                    .into_owned();
+                stack.last_mut().unwrap().text += &text;
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    match stack.pop().map(|x| x.children) {
+        Some(mut children) if stack.is_empty() && children.len() == 1 => Ok(children.remove(0)),
+        _ => Err(invalid_data("xml document has no single root element")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn test_parse_namespaced_document() {
+        let root = parse(
+            r#"<?xml version="1.0"?>
+            <d:multistatus xmlns:d="DAV:">
+                <d:response><d:href>/a&amp;b</d:href><d:collection/></d:response>
+                <d:response><d:href><![CDATA[/c]]></d:href></d:response>
+            </d:multistatus>"#,
+        )
+        .unwrap();
+        assert_eq!(root.name, "multistatus");
+        let hrefs = root
+            .children("response")
+            .map(|x| x.child_text("href").unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(hrefs, vec!["/a&b", "/c"]);
+        assert!(root.children[0].child("collection").is_some());
+    }
+}